@@ -8,6 +8,7 @@ pub enum EventType {
     EV_SYN = 0x0,
     EV_KEY = 0x1,
     EV_REL = 0x2,
+    EV_ABS = 0x3,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, enumn::N)]
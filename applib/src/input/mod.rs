@@ -54,6 +54,12 @@ impl InputState {
         }
     }
 
+    // Keeps the pointer on screen after the display size changed
+    pub fn set_screen_size(&mut self, w: u32, h: u32) {
+        self.pointer.x = i64::max(0, i64::min(w as i64 - 1, self.pointer.x));
+        self.pointer.y = i64::max(0, i64::min(h as i64 - 1, self.pointer.y));
+    }
+
     pub fn change_origin(&mut self, origin: Point2D<i64>) {
         self.pointer.x -= origin.x;
         self.pointer.y -= origin.y;
@@ -99,3 +105,13 @@ pub enum InputEvent {
     KeyRelease { keycode: Keycode },
     Scroll { delta: i64 },
 }
+
+// Pointer sprite shown by the kernel. Apps can request one while the pointer is over their window.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, enumn::N)]
+#[repr(u32)]
+pub enum CursorShape {
+    Arrow = 0,
+    Move = 1,
+    Resize = 2,
+    IBeam = 3,
+}
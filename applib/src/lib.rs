@@ -9,6 +9,7 @@ pub mod drawing;
 pub mod geometry;
 pub mod hash;
 pub mod input;
+pub mod logging;
 pub mod uitk;
 mod stylesheet;
 
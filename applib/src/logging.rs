@@ -0,0 +1,90 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use log::Level;
+
+// A kernel or app log record, as exposed to WASM apps by host_read_log()
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub seq: u64,
+    pub timestamp: f64, // milliseconds since UNIX epoch
+    pub level: Level,
+    pub module: String,
+    pub app_name: Option<String>,
+    pub message: String,
+}
+
+impl LogRecord {
+
+    //
+    // Serialized layout (little-endian):
+    //   seq: u64, timestamp: f64, level: u8,
+    //   then module, app name and message as (len: u32, UTF-8 bytes)
+    //   An empty app name means the record comes from the kernel itself
+
+    pub fn serialized_len(&self) -> usize {
+        let app_name_len = self.app_name.as_ref().map(|s| s.len()).unwrap_or(0);
+        8 + 8 + 1 + 3 * 4 + self.module.len() + app_name_len + self.message.len()
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.seq.to_le_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+        out.push(self.level as u8);
+
+        let app_name = self.app_name.as_deref().unwrap_or("");
+        for s in [self.module.as_str(), app_name, self.message.as_str()] {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+
+    // Returns the record and the number of bytes read, or None if the buffer
+    // doesn't start with a complete and valid record
+    pub fn deserialize(buf: &[u8]) -> Option<(LogRecord, usize)> {
+
+        let mut pos = 0;
+
+        let mut take = |n: usize| -> Option<&[u8]> {
+            let bytes = buf.get(pos..pos + n)?;
+            pos += n;
+            Some(bytes)
+        };
+
+        let seq = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let timestamp = f64::from_le_bytes(take(8)?.try_into().ok()?);
+        let level = level_from_u8(take(1)?[0])?;
+
+        let mut strings: [String; 3] = Default::default();
+        for s in strings.iter_mut() {
+            let len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            *s = String::from(core::str::from_utf8(take(len)?).ok()?);
+        }
+
+        let [module, app_name, message] = strings;
+
+        let record = LogRecord {
+            seq,
+            timestamp,
+            level,
+            module,
+            app_name: match app_name.is_empty() {
+                true => None,
+                false => Some(app_name),
+            },
+            message,
+        };
+
+        Some((record, pos))
+    }
+}
+
+fn level_from_u8(val: u8) -> Option<Level> {
+    match val {
+        1 => Some(Level::Error),
+        2 => Some(Level::Warn),
+        3 => Some(Level::Info),
+        4 => Some(Level::Debug),
+        5 => Some(Level::Trace),
+        _ => None,
+    }
+}
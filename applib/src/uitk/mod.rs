@@ -133,6 +133,11 @@ impl UiStore {
             font_family: &DEFAULT_FONT_FAMILY,
         }
     }
+
+    // Drops all cached tiles, e.g when the system is running low on memory
+    pub fn evict_caches(&mut self) {
+        self.tile_cache.tiles.clear();
+    }
 }
 
 pub struct IconStore {
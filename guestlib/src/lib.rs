@@ -4,7 +4,9 @@ extern crate alloc;
 use alloc::format;
 use alloc::vec;
 use alloc::vec::Vec;
+use applib::logging::LogRecord;
 use applib::StyleSheet;
+use applib::input::CursorShape;
 use applib::{input::InputState, BorrowedMutPixels, Framebuffer, Rect, Color};
 use core::fmt::Debug;
 use core::mem::size_of;
@@ -19,6 +21,7 @@ extern "C" {
     fn host_get_input_state(addr: i32);
     fn host_get_win_rect(addr: i32);
     fn host_set_framebuffer(addr: i32, w: i32, h: i32);
+    fn host_set_cursor_shape(shape: i32);
 
     fn host_tcp_connect(ip_addr: i32, port: i32) -> i32;
     fn host_tcp_may_send(handle_id: i32) -> i32;
@@ -26,6 +29,12 @@ extern "C" {
     fn host_tcp_write(addr: i32, len: i32, handle_id: i32) -> i32;
     fn host_tcp_read(addr: i32, len: i32, handle_id: i32) -> i32;
     fn host_tcp_close(handle_id: i32);
+
+    fn host_vsock_connect(cid: i32, port: i32) -> i32;
+    fn host_vsock_write(addr: i32, len: i32, handle_id: i32) -> i32;
+    fn host_vsock_read(addr: i32, len: i32, handle_id: i32) -> i32;
+    fn host_vsock_close(handle_id: i32);
+
     fn host_get_time(buf: i32);
     fn host_get_stylesheet(buf: i32);
 
@@ -33,6 +42,8 @@ extern "C" {
     fn host_save_timing(key_addr: i32, key_len: i32, consumed_addr: i32);
 
     fn host_qemu_dump(addr: i32, len: i32);
+
+    fn host_read_log(since_seq: i64, buf_addr: i32, buf_len: i32) -> i32;
 }
 
 #[derive(Debug)]
@@ -85,6 +96,11 @@ pub fn get_win_rect() -> Rect {
     }
 }
 
+// Shape of the pointer while it is over the app window, kept until changed
+pub fn set_cursor_shape(shape: CursorShape) {
+    unsafe { host_set_cursor_shape(shape as i32) };
+}
+
 pub struct PixelData {
     fb_handle: FramebufferHandle,
 }
@@ -169,6 +185,56 @@ pub fn tcp_close(handle_id: i32) {
     unsafe { host_tcp_close(handle_id) }
 }
 
+// Context ID of the host, for vsock_connect()
+pub const VSOCK_HOST_CID: u32 = 2;
+
+// The connection is established asynchronously, vsock_write() returns 0 until then
+pub fn vsock_connect(cid: u32, port: u32) -> anyhow::Result<i32> {
+    let retval = unsafe { host_vsock_connect(cid as i32, port as i32) };
+
+    if retval < 0 {
+        Err(anyhow::Error::msg("vsock connect failed"))
+    } else {
+        let handle_id = retval;
+        Ok(handle_id)
+    }
+}
+
+pub fn vsock_write(buf: &[u8], handle_id: i32) -> anyhow::Result<usize> {
+    let retval = unsafe {
+        let addr = buf.as_ptr() as i32;
+        let len = buf.len() as i32;
+        host_vsock_write(addr, len, handle_id)
+    };
+
+    if retval < 0 {
+        Err(anyhow::Error::msg("vsock write failed"))
+    } else {
+        let written_len = retval.try_into().map_err(anyhow::Error::msg)?;
+        Ok(written_len)
+    }
+}
+
+// Fails once the peer has closed the connection and everything it sent has been read
+pub fn vsock_read(buf: &mut [u8], handle_id: i32) -> anyhow::Result<usize> {
+    let retval = unsafe {
+        let addr = buf.as_ptr() as i32;
+        let len = buf.len() as i32;
+        host_vsock_read(addr, len, handle_id)
+    };
+
+    if retval < 0 {
+        Err(anyhow::Error::msg("vsock read failed"))
+    } else {
+        let read_len = retval.try_into().map_err(anyhow::Error::msg)?;
+        Ok(read_len)
+    }
+}
+
+pub fn vsock_close(handle_id: i32) {
+    unsafe { host_vsock_close(handle_id) }
+}
+
 pub fn get_time() -> f64 {
     let mut buf = [0u8; 8];
     unsafe { host_get_time(buf.as_mut_ptr() as i32);}
@@ -235,6 +301,34 @@ pub fn qemu_dump(buf: &[u8]) {
     unsafe { host_qemu_dump(addr, len) };
 }
 
+// Returns all the kernel log records with a sequence number >= since_seq
+pub fn read_log(since_seq: u64) -> Vec<LogRecord> {
+    const BUF_SIZE: usize = 64 * 1024;
+
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut records = Vec::new();
+    let mut next_seq = since_seq;
+
+    loop {
+        let len = unsafe {
+            host_read_log(next_seq as i64, buf.as_mut_ptr() as i32, BUF_SIZE as i32)
+        };
+
+        if len <= 0 {
+            break;
+        }
+
+        let mut data = &buf[..len as usize];
+        while let Some((record, record_len)) = LogRecord::deserialize(data) {
+            next_seq = record.seq + 1;
+            records.push(record);
+            data = &data[record_len..];
+        }
+    }
+
+    records
+}
+
 pub struct WasmLogger;
 
 impl Log for WasmLogger {
@@ -0,0 +1,497 @@
+use alloc::vec::Vec;
+use anyhow::bail;
+use core::convert::Infallible;
+use spin::Once;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+use uefi::table::{Boot, SystemTable};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const SDT_HEADER_LEN: usize = 36;
+
+// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_EN: u16 = 1 << 13;
+
+// FADT flag telling that RESET_REG is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+// FADT flag telling that the PM timer is 32 bits wide instead of 24
+const TMR_VAL_EXT: u32 = 1 << 8;
+
+// Generic Address Structure address spaces
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+static ACPI: Once<AcpiInfo> = Once::new();
+
+struct AcpiInfo {
+    revision: u8,
+    tables: Vec<&'static [u8]>,
+    power: PowerControl,
+}
+
+#[derive(Debug, Clone)]
+struct PowerControl {
+    smi_cmd: u16,
+    acpi_enable: u8,
+    pm1a_cnt: u16,
+    pm1b_cnt: Option<u16>,
+    s5: Option<SleepType>,
+    reset_reg: Option<ResetRegister>,
+}
+
+// SLP_TYPa/b values for a sleep state, found in the \_Sx_ objects of the DSDT
+#[derive(Debug, Clone, Copy)]
+struct SleepType {
+    typ_a: u8,
+    typ_b: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ResetRegister {
+    Io { port: u16, value: u8 },
+    Memory { addr: u64, value: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    pub base_addr: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PmTimer {
+    pub port: u16,
+    pub extended: bool,
+}
+
+// Must be called before exiting boot services, since the UEFI configuration table
+// is where the firmware tells us where the ACPI tables are
+pub fn find_rsdp(system_table: &SystemTable<Boot>) -> Option<PhysAddr> {
+
+    let config_table = system_table.config_table();
+
+    // Preferring the ACPI 2.0 RSDP, which points to the 64-bit XSDT
+    [ACPI2_GUID, ACPI_GUID].iter().find_map(|guid| {
+        config_table
+            .iter()
+            .find(|entry| entry.guid == *guid)
+            .map(|entry| PhysAddr::new(entry.address as u64))
+    })
+}
+
+pub fn init(rsdp_addr: Option<PhysAddr>) {
+
+    let Some(rsdp_addr) = rsdp_addr else {
+        log::warn!("No ACPI RSDP found, power management unavailable");
+        return;
+    };
+
+    let rsdp = unsafe { phys_slice(rsdp_addr.as_u64(), 36) };
+    assert_eq!(&rsdp[..8], b"RSD PTR ", "Invalid RSDP signature");
+
+    let revision = rsdp[15];
+
+    // ACPI 1.0 only has the RSDT, with 32-bit table pointers
+    let (root_addr, entry_size) = match revision {
+        0 => (read_u32(rsdp, 16) as u64, 4),
+        _ => (read_u64(rsdp, 24), 8),
+    };
+
+    let root = unsafe { load_table(root_addr) }.expect("Invalid ACPI root table");
+
+    let tables: Vec<&'static [u8]> = root[SDT_HEADER_LEN..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            4 => read_u32(entry, 0) as u64,
+            _ => read_u64(entry, 0),
+        })
+        .filter_map(|addr| unsafe { load_table(addr) })
+        .collect();
+
+    let signatures: Vec<&str> = tables
+        .iter()
+        .map(|table| core::str::from_utf8(&table[..4]).unwrap_or("????"))
+        .collect();
+    log::info!("ACPI revision {}, tables: {}", revision, signatures.join(" "));
+
+    let fadt = *tables
+        .iter()
+        .find(|table| &table[..4] == b"FACP")
+        .expect("No FADT in ACPI tables");
+
+    let power = parse_fadt(fadt, &tables);
+    log::debug!("ACPI power control: {:x?}", power);
+
+    ACPI.call_once(|| AcpiInfo { revision, tables, power });
+}
+
+// Returns the whole table (including its header), e.g b"APIC" for the MADT
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let acpi = ACPI.r#try()?;
+    acpi.tables.iter().find(|table| &table[..4] == signature).copied()
+}
+
+// APIC IDs of the usable processors listed in the MADT, including the boot CPU
+pub fn local_apic_ids() -> Vec<u8> {
+
+    const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+    const ENTRY_LOCAL_APIC: u8 = 0;
+    const FLAG_ENABLED: u32 = 1 << 0;
+    const FLAG_ONLINE_CAPABLE: u32 = 1 << 1;
+
+    let Some(madt) = find_table(b"APIC") else {
+        return Vec::new();
+    };
+
+    let mut apic_ids = Vec::new();
+    let mut i = MADT_ENTRIES_OFFSET;
+    while i + 2 <= madt.len() {
+        let (entry_type, entry_len) = (madt[i], madt[i + 1] as usize);
+        if entry_len < 2 || i + entry_len > madt.len() {
+            break;
+        }
+        if entry_type == ENTRY_LOCAL_APIC && entry_len >= 8 {
+            let flags = read_u32(madt, i + 4);
+            if flags & (FLAG_ENABLED | FLAG_ONLINE_CAPABLE) != 0 {
+                apic_ids.push(madt[i + 3]);
+            }
+        }
+        i += entry_len;
+    }
+
+    apic_ids
+}
+
+// PCIe enhanced configuration space (ECAM) ranges listed in the MCFG
+pub fn ecam_regions() -> Vec<EcamRegion> {
+
+    const MCFG_ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+    const ENTRY_LEN: usize = 16;
+
+    let Some(mcfg) = find_table(b"MCFG") else {
+        return Vec::new();
+    };
+
+    (MCFG_ENTRIES_OFFSET..)
+        .step_by(ENTRY_LEN)
+        .take_while(|i| i + ENTRY_LEN <= mcfg.len())
+        .map(|i| EcamRegion {
+            base_addr: read_u64(mcfg, i),
+            segment: u16::from_le_bytes([mcfg[i + 8], mcfg[i + 9]]),
+            start_bus: mcfg[i + 10],
+            end_bus: mcfg[i + 11],
+        })
+        .collect()
+}
+
+// Physical address of the HPET registers, from the HPET table
+pub fn hpet_base() -> Option<u64> {
+
+    const ADDRESS_OFFSET: usize = SDT_HEADER_LEN + 4;
+
+    let hpet = find_table(b"HPET")?;
+    if hpet.len() < ADDRESS_OFFSET + 12 || hpet[ADDRESS_OFFSET] != GAS_SYSTEM_MEMORY {
+        return None;
+    }
+
+    match read_u64(hpet, ADDRESS_OFFSET + 4) {
+        0 => None,
+        addr => Some(addr),
+    }
+}
+
+// The ACPI power management timer, a free-running 3.579545MHz counter
+pub fn pm_timer() -> Option<PmTimer> {
+
+    let fadt = find_table(b"FACP")?;
+
+    // X_PM_TMR_BLK takes precedence over the 32-bit PM_TMR_BLK
+    let x_port = match fadt.len() >= 220 && fadt[208] == GAS_SYSTEM_IO {
+        true => read_u64(fadt, 212),
+        false => 0,
+    };
+    let port = match x_port {
+        0 => read_u32(fadt, 76) as u64,
+        port => port,
+    };
+
+    match port {
+        0 => None,
+        port => Some(PmTimer {
+            port: port as u16,
+            extended: read_u32(fadt, 112) & TMR_VAL_EXT != 0,
+        }),
+    }
+}
+
+#[allow(dead_code)]
+pub fn revision() -> Option<u8> {
+    ACPI.r#try().map(|acpi| acpi.revision)
+}
+
+pub fn shutdown_supported() -> bool {
+    ACPI.r#try().map(|acpi| acpi.power.s5.is_some()).unwrap_or(false)
+}
+
+// ACPI S5 "soft off" state. Only returns if that failed.
+pub fn shutdown() -> anyhow::Result<Infallible> {
+
+    let Some(acpi) = ACPI.r#try() else {
+        bail!("ACPI not initialized");
+    };
+    let power = &acpi.power;
+    let Some(s5) = power.s5 else {
+        bail!("No \\_S5_ object in ACPI tables");
+    };
+
+    log::info!("Shutting down");
+
+    x86_64::instructions::interrupts::disable();
+
+    unsafe {
+        power.enable_acpi();
+
+        let mut pm1a = Port::<u16>::new(power.pm1a_cnt);
+        let val = pm1a.read() & !(0b111 << 10);
+        pm1a.write(val | ((s5.typ_a as u16) << 10) | SLP_EN);
+
+        if let Some(pm1b_cnt) = power.pm1b_cnt {
+            let mut pm1b = Port::<u16>::new(pm1b_cnt);
+            let val = pm1b.read() & !(0b111 << 10);
+            pm1b.write(val | ((s5.typ_b as u16) << 10) | SLP_EN);
+        }
+    }
+
+    delay();
+
+    x86_64::instructions::interrupts::enable();
+
+    bail!("ACPI shutdown had no effect");
+}
+
+pub fn reboot() -> ! {
+
+    log::info!("Rebooting");
+
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(reset_reg) = ACPI.r#try().and_then(|acpi| acpi.power.reset_reg) {
+        unsafe {
+            match reset_reg {
+                ResetRegister::Io { port, value } => Port::<u8>::new(port).write(value),
+                ResetRegister::Memory { addr, value } => {
+                    let ptr = memory::get_mapper().phys_to_virt(PhysAddr::new(addr)).as_mut_ptr::<u8>();
+                    core::ptr::write_volatile(ptr, value);
+                }
+            }
+        }
+        delay();
+        log::warn!("ACPI reset register had no effect");
+    }
+
+    unsafe {
+        // PCI reset control register (hard reset)
+        Port::<u8>::new(0xCF9).write(0x06);
+        delay();
+
+        // Pulsing the CPU reset line through the 8042 keyboard controller
+        Port::<u8>::new(0x64).write(0xFE);
+        delay();
+    }
+
+    panic!("Reboot failed");
+}
+
+impl PowerControl {
+    unsafe fn enable_acpi(&self) {
+
+        let mut pm1a = Port::<u16>::new(self.pm1a_cnt);
+
+        // Already in ACPI mode (always the case with UEFI firmwares in practice)
+        if pm1a.read() & SCI_EN != 0 || self.smi_cmd == 0 || self.acpi_enable == 0 {
+            return;
+        }
+
+        Port::<u8>::new(self.smi_cmd).write(self.acpi_enable);
+
+        for _ in 0..1000 {
+            if pm1a.read() & SCI_EN != 0 {
+                return;
+            }
+            delay();
+        }
+
+        log::warn!("Failed to switch to ACPI mode");
+    }
+}
+
+fn parse_fadt(fadt: &[u8], tables: &[&'static [u8]]) -> PowerControl {
+
+    // Fields added in ACPI 2.0 are only there if the table is long enough
+    let read_gas = |offset: usize| -> Option<(u8, u64)> {
+        match fadt.len() >= offset + 12 {
+            true => Some((fadt[offset], read_u64(fadt, offset + 4))),
+            false => None,
+        }
+    };
+
+    // X_DSDT takes precedence over the 32-bit DSDT pointer
+    let x_dsdt = match fadt.len() >= 148 {
+        true => read_u64(fadt, 140),
+        false => 0,
+    };
+    let dsdt_addr = match x_dsdt {
+        0 => read_u32(fadt, 40) as u64,
+        addr => addr,
+    };
+
+    let pm1_cnt = |legacy_offset: usize, gas_offset: usize| -> Option<u16> {
+        match read_u32(fadt, legacy_offset) {
+            0 => match read_gas(gas_offset) {
+                Some((GAS_SYSTEM_IO, addr)) if addr != 0 => Some(addr as u16),
+                _ => None,
+            },
+            port => Some(port as u16),
+        }
+    };
+
+    let flags = read_u32(fadt, 112);
+    let reset_reg = match (flags & RESET_REG_SUP != 0, read_gas(116), fadt.get(128)) {
+        (true, Some((space_id, addr)), Some(&value)) => {
+            match space_id {
+                GAS_SYSTEM_IO => Some(ResetRegister::Io { port: addr as u16, value }),
+                GAS_SYSTEM_MEMORY => Some(ResetRegister::Memory { addr, value }),
+                _ => None,
+            }
+        },
+        _ => None,
+    };
+
+    // \_S5_ is normally in the DSDT, but may also be defined in an SSDT
+    let dsdt = unsafe { load_table(dsdt_addr) };
+    let s5 = dsdt
+        .iter()
+        .chain(tables.iter().filter(|table| &table[..4] == b"SSDT"))
+        .find_map(|table| find_sleep_type(&table[SDT_HEADER_LEN..], b"_S5_"));
+
+    PowerControl {
+        smi_cmd: read_u32(fadt, 48) as u16,
+        acpi_enable: fadt[52],
+        pm1a_cnt: pm1_cnt(64, 172).expect("No PM1a control block in FADT"),
+        pm1b_cnt: pm1_cnt(68, 184),
+        s5,
+        reset_reg,
+    }
+}
+
+// Finds a "Name(\_Sx_, Package() { SLP_TYPa, SLP_TYPb, ... })" in AML bytecode.
+// Not a real AML interpreter, but enough for what firmwares generate in practice.
+fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<SleepType> {
+    // The name may also appear elsewhere, e.g. in a method referencing it
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, window)| window == name)
+        .find_map(|(pos, _)| parse_sleep_type(aml, pos))
+}
+
+// pos is where the name of the object is
+fn parse_sleep_type(aml: &[u8], pos: usize) -> Option<SleepType> {
+
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ROOT_CHAR: u8 = b'\\';
+
+    let is_name_decl = match pos {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[pos - 1] == NAME_OP || (aml[pos - 1] == ROOT_CHAR && aml[pos - 2] == NAME_OP),
+    };
+    if !is_name_decl {
+        return None;
+    }
+
+    let mut i = pos + 4;
+    if *aml.get(i)? != PACKAGE_OP {
+        return None;
+    }
+    i += 1;
+
+    // PkgLength: bits 6-7 of the lead byte give the number of extra bytes
+    i += (aml.get(i)? >> 6) as usize + 1;
+
+    // NumElements
+    i += 1;
+
+    let typ_a = read_aml_byte(aml, &mut i)?;
+    let typ_b = read_aml_byte(aml, &mut i)?;
+
+    Some(SleepType { typ_a, typ_b })
+}
+
+fn read_aml_byte(aml: &[u8], i: &mut usize) -> Option<u8> {
+
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let val = match *aml.get(*i)? {
+        ZERO_OP => 0,
+        ONE_OP => 1,
+        BYTE_PREFIX => {
+            *i += 1;
+            *aml.get(*i)?
+        },
+        // Some firmwares put raw bytes in there
+        raw => raw,
+    };
+
+    *i += 1;
+
+    Some(val)
+}
+
+// Maps a table with its full length, checking its checksum
+unsafe fn load_table(addr: u64) -> Option<&'static [u8]> {
+
+    if addr == 0 {
+        return None;
+    }
+
+    let header = phys_slice(addr, SDT_HEADER_LEN);
+    let len = read_u32(header, 4) as usize;
+    let table = phys_slice(addr, len);
+
+    let sum = table.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    if sum != 0 {
+        log::warn!("Invalid checksum for ACPI table at {:#x}", addr);
+        return None;
+    }
+
+    Some(table)
+}
+
+// ACPI tables live in memory reserved by the firmware, which UEFI identity-maps
+unsafe fn phys_slice(addr: u64, len: usize) -> &'static [u8] {
+    let virt = memory::get_mapper().phys_to_virt(PhysAddr::new(addr));
+    core::slice::from_raw_parts(virt.as_ptr::<u8>(), len)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn delay() {
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
@@ -1,193 +1,372 @@
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use spin::Mutex;
 use x86_64::VirtAddr;
 
-const NB_BLOCK_SIZES: usize = 28;
+// Smallest block is 16 bytes, enough to hold the free list links
+const MIN_ORDER: usize = 4;
+const MAX_ORDER: usize = 34;
+const NB_ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
 
-pub struct SimpleAllocator {
-    pub heap: UnsafeCell<Option<SimpleHeap>>,
+// Blocks are aligned relative to the heap base, so the base itself
+// must satisfy the largest supported alignment
+const MAX_ALIGN: usize = 1 << 21;
+
+// Emergency reserve (4MiB), set aside at init and released when an allocation fails,
+// so that the kernel gets a chance to evict its caches instead of panicking right away
+const RESERVE_ORDER: usize = 22;
+
+pub struct BuddyAllocator {
+    heap: Mutex<Option<BuddyHeap>>,
+    initialized: AtomicBool,
+    memory_pressure: AtomicBool,
 }
 
-pub struct SimpleHeap {
+// Binary buddy allocator: a free block of order k (size 2^k) can be split into two
+// "buddies" of order k-1, and two free buddies are merged back when deallocating.
+// Free blocks are kept in intrusive doubly-linked lists (one per order), and a bitmap
+// per order tells whether a given block is currently free, which is needed to know
+// if a buddy can be merged.
+struct BuddyHeap {
+
+    base: usize,
+    end: usize,
+    max_block_size: usize,
 
-    start: *mut u8,
-    ptr: *mut u8,
+    free_lists: [*mut FreeBlock; NB_ORDERS],
+    bitmaps: [*mut u64; NB_ORDERS],
 
-    // This represents a matrix
-    // <nb of possible block sizes> x <nb of possible block alignments>
-    // Alignment cannot be larger than size, so half the matrix is unused.
-    // But this keeps the code simple and doesn't waste that much memory.
-    trackers: [Option<*mut u8>; NB_BLOCK_SIZES * NB_BLOCK_SIZES],
+    reserve: Option<usize>,
 
     stats: AllocStats,
-} 
+}
+
+unsafe impl Send for BuddyHeap {}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
 
 #[derive(Debug, Clone,)]
 pub struct AllocStats {
     pub total: usize,
-    pub explored: usize,
-    pub allocated: usize,
-    pub lost: usize,
-    pub reclaimable: usize
+    pub allocated: usize,   // Requested by callers
+    pub used: usize,        // Actually taken, including rounding to block sizes and the emergency reserve
+    pub lost: usize,        // Allocator metadata and heap alignment
+    pub largest_free: usize,
+    pub fragmentation: f32, // 0 when free memory is in blocks as large as possible, towards 1 when scattered
 }
 
-impl SimpleAllocator {
+impl BuddyAllocator {
 
     pub const fn new() -> Self {
-        Self { heap: UnsafeCell::new(None) }
+        Self {
+            heap: Mutex::new(None),
+            initialized: AtomicBool::new(false),
+            memory_pressure: AtomicBool::new(false),
+        }
     }
 
     pub fn init(&self, heap_addr: VirtAddr, heap_size: usize) {
 
-        let heap = self.heap.get();
-        let start_ptr = heap_addr.as_mut_ptr();
+        let start = heap_addr.as_u64() as usize;
+        let end = start + heap_size;
+
+        //
+        // Placing the bitmaps at the start of the region
+        // (sized for the whole region, which slightly overestimates)
+
+        let mut bitmaps = [null_mut(); NB_ORDERS];
+        let mut meta_ptr = start;
+        for (i, bitmap) in bitmaps.iter_mut().enumerate() {
+            let nb_blocks = heap_size >> (MIN_ORDER + i);
+            let nb_words = (nb_blocks + 63) / 64;
+            *bitmap = meta_ptr as *mut u64;
+            unsafe { core::ptr::write_bytes(*bitmap, 0, nb_words) };
+            meta_ptr += 8 * nb_words;
+        }
 
-        unsafe {
-            *heap = Some(SimpleHeap {
+        let base = align_up(meta_ptr, MAX_ALIGN);
+        assert!(base < end, "Heap region too small");
+
+        let mut heap = BuddyHeap {
+            base,
+            end,
+            max_block_size: 0,
+            free_lists: [null_mut(); NB_ORDERS],
+            bitmaps,
+            reserve: None,
+            stats: AllocStats {
+                total: 0,
+                allocated: 0,
+                used: 0,
+                lost: base - start,
+                largest_free: 0,
+                fragmentation: 0.0,
+            }
+        };
 
-                start: start_ptr,
-                ptr: start_ptr,
+        //
+        // Carving the region into the largest possible blocks. Since sizes are
+        // decreasing, each block ends up aligned to its own size.
+
+        let mut addr = base;
+        for order in (MIN_ORDER..=MAX_ORDER).rev() {
+            let block_size = 1 << order;
+            while end - addr >= block_size {
+                unsafe { heap.push_free(addr, order) };
+                heap.max_block_size = usize::max(heap.max_block_size, block_size);
+                addr += block_size;
+            }
+        }
 
-                trackers: [None; NB_BLOCK_SIZES * NB_BLOCK_SIZES],
+        heap.stats.total = addr - base;
+        heap.stats.lost += end - addr;
 
-                stats: AllocStats {
-                    total: heap_size,
-                    explored: 0,
-                    allocated: 0,
-                    lost: 0,
-                    reclaimable: 0,
-                }
-            })
-        }
-    }
+        heap.take_reserve();
 
-    pub fn size(&self) -> usize {
-        self.get_heap().stats.total
+        let (total, lost) = (heap.stats.total, heap.stats.lost);
+
+        *self.heap.lock() = Some(heap);
+        self.initialized.store(true, Ordering::Release);
+
+        log::debug!("Buddy heap: {}B usable, {}B lost to metadata and alignment", total, lost);
     }
 
-    pub fn get_stats(&self) -> AllocStats {
-        self.get_heap().stats.clone()
+    // Doesn't take the lock, so that it can be called from anywhere (e.g the logger)
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Acquire)
     }
 
-    fn get_heap(&self) -> &SimpleHeap {
-        unsafe {
-            self.heap.get().as_ref().unwrap().as_ref().expect("Allocator not initialized")
-        }
+    // Whether the emergency reserve was released since the last call
+    pub fn take_memory_pressure(&self) -> bool {
+        self.memory_pressure.swap(false, Ordering::AcqRel)
     }
 
-    fn get_heap_mut(&self) -> &mut SimpleHeap {
-        unsafe {
-            self.heap.get().as_mut().unwrap().as_mut().expect("Allocator not initialized")
-        }
+    // Tries to set aside the emergency reserve again, if it was released.
+    // Returns false if there is not enough memory for it yet.
+    pub fn refill_reserve(&self) -> bool {
+        let mut heap = self.heap.lock();
+        let heap = heap.as_mut().expect("Allocator not initialized");
+        heap.reserve.is_some() || heap.take_reserve()
     }
-}
 
-unsafe impl Sync for SimpleAllocator {}
+    pub fn size(&self) -> usize {
+        self.get_stats().total
+    }
 
-unsafe impl GlobalAlloc for SimpleAllocator {
+    pub fn get_stats(&self) -> AllocStats {
 
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = self.heap.lock();
+        let heap = heap.as_ref().expect("Allocator not initialized");
 
-        let size = layout.size();
-        let align = layout.align();
+        let mut stats = heap.stats.clone();
 
-        let (tracker_index, block_size) = get_tracker(size, align);
+        // Comparing the largest free block to the largest one we could have,
+        // given that blocks can't be larger than the ones the heap was carved into
+        let free = stats.total - stats.used;
+        let best_case = usize::min(free, heap.max_block_size);
+        stats.largest_free = heap.largest_free();
+        stats.fragmentation = match best_case {
+            0 => 0.0,
+            _ => 1.0 - stats.largest_free as f32 / best_case as f32,
+        };
 
-        let heap = self.get_heap_mut();
+        stats
+    }
+}
 
-        heap.stats.allocated += size;
+unsafe impl GlobalAlloc for BuddyAllocator {
 
-        let alloc_ptr = match heap.trackers[tracker_index] {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
 
-            // Reclaiming a previously allocated and unused block
-            Some(tracker_ptr) => {
+        let order = match get_order(layout) {
+            Some(order) => order,
+            None => return null_mut(),
+        };
 
-                // Getting the next block in the linked list by reading at this memory location
-                let next_addr = {
-                    let s = core::slice::from_raw_parts(tracker_ptr, 8);
-                    let s: &[u8; 8] = s.try_into().unwrap();
-                    u64::from_le_bytes(*s)
-                };
-                
-                heap.trackers[tracker_index] = {
-                    if next_addr == 0x0 { None } // We've exhausted the reclaimable blocks for this block size
-                    else { Some(VirtAddr::new(next_addr).as_mut_ptr()) }
-                };
-    
-                heap.stats.reclaimable -= block_size;
-    
-                tracker_ptr
+        let mut heap = self.heap.lock();
+        let heap = heap.as_mut().expect("Allocator not initialized");
+
+        let addr = match heap.alloc_block(order) {
+            Some(addr) => addr,
+
+            // Out of memory: releasing the reserve and letting the kernel know,
+            // it can't be done from here since we're holding the lock
+            None => match heap.release_reserve() {
+                true => {
+                    self.memory_pressure.store(true, Ordering::Release);
+                    match heap.alloc_block(order) {
+                        Some(addr) => addr,
+                        None => return null_mut(),
+                    }
+                },
+                false => return null_mut(),
             },
-
-            // Creating a new block
-            None => {
-
-                let offset = heap.ptr.align_offset(align);
-                heap.ptr = heap.ptr.add(offset);
-        
-                let alloc_ptr = heap.ptr;
-        
-                heap.ptr = heap.ptr.add(block_size);
-                heap.stats.lost += offset;
-                heap.stats.explored = {
-                    let p0 = heap.start as usize;
-                    let p1 = heap.ptr as usize;
-                    p1 - p0
-                };
-    
-                alloc_ptr
-            }
         };
 
-        alloc_ptr
+        heap.stats.allocated += layout.size();
+        heap.stats.used += 1 << order;
+
+        addr as *mut u8
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
 
-        let size = layout.size();
-        let align = layout.align();
+        let order = get_order(layout).expect("Invalid layout");
 
-        let (tracker_index, block_size) = get_tracker(size, align);
+        let mut heap = self.heap.lock();
+        let heap = heap.as_mut().expect("Allocator not initialized");
 
-        let heap = self.get_heap_mut();
+        heap.stats.allocated -= layout.size();
+        heap.stats.used -= 1 << order;
 
-        let prev_addr = match heap.trackers[tracker_index] {
+        heap.free_block(ptr as usize, order);
+    }
+}
 
-            Some(tracker_ptr) => VirtAddr::from_ptr(tracker_ptr).as_u64(),
+impl BuddyHeap {
 
-            // No other block in the list to link to
-            None => 0x0,
-        };
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
 
-        let s = core::slice::from_raw_parts_mut(ptr, 8);
-        s.copy_from_slice(&prev_addr.to_le_bytes());
+        // Finding the smallest free block that fits
+        let mut block_order = (order..=MAX_ORDER).find(|&o| !self.free_list(o).is_null())?;
 
-        heap.trackers[tracker_index] = Some(ptr);
+        let addr = unsafe { self.pop_free(block_order) };
 
-        heap.stats.allocated -= size;
-        heap.stats.reclaimable += block_size;
+        // Splitting it until it has the requested size, freeing the upper halves
+        while block_order > order {
+            block_order -= 1;
+            unsafe { self.push_free(addr + (1 << block_order), block_order) };
+        }
+
+        Some(addr)
     }
-}
 
+    fn free_block(&mut self, mut addr: usize, mut order: usize) {
+
+        // Merging with the buddy for as long as it is free
+        while order < MAX_ORDER {
+            let buddy = self.base + ((addr - self.base) ^ (1 << order));
+            if buddy + (1 << order) > self.end || !self.is_free(buddy, order) {
+                break;
+            }
+            unsafe { self.remove_free(buddy, order) };
+            addr = usize::min(addr, buddy);
+            order += 1;
+        }
+
+        unsafe { self.push_free(addr, order) };
+    }
+
+    fn take_reserve(&mut self) -> bool {
+        self.reserve = self.alloc_block(RESERVE_ORDER);
+        if self.reserve.is_some() {
+            self.stats.used += 1 << RESERVE_ORDER;
+        }
+        self.reserve.is_some()
+    }
+
+    fn release_reserve(&mut self) -> bool {
+        match self.reserve.take() {
+            Some(addr) => {
+                self.free_block(addr, RESERVE_ORDER);
+                self.stats.used -= 1 << RESERVE_ORDER;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn free_list(&self, order: usize) -> *mut FreeBlock {
+        self.free_lists[order - MIN_ORDER]
+    }
+
+    fn largest_free(&self) -> usize {
+        (MIN_ORDER..=MAX_ORDER)
+            .rev()
+            .find(|&order| !self.free_list(order).is_null())
+            .map(|order| 1 << order)
+            .unwrap_or(0)
+    }
+
+    fn bit_pos(&self, addr: usize, order: usize) -> (*mut u64, u64) {
+        let index = (addr - self.base) >> order;
+        let word = unsafe { self.bitmaps[order - MIN_ORDER].add(index / 64) };
+        (word, 1 << (index % 64))
+    }
+
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        let (word, mask) = self.bit_pos(addr, order);
+        unsafe { *word & mask != 0 }
+    }
+
+    fn set_free(&mut self, addr: usize, order: usize, free: bool) {
+        let (word, mask) = self.bit_pos(addr, order);
+        unsafe {
+            match free {
+                true => *word |= mask,
+                false => *word &= !mask,
+            }
+        }
+    }
+
+    unsafe fn push_free(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let head = self.free_list(order);
+
+        (*block).next = head;
+        (*block).prev = null_mut();
+        if !head.is_null() {
+            (*head).prev = block;
+        }
+
+        self.free_lists[order - MIN_ORDER] = block;
+        self.set_free(addr, order, true);
+    }
 
-fn get_tracker(size: usize, align: usize) -> (usize, usize) {
+    unsafe fn pop_free(&mut self, order: usize) -> usize {
+        let addr = self.free_list(order) as usize;
+        self.remove_free(addr, order);
+        addr
+    }
 
-    // Block needs to be big enough to contain a linked list pointer
-    let block_size = usize::max(8, size.next_power_of_two());
+    unsafe fn remove_free(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let FreeBlock { next, prev } = *block;
 
-    let size_index = usize::ilog2(block_size) as usize;
-    let align_index = usize::ilog2(align) as usize;
+        match prev.is_null() {
+            true => self.free_lists[order - MIN_ORDER] = next,
+            false => (*prev).next = next,
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
 
-    if size_index >= NB_BLOCK_SIZES {
-        panic!("Cannot allocate block of size 2^{} bytes", size_index);
+        self.set_free(addr, order, false);
     }
+}
+
+fn get_order(layout: Layout) -> Option<usize> {
 
-    if align_index >= NB_BLOCK_SIZES {
-        panic!("Cannot allocate block of alignment 2^{} bytes", align_index);
+    if layout.align() > MAX_ALIGN {
+        return None;
     }
 
-    let tracker_index = size_index * NB_BLOCK_SIZES + align_index;
+    // A block of size 2^k is always aligned to 2^k
+    let size = usize::max(layout.size(), layout.align());
+    let block_size = usize::max(1 << MIN_ORDER, size.checked_next_power_of_two()?);
+    let order = usize::ilog2(block_size) as usize;
+
+    match order <= MAX_ORDER {
+        true => Some(order),
+        false => None,
+    }
+}
 
-    (tracker_index, block_size)
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
 }
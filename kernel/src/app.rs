@@ -1,9 +1,10 @@
 use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use applib::input::PointerState;
+use applib::input::{CursorShape, PointerState};
 use applib::{BorrowedPixels, FbView, StyleSheet};
 
 use crate::shell::{pie_menu, PieDrawCalls, PieMenuEntry};
@@ -15,9 +16,11 @@ use applib::uitk::{self, GraphSeries, UiContext, TextBoxState};
 use applib::{input::InputState, Color, FbViewMut, Framebuffer, OwnedPixels, Rect};
 use applib::content::{TrackedContent, UuidProvider};
 
-use crate::{app, resources, TOPBAR_H};
+use crate::{acpi, app, config, resources, TOPBAR_H};
 use crate::system::System;
-use crate::wasm::{WasmApp, WasmEngine};
+use crate::smp::{self, AssertSend};
+use crate::stats::CoreDataPoint;
+use crate::wasm::{HostState, WasmApp, WasmEngine};
 
 #[derive(Clone)]
 pub struct AppDescriptor {
@@ -25,6 +28,7 @@ pub struct AppDescriptor {
     pub name: &'static str,
     pub init_win_rect: Rect,
     pub icon: &'static Framebuffer<OwnedPixels>,
+    pub mem_quota: usize, // Max size of the app linear memory, in bytes
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -60,14 +64,79 @@ pub enum AppsInteractionState {
     },
 }
 
+#[derive(Clone, Copy)]
+enum DesktopMenuAction {
+    OpenApp(&'static str),
+    Reboot,
+    ShutDown,
+}
+
 pub struct AppsManager {
-    z_ordered: Vec<App>
+    z_ordered: Vec<App>,
+
+    // What was on screen at the previous frame, to find out what needs to be flushed
+    drawn_windows: BTreeMap<&'static str, DrawnWindow>,
+    pie_menu_drawn: bool,
+}
+
+// Everything that affects how a window looks on screen, apart from the app's own pixels
+#[derive(PartialEq)]
+struct WindowLook {
+    region: Rect,
+    audit_rect: Option<Rect>,
+    z_index: usize,
+    highlight: bool,
+    resize_hover: bool,
+    responding: bool,
+    paused: bool,
+    crashed: bool,
+}
+
+struct DrawnWindow {
+    look: WindowLook,
+    // Hash of each row of the app framebuffer, with its shape
+    content: Option<((u32, u32), Vec<u64>)>,
 }
 
 impl AppsManager {
+    // Depends on what the pointer is doing; inside a window, the app decides
+    pub fn get_cursor_shape(&self, interaction_state: &AppsInteractionState) -> CursorShape {
+        match *interaction_state {
+            AppsInteractionState::AppHover { hover_kind: HoverKind::Titlebar, .. } => CursorShape::Move,
+            AppsInteractionState::AppHover { hover_kind: HoverKind::Resize, .. } => CursorShape::Resize,
+            AppsInteractionState::AppHover { app_name, hover_kind: HoverKind::Window } => {
+                let app = self.z_ordered.iter().find(|app| app.descriptor.name == app_name);
+                match app.map(|app| &app.app_state) {
+                    Some(AppState::Active { wasm_app, .. }) => wasm_app.get_cursor_shape(),
+                    _ => CursorShape::Arrow,
+                }
+            },
+            AppsInteractionState::TitlebarHold { .. } => CursorShape::Move,
+            AppsInteractionState::ResizeHold { .. } => CursorShape::Resize,
+            _ => CursorShape::Arrow,
+        }
+    }
+
     fn get_by_name(&mut self, app_name: &str) -> &mut App {
         self.z_ordered.iter_mut().find(|app| app.descriptor.name == app_name).expect("Unknown app")
     }
+
+    // Bottom to top
+    pub fn apps(&self) -> &[App] {
+        &self.z_ordered
+    }
+
+    // The app is instantiated again at the next frame; returns false if there is no such app
+    pub fn reload_app(&mut self, app_name: &str, system: &mut System) -> bool {
+        match self.z_ordered.iter_mut().find(|app| app.descriptor.name == app_name) {
+            Some(app) => {
+                log::info!("De-loading app {}", app.descriptor.name);
+                app.set_state(AppState::Init, system);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 pub struct App {
@@ -78,6 +147,16 @@ pub struct App {
     pub time_used: f64,
 }
 
+impl App {
+    // Releases what an instantiated app holds in the kernel before dropping it
+    fn set_state(&mut self, app_state: AppState, system: &mut System) {
+        if let AppState::Active { wasm_app, .. } = &mut self.app_state {
+            wasm_app.release_resources(system);
+        }
+        self.app_state = app_state;
+    }
+}
+
 pub enum AppState {
     Init,
     Active { 
@@ -101,12 +180,12 @@ impl AppAuditMode {
         deco: &AppDecorations,
         stats: &SystemStats,
         console_log: &TrackedContent<String>,
-    ) {
+    ) -> Option<Rect> {
 
         match self {
-            AppAuditMode::Disabled => return,
+            AppAuditMode::Disabled => None,
             AppAuditMode::Enabled { scrollable_text_state } => {
-                app_audit_window(
+                let audit_rect = app_audit_window(
                     uitk_context,
                     app_name,
                     deco,
@@ -114,6 +193,7 @@ impl AppAuditMode {
                     console_log,
                     scrollable_text_state,
                 );
+                Some(audit_rect)
             }
         }
     }
@@ -122,7 +202,11 @@ impl AppAuditMode {
 impl AppsManager {
 
     pub fn new(apps: Vec<App>) -> Self {
-        Self { z_ordered: apps }
+        Self {
+            z_ordered: apps,
+            drawn_windows: BTreeMap::new(),
+            pie_menu_drawn: false,
+        }
     }
 
     fn get_mut(&mut self, app_name: &'static str) -> &mut App {
@@ -134,6 +218,14 @@ impl AppsManager {
         let app = self.z_ordered.remove(index);
         self.z_ordered.push(app);
     }
+
+    // Moves open windows back on screen after the display was resized
+    pub fn fit_to_screen(&mut self, fb_shape: (u32, u32), input_state: &InputState) {
+        for app in self.z_ordered.iter_mut().filter(|app| app.is_open) {
+            let deco = compute_decorations(app, input_state);
+            app.rect = position_window(&app.rect, fb_shape, &deco);
+        }
+    }
 }
 
 pub fn run_apps<F: FbViewMut>(
@@ -143,6 +235,7 @@ pub fn run_apps<F: FbViewMut>(
     apps_manager: &mut AppsManager,
     input_state: &InputState,
     interaction_state: &mut AppsInteractionState,
+    damage: &mut Vec<Rect>,
 ) {
     const MIN_APP_SIZE: u32 = 200;
 
@@ -310,7 +403,7 @@ pub fn run_apps<F: FbViewMut>(
 
             pie_draw_calls.replace(draw_calls);
 
-            match selected {
+            match selected.and_then(|i| entries[i].text()) {
                 Some("Close") => {
                     app.is_open = false;
                     *is = AppsInteractionState::Idle;
@@ -321,7 +414,7 @@ pub fn run_apps<F: FbViewMut>(
                 },
                 Some("Reload") => {
                     log::info!("De-loading app {}", app.descriptor.name);
-                    app.app_state = AppState::Init;
+                    app.set_state(AppState::Init, system);
                     *is = AppsInteractionState::Idle;
                 },
                 Some("Pause") => if let AppState::Active { paused, .. } = &mut app.app_state {
@@ -352,22 +445,64 @@ pub fn run_apps<F: FbViewMut>(
 
         AppsInteractionState::PieDesktopMenu { anchor } => {
 
-            let entries: Vec<PieMenuEntry> = apps_manager.z_ordered.iter()
-                .map(|app| PieMenuEntry::Button {
-                    icon: app.descriptor.icon,
-                    color: stylesheet.colors.background,
-                    text: app.descriptor.name.to_string(),
-                    text_color: stylesheet.colors.text,
-                    weight: 1.0,
+            // Each entry comes with what selecting it does, since labels may collide with app names
+            let mut menu: Vec<(PieMenuEntry, Option<DesktopMenuAction>)> = apps_manager.z_ordered.iter()
+                .map(|app| {
+                    let entry = PieMenuEntry::Button {
+                        icon: app.descriptor.icon,
+                        color: stylesheet.colors.background,
+                        text: app.descriptor.name.to_string(),
+                        text_color: stylesheet.colors.text,
+                        weight: 1.0,
+                    };
+                    (entry, Some(DesktopMenuAction::OpenApp(app.descriptor.name)))
                 })
                 .collect();
 
+            menu.push((PieMenuEntry::Spacer { weight: 1.0 }, None));
+            menu.push((
+                PieMenuEntry::Button {
+                    icon: &resources::RELOAD_ICON,
+                    color: stylesheet.colors.yellow,
+                    text: "Reboot".to_owned(),
+                    text_color: stylesheet.colors.text,
+                    weight: 1.0,
+                },
+                Some(DesktopMenuAction::Reboot),
+            ));
+
+            // Not offered if the firmware doesn't tell how to do it
+            if acpi::shutdown_supported() {
+                menu.push((
+                    PieMenuEntry::Button {
+                        icon: &resources::CLOSE_ICON,
+                        color: stylesheet.colors.red,
+                        text: "Shut down".to_owned(),
+                        text_color: stylesheet.colors.text,
+                        weight: 1.0,
+                    },
+                    Some(DesktopMenuAction::ShutDown),
+                ));
+            }
+
+            let (entries, actions): (Vec<PieMenuEntry>, Vec<Option<DesktopMenuAction>>) = menu.into_iter().unzip();
+
             let (selected, draw_calls) = pie_menu(uitk_context, &entries, anchor);
 
             pie_draw_calls.replace(draw_calls);
 
-            match selected {
-                Some(selected_app_name) => {
+            match selected.and_then(|i| actions[i]) {
+                Some(DesktopMenuAction::ShutDown) => {
+                    flush_filesystems(system);
+                    if let Err(err) = acpi::shutdown() {
+                        log::error!("Cannot shut down: {}", err);
+                    }
+                }
+                Some(DesktopMenuAction::Reboot) => {
+                    flush_filesystems(system);
+                    acpi::reboot()
+                }
+                Some(DesktopMenuAction::OpenApp(selected_app_name)) => {
 
                     let app = apps_manager.get_by_name(selected_app_name);
 
@@ -393,12 +528,65 @@ pub fn run_apps<F: FbViewMut>(
 
 
     //
-    // Step and draw apps
-
-    let font = uitk_context.font_family.get_default();
+    // Step apps, in parallel on all cores
 
     let n = apps_manager.z_ordered.len();
 
+    let mut step_results: Vec<Option<anyhow::Result<()>>> = (0..n).map(|_| None).collect();
+
+    let clock = system.clock.clone();
+
+    let core_reports = {
+
+        let host = HostState::new(system, uitk_context.uuid_provider);
+
+        let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = apps_manager.z_ordered
+            .iter_mut()
+            .zip(step_results.iter_mut())
+            .enumerate()
+            .filter_map(|(i, (app, result))| {
+
+                let App { app_state, rect, is_open, .. } = app;
+
+                let AppState::Active { wasm_app, paused, .. } = app_state else {
+                    return None;
+                };
+
+                if !*is_open {
+                    return None;
+                }
+
+                let is_foreground = i == n - 1;
+                let paused = *paused;
+
+                // Safety: each app is only stepped by one core, and host calls go through HostState
+                let args = unsafe { AssertSend::new((wasm_app, result, &host, input_state, &*rect)) };
+
+                let task = move || {
+                    let (wasm_app, result, host, input_state, rect) = args.into_inner();
+                    *result = Some(wasm_app.step(host, input_state, rect, is_foreground, paused));
+                };
+
+                Some(Box::new(task) as Box<dyn FnOnce() + Send + '_>)
+            })
+            .collect();
+
+        smp::run_parallel(&clock, tasks)
+    };
+
+    for (core, report) in core_reports.iter().enumerate() {
+        *system.stats.get_core_point_mut(core) = CoreDataPoint {
+            busy_time: report.busy_time,
+            nb_tasks: report.nb_tasks,
+        };
+    }
+
+
+    //
+    // Draw apps
+
+    let font = uitk_context.font_family.get_default();
+
     for (i, app) in apps_manager.z_ordered.iter_mut().enumerate() {
 
         if !app.is_open {
@@ -416,9 +604,15 @@ pub fn run_apps<F: FbViewMut>(
             _ => false,
         };
 
-        let is_foreground = i == n - 1;
+        let responding = match &app.app_state {
+            AppState::Active { wasm_app, .. } => wasm_app.is_responding(),
+            _ => true,
+        };
+
+        draw_decorations(uitk_context.fb, &stylesheet, font, app_name, &deco, highlight, responding);
 
-        draw_decorations(uitk_context.fb, &stylesheet, font, app_name, &deco, highlight);
+        let mut content_drawn = false;
+        let mut audit_rect = None;
     
         match &mut app.app_state {
 
@@ -427,16 +621,20 @@ pub fn run_apps<F: FbViewMut>(
                 let desc = &app.descriptor;
 
                 log::info!("Initializing app {}", desc.name);
-                let wasm_app = wasm_engine.instantiate_app(
-                    system,
-                    uitk_context.uuid_provider,
+                let host = HostState::new(system, uitk_context.uuid_provider);
+                let wasm_res = wasm_engine.instantiate_app(
+                    &host,
                     input_state,
                     desc.data,
                     desc.name,
+                    desc.mem_quota,
                     &app.rect,
                 );
 
-                app.app_state = AppState::Active { wasm_app, audit_mode: AppAuditMode::Disabled, paused: false };
+                app.app_state = match wasm_res {
+                    Ok(wasm_app) => AppState::Active { wasm_app, audit_mode: AppAuditMode::Disabled, paused: false },
+                    Err(error) => AppState::Crashed { error },
+                };
             },
 
             AppState::Active { wasm_app, audit_mode, paused } => {
@@ -453,7 +651,7 @@ pub fn run_apps<F: FbViewMut>(
                     );
                 }
                 
-                let wasm_res = wasm_app.step(system, uitk_context.uuid_provider, input_state, &app.rect, is_foreground, *paused);
+                let wasm_res = step_results[i].take().unwrap_or(Ok(()));
 
                 match wasm_res {
                     Ok(()) => if let Some(app_fb) = wasm_app.get_framebuffer() {
@@ -473,8 +671,9 @@ pub fn run_apps<F: FbViewMut>(
                         let src = app_fb.subregion(&Rect { x0: 0, y0: 0, w: dst_w, h: dst_h});
 
                         uitk_context.fb.copy_from_fb(&src, deco.content_rect.origin(), false);
+                        content_drawn = true;
 
-                        audit_mode.audit_window(
+                        audit_rect = audit_mode.audit_window(
                             uitk_context,
                             &app.descriptor.name,
                             &deco,
@@ -483,7 +682,10 @@ pub fn run_apps<F: FbViewMut>(
                         );
 
                     },
-                    Err(error) => app.app_state = AppState::Crashed { error },
+                    Err(error) => {
+                        wasm_app.release_resources(system);
+                        app.app_state = AppState::Crashed { error };
+                    },
                 }
             },
 
@@ -501,13 +703,131 @@ pub fn run_apps<F: FbViewMut>(
                 );
             },
         }
+
+        let (paused, crashed) = match &app.app_state {
+            AppState::Active { paused, .. } => (*paused, false),
+            AppState::Crashed { .. } => (false, true),
+            AppState::Init => (false, false),
+        };
+
+        let look = WindowLook {
+            region: deco.handle_rects.iter().fold(deco.window_rect.clone(), |r, h| r.bounding_box(h)),
+            audit_rect,
+            z_index: i,
+            highlight,
+            resize_hover: deco.resize_hover,
+            responding,
+            paused,
+            crashed,
+        };
+
+        let app_fb = match &app.app_state {
+            AppState::Active { wasm_app, .. } if content_drawn => wasm_app.get_framebuffer(),
+            _ => None,
+        };
+
+        track_window_damage(&mut apps_manager.drawn_windows, app.descriptor.name, look, &deco.content_rect, app_fb, damage);
     }
 
+    // Closed windows leave the desktop visible where they were
+    let AppsManager { z_ordered, drawn_windows, .. } = apps_manager;
+    drawn_windows.retain(|app_name, drawn| {
+        let is_open = z_ordered.iter().any(|app| app.is_open && app.descriptor.name == *app_name);
+        if !is_open {
+            drawn.look.add_damage(damage);
+        }
+        is_open
+    });
+
+    // Pie menus are large and rare, they simply damage the whole screen, including when they close
+    let pie_menu_drawn = pie_draw_calls.is_some();
+    if pie_menu_drawn || apps_manager.pie_menu_drawn {
+        damage.push(uitk_context.fb.shape_as_rect());
+    }
+    apps_manager.pie_menu_drawn = pie_menu_drawn;
+
     if let Some(draw_calls) = pie_draw_calls {
         draw_calls.draw(uitk_context.fb);
     }
 }
 
+impl WindowLook {
+    fn add_damage(&self, damage: &mut Vec<Rect>) {
+        damage.push(self.region.clone());
+        damage.extend(self.audit_rect.clone());
+    }
+}
+
+// Compares a window with how it was drawn at the previous frame, and records the areas that changed
+fn track_window_damage(
+    drawn_windows: &mut BTreeMap<&'static str, DrawnWindow>,
+    app_name: &'static str,
+    look: WindowLook,
+    content_rect: &Rect,
+    app_fb: Option<Framebuffer<BorrowedPixels>>,
+    damage: &mut Vec<Rect>,
+) {
+    let content = app_fb.as_ref().map(|fb| (fb.shape(), row_hashes(fb)));
+
+    // The audit window shows live stats
+    damage.extend(look.audit_rect.clone());
+
+    match drawn_windows.get_mut(app_name) {
+        Some(drawn) if drawn.look == look => {
+            match (&drawn.content, &content) {
+                (Some((prev_shape, prev_hashes)), Some((shape, hashes))) if prev_shape == shape => {
+                    add_rows_damage(content_rect, prev_hashes, hashes, damage);
+                }
+                (None, None) => (),
+                _ => damage.push(content_rect.clone()),
+            }
+            drawn.content = content;
+        }
+        prev => {
+            if let Some(drawn) = prev {
+                drawn.look.add_damage(damage);
+            }
+            look.add_damage(damage);
+            drawn_windows.insert(app_name, DrawnWindow { look, content });
+        }
+    }
+}
+
+// Cheaper to keep than a copy of the framebuffer (FNV-1a on whole pixels)
+fn row_hashes(fb: &Framebuffer<BorrowedPixels>) -> Vec<u64> {
+    let (w, _) = fb.shape();
+    fb.get_data()
+        .chunks_exact(w.max(1) as usize)
+        .map(|row| {
+            row.iter().fold(0xcbf29ce484222325, |hash, color| {
+                (hash ^ u64::from(u32::from_ne_bytes(color.0))).wrapping_mul(0x100000001b3)
+            })
+        })
+        .collect()
+}
+
+// Consecutive rows that changed are merged, and rows the window doesn't show are ignored
+fn add_rows_damage(content_rect: &Rect, prev_hashes: &[u64], hashes: &[u64], damage: &mut Vec<Rect>) {
+    let nb_rows = usize::min(hashes.len(), content_rect.h as usize);
+    let mut y = 0;
+    while y < nb_rows {
+        if prev_hashes[y] == hashes[y] {
+            y += 1;
+            continue;
+        }
+        let y_start = y;
+        while y < nb_rows && prev_hashes[y] != hashes[y] {
+            y += 1;
+        }
+        damage.push(Rect {
+            x0: content_rect.x0,
+            y0: content_rect.y0 + y_start as i64,
+            w: content_rect.w,
+            h: (y - y_start) as u32,
+        });
+    }
+}
+
 struct AppDecorations {
     content_rect: Rect,
     window_rect: Rect,
@@ -529,19 +849,20 @@ fn app_audit_window<F: FbViewMut>(
     stats: &SystemStats,
     console_log: &TrackedContent<String>,
     scrollable_text_state: &mut TextBoxState,
-) {
+) -> Rect {
 
     const ROW_H: u32 = 50;
     const AUDIT_WIN_W: u32 = 300;
     const MIN_AUDIT_WIN_H: u32 = 100;
     const MARGIN_H: u32 = 5;
 
-    let target_frametime: f32 = 1000.0 / crate::FPS_TARGET as f32;
+    let target_frametime: f32 = 1000.0 / config::get().fps_target as f32;
 
     let frametime_data = stats.get_app_history(app_name, |dp| dp.frametime_used as f32);
     let mem_data = stats.get_app_history(app_name, |dp| dp.mem_used as f32);
     let net_recv_data = stats.get_app_history(app_name, |dp| dp.net_recv as f32);
     let net_sent_data = stats.get_app_history(app_name, |dp| dp.net_sent as f32);
+    let last_core = stats.get_app_history(app_name, |dp| dp.core)[0];
 
     let frametime_avg = frametime_data.iter().fold(0.0, |acc, v| acc + v / frametime_data.len() as f32);
     let frametime_frac = frametime_avg / target_frametime;
@@ -562,8 +883,8 @@ fn app_audit_window<F: FbViewMut>(
     let graph_specs = [
         AuditGraph {
             name: &format!(
-                "Frametime used: {:.1}ms ({:.1}% of system)",
-                frametime_avg, frametime_frac * 100.0
+                "Frametime used: {:.1}ms ({:.1}% of system, core {})",
+                frametime_avg, frametime_frac * 100.0, last_core
             ),
             max_val: 1000.0 / 60.0,
             series: &[
@@ -612,6 +933,13 @@ fn app_audit_window<F: FbViewMut>(
     let mut y = deco.window_rect.y0 + deco.handle_h as i64;
     let x = deco.window_rect.x0 + deco.window_rect.w as i64 + 10;
 
+    // Graph titles may be wider than the graphs
+    let y_top = y;
+    let text_w = graph_specs.iter()
+        .map(|spec| (spec.name.len() * uitk_context.font_family.get_default().char_w) as u32)
+        .max()
+        .unwrap_or(0);
+
     for spec in graph_specs {
 
         let font = uitk_context.font_family.get_default();
@@ -653,6 +981,11 @@ fn app_audit_window<F: FbViewMut>(
         scrollable_text_state,
         true
     );
+
+    let [_, _, _, console_y1] = console_rect.as_xyxy();
+    let audit_w = u32::max(AUDIT_WIN_W, text_w);
+
+    Rect::from_xyxy([x, y_top, x + audit_w as i64 - 1, console_y1])
 }
 
 
@@ -662,6 +995,13 @@ fn get_hold_anchor(pointer: &PointerState, rect: &Rect) -> Point2D<i64> {
     Point2D { x: dx, y: dy }
 }
 
+// So that pending writes and the disk write cache aren't lost when powering off
+fn flush_filesystems(system: &mut System) {
+    if let Err(err) = system.vfs.flush() {
+        log::error!("Failed to flush filesystems: {}", err);
+    }
+}
+
 fn position_window(preferred_rect: &Rect, fb_shape: (u32, u32), deco: &AppDecorations) -> Rect {
 
     const TOPBAR_GAP: u32 = 5;
@@ -676,10 +1016,9 @@ fn position_window(preferred_rect: &Rect, fb_shape: (u32, u32), deco: &AppDecora
 
     let min_y0 = TOPBAR_H + TOPBAR_GAP + deco.titlebar_rect.h;
 
-    x0 = i64::max(0, x0);
-    y0 = i64::max(min_y0 as i64, y0);
-    x0 = i64::min((fb_w - w - 1) as i64, x0);
-    y0 = i64::min((fb_h - h - 1) as i64, y0);
+    // The display may be smaller than the window, in which case it sticks to the top-left
+    x0 = i64::max(0, i64::min(fb_w as i64 - w as i64 - 1, x0));
+    y0 = i64::max(min_y0 as i64, i64::min(fb_h as i64 - h as i64 - 1, y0));
 
     Rect { x0, y0, w, h }
 }
@@ -780,11 +1119,13 @@ fn draw_decorations<F: FbViewMut>(
     app_name: &str,
     deco: &AppDecorations,
     highlight: bool,
+    responding: bool,
 ) {
 
-    let color_deco = match highlight {
-        true => stylesheet.colors.hover_overlay,
-        false => stylesheet.colors.background,
+    let color_deco = match (responding, highlight) {
+        (false, _) => stylesheet.colors.red,
+        (true, true) => stylesheet.colors.hover_overlay,
+        (true, false) => stylesheet.colors.background,
     };
 
     draw_rect(fb, &deco.titlebar_rect, color_deco, false);
@@ -803,7 +1144,12 @@ fn draw_decorations<F: FbViewMut>(
     }
     .align_to_rect_vert(&deco.titlebar_rect);
 
-    let ellipsized_title = ellipsize_text(app_name, font, text_rect.w);
+    let title = match responding {
+        true => app_name.to_owned(),
+        false => format!("{} (not responding)", app_name),
+    };
+
+    let ellipsized_title = ellipsize_text(&title, font, text_rect.w);
 
     draw_str(
         fb,
@@ -0,0 +1,32 @@
+use anyhow::bail;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Storage addressed in fixed-size sectors, for filesystems to be layered on top.
+// Buffers must be a whole number of sectors long.
+pub trait BlockDevice {
+    fn nb_sectors(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    fn read_sectors(&mut self, start: u64, buf: &mut [u8]) -> anyhow::Result<()>;
+
+    fn write_sectors(&mut self, start: u64, buf: &[u8]) -> anyhow::Result<()>;
+
+    // Returns once all previous writes have reached persistent storage
+    fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+// For implementations to validate requests before sending them to the hardware
+pub fn check_request(nb_sectors: u64, start: u64, buf_len: usize) -> anyhow::Result<()> {
+
+    if buf_len % SECTOR_SIZE != 0 {
+        bail!("Buffer length {} is not a multiple of the sector size", buf_len);
+    }
+
+    let end = start.checked_add((buf_len / SECTOR_SIZE) as u64);
+    match end {
+        Some(end) if end <= nb_sectors => Ok(()),
+        _ => bail!("Sectors {}+{} out of range (device has {})", start, buf_len / SECTOR_SIZE, nb_sectors),
+    }
+}
@@ -0,0 +1,262 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail};
+use applib::Rect;
+use log::LevelFilter;
+use smoltcp::wire::{IpCidr, Ipv4Address};
+use spin::Once;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use uefi::table::boot::MemoryType;
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, Handle};
+
+// Bigger files are most likely not a config file
+const MAX_CONFIG_SIZE: usize = 64 * 1024;
+
+static CONFIG: Once<Config> = Once::new();
+
+//
+// Settings read from \config.ini on the ESP, e.g:
+//
+//   log_level = info
+//   fps_target = 30
+//   ip_address = 10.0.2.15/24
+//   gateway = 10.0.2.2
+//
+//   [app "Web Browser"]
+//   rect = 100, 100, 1024, 768
+//
+//   [app "3D Demo"]
+//   enabled = false
+//
+// Anything not in the file keeps its default value.
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    pub fps_target: f64,
+    pub iface_addr: IpCidr,
+    pub gateway_addr: Ipv4Address,
+    pub apps: Vec<AppConfig>,
+}
+
+// Overrides for one of the built-in apps, matched by name
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub name: String,
+    pub enabled: bool,
+    pub win_rect: Option<Rect>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: LevelFilter::Debug,
+            fps_target: 60.0,
+            iface_addr: IpCidr::new(Ipv4Address([10, 0, 2, 15]).into(), 24),
+            gateway_addr: Ipv4Address([10, 0, 2, 2]),
+            apps: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn app(&self, name: &str) -> Option<&AppConfig> {
+        self.apps.iter().find(|app| app.name == name)
+    }
+}
+
+// Must be called before exiting boot services. The kernel heap doesn't exist yet,
+// so the file is read into UEFI pool memory, which stays around afterwards.
+pub fn read_file(image: Handle, system_table: &SystemTable<Boot>) -> Option<&'static [u8]> {
+
+    let boot_services = system_table.boot_services();
+
+    let file = boot_services
+        .get_image_file_system(image)
+        .and_then(|mut fs| fs.open_volume())
+        .and_then(|mut volume| {
+            volume.open(cstr16!("\\config.ini"), FileMode::Read, FileAttribute::empty())
+        });
+
+    let mut file: RegularFile = match file.map(|file| file.into_regular_file()) {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            log::warn!("config.ini is a directory");
+            return None;
+        }
+        Err(err) => {
+            log::info!("No config.ini on the ESP ({:?}), using defaults", err.status());
+            return None;
+        }
+    };
+
+    let size = file
+        .set_position(RegularFile::END_OF_FILE)
+        .and_then(|_| file.get_position())
+        .and_then(|size| file.set_position(0).map(|_| size as usize));
+
+    let size = match size {
+        Ok(size) if size <= MAX_CONFIG_SIZE => size,
+        Ok(size) => {
+            log::warn!("config.ini is too large ({}B), ignoring it", size);
+            return None;
+        }
+        Err(err) => {
+            log::warn!("Cannot read config.ini: {:?}", err.status());
+            return None;
+        }
+    };
+
+    if size == 0 {
+        return Some(&[]);
+    }
+
+    let buf = match boot_services.allocate_pool(MemoryType::LOADER_DATA, size) {
+        Ok(ptr) => unsafe { core::slice::from_raw_parts_mut(ptr, size) },
+        Err(err) => {
+            log::warn!("Cannot allocate memory for config.ini: {:?}", err.status());
+            return None;
+        }
+    };
+
+    let mut read = 0;
+    while read < size {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) => {
+                log::warn!("Cannot read config.ini: {:?}", err.status());
+                return None;
+            }
+        }
+    }
+
+    Some(&buf[..read])
+}
+
+pub fn init(file_contents: Option<&[u8]>) {
+
+    let config = match file_contents.map(core::str::from_utf8) {
+        None => Config::default(),
+        Some(Ok(text)) => parse(text),
+        Some(Err(_)) => {
+            log::warn!("config.ini is not valid UTF-8, using defaults");
+            Config::default()
+        }
+    };
+
+    log::debug!("Config: {:?}", config);
+
+    CONFIG.call_once(|| config);
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.r#try().expect("Config not loaded")
+}
+
+// Invalid lines are skipped with a warning, so that a typo doesn't prevent booting
+fn parse(text: &str) -> Config {
+
+    let mut config = Config::default();
+
+    // Index of the app whose section we're in, if any
+    let mut app_section: Option<usize> = None;
+
+    for (i, line) in text.lines().enumerate() {
+
+        let line = line.split_once('#').map(|(line, _comment)| line).unwrap_or(line).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let res = match line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            Some(section) => parse_section(section).map(|name| {
+                let index = match config.apps.iter().position(|app| app.name == name) {
+                    Some(index) => index,
+                    None => {
+                        config.apps.push(AppConfig { name, enabled: true, win_rect: None });
+                        config.apps.len() - 1
+                    }
+                };
+                app_section = Some(index);
+            }),
+            None => match line.split_once('=') {
+                Some((key, value)) => {
+                    let (key, value) = (key.trim(), value.trim());
+                    match app_section {
+                        None => parse_setting(&mut config, key, value),
+                        Some(index) => parse_app_setting(&mut config.apps[index], key, value),
+                    }
+                }
+                None => Err(anyhow!("expected key = value")),
+            },
+        };
+
+        if let Err(err) = res {
+            log::warn!("config.ini line {}: {}", i + 1, err);
+        }
+    }
+
+    config
+}
+
+// [app "Name"]
+fn parse_section(section: &str) -> anyhow::Result<String> {
+    let name = section
+        .trim()
+        .strip_prefix("app")
+        .map(|name| name.trim())
+        .and_then(|name| name.strip_prefix('"'))
+        .and_then(|name| name.strip_suffix('"'))
+        .ok_or(anyhow!("invalid section [{}], expected [app \"Name\"]", section))?;
+
+    Ok(name.to_string())
+}
+
+fn parse_setting(config: &mut Config, key: &str, value: &str) -> anyhow::Result<()> {
+    match key {
+        "log_level" => {
+            config.log_level = value.parse().map_err(|_| anyhow!("invalid log level {}", value))?;
+        }
+        "fps_target" => {
+            config.fps_target = match value.parse::<f64>() {
+                Ok(fps) if fps > 0.0 => fps,
+                _ => bail!("invalid FPS target {}", value),
+            };
+        }
+        "ip_address" => {
+            config.iface_addr = value.parse().map_err(|_| anyhow!("invalid IP address {} (expected a.b.c.d/prefix)", value))?;
+        }
+        "gateway" => {
+            config.gateway_addr = value.parse().map_err(|_| anyhow!("invalid gateway address {}", value))?;
+        }
+        _ => bail!("unknown setting {}", key),
+    }
+
+    Ok(())
+}
+
+fn parse_app_setting(app: &mut AppConfig, key: &str, value: &str) -> anyhow::Result<()> {
+    match key {
+        "enabled" => {
+            app.enabled = value.parse().map_err(|_| anyhow!("expected true or false, got {}", value))?;
+        }
+        "rect" => {
+            let values = value
+                .split(',')
+                .map(|v| v.trim().parse::<i64>())
+                .collect::<Result<Vec<i64>, _>>()
+                .map_err(|_| anyhow!("invalid rect {}", value))?;
+
+            app.win_rect = match values[..] {
+                [x0, y0, w, h] if w > 0 && h > 0 => Some(Rect { x0, y0, w: w as u32, h: h as u32 }),
+                _ => bail!("invalid rect {}, expected x0, y0, width, height", value),
+            };
+        }
+        _ => bail!("unknown app setting {}", key),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use log::LevelFilter;
+
+use crate::app::{AppState, AppsManager};
+use crate::config;
+use crate::logging;
+use crate::stats::SystemStats;
+use crate::system::System;
+use crate::virtio::console::VirtioConsole;
+
+// Names given to the ports on the QEMU command line (virtserialport's name=)
+const LOG_PORT: &str = "log";
+const SHELL_PORT: &str = "shell";
+
+// No more log records are read while this much is still waiting to be sent
+const MAX_PENDING_LOGS: usize = 64 * 1024;
+
+const MAX_LINE_LEN: usize = 1024;
+
+const HELP: &str = "\
+help              Show this help
+apps              List apps and their state
+reload <app>      Reload an app
+stats             Show system, core and app statistics
+loglevel [level]  Show or change the log level (off, error, warn, info, debug, trace)
+";
+
+// Host-facing ports of the virtio console: one to get logs, one to run shell commands
+pub struct Console {
+    device: VirtioConsole,
+    next_log_seq: u64,
+    shell_connected: bool,
+    shell_line: Vec<u8>,
+}
+
+impl Console {
+    pub fn new(device: VirtioConsole) -> Self {
+        Console {
+            device,
+            next_log_seq: 0,
+            shell_connected: false,
+            shell_line: Vec::new(),
+        }
+    }
+
+    // Called every frame
+    pub fn update(&mut self, system: &mut System, apps_manager: &mut AppsManager) {
+        self.device.poll();
+        self.forward_logs();
+        self.run_shell(system, apps_manager);
+    }
+
+    // Records stay in the log ring until a client connects, so recent history isn't lost
+    fn forward_logs(&mut self) {
+        if !self.device.is_connected(LOG_PORT) {
+            return;
+        }
+
+        let budget = MAX_PENDING_LOGS.saturating_sub(self.device.pending_len(LOG_PORT));
+        let mut out = String::new();
+        let mut next_seq = self.next_log_seq;
+
+        logging::read_records(self.next_log_seq, |record| {
+            if out.len() >= budget {
+                return false;
+            }
+            let source = record.app_name.as_deref().unwrap_or(&record.module);
+            writeln!(out, "{}: {} -- {}", record.level, source, record.message).unwrap();
+            next_seq = record.seq + 1;
+            true
+        });
+
+        self.next_log_seq = next_seq;
+        self.device.write(LOG_PORT, out.as_bytes());
+    }
+
+    fn run_shell(&mut self, system: &mut System, apps_manager: &mut AppsManager) {
+        let connected = self.device.is_connected(SHELL_PORT);
+        if connected && !self.shell_connected {
+            self.shell_line.clear();
+            self.device.write(SHELL_PORT, b"Kernel shell, type \"help\" for the list of commands\n> ");
+        }
+        self.shell_connected = connected;
+
+        for byte in self.device.read(SHELL_PORT) {
+            if byte != b'\n' {
+                if self.shell_line.len() < MAX_LINE_LEN {
+                    self.shell_line.push(byte);
+                }
+                continue;
+            }
+
+            // Also removes the \r sent by telnet-like clients
+            let line = String::from_utf8_lossy(&self.shell_line).trim().to_owned();
+            self.shell_line.clear();
+
+            let mut out = run_command(&line, system, apps_manager);
+            out.push_str("> ");
+            self.device.write(SHELL_PORT, out.as_bytes());
+        }
+    }
+}
+
+fn run_command(line: &str, system: &mut System, apps_manager: &mut AppsManager) -> String {
+    let mut out = String::new();
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (None, _) => (),
+
+        (Some("help"), _) => out.push_str(HELP),
+
+        (Some("apps"), _) => list_apps(&mut out, apps_manager),
+
+        // App names may contain spaces
+        (Some("reload"), Some(_)) => {
+            let app_name = line.strip_prefix("reload").unwrap().trim();
+            match apps_manager.reload_app(app_name, system) {
+                true => writeln!(out, "Reloading {}", app_name).unwrap(),
+                false => writeln!(out, "Unknown app {}", app_name).unwrap(),
+            }
+        }
+        (Some("reload"), None) => out.push_str("Usage: reload <app>\n"),
+
+        (Some("stats"), _) => dump_stats(&mut out, &system.stats, apps_manager),
+
+        (Some("loglevel"), None) => writeln!(out, "{}", log::max_level()).unwrap(),
+        (Some("loglevel"), Some(level)) => match level.parse::<LevelFilter>() {
+            Ok(level) => {
+                log::set_max_level(level);
+                writeln!(out, "Log level set to {}", level).unwrap();
+            }
+            Err(_) => writeln!(out, "Invalid log level {}", level).unwrap(),
+        },
+
+        (Some(cmd), _) => writeln!(out, "Unknown command {}, type \"help\" for the list of commands", cmd).unwrap(),
+    }
+
+    out
+}
+
+fn list_apps(out: &mut String, apps_manager: &AppsManager) {
+    for app in apps_manager.apps() {
+        let state = match &app.app_state {
+            AppState::Init => "not loaded",
+            AppState::Active { paused: true, .. } => "paused",
+            AppState::Active { wasm_app, .. } if !wasm_app.is_responding() => "not responding",
+            AppState::Active { .. } => "running",
+            AppState::Crashed { .. } => "crashed",
+        };
+        let visibility = if app.is_open { "open" } else { "closed" };
+        writeln!(out, "{:<20} {:<8} {}", app.descriptor.name, visibility, state).unwrap();
+    }
+}
+
+// Averaged over the stats history, like the topbar does
+fn dump_stats(out: &mut String, stats: &SystemStats, apps_manager: &AppsManager) {
+    let avg = |data: &[f32]| data.iter().sum::<f32>() / data.len() as f32;
+
+    let target_frametime = 1000.0 / config::get().fps_target as f32;
+
+    let frametime = avg(&stats.get_system_history(|dp| dp.frametime_used as f32));
+    writeln!(out, "Frame time: {:.2}ms used of {:.2}ms", frametime, target_frametime).unwrap();
+
+    let alloc = &stats.get_system_history(|dp| dp.alloc.clone())[0];
+    writeln!(
+        out,
+        "Heap: {:.1}MB allocated, {:.1}MB used, of {:.1}MB ({:.0}% frag.)",
+        alloc.allocated as f32 / 1_000_000.0,
+        alloc.used as f32 / 1_000_000.0,
+        stats.heap_total as f32 / 1_000_000.0,
+        alloc.fragmentation * 100.0,
+    )
+    .unwrap();
+
+    let net_sent_data = stats.get_system_history(|dp| dp.net_sent as f32);
+    let net_recv_data = stats.get_system_history(|dp| dp.net_recv as f32);
+    let history_duration_sec = target_frametime * net_recv_data.len() as f32 / 1000.0;
+    writeln!(
+        out,
+        "Network: {:.1} kB/s up, {:.1} kB/s down",
+        net_sent_data.iter().sum::<f32>() / history_duration_sec / 1000.0,
+        net_recv_data.iter().sum::<f32>() / history_duration_sec / 1000.0,
+    )
+    .unwrap();
+
+    for core in 0..stats.nb_cores() {
+        let busy = avg(&stats.get_core_history(core, |dp| dp.busy_time as f32));
+        let nb_tasks = stats.get_core_history(core, |dp| dp.nb_tasks)[0];
+        writeln!(out, "Core {}: {:.2}ms busy, {} apps", core, busy, nb_tasks).unwrap();
+    }
+
+    for app in apps_manager.apps() {
+        let app_name = app.descriptor.name;
+        let frametime = avg(&stats.get_app_history(app_name, |dp| dp.frametime_used as f32));
+        let mem_used = stats.get_app_history(app_name, |dp| dp.mem_used)[0];
+        let core = stats.get_app_history(app_name, |dp| dp.core)[0];
+        writeln!(
+            out,
+            "App {}: {:.2}ms, {:.1}MB memory, core {}",
+            app_name,
+            frametime,
+            mem_used as f32 / 1_000_000.0,
+            core,
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use alloc::vec;
+use applib::input::CursorShape;
+use applib::Color;
+
+use crate::virtio::gpu::{VirtioGPU, CURSOR_SIZE};
+
+// 'X' is the outline, '.' the fill, and spaces are transparent
+struct CursorSprite {
+    shape: CursorShape,
+    hot_x: u32,
+    hot_y: u32,
+    rows: &'static [&'static str],
+}
+
+const SPRITES: [CursorSprite; 4] = [
+    CursorSprite {
+        shape: CursorShape::Arrow,
+        hot_x: 0,
+        hot_y: 0,
+        rows: &[
+            "X",
+            "XX",
+            "X.X",
+            "X..X",
+            "X...X",
+            "X....X",
+            "X.....X",
+            "X......X",
+            "X.......X",
+            "X........X",
+            "X.........X",
+            "X......XXXXX",
+            "X...X..X",
+            "X..XX..X",
+            "X.X  X..X",
+            "XX   X..X",
+            "X     X..X",
+            "      X..X",
+            "       XX",
+        ],
+    },
+    CursorSprite {
+        shape: CursorShape::Move,
+        hot_x: 8,
+        hot_y: 8,
+        rows: &[
+            "        X",
+            "       X.X",
+            "      X...X",
+            "     X.....X",
+            "     XXX.XXX",
+            "   XX  X.X  XX",
+            "  X.X  X.X  X.X",
+            " X..XXXX.XXXX..X",
+            "X...............X",
+            " X..XXXX.XXXX..X",
+            "  X.X  X.X  X.X",
+            "   XX  X.X  XX",
+            "     XXX.XXX",
+            "     X.....X",
+            "      X...X",
+            "       X.X",
+            "        X",
+        ],
+    },
+    // Windows are resized from their bottom-right corner
+    CursorSprite {
+        shape: CursorShape::Resize,
+        hot_x: 6,
+        hot_y: 7,
+        rows: &[
+            "XXXXXX",
+            "X....X",
+            "X...X",
+            "X..X",
+            "X.X.X",
+            "XX X.X",
+            "X   X.X",
+            "     X.X",
+            "      X.X   X",
+            "       X.X XX",
+            "        X.X.X",
+            "         X..X",
+            "        X...X",
+            "       X....X",
+            "       XXXXXX",
+        ],
+    },
+    CursorSprite {
+        shape: CursorShape::IBeam,
+        hot_x: 3,
+        hot_y: 8,
+        rows: &[
+            "XXX XXX",
+            "X..X..X",
+            "XXX.XXX",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "  X.X",
+            "XXX.XXX",
+            "X..X..X",
+            "XXX XXX",
+        ],
+    },
+];
+
+// Uploads an image for every shape, at the index given by the CursorShape value
+pub fn load_cursors(virtio_gpu: &mut VirtioGPU) {
+    for sprite in SPRITES.iter() {
+        let mut pixels = vec![0u8; CURSOR_SIZE * CURSOR_SIZE * 4].into_boxed_slice();
+
+        for (y, row) in sprite.rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let color = match c {
+                    'X' => Color::BLACK,
+                    '.' => Color::WHITE,
+                    _ => continue,
+                };
+                let i = (y * CURSOR_SIZE + x) * 4;
+                pixels[i..i + 4].copy_from_slice(&color.0);
+            }
+        }
+
+        let index = virtio_gpu.add_cursor(pixels, sprite.hot_x, sprite.hot_y);
+        assert_eq!(index, sprite.shape as usize);
+    }
+}
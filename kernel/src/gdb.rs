@@ -0,0 +1,690 @@
+use core::arch::global_asm;
+use core::fmt::Write;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+use crate::interrupts::{self, GDB_SERIAL_VECTOR};
+use crate::logging::LineBuffer;
+use crate::memory;
+use crate::serial::{COM2_BASE, SERIAL2};
+
+//
+// GDB remote serial protocol stub, listening on the second UART.
+// The debugger takes control of the CPU when a software breakpoint is hit, after a
+// single step, or when it sends a packet / Ctrl-C (received through the UART interrupt).
+// Other cores keep running while the stub is active.
+//
+// To attach: gdb kernel/target/x86_64-unknown-uefi/release/kernel.efi -ex "target remote localhost:1235"
+
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+
+const COM2_IRQ: u8 = 3;
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+// rax..r15, rip, eflags, cs, ss, ds, es, fs, gs (the amd64 "general" register group)
+const NB_REGISTERS: usize = 24;
+
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+static STUB: Mutex<GdbStub> = Mutex::new(GdbStub::new());
+
+// Saved by gdb_common_entry, followed by the frame pushed by the CPU
+#[repr(C)]
+struct TrapFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    vector: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+global_asm!(
+    r#"
+    .global gdb_debug_entry
+    .global gdb_breakpoint_entry
+    .global gdb_serial_entry
+
+gdb_debug_entry:
+    push 1
+    jmp gdb_common_entry
+
+gdb_breakpoint_entry:
+    push 3
+    jmp gdb_common_entry
+
+    // GDB_SERIAL_VECTOR
+gdb_serial_entry:
+    push 0x32
+    jmp gdb_common_entry
+
+gdb_common_entry:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    // The stub is free to use SSE registers, so the interrupted code's have to be saved too
+    mov rbx, rsp
+    and rsp, -16
+    sub rsp, 512
+    fxsave64 [rsp]
+
+    mov rdi, rbx
+    cld
+    call {handler}
+
+    fxrstor64 [rsp]
+    mov rsp, rbx
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+
+    // Vector
+    add rsp, 8
+    iretq
+"#,
+    handler = sym handle_trap,
+);
+
+const _: () = assert!(GDB_SERIAL_VECTOR == 0x32);
+
+extern "C" {
+    fn gdb_debug_entry();
+    fn gdb_breakpoint_entry();
+    fn gdb_serial_entry();
+}
+
+// Called while building the IDT, the handlers have to be in place on every core
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.debug.set_handler_addr(VirtAddr::new(gdb_debug_entry as usize as u64));
+        idt.breakpoint.set_handler_addr(VirtAddr::new(gdb_breakpoint_entry as usize as u64));
+        idt[GDB_SERIAL_VECTOR as usize].set_handler_addr(VirtAddr::new(gdb_serial_entry as usize as u64));
+    }
+}
+
+pub fn init() {
+
+    // The line status register reads as all ones when nothing is there
+    let mut line_status = Port::<u8>::new(COM2_BASE + 5);
+    if unsafe { line_status.read() } == 0xFF {
+        log::info!("No second UART, GDB stub disabled");
+        return;
+    }
+
+    // Also enables the "data received" interrupt
+    lazy_static::initialize(&SERIAL2);
+
+    interrupts::route_isa_irq(COM2_IRQ, GDB_SERIAL_VECTOR);
+
+    log::info!("GDB stub listening on COM2");
+}
+
+// Not logging or allocating from here: the interrupted code may be holding those locks
+extern "sysv64" fn handle_trap(frame: &mut TrapFrame) {
+
+    let mut stub = STUB.lock();
+
+    // Breakpoints are only in memory while the debugged code runs
+    stub.remove_breakpoints();
+
+    let stop = match frame.vector {
+
+        DEBUG_VECTOR => {
+            frame.rflags &= !TRAP_FLAG;
+            match core::mem::take(&mut stub.stepping_over) {
+                // Stepped over a breakpoint on behalf of a continue, nothing to report
+                true => None,
+                false => Some(Stop::Signal(SIGTRAP)),
+            }
+        }
+
+        BREAKPOINT_VECTOR => {
+            // Pointing back to the int3 if it's one of ours, so that the original
+            // instruction runs when resuming
+            let int3_addr = frame.rip.wrapping_sub(1);
+            if stub.has_breakpoint(int3_addr) {
+                frame.rip = int3_addr;
+            }
+            Some(Stop::Signal(SIGTRAP))
+        }
+
+        // Serial interrupt, the debugger is either breaking in or (re)attaching
+        _ => {
+            let mut stop = None;
+            while let Some(byte) = try_receive() {
+                match byte {
+                    0x03 => stop = Some(Stop::Signal(SIGINT)),
+                    b'$' => {
+                        stop = Some(Stop::Packet);
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+            stop
+        }
+    };
+
+    if let Some(stop) = stop {
+        stub.run(frame, stop);
+    }
+
+    // Not putting back the breakpoint at the current instruction when stepping, or it would trigger right away
+    let skipped = match frame.rflags & TRAP_FLAG != 0 {
+        true => Some(frame.rip),
+        false => None,
+    };
+    stub.insert_breakpoints(skipped);
+
+    if frame.vector == GDB_SERIAL_VECTOR as u64 {
+        interrupts::end_of_interrupt();
+    }
+}
+
+enum Stop {
+    Signal(u8),
+    // Start of a packet already received
+    Packet,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    // Original byte, while the int3 is in memory
+    saved: Option<u8>,
+}
+
+struct GdbStub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    stepping_over: bool,
+    packet: [u8; PACKET_SIZE],
+    reply: LineBuffer<PACKET_SIZE>,
+}
+
+impl GdbStub {
+    const fn new() -> Self {
+        GdbStub {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            stepping_over: false,
+            packet: [0u8; PACKET_SIZE],
+            reply: LineBuffer::new(),
+        }
+    }
+
+    // Exchanges packets with the debugger until it resumes execution
+    fn run(&mut self, frame: &mut TrapFrame, stop: Stop) {
+
+        let mut started = match stop {
+            Stop::Signal(signal) => {
+                self.reply = LineBuffer::new();
+                write!(self.reply, "S{:02x}", signal).unwrap();
+                send_packet(self.reply.as_str().as_bytes());
+                false
+            }
+            Stop::Packet => true,
+        };
+
+        loop {
+            let len = read_packet(&mut self.packet, started);
+            started = false;
+
+            self.reply = LineBuffer::new();
+            let resume = self.handle_packet(frame, len);
+
+            match resume {
+                Some(Resume::Continue) => {
+                    self.stepping_over = self.has_breakpoint(frame.rip);
+                    if self.stepping_over {
+                        frame.rflags |= TRAP_FLAG;
+                    }
+                    return;
+                }
+                Some(Resume::Step) => {
+                    self.stepping_over = false;
+                    frame.rflags |= TRAP_FLAG;
+                    return;
+                }
+                None => send_packet(self.reply.as_str().as_bytes()),
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, frame: &mut TrapFrame, len: usize) -> Option<Resume> {
+
+        let packet = &self.packet[..len];
+        let reply = &mut self.reply;
+
+        let Some((&command, args)) = packet.split_first() else {
+            return None;
+        };
+
+        match command {
+
+            b'?' => write!(reply, "S{:02x}", SIGTRAP).unwrap(),
+
+            // All registers
+            b'g' => {
+                for reg in 0..NB_REGISTERS {
+                    let (val, size) = read_register(frame, reg);
+                    write_hex_le(reply, val, size);
+                }
+            }
+
+            b'G' => {
+                let mut rest = args;
+                for reg in 0..NB_REGISTERS {
+                    let size = read_register(frame, reg).1;
+                    if rest.len() < 2 * size {
+                        break;
+                    }
+                    let (hex, tail) = rest.split_at(2 * size);
+                    match parse_hex_le(hex) {
+                        Some(val) => write_register(frame, reg, val),
+                        None => break,
+                    }
+                    rest = tail;
+                }
+                reply.write_str("OK").unwrap();
+            }
+
+            // Single register
+            b'p' => match parse_hex(args).map(|reg| reg as usize) {
+                Some(reg) if reg < NB_REGISTERS => {
+                    let (val, size) = read_register(frame, reg);
+                    write_hex_le(reply, val, size);
+                }
+                _ => reply.write_str("E00").unwrap(),
+            },
+
+            b'P' => {
+                let parsed = split_once(args, b'=')
+                    .and_then(|(reg, val)| Some((parse_hex(reg)? as usize, parse_hex_le(val)?)));
+                match parsed {
+                    Some((reg, val)) if reg < NB_REGISTERS => {
+                        write_register(frame, reg, val);
+                        reply.write_str("OK").unwrap();
+                    }
+                    _ => reply.write_str("E00").unwrap(),
+                }
+            }
+
+            // Memory read: m addr,length
+            b'm' => {
+                let parsed = split_once(args, b',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)? as usize)));
+                match parsed {
+                    Some((addr, len)) if len <= (PACKET_SIZE - 4) / 2 && is_accessible(addr, len) => {
+                        for i in 0..len as u64 {
+                            let byte = unsafe { core::ptr::read_volatile((addr + i) as *const u8) };
+                            write!(reply, "{:02x}", byte).unwrap();
+                        }
+                    }
+                    _ => reply.write_str("E14").unwrap(),
+                }
+            }
+
+            // Memory write: M addr,length:XX...
+            b'M' => {
+                let parsed = split_once(args, b':').and_then(|(header, data)| {
+                    let (addr, len) = split_once(header, b',')?;
+                    Some((parse_hex(addr)?, parse_hex(len)? as usize, data))
+                });
+                match parsed {
+                    Some((addr, len, data)) if data.len() == 2 * len && is_accessible(addr, len) => {
+                        for (i, hex) in data.chunks(2).enumerate() {
+                            let byte = parse_hex(hex).unwrap_or(0) as u8;
+                            unsafe { write_byte(addr + i as u64, byte) };
+                        }
+                        reply.write_str("OK").unwrap();
+                    }
+                    _ => reply.write_str("E14").unwrap(),
+                }
+            }
+
+            // Software breakpoints: Z0,addr,kind / z0,addr,kind
+            b'Z' | b'z' => {
+                let addr = match args.strip_prefix(b"0,") {
+                    Some(rest) => split_once(rest, b',').and_then(|(addr, _kind)| parse_hex(addr)),
+                    // Other breakpoint and watchpoint types are not supported
+                    None => return None,
+                };
+
+                let ok = match (command, addr) {
+                    (b'Z', Some(addr)) if is_accessible(addr, 1) => self.add_breakpoint(addr),
+                    (b'z', Some(addr)) => {
+                        self.remove_breakpoint(addr);
+                        true
+                    }
+                    _ => false,
+                };
+
+                self.reply.write_str(if ok { "OK" } else { "E0e" }).unwrap();
+            }
+
+            // Continue / step, at an optional address
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                return match command {
+                    b'c' => Some(Resume::Continue),
+                    _ => Some(Resume::Step),
+                };
+            }
+
+            // Detach
+            b'D' => {
+                self.breakpoints = [None; MAX_BREAKPOINTS];
+                send_packet(b"OK");
+                return Some(Resume::Continue);
+            }
+
+            // Kill: the best we can do is to let the OS run without the debugger
+            b'k' => {
+                self.breakpoints = [None; MAX_BREAKPOINTS];
+                return Some(Resume::Continue);
+            }
+
+            // Single thread, always alive
+            b'H' | b'T' => reply.write_str("OK").unwrap(),
+
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    write!(reply, "PacketSize={:x}", PACKET_SIZE).unwrap();
+                } else if args.starts_with(b"Attached") {
+                    reply.write_str("1").unwrap();
+                }
+            }
+
+            // Anything else is unsupported, which is an empty reply
+            _ => (),
+        }
+
+        None
+    }
+
+    fn has_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.iter().flatten().any(|bp| bp.addr == addr)
+    }
+
+    fn add_breakpoint(&mut self, addr: u64) -> bool {
+        if self.has_breakpoint(addr) {
+            return true;
+        }
+        match self.breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => {
+                *slot = Some(Breakpoint { addr, saved: None });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if matches!(slot, Some(bp) if bp.addr == addr) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn insert_breakpoints(&mut self, skipped: Option<u64>) {
+        for bp in self.breakpoints.iter_mut().flatten() {
+            if Some(bp.addr) != skipped && bp.saved.is_none() {
+                unsafe {
+                    bp.saved = Some(core::ptr::read_volatile(bp.addr as *const u8));
+                    write_byte(bp.addr, INT3);
+                }
+            }
+        }
+    }
+
+    fn remove_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut().flatten() {
+            if let Some(saved) = bp.saved.take() {
+                unsafe { write_byte(bp.addr, saved) };
+            }
+        }
+    }
+}
+
+// Returns the value of a register in GDB's amd64 numbering, and its size in bytes
+fn read_register(frame: &TrapFrame, reg: usize) -> (u64, usize) {
+    match reg {
+        0 => (frame.rax, 8),
+        1 => (frame.rbx, 8),
+        2 => (frame.rcx, 8),
+        3 => (frame.rdx, 8),
+        4 => (frame.rsi, 8),
+        5 => (frame.rdi, 8),
+        6 => (frame.rbp, 8),
+        7 => (frame.rsp, 8),
+        8 => (frame.r8, 8),
+        9 => (frame.r9, 8),
+        10 => (frame.r10, 8),
+        11 => (frame.r11, 8),
+        12 => (frame.r12, 8),
+        13 => (frame.r13, 8),
+        14 => (frame.r14, 8),
+        15 => (frame.r15, 8),
+        16 => (frame.rip, 8),
+        17 => (frame.rflags, 4),
+        18 => (frame.cs, 4),
+        19 => (frame.ss, 4),
+        20 => (DS::get_reg().0.into(), 4),
+        21 => (ES::get_reg().0.into(), 4),
+        22 => (FS::get_reg().0.into(), 4),
+        23 => (GS::get_reg().0.into(), 4),
+        _ => panic!("Invalid register {}", reg),
+    }
+}
+
+// Segment registers are read-only
+fn write_register(frame: &mut TrapFrame, reg: usize, val: u64) {
+    let reg = match reg {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return,
+    };
+    *reg = val;
+}
+
+// Whether the range is mapped, so that accessing it from the stub doesn't fault
+fn is_accessible(addr: u64, len: usize) -> bool {
+    let Some(end) = addr.checked_add(len as u64) else {
+        return false;
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let mapper = memory::get_mapper();
+    let first_page = addr & !0xFFF;
+    let last_page = (end - 1) & !0xFFF;
+
+    (first_page..=last_page)
+        .step_by(4096)
+        .all(|page| match VirtAddr::try_new(page) {
+            Ok(page) => mapper.is_mapped(page),
+            Err(_) => false,
+        })
+}
+
+// Code pages may be write-protected, which we ignore while writing
+unsafe fn write_byte(addr: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(addr as *mut u8, byte);
+    Cr0::write(cr0);
+}
+
+//
+// Packet framing: $data#checksum, acknowledged with + or -
+
+fn read_packet(buf: &mut [u8; PACKET_SIZE], mut started: bool) -> usize {
+    loop {
+        while !started {
+            started = receive() == b'$';
+        }
+        started = false;
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        loop {
+            match receive() {
+                b'#' => break,
+                byte => {
+                    if len < PACKET_SIZE {
+                        buf[len] = byte;
+                        len += 1;
+                    }
+                    checksum = checksum.wrapping_add(byte);
+                }
+            }
+        }
+
+        let expected = [receive(), receive()];
+        if parse_hex(&expected) == Some(checksum.into()) {
+            send(b'+');
+            return len;
+        }
+
+        send(b'-');
+    }
+}
+
+fn send_packet(data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let mut trailer = LineBuffer::<3>::new();
+    write!(trailer, "#{:02x}", checksum).unwrap();
+
+    loop {
+        send(b'$');
+        data.iter().for_each(|byte| send(*byte));
+        trailer.as_str().bytes().for_each(send);
+
+        // Resending on a negative acknowledgement
+        if receive() != b'-' {
+            break;
+        }
+    }
+}
+
+fn send(byte: u8) {
+    SERIAL2.lock().send_raw(byte);
+}
+
+fn receive() -> u8 {
+    SERIAL2.lock().receive()
+}
+
+fn try_receive() -> Option<u8> {
+    SERIAL2.lock().try_receive().ok()
+}
+
+//
+// Hex encoding
+
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes.iter().try_fold(0u64, |val, byte| {
+        let digit = (*byte as char).to_digit(16)?;
+        Some(val << 4 | digit as u64)
+    })
+}
+
+// Register values are sent as little-endian byte sequences
+fn parse_hex_le(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() % 2 != 0 || bytes.len() > 16 {
+        return None;
+    }
+    bytes.chunks(2).enumerate().try_fold(0u64, |val, (i, hex)| {
+        Some(val | parse_hex(hex)? << (8 * i))
+    })
+}
+
+fn write_hex_le<const N: usize>(buf: &mut LineBuffer<N>, val: u64, size: usize) {
+    for byte in &val.to_le_bytes()[..size] {
+        write!(buf, "{:02x}", byte).unwrap();
+    }
+}
+
+fn split_once(bytes: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|byte| *byte == sep)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
@@ -0,0 +1,195 @@
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+
+// Standard IOAPIC location on PC-compatible machines (and QEMU)
+pub const DEFAULT_IOAPIC_ADDR: u64 = 0xFEC0_0000;
+
+#[repr(u32)]
+#[allow(non_camel_case_types, dead_code)]
+enum LapicReg {
+    ID = 0x020,
+    TPR = 0x080,
+    EOI = 0x0B0,
+    SPURIOUS = 0x0F0,
+    ICR_LOW = 0x300,
+    ICR_HIGH = 0x310,
+    LVT_TIMER = 0x320,
+    TIMER_INITIAL_COUNT = 0x380,
+    TIMER_CURRENT_COUNT = 0x390,
+    TIMER_DIVIDE = 0x3E0,
+}
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    pub fn new() -> Self {
+        let apic_base_msr = unsafe { Msr::new(IA32_APIC_BASE_MSR).read() };
+
+        // Bits 12..52 hold the physical base address
+        let phys_addr = PhysAddr::new(apic_base_msr & 0x000F_FFFF_FFFF_F000);
+        let base = memory::get_mapper().phys_to_virt(phys_addr);
+
+        LocalApic { base }
+    }
+
+    pub fn enable(&self, spurious_vector: u8) {
+        unsafe {
+            // Accept all interrupt priorities
+            self.write(LapicReg::TPR, 0x0);
+
+            // Software-enable the APIC (bit 8)
+            self.write(LapicReg::SPURIOUS, 0x100 | spurious_vector as u32);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        let val = unsafe { self.read(LapicReg::ID) };
+        (val >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(LapicReg::EOI, 0x0) };
+    }
+
+    pub fn start_oneshot_timer(&self, vector: u8, initial_count: u32) {
+        unsafe {
+            self.write(LapicReg::TIMER_DIVIDE, 0x3); // Divide by 16
+            self.write(LapicReg::LVT_TIMER, vector as u32);
+            self.write(LapicReg::TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    pub fn start_periodic_timer(&self, vector: u8, initial_count: u32) {
+        unsafe {
+            self.write(LapicReg::TIMER_DIVIDE, 0x3); // Divide by 16
+            self.write(LapicReg::LVT_TIMER, (1 << 17) | vector as u32);
+            self.write(LapicReg::TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(LapicReg::LVT_TIMER, 1 << 16); // Masked
+            self.write(LapicReg::TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read(LapicReg::TIMER_CURRENT_COUNT) }
+    }
+
+    //
+    // Inter-processor interrupts
+
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_ipi(apic_id, 0b101 << 8 | 1 << 14); // INIT, level assert
+    }
+
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id, 0b110 << 8 | 1 << 14 | page as u32); // Start-up (SIPI)
+    }
+
+    pub fn send_fixed(&self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, 1 << 14 | vector as u32);
+    }
+
+    fn send_ipi(&self, apic_id: u8, icr_low: u32) {
+        unsafe {
+            self.write(LapicReg::ICR_HIGH, (apic_id as u32) << 24);
+            self.write(LapicReg::ICR_LOW, icr_low);
+
+            // Waiting for the delivery status to go back to idle
+            while self.read(LapicReg::ICR_LOW) & (1 << 12) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    unsafe fn read(&self, reg: LapicReg) -> u32 {
+        let ptr: *const u32 = (self.base + reg as u64).as_ptr();
+        read_volatile(ptr)
+    }
+
+    unsafe fn write(&self, reg: LapicReg, val: u32) {
+        let ptr: *mut u32 = (self.base + reg as u64).as_mut_ptr();
+        write_volatile(ptr, val);
+    }
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicRedirection {
+    pub vector: u8,
+    pub dest_apic_id: u8,
+    pub level_triggered: bool,
+    pub active_low: bool,
+    pub masked: bool,
+}
+
+impl IoApic {
+    pub fn new(phys_addr: u64) -> Self {
+        let base = memory::get_mapper().phys_to_virt(PhysAddr::new(phys_addr));
+        IoApic { base }
+    }
+
+    pub fn nb_redirection_entries(&self) -> u8 {
+        let version = unsafe { self.read(0x01) };
+        ((version >> 16) & 0xFF) as u8 + 1
+    }
+
+    pub fn mask_all(&self) {
+        for gsi in 0..self.nb_redirection_entries() {
+            self.set_redirection(
+                gsi,
+                IoApicRedirection {
+                    vector: 0,
+                    dest_apic_id: 0,
+                    level_triggered: false,
+                    active_low: false,
+                    masked: true,
+                },
+            );
+        }
+    }
+
+    pub fn set_redirection(&self, gsi: u8, redir: IoApicRedirection) {
+        let low: u32 = (redir.vector as u32)
+            | ((redir.active_low as u32) << 13)
+            | ((redir.level_triggered as u32) << 15)
+            | ((redir.masked as u32) << 16);
+
+        let high: u32 = (redir.dest_apic_id as u32) << 24;
+
+        let reg = 0x10 + 2 * gsi as u32;
+
+        unsafe {
+            self.write(reg, low | (1 << 16)); // Masking while the entry is inconsistent
+            self.write(reg + 1, high);
+            self.write(reg, low);
+        }
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        let sel_ptr: *mut u32 = self.base.as_mut_ptr();
+        let win_ptr: *const u32 = (self.base + 0x10u64).as_ptr();
+        write_volatile(sel_ptr, reg);
+        read_volatile(win_ptr)
+    }
+
+    unsafe fn write(&self, reg: u32, val: u32) {
+        let sel_ptr: *mut u32 = self.base.as_mut_ptr();
+        let win_ptr: *mut u32 = (self.base + 0x10u64).as_mut_ptr();
+        write_volatile(sel_ptr, reg);
+        write_volatile(win_ptr, val);
+    }
+}
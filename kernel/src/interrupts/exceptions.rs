@@ -0,0 +1,128 @@
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use super::gdt::DOUBLE_FAULT_IST_INDEX;
+
+// The debug and breakpoint exceptions belong to the GDB stub
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+
+    // A kernel stack overflow faults again when pushing the page fault frame,
+    // so the double fault handler needs a known good stack
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+}
+
+// The panic handler logs the message and shows the panic screen. Not allocating,
+// in case the exception happened in the allocator.
+fn exception(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let rsp = stack_frame.stack_pointer.as_u64();
+
+    match error_code {
+        Some(error_code) => panic!(
+            "CPU exception: {}, error code {:#x} at RIP {:#x} (RSP {:#x})",
+            name, error_code, rip, rsp
+        ),
+        None => panic!("CPU exception: {} at RIP {:#x} (RSP {:#x})", name, rip, rsp),
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+
+    // Address whose access faulted
+    let addr = Cr2::read_raw();
+
+    let access = match error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        true => "write to",
+        false if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) => "execution of",
+        false => "read from",
+    };
+
+    let reason = match error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        true => "protection violation",
+        false => "page not present",
+    };
+
+    panic!(
+        "CPU exception: page fault on {} {:#x} (CR2), {}, error code {:#x} at RIP {:#x} (RSP {:#x})",
+        access,
+        addr,
+        reason,
+        error_code.bits(),
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+    );
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    // CR2 is still set if this was caused by a page fault (e.g a stack overflow)
+    let addr = Cr2::read_raw();
+    panic!(
+        "CPU exception: double fault (CR2 {:#x}), error code {:#x} at RIP {:#x} (RSP {:#x})",
+        addr,
+        error_code,
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    exception("machine check", &stack_frame, None)
+}
+
+macro_rules! exception_handlers {
+    ($($handler:ident => $name:literal),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+                exception($name, &stack_frame, None)
+            }
+        )*
+    };
+    (error_code; $($handler:ident => $name:literal),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+                exception($name, &stack_frame, Some(error_code))
+            }
+        )*
+    };
+}
+
+exception_handlers!(
+    divide_error_handler => "divide error",
+    nmi_handler => "non-maskable interrupt",
+    overflow_handler => "overflow",
+    bound_range_handler => "bound range exceeded",
+    invalid_opcode_handler => "invalid opcode",
+    device_not_available_handler => "device not available",
+    x87_floating_point_handler => "x87 floating point",
+    simd_floating_point_handler => "SIMD floating point",
+);
+
+exception_handlers!(
+    error_code;
+    invalid_tss_handler => "invalid TSS",
+    segment_not_present_handler => "segment not present",
+    stack_segment_fault_handler => "stack segment fault",
+    general_protection_handler => "general protection fault",
+    alignment_check_handler => "alignment check",
+);
@@ -0,0 +1,38 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+// Also used by the panic handler, which may draw the panic screen from there
+const DOUBLE_FAULT_STACK_SIZE: usize = 64 * 1024;
+
+// Each core needs its own TSS (and so its own GDT), but they all have the same layout:
+// the IDT gates hold the code selector, and the IDT is shared between cores.
+pub fn load() {
+
+    let stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = VirtAddr::from_ptr(stack.as_ptr_range().end);
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt: &'static mut GlobalDescriptorTable = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    gdt.load();
+
+    unsafe {
+        CS::set_reg(code);
+        SS::set_reg(data);
+        DS::set_reg(data);
+        ES::set_reg(data);
+        load_tss(tss_selector);
+    }
+}
@@ -0,0 +1,325 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::gdb;
+use crate::time::SystemClock;
+use crate::virtio::{IsrStatus, VirtioInterruptAck};
+
+pub mod apic;
+mod exceptions;
+mod gdt;
+
+use apic::{IoApic, IoApicRedirection, LocalApic};
+
+// Legacy PIC vectors, remapped out of the way of CPU exceptions before being masked
+const PIC_1_OFFSET: u8 = 0x20;
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub const TIMER_VECTOR: u8 = 0x30;
+pub const WAKEUP_VECTOR: u8 = 0x31;
+pub const GDB_SERIAL_VECTOR: u8 = 0x32;
+pub const IRQ_BASE_VECTOR: u8 = 0x40;
+pub const MSI_BASE_VECTOR: u8 = 0x60;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const NB_IRQ_LINES: u8 = 24;
+const NB_MSI_VECTORS: u8 = 32;
+const TIMER_PERIOD_MS: f64 = 1.0;
+const TIMER_CALIBRATION_MS: f64 = 10.0;
+
+static LAPIC: Once<LocalApic> = Once::new();
+static IOAPIC: Once<IoApic> = Once::new();
+
+static IRQ_SOURCES: Mutex<Vec<IrqSource>> = Mutex::new(Vec::new());
+
+// Not behind a lock, so that MSI handlers can't be blocked by the code they interrupt
+static MSI_EVENTS: [Once<Arc<IrqEvent>>; NB_MSI_VECTORS as usize] = [const { Once::new() }; NB_MSI_VECTORS as usize];
+// Bit i is set when MSI_BASE_VECTOR + i is allocated
+static USED_MSI_VECTORS: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+
+        exceptions::install_handlers(&mut idt);
+
+        idt[TIMER_VECTOR as usize].set_handler_fn(timer_handler);
+        idt[WAKEUP_VECTOR as usize].set_handler_fn(wakeup_handler);
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+
+        gdb::install_handlers(&mut idt);
+
+        for (line, handler) in IRQ_HANDLERS.iter().enumerate() {
+            idt[IRQ_BASE_VECTOR as usize + line].set_handler_fn(*handler);
+        }
+
+        for (index, handler) in MSI_HANDLERS.iter().enumerate() {
+            idt[MSI_BASE_VECTOR as usize + index].set_handler_fn(*handler);
+        }
+
+        idt
+    };
+}
+
+#[derive(Default)]
+pub struct IrqEvent {
+    queue: AtomicBool,
+    config: AtomicBool,
+}
+
+impl IrqEvent {
+    fn signal(&self, status: IsrStatus) {
+        if status.queue {
+            self.queue.store(true, Ordering::Release);
+        }
+        if status.config {
+            self.config.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn take_queue(&self) -> bool {
+        self.queue.swap(false, Ordering::Acquire)
+    }
+
+    pub fn take_config(&self) -> bool {
+        self.config.swap(false, Ordering::Acquire)
+    }
+}
+
+struct IrqSource {
+    line: u8,
+    ack: VirtioInterruptAck,
+    event: Arc<IrqEvent>,
+}
+
+pub fn init(clock: &SystemClock) {
+
+    log::info!("Initializing interrupts");
+
+    // The legacy 8259 PICs are replaced by the IOAPIC, but still need to be
+    // remapped so that spurious interrupts don't look like CPU exceptions
+    unsafe {
+        let mut pics = ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET);
+        pics.initialize();
+        pics.disable();
+    }
+
+    // Before building the IDT, whose gates use the current code selector
+    gdt::load();
+    IDT.load();
+
+    let lapic = LAPIC.call_once(LocalApic::new);
+    lapic.enable(SPURIOUS_VECTOR);
+
+    let ioapic = IOAPIC.call_once(|| IoApic::new(apic::DEFAULT_IOAPIC_ADDR));
+    ioapic.mask_all();
+
+    log::debug!(
+        "LAPIC ID {}, IOAPIC has {} redirection entries",
+        lapic.id(),
+        ioapic.nb_redirection_entries()
+    );
+
+    // Calibrating the LAPIC timer against the TSC clock
+    lapic.start_oneshot_timer(TIMER_VECTOR, u32::MAX);
+    clock.spin_delay(TIMER_CALIBRATION_MS);
+    let elapsed_ticks = u32::MAX - lapic.timer_current_count();
+    lapic.stop_timer();
+
+    let ticks_per_ms = elapsed_ticks as f64 / TIMER_CALIBRATION_MS;
+    log::debug!("LAPIC timer calibrated to {:.0} ticks/ms", ticks_per_ms);
+
+    let timer_count = (ticks_per_ms * TIMER_PERIOD_MS) as u32;
+    lapic.start_periodic_timer(TIMER_VECTOR, timer_count);
+
+    x86_64::instructions::interrupts::enable();
+
+    log::info!("Interrupts enabled");
+}
+
+// Called on each application processor once it runs 64-bit code. APs only ever
+// receive wakeup IPIs: the timer and device interrupts all go to the boot CPU.
+pub fn init_ap() {
+    gdt::load();
+    IDT.load();
+    let lapic = LAPIC.r#try().expect("Interrupts not initialized");
+    lapic.enable(SPURIOUS_VECTOR);
+}
+
+pub fn local_apic() -> &'static LocalApic {
+    LAPIC.r#try().expect("Interrupts not initialized")
+}
+
+// Routes a legacy PCI interrupt line to the boot CPU. The returned event is set from
+// the interrupt handler whenever the device reports activity in its ISR.
+pub fn register_virtio_irq(line: u8, ack: VirtioInterruptAck) -> Arc<IrqEvent> {
+
+    assert!(line < NB_IRQ_LINES, "Unsupported IRQ line {}", line);
+
+    let event = Arc::new(IrqEvent::default());
+
+    without_interrupts(|| {
+        IRQ_SOURCES.lock().push(IrqSource {
+            line,
+            ack,
+            event: event.clone(),
+        });
+    });
+
+    let lapic = LAPIC.r#try().expect("Interrupts not initialized");
+    let ioapic = IOAPIC.r#try().expect("Interrupts not initialized");
+
+    // PCI interrupts are level-triggered; QEMU's PIIX/ICH9 wire them active-high
+    ioapic.set_redirection(
+        line,
+        IoApicRedirection {
+            vector: IRQ_BASE_VECTOR + line,
+            dest_apic_id: lapic.id(),
+            level_triggered: true,
+            active_low: false,
+            masked: false,
+        },
+    );
+
+    log::debug!("Registered VirtIO interrupt on line {}", line);
+
+    event
+}
+
+// Routes an ISA interrupt (edge-triggered, active-high) to the boot CPU. ISA IRQs are
+// wired to the IOAPIC input with the same number, which holds on QEMU except for the PIT.
+pub fn route_isa_irq(irq: u8, vector: u8) {
+
+    let lapic = LAPIC.r#try().expect("Interrupts not initialized");
+    let ioapic = IOAPIC.r#try().expect("Interrupts not initialized");
+
+    ioapic.set_redirection(
+        irq,
+        IoApicRedirection {
+            vector,
+            dest_apic_id: lapic.id(),
+            level_triggered: false,
+            active_low: false,
+            masked: false,
+        },
+    );
+
+    log::debug!("Routed ISA IRQ {} to vector {:#x}", irq, vector);
+}
+
+// Allocates a vector for a message-signaled interrupt, delivered to the boot CPU.
+// Returns None once all vectors are taken. The returned event's queue flag is set
+// every time the interrupt fires, since the vector alone identifies its source.
+pub fn register_msi() -> Option<(MsiTarget, Arc<IrqEvent>)> {
+
+    let used = USED_MSI_VECTORS
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            let index = used.trailing_ones();
+            (index < NB_MSI_VECTORS as u32).then_some(used | 1 << index)
+        })
+        .ok()?;
+    let index = used.trailing_ones() as u8;
+
+    let event = MSI_EVENTS[index as usize].call_once(|| Arc::new(IrqEvent::default())).clone();
+
+    // The vector may have been used before
+    event.take_queue();
+    event.take_config();
+
+    let lapic = LAPIC.r#try().expect("Interrupts not initialized");
+    let target = MsiTarget {
+        apic_id: lapic.id(),
+        vector: MSI_BASE_VECTOR + index,
+    };
+
+    log::debug!("Registered MSI on vector {:#x}", target.vector);
+
+    Some((target, event))
+}
+
+// Gives back a vector from register_msi(), once nothing can send that interrupt anymore
+pub fn unregister_msi(vector: u8) {
+    let index = vector - MSI_BASE_VECTOR;
+    assert!(index < NB_MSI_VECTORS, "Invalid MSI vector {:#x}", vector);
+
+    USED_MSI_VECTORS.fetch_and(!(1 << index), Ordering::AcqRel);
+
+    log::debug!("Unregistered MSI on vector {:#x}", vector);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsiTarget {
+    pub apic_id: u8,
+    pub vector: u8,
+}
+
+pub fn end_of_interrupt() {
+    if let Some(lapic) = LAPIC.r#try() {
+        lapic.end_of_interrupt();
+    }
+}
+
+fn handle_irq(line: u8) {
+
+    // Not allocating or logging here: the interrupted code may be holding
+    // the allocator or serial locks.
+    if let Some(mut sources) = IRQ_SOURCES.try_lock() {
+        for source in sources.iter_mut().filter(|source| source.line == line) {
+            let status = source.ack.ack();
+            source.event.signal(status);
+        }
+    }
+
+    end_of_interrupt();
+}
+
+fn handle_msi(index: u8) {
+    if let Some(event) = MSI_EVENTS[index as usize].r#try() {
+        event.signal(IsrStatus { queue: true, config: false });
+    }
+
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+    // Only used to wake up the CPU from hlt
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    // Only used to wake up an AP from hlt when work is queued for it
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    // No EOI for spurious interrupts
+}
+
+macro_rules! irq_handlers {
+    ($handle:ident, $($line:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+                $handle($line);
+            }
+            handler as extern "x86-interrupt" fn(InterruptStackFrame)
+        }),*]
+    };
+}
+
+const IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); NB_IRQ_LINES as usize] = irq_handlers!(
+    handle_irq,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23
+);
+
+const MSI_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); NB_MSI_VECTORS as usize] = irq_handlers!(
+    handle_msi,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+);
@@ -1,6 +1,34 @@
+use alloc::borrow::ToOwned;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use applib::logging::LogRecord;
+use core::fmt::Write;
 use log::{Log, Metadata, Record};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
 
+use crate::memory;
+use crate::panic_screen;
 use crate::serial_println;
+use crate::smp;
+use crate::time::SystemClock;
+
+// Recent log lines, kept in fixed-size buffers so that they can be displayed
+// by the panic handler without allocating.
+const HISTORY_LEN: usize = 32;
+const HISTORY_LINE_LEN: usize = 160;
+
+static HISTORY: Mutex<LogHistory> = Mutex::new(LogHistory::new());
+
+// Bounded ring of all kernel and app log records, readable by apps via host_read_log()
+const RING_CAPACITY: usize = 1000;
+const MAX_MESSAGE_LEN: usize = 1024;
+
+static RING: Mutex<LogRing> = Mutex::new(LogRing::new());
+static CLOCK: Once<SystemClock> = Once::new();
+
+// App currently being stepped on each core, if any
+static CURRENT_APP: [Mutex<Option<String>>; smp::MAX_CPUS] = [const { Mutex::new(None) }; smp::MAX_CPUS];
 
 pub struct SerialLogger;
 
@@ -15,13 +43,164 @@ impl Log for SerialLogger {
             return;
         }
 
+        // Same as the module path, unless overridden (e.g for WASM app logs)
+        let target = record.target();
+
         serial_println!(
             "{}: {} -- {}",
             record.level(),
-            record.module_path().unwrap(), // Not sure why this can fail?
+            target,
             record.args(),
         );
+
+        let mut line = LineBuffer::new();
+        write!(line, "{}: {} -- {}", record.level(), target, record.args()).unwrap();
+
+        without_interrupts(|| {
+            if let Some(mut history) = HISTORY.try_lock() {
+                history.push(line);
+            }
+        });
+
+        // The allocator may not be usable (yet, or anymore)
+        if panic_screen::is_panicking() || !memory::ALLOCATOR.is_initialized() {
+            return;
+        }
+
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        let timestamp = CLOCK.r#try().map(|clock| clock.time()).unwrap_or(0.0);
+
+        without_interrupts(|| {
+            let app_name = CURRENT_APP[smp::cpu_index()].lock().clone();
+            RING.lock().push(LogRecord {
+                seq: 0, // Set by the ring
+                timestamp,
+                level: record.level(),
+                module: target.to_owned(),
+                app_name,
+                message,
+            });
+        });
     }
 
     fn flush(&self) {}
 }
+
+pub fn set_clock(clock: SystemClock) {
+    CLOCK.call_once(|| clock);
+}
+
+pub fn set_current_app(app_name: Option<&str>) {
+    without_interrupts(|| {
+        *CURRENT_APP[smp::cpu_index()].lock() = app_name.map(|s| s.to_owned());
+    });
+}
+
+// Calls func on each record with a sequence number >= since_seq, oldest first,
+// until it returns false
+pub fn read_records<F: FnMut(&LogRecord) -> bool>(since_seq: u64, mut func: F) {
+    without_interrupts(|| {
+        let ring = RING.lock();
+        for record in ring.records.iter().filter(|record| record.seq >= since_seq) {
+            if !func(record) {
+                break;
+            }
+        }
+    });
+}
+
+// Calls func on each of the recent log lines, oldest first.
+// Does nothing if the history is locked (e.g we panicked while logging).
+pub fn for_each_recent_line<F: FnMut(&str)>(mut func: F) {
+    if let Some(history) = HISTORY.try_lock() {
+        let first = (history.next + HISTORY_LEN - history.count) % HISTORY_LEN;
+        for i in 0..history.count {
+            func(history.lines[(first + i) % HISTORY_LEN].as_str());
+        }
+    }
+}
+
+struct LogRing {
+    records: VecDeque<LogRecord>,
+    next_seq: u64,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        LogRing {
+            records: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn push(&mut self, mut record: LogRecord) {
+        record.seq = self.next_seq;
+        self.next_seq += 1;
+
+        if self.records.len() >= RING_CAPACITY {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+struct LogHistory {
+    lines: [LineBuffer<HISTORY_LINE_LEN>; HISTORY_LEN],
+    next: usize,
+    count: usize,
+}
+
+impl LogHistory {
+    const fn new() -> Self {
+        LogHistory {
+            lines: [LineBuffer::new(); HISTORY_LEN],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, line: LineBuffer<HISTORY_LINE_LEN>) {
+        self.lines[self.next] = line;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = usize::min(self.count + 1, HISTORY_LEN);
+    }
+}
+
+// Fixed-capacity text buffer, silently truncating what doesn't fit
+#[derive(Clone, Copy)]
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        LineBuffer { buf: [0u8; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.buf[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            // Truncation may have split a multi-byte character
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl<const N: usize> Write for LineBuffer<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = usize::min(s.len(), N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
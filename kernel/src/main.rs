@@ -3,66 +3,105 @@
 #![feature(alloc_error_handler)]
 #![feature(abi_x86_interrupt)]
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::format;
 use core::panic::PanicInfo;
 use num_traits::Float;
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
 use uefi::prelude::{entry, Boot, Handle, Status, SystemTable};
-use uefi::table::boot::MemoryType;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::{AllocateType, MemoryType};
+use x86_64::PhysAddr;
 
-use applib::drawing::primitives::draw_rect;
 use applib::drawing::text::{draw_str};
-use applib::input::{InputEvent, InputState};
+use applib::input::{InputEvent, InputState, PointerState};
 use applib::uitk::{self, UiContext};
-use applib::{BorrowedMutPixels, Color, FbView, FbViewMut, Framebuffer, OwnedPixels, Rect};
+use applib::{BorrowedMutPixels, FbView, FbViewMut, Framebuffer, OwnedPixels, Rect};
 
 extern crate alloc;
 
+mod acpi;
 mod app;
+mod block;
+mod config;
+mod console;
+mod cursor;
+mod gdb;
+mod interrupts;
 mod logging;
 mod memory;
 mod network;
+mod panic_screen;
 mod pci;
 mod resources;
+mod rng;
 mod serial;
 mod shell;
+mod smp;
 mod system;
 mod time;
 mod virtio;
 mod wasm;
 mod topbar;
+mod vfs;
 mod allocator;
 mod stats;
 
 use time::SystemClock;
 
+use virtio::block::VirtioBlock;
+use virtio::console::VirtioConsole;
 use virtio::gpu::VirtioGPU;
-use virtio::input::VirtioInput;
+use virtio::input::{VirtioInput, VirtioInputEvent};
 use virtio::network::VirtioNetwork;
+use virtio::p9::VirtioP9;
+use virtio::rng::VirtioRng;
+use virtio::vsock::VirtioVsock;
+use vfs::{fat32::Fat32, p9fs::P9Fs, tmpfs::TmpFs, Vfs};
 
-use app::{run_apps, App, AppsInteractionState, AppsManager, AppState};
+use app::{run_apps, App, AppDescriptor, AppsInteractionState, AppsManager, AppState};
 use applib::input::keymap::{EventType, Keycode};
 use resources::{APPLICATIONS, WALLPAPER, STYLESHEET};
 use system::System;
 use wasm::WasmEngine;
 
-pub const FPS_TARGET: f64 = 60.0;
 const LIMIT_FPS: bool = true;
 
 static LOGGER: logging::SerialLogger = logging::SerialLogger;
-const LOGGING_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+
+// Until the config file is loaded
+const DEFAULT_LOGGING_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
 
 pub const TOPBAR_H: u32 = 40;
 
 #[entry]
 fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
-    log::set_max_level(LOGGING_LEVEL);
+    log::set_max_level(DEFAULT_LOGGING_LEVEL);
     log::set_logger(&LOGGER).unwrap();
 
     log::info!("Booting kernel");
 
+    {
+        let loaded_image = system_table
+            .boot_services()
+            .open_protocol_exclusive::<LoadedImage>(image)
+            .unwrap();
+        let (image_base, _image_size) = loaded_image.info();
+        panic_screen::set_image_base(image_base as u64);
+        log::info!("Kernel image loaded at {:p}", image_base);
+    }
+
+    let rsdp_addr = acpi::find_rsdp(&system_table);
+
+    let config_file = config::read_file(image, &system_table);
+
+    // Application processors start in real mode, so their startup code must be below 1MB
+    let ap_trampoline_page = system_table
+        .boot_services()
+        .allocate_pages(AllocateType::MaxAddress(0xF_FFFF), MemoryType::LOADER_DATA, 1)
+        .map(PhysAddr::new)
+        .expect("Cannot allocate AP trampoline page");
+
     let (system_table, memory_map) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
 
     log::info!("Exited UEFI boot services");
@@ -70,24 +109,44 @@ fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
     memory::init_mapper();
     memory::init_allocator(&memory_map);
 
-    let mut pci_devices = pci::enumerate();
+    config::init(config_file);
+    let config = config::get();
+    log::set_max_level(config.log_level);
 
-    let mut virtio_gpu = VirtioGPU::new(&mut pci_devices);
-    let mut virtio_inputs = [
-        VirtioInput::new(&mut pci_devices),
-        VirtioInput::new(&mut pci_devices),
-    ];
-    let virtio_net = VirtioNetwork::new(&mut pci_devices);
-
-    log::info!("All VirtIO devices created");
+    acpi::init(rsdp_addr);
 
     let runtime_services = unsafe { system_table.runtime_services() };
     let clock = SystemClock::new(runtime_services);
 
     log::info!("System clock initialized");
 
+    logging::set_clock(clock.clone());
+
+    interrupts::init(&clock);
+
+    gdb::init();
+
+    smp::init(&clock, ap_trampoline_page);
+
+    pci::init();
+    let mut pci_devices = pci::enumerate();
+
+    let mut virtio_gpu = VirtioGPU::new(&mut pci_devices);
+    let mut virtio_inputs: Vec<VirtioInput> = core::iter::from_fn(|| VirtioInput::new(&mut pci_devices)).collect();
+    let virtio_net = VirtioNetwork::new(&mut pci_devices);
+    let virtio_block = VirtioBlock::new(&mut pci_devices);
+    let virtio_rng = VirtioRng::new(&mut pci_devices);
+    let virtio_p9_shares: Vec<VirtioP9> = core::iter::from_fn(|| VirtioP9::new(&mut pci_devices)).collect();
+    let virtio_console = VirtioConsole::new(&mut pci_devices);
+    let virtio_vsock = VirtioVsock::new(&mut pci_devices);
+
+    log::info!("All VirtIO devices created");
+
     virtio_gpu.init_framebuffer();
-    virtio_gpu.flush();
+    virtio_gpu.flush_all();
+    cursor::load_cursors(&mut virtio_gpu);
+
+    panic_screen::register_display(&mut virtio_gpu);
 
     log::info!("Display initialized");
 
@@ -98,47 +157,85 @@ fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
     log::info!("TCP stack initialized");
 
     let (w, h) = virtio_gpu.get_dims();
-    let (w, h) = (w as u32, h as u32);
+    let (mut w, mut h) = (w as u32, h as u32);
     let wasm_engine = WasmEngine::new();
 
     let mut input_state = InputState::new(w, h);
 
-    let app_names: Vec<&str> = APPLICATIONS.iter().map(|desc| desc.name).collect();
+    // Built-in apps, minus the ones disabled in the config
+    let app_descriptors: Vec<&AppDescriptor> = APPLICATIONS
+        .iter()
+        .filter(|desc| config.app(desc.name).map(|app| app.enabled).unwrap_or(true))
+        .collect();
+
+    let app_names: Vec<&str> = app_descriptors.iter().map(|desc| desc.name).collect();
     
     let alloc_stats = memory::ALLOCATOR.get_stats();
 
-    let system_stats = stats::SystemStats::new(&alloc_stats, &app_names);
+    let system_stats = stats::SystemStats::new(&alloc_stats, &app_names, smp::nb_cores());
+
+    let mut vfs = Vfs::new(Box::new(TmpFs::new(clock.clone())));
+    if let Some(virtio_block) = virtio_block {
+        match Fat32::mount(Box::new(virtio_block), clock.clone()) {
+            Ok(fat32) => {
+                vfs.mount("/disk", Box::new(fat32)).expect("Cannot mount disk");
+                log::info!("Disk mounted at /disk");
+            }
+            Err(err) => log::warn!("Cannot mount disk: {}", err),
+        }
+    }
+    for virtio_p9 in virtio_p9_shares {
+        // Each shared directory is mounted under its tag, e.g. -virtfs ...,mount_tag=host on /host
+        let mount_path = format!("/{}", virtio_p9.mount_tag());
+        let res = P9Fs::mount(virtio_p9).and_then(|p9fs| vfs.mount(&mount_path, Box::new(p9fs)));
+        match res {
+            Ok(()) => log::info!("Host directory mounted at {}", mount_path),
+            Err(err) => log::warn!("Cannot mount host directory at {}: {}", mount_path, err),
+        }
+    }
 
     let mut system = System {
         clock,
         tcp_stack,
-        rng: SmallRng::seed_from_u64(0),
+        vsock: virtio_vsock,
+        rng: rng::new_system_rng(virtio_rng),
         stylesheet: &STYLESHEET,
         stats: system_stats,
+        vfs,
     };
 
-    let apps: Vec<App> = APPLICATIONS
+    let apps: Vec<App> = app_descriptors
         .iter()
-        .map(|app_desc| App {
-            descriptor: app_desc.clone(),
-            app_state: AppState::Init,
-            is_open: false,
-            rect: app_desc.init_win_rect.clone(),
-            time_used: 0.0,
+        .map(|app_desc| {
+            let mut descriptor = (*app_desc).clone();
+            if let Some(rect) = config.app(app_desc.name).and_then(|app| app.win_rect.as_ref()) {
+                descriptor.init_win_rect = rect.clone();
+            }
+            App {
+                rect: descriptor.init_win_rect.clone(),
+                descriptor,
+                app_state: AppState::Init,
+                is_open: false,
+                time_used: 0.0,
+            }
         })
         .collect();
 
     let mut apps_manager = AppsManager::new(apps);
+    let mut console = virtio_console.map(console::Console::new);
 
     log::info!("Applications loaded");
 
-    let mut fps_manager = FpsManager::new(FPS_TARGET);
+    let mut fps_manager = FpsManager::new(config.fps_target);
 
     let mut ui_store = uitk::UiStore::new();
     let mut uuid_provider = uitk::UuidProvider::new();
 
     let mut apps_interaction_state = AppsInteractionState::Idle;
 
+    // The first frame has to be flushed entirely
+    let mut full_damage = true;
+
     log::info!("Entering main loop");
 
     loop {
@@ -155,14 +252,28 @@ fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
 
         let time = system.clock.time();
 
-        let datetime = SystemClock::utc_datetime(runtime_services);
+        let datetime = system.clock.utc_datetime();
+
+        if let Some((new_w, new_h)) = virtio_gpu.poll_resize() {
+            (w, h) = (new_w as u32, new_h as u32);
+            input_state.set_screen_size(w, h);
+            apps_manager.fit_to_screen((w, h), &input_state);
+            full_damage = true;
+        }
+
+        // Areas of the framebuffer that changed since the previous frame.
+        // The cursor is on its own plane, so moving it doesn't damage anything.
+        let mut damage = Vec::new();
+        if full_damage {
+            damage.push(Rect { x0: 0, y0: 0, w, h });
+            full_damage = false;
+        }
 
         update_input_state(&mut input_state, (w, h), &mut virtio_inputs);
 
         let mut framebuffer = Framebuffer::<BorrowedMutPixels>::from_bytes(&mut virtio_gpu.framebuffer, w, h);
 
-        let wallpaper: &Framebuffer<OwnedPixels> = &WALLPAPER;
-        framebuffer.copy_from_fb(wallpaper, (0, 0), false);
+        draw_wallpaper(&mut framebuffer, &system);
 
 
         let mut uitk_context = ui_store.get_context(
@@ -180,11 +291,15 @@ fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
             &mut apps_manager,
             &input_state,
             &mut apps_interaction_state,
+            &mut damage,
         );
 
-        topbar::topbar(&mut uitk_context, &system.stats, datetime);
+        let topbar_rect = topbar::topbar(&mut uitk_context, &system.stats, datetime);
+        damage.push(topbar_rect);
 
-        draw_cursor(uitk_context.fb, &input_state);
+        let cursor_shape = apps_manager.get_cursor_shape(&apps_interaction_state);
+        let PointerState { x, y, .. } = input_state.pointer;
+        virtio_gpu.set_cursor(cursor_shape as usize, x as u32, y as u32);
 
         let (net_recv, net_sent) = system.tcp_stack.pop_counters();
 
@@ -199,37 +314,80 @@ fn main(image: Handle, system_table: SystemTable<Boot>) -> Status {
             net_sent,
         };
 
+        // An allocation had to dip into the emergency reserve
+        if memory::ALLOCATOR.take_memory_pressure() {
+            log::warn!("Kernel heap exhausted, evicting UI caches");
+            ui_store.evict_caches();
+        }
+        memory::ALLOCATOR.refill_reserve();
+
+        if let Some(console) = console.as_mut() {
+            console.update(&mut system, &mut apps_manager);
+        }
+
+        // Apps poll their own connections, this answers the peer when they don't
+        if let Some(vsock) = system.vsock.as_mut() {
+            vsock.poll();
+        }
+
         system.stats.next_frame();
-        fps_manager.end_frame(&system.clock);
-        virtio_gpu.flush();
+        fps_manager.end_frame(&system.clock, || {
+            service_devices(
+                &system.clock,
+                &mut system.tcp_stack,
+                &mut virtio_inputs,
+                &mut virtio_gpu,
+                &input_state,
+                (w, h),
+            )
+        });
+        virtio_gpu.flush(&damage);
     }
+}
 
-    //loop { x86_64::instructions::hlt(); }
+// Called every time the CPU wakes up while waiting for the next frame
+fn service_devices(
+    clock: &SystemClock,
+    tcp_stack: &mut network::TcpStack,
+    virtio_inputs: &mut [VirtioInput],
+    virtio_gpu: &mut VirtioGPU,
+    input_state: &InputState,
+    dims: (u32, u32),
+) {
+
+    if tcp_stack.take_irq() {
+        tcp_stack.poll_interface(clock);
+    }
+
+    for virtio_inp in virtio_inputs.iter_mut() {
+        virtio_inp.service_irq();
+    }
+
+    // The cursor follows pointer motion right away, even if the next frame is late.
+    // The events stay buffered, and are applied to the input state on the next frame.
+    let mut pointer = input_state.pointer.clone();
+    for virtio_inp in virtio_inputs.iter() {
+        for event in virtio_inp.buffered_events() {
+            move_pointer(&mut pointer, dims, virtio_inp, event);
+        }
+    }
+    virtio_gpu.move_cursor(pointer.x as u32, pointer.y as u32);
 }
 
-fn draw_cursor<F: FbViewMut>(fb: &mut F, input_state: &InputState) {
-    const SIZE: u32 = 5;
-    const BORDER: u32 = 1;
+// Centered, with the background color around it if the display is larger
+fn draw_wallpaper<F: FbViewMut>(fb: &mut F, system: &System) {
+    let wallpaper: &Framebuffer<OwnedPixels> = &WALLPAPER;
 
-    let pointer_state = &input_state.pointer;
-    let x = pointer_state.x;
-    let y = pointer_state.y;
+    let (fb_w, fb_h) = fb.shape();
+    let (wp_w, wp_h) = wallpaper.shape();
 
-    let rect_outer = Rect {
-        x0: x,
-        y0: y,
-        w: SIZE,
-        h: SIZE,
-    };
-    let rect_inner = Rect {
-        x0: x + BORDER as i64,
-        y0: y + BORDER as i64,
-        w: SIZE - 2 * BORDER,
-        h: SIZE - 2 * BORDER,
-    };
+    if (fb_w, fb_h) != (wp_w, wp_h) {
+        fb.fill(system.stylesheet.colors.background);
+    }
 
-    draw_rect(fb, &rect_outer, Color::BLACK, false);
-    draw_rect(fb, &rect_inner, Color::WHITE, false);
+    let x0 = (fb_w as i64 - wp_w as i64) / 2;
+    let y0 = (fb_h as i64 - wp_h as i64) / 2;
+    fb.copy_from_fb(wallpaper, (x0, y0), false);
 }
 
 fn update_input_state(
@@ -237,9 +395,6 @@ fn update_input_state(
     dims: (u32, u32),
     virtio_inputs: &mut [VirtioInput],
 ) {
-    let (w, h) = dims;
-    let (w, h) = (w as i32, h as i32);
-
     input_state.clear_events();
     input_state.pointer.left_click_trigger = false;
     input_state.pointer.right_click_trigger = false;
@@ -247,7 +402,8 @@ fn update_input_state(
     input_state.pointer.delta_y = 0;
 
     for virtio_inp in virtio_inputs.iter_mut() {
-        for event in virtio_inp.poll() {
+        let events = virtio_inp.poll();
+        for event in events.iter() {
             //log::debug!("{:?}", event);
 
             match EventType::n(event._type) {
@@ -285,24 +441,7 @@ fn update_input_state(
 
                 // Mouse movement
                 Some(EventType::EV_REL) => match event.code {
-                    0 => {
-                        // X axis
-                        let dx = (event.value as i32) as i64;
-                        let pointer_state = &mut input_state.pointer;
-                        let new_x =
-                            i64::max(0, i64::min(w as i64 - 1, pointer_state.x as i64 + dx));
-                        pointer_state.delta_x += dx;
-                        pointer_state.x = new_x;
-                    }
-                    1 => {
-                        // Y axis
-                        let dy = (event.value as i32) as i64;
-                        let pointer_state = &mut input_state.pointer;
-                        let new_y =
-                            i64::max(0, i64::min(h as i64 - 1, pointer_state.y as i64 + dy));
-                        pointer_state.delta_y += dy;
-                        pointer_state.y = new_y;
-                    }
+                    0 | 1 => move_pointer(&mut input_state.pointer, dims, virtio_inp, event),
                     8 => {
                         // Scroll wheel
                         let delta = (event.value as i32) as i64;
@@ -311,12 +450,38 @@ fn update_input_state(
                     _ => log::warn!("Unknown event code {} for pointer event", event.code),
                 },
 
+                // Tablet position
+                Some(EventType::EV_ABS) => move_pointer(&mut input_state.pointer, dims, virtio_inp, event),
+
                 _ => log::warn!("Unknown event type {}", event._type),
             };
         }
     }
 }
 
+// Applies the X (code 0) or Y (code 1) axis of a relative or absolute pointer event, keeping the pointer on screen
+fn move_pointer(pointer_state: &mut PointerState, dims: (u32, u32), virtio_inp: &VirtioInput, event: &VirtioInputEvent) {
+    let (w, h) = dims;
+
+    let (pos, delta, screen_len) = match event.code {
+        0 => (&mut pointer_state.x, &mut pointer_state.delta_x, w),
+        1 => (&mut pointer_state.y, &mut pointer_state.delta_y, h),
+        _ => return,
+    };
+
+    let moved = match EventType::n(event._type) {
+        Some(EventType::EV_REL) => (event.value as i32) as i64,
+        Some(EventType::EV_ABS) => match virtio_inp.abs_to_screen(event.code, event.value, screen_len) {
+            Some(new_pos) => new_pos - *pos,
+            None => return,
+        },
+        _ => return,
+    };
+
+    *pos = i64::max(0, i64::min(screen_len as i64 - 1, *pos + moved));
+    *delta += moved;
+}
+
 struct FpsManager {
     fps_target: f64,
     frame_start_t: f64,
@@ -338,7 +503,7 @@ impl FpsManager {
         self.frame_start_t = clock.time();
     }
 
-    fn end_frame(&mut self, clock: &SystemClock) {
+    fn end_frame<F: FnMut()>(&mut self, clock: &SystemClock, mut on_wake: F) {
 
         const SMOOTHING: f64 = 0.8;
 
@@ -350,7 +515,13 @@ impl FpsManager {
 
         let new_frametime = match (self.used < frametime_target) && LIMIT_FPS {
             true => {
-                clock.spin_delay(frametime_target - self.used);
+                // Sleeping until the next frame; the LAPIC timer guarantees we wake up
+                // at least every millisecond, and device interrupts wake us up earlier
+                let deadline = self.frame_start_t + frametime_target;
+                while clock.time() < deadline {
+                    x86_64::instructions::hlt();
+                    on_wake();
+                }
                 frametime_target
             }
             false => self.used,
@@ -362,6 +533,19 @@ impl FpsManager {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    log::error!("{}", info);
-    loop {}
+    panic_screen::handle_panic(info)
+}
+
+// Only reached once the emergency reserve of the allocator is exhausted too
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    let stats = memory::ALLOCATOR.get_stats();
+    panic!(
+        "Kernel out of memory: failed to allocate {} bytes (align {}), {}/{} bytes used, largest free block {} bytes",
+        layout.size(),
+        layout.align(),
+        stats.used,
+        stats.total,
+        stats.largest_free,
+    );
 }
@@ -5,10 +5,10 @@ use x86_64::structures::paging::{mapper::TranslateResult, OffsetPageTable, PageT
 use x86_64::{PhysAddr, VirtAddr};
 use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 
-use super::allocator::{SimpleAllocator};
+use super::allocator::{BuddyAllocator};
 
 #[global_allocator]
-pub static ALLOCATOR: SimpleAllocator = SimpleAllocator::new();
+pub static ALLOCATOR: BuddyAllocator = BuddyAllocator::new();
 
 pub static mut MAPPER: OnceCell<MemoryMapper> = OnceCell::new();
 
@@ -56,6 +56,10 @@ impl MemoryMapper {
         frame.start_address() + offset
     }
 
+    pub fn is_mapped(&self, virt: VirtAddr) -> bool {
+        matches!(self.page_table.translate(virt), TranslateResult::Mapped { .. })
+    }
+
     // Note: technically there can be more than one VirtAddr mapped to
     // a given PhysAddr, but we only care about the one that has been
     // offset-mapped by UEFI
@@ -2,21 +2,16 @@ mod device;
 
 use alloc::vec;
 
+use crate::config;
 use crate::time::SystemClock;
 use crate::virtio::network::VirtioNetwork;
 
 use device::SmolTcpVirtio;
-use lazy_static::lazy_static;
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
 use smoltcp::phy::{Device, Medium};
 use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
-
-lazy_static! {
-    static ref IFACE_ADDR: IpCidr = IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24);
-    static ref GATEWAY_ADDR: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
-}
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
 
 const BUF_SIZE: usize = 4096;
 
@@ -40,13 +35,15 @@ impl TcpStack {
 
         let mut interface =
             Interface::new(config, &mut device, Instant::from_millis(timestamp as i64));
+        let config = config::get();
+
         interface.update_ip_addrs(|ip_addrs| {
-            ip_addrs.push(*IFACE_ADDR).unwrap();
+            ip_addrs.push(config.iface_addr).unwrap();
         });
 
         interface
             .routes_mut()
-            .add_default_ipv4_route(*GATEWAY_ADDR)
+            .add_default_ipv4_route(config.gateway_addr)
             .unwrap();
 
         let sockets_storage: [_; 1] = Default::default();
@@ -134,6 +131,10 @@ impl TcpStack {
             .poll(elapsed, &mut self.device, &mut self.sockets);
     }
 
+    pub fn take_irq(&self) -> bool {
+        self.device.virtio_dev.take_irq()
+    }
+
     pub fn pop_counters(&mut self) -> (usize, usize) {
         self.device.virtio_dev.get_counters()
     }
@@ -0,0 +1,203 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use applib::drawing::primitives::draw_rect;
+use applib::drawing::text::{draw_str, Font, DEFAULT_FONT_FAMILY};
+use applib::{BorrowedMutPixels, Color, Framebuffer, Rect};
+
+use crate::logging::{self, LineBuffer};
+use crate::serial::SERIAL1;
+use crate::serial_println;
+use crate::virtio::gpu::VirtioGPU;
+
+const MAX_FRAMES: usize = 16;
+const MARGIN: i64 = 20;
+
+const BG_COLOR: Color = Color::rgb(90, 0, 0);
+const TITLE_COLOR: Color = Color::YELLOW;
+const TEXT_COLOR: Color = Color::WHITE;
+const DIM_TEXT_COLOR: Color = Color::rgb(200, 200, 200);
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static DISPLAY: AtomicPtr<VirtioGPU> = AtomicPtr::new(core::ptr::null_mut());
+static IMAGE_BASE: AtomicU64 = AtomicU64::new(0);
+
+// The GPU must outlive everything else, which is the case for the one owned by main()
+pub fn register_display(virtio_gpu: &mut VirtioGPU) {
+    DISPLAY.store(virtio_gpu as *mut VirtioGPU, Ordering::Release);
+}
+
+// Used to print backtrace addresses relative to the kernel image
+pub fn set_image_base(image_base: u64) {
+    IMAGE_BASE.store(image_base, Ordering::Relaxed);
+}
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Acquire)
+}
+
+pub fn handle_panic(info: &PanicInfo) -> ! {
+
+    x86_64::instructions::interrupts::disable();
+
+    // Panicking again while drawing the panic screen: only trying the serial port
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        unsafe { SERIAL1.force_unlock() };
+        serial_println!("PANIC while handling a panic: {}", info);
+        halt();
+    }
+
+    // We may have panicked while printing something
+    unsafe { SERIAL1.force_unlock() };
+
+    log::error!("{}", info);
+
+    let mut frames = [0u64; MAX_FRAMES];
+    let nb_frames = walk_stack(&mut frames);
+    let image_base = IMAGE_BASE.load(Ordering::Relaxed);
+
+    serial_println!("Backtrace (image base 0x{:x}):", image_base);
+    for (i, addr) in frames[..nb_frames].iter().enumerate() {
+        serial_println!("  #{:<2} 0x{:016x} (+0x{:x})", i, addr, addr.wrapping_sub(image_base));
+    }
+
+    let gpu_ptr = DISPLAY.load(Ordering::Acquire);
+    if let Some(virtio_gpu) = unsafe { gpu_ptr.as_mut() } {
+        draw_panic_screen(virtio_gpu, info, &frames[..nb_frames], image_base);
+        virtio_gpu.flush_all();
+    }
+
+    halt();
+}
+
+fn draw_panic_screen(virtio_gpu: &mut VirtioGPU, info: &PanicInfo, frames: &[u64], image_base: u64) {
+
+    let (w, h) = virtio_gpu.get_dims();
+    let (w, h) = (w as u32, h as u32);
+
+    let mut fb = Framebuffer::<BorrowedMutPixels>::from_bytes(&mut virtio_gpu.framebuffer, w, h);
+
+    draw_rect(&mut fb, &Rect { x0: 0, y0: 0, w, h }, BG_COLOR, false);
+
+    let font = DEFAULT_FONT_FAMILY.get_default();
+    let max_chars = (w as usize - 2 * MARGIN as usize) / font.char_w;
+
+    let mut writer = ScreenWriter {
+        fb: &mut fb,
+        font,
+        y: MARGIN,
+        max_y: h as i64 - MARGIN,
+        max_chars,
+    };
+
+    writer.line("KERNEL PANIC", TITLE_COLOR);
+    writer.skip();
+
+    let mut msg = LineBuffer::<1024>::new();
+    write!(msg, "{}", info).unwrap();
+    writer.wrapped(msg.as_str(), TEXT_COLOR);
+    writer.skip();
+
+    let mut line = LineBuffer::<128>::new();
+    write!(line, "Backtrace (image base 0x{:x}):", image_base).unwrap();
+    writer.line(line.as_str(), TITLE_COLOR);
+    for (i, addr) in frames.iter().enumerate() {
+        let mut line = LineBuffer::<128>::new();
+        write!(line, "  #{:<2} 0x{:016x} (+0x{:x})", i, addr, addr.wrapping_sub(image_base)).unwrap();
+        writer.line(line.as_str(), TEXT_COLOR);
+    }
+    writer.skip();
+
+    writer.line("Recent log:", TITLE_COLOR);
+
+    // Only keeping the most recent lines that fit on screen
+    let line_h = font.char_h as i64;
+    let max_log_lines = (i64::max(0, writer.max_y - writer.y) / line_h) as usize;
+    let mut nb_lines: usize = 0;
+    logging::for_each_recent_line(|_| nb_lines += 1);
+    let mut skipped = nb_lines.saturating_sub(max_log_lines);
+    logging::for_each_recent_line(|log_line| {
+        match skipped {
+            0 => writer.line(log_line, DIM_TEXT_COLOR),
+            _ => skipped -= 1,
+        }
+    });
+}
+
+struct ScreenWriter<'a, 'b> {
+    fb: &'a mut Framebuffer<BorrowedMutPixels<'b>>,
+    font: &'a Font,
+    y: i64,
+    max_y: i64,
+    max_chars: usize,
+}
+
+impl<'a, 'b> ScreenWriter<'a, 'b> {
+    fn line(&mut self, s: &str, color: Color) {
+        if self.y + self.font.char_h as i64 > self.max_y {
+            return;
+        }
+        let end = s.char_indices().nth(self.max_chars).map(|(i, _)| i).unwrap_or(s.len());
+        draw_str(self.fb, &s[..end], MARGIN, self.y, self.font, color, None);
+        self.y += self.font.char_h as i64;
+    }
+
+    fn wrapped(&mut self, s: &str, color: Color) {
+        for text_line in s.lines() {
+            let mut rest = text_line;
+            loop {
+                let end = rest.char_indices().nth(self.max_chars).map(|(i, _)| i).unwrap_or(rest.len());
+                self.line(&rest[..end], color);
+                rest = &rest[end..];
+                if rest.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn skip(&mut self) {
+        self.y += self.font.char_h as i64 / 2;
+    }
+}
+
+// Follows the chain of saved frame pointers (requires -C force-frame-pointers)
+fn walk_stack(frames: &mut [u64]) -> usize {
+
+    const MAX_FRAME_SIZE: u64 = 1 << 20;
+
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+
+    let mut n = 0;
+    while n < frames.len() && rbp != 0 && rbp % 8 == 0 {
+        let (next_rbp, ret_addr) = unsafe {
+            let frame = rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+
+        if ret_addr == 0 {
+            break;
+        }
+
+        frames[n] = ret_addr;
+        n += 1;
+
+        // The stack grows down, so caller frames must be at higher addresses
+        if next_rbp <= rbp || next_rbp - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+
+        rbp = next_rbp;
+    }
+
+    n
+}
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
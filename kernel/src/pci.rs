@@ -4,17 +4,44 @@ use alloc::vec::Vec;
 use bitvec::field::BitField;
 use bitvec::prelude::Lsb0;
 use bitvec::view::BitView;
+use core::fmt;
 use core::mem;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Once;
 use x86_64::instructions::port::{Port, PortWriteOnly};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{self, EcamRegion};
+use crate::memory;
+
+// Size of the configuration space of a function, through port I/O or ECAM
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+const CAP_ID_MSIX: u8 = 0x11;
+
+// MSI messages are memory writes to the local APIC region, the destination APIC ID in bits 12-19
+const MSI_ADDR_BASE: u32 = 0xFEE0_0000;
+
+static ECAM_REGIONS: Once<Vec<EcamRegion>> = Once::new();
 
 #[derive(Debug)]
 pub struct PciDevice {
     pub addr: PciAddress,
     pub vendor_id: u16,
     pub device_id: u16,
+    // Only logged for now, drivers match on vendor and device IDs
+    #[allow(dead_code)]
     pub class: u8,
+    #[allow(dead_code)]
+    pub subclass: u8,
+    #[allow(dead_code)]
+    pub prog_if: u8,
 
     pub capabilities: Vec<PciCapability>,
+    // Only logged for now, no driver needs PCIe capabilities yet
+    #[allow(dead_code)]
+    pub extended_capabilities: Vec<PciExtendedCapability>,
     pub bars: BTreeMap<u32, PciBar>,
 }
 
@@ -28,13 +55,28 @@ pub struct PciAddress {
 #[derive(Debug, Clone)]
 pub struct PciCapability {
     pub vendor: u8,
-    pub offset: u8,
+    pub offset: u16,
+}
+
+// PCIe capabilities, located in the extended configuration space (only reachable through ECAM)
+#[derive(Debug, Clone)]
+pub struct PciExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
 }
 
 #[derive(Debug)]
 pub struct PciConfigSpace {
     address_port: PortWriteOnly<u32>,
     data_port: Port<u32>,
+    ecam_regions: &'static [EcamRegion],
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -57,6 +99,51 @@ pub enum BarAddrType {
     Bar64,
 }
 
+// Table of MSI-X vectors, mapped from one of the device's memory BARs.
+// Each 16-byte entry holds a message address, message data and a vector control word.
+pub struct MsixTable {
+    entries: *mut u32,
+    size: u16,
+}
+
+unsafe impl Send for MsixTable {}
+
+impl MsixTable {
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    // Routes an entry to a vector of a local APIC (fixed delivery, edge-triggered) and unmasks it
+    pub fn set_entry(&mut self, index: u16, apic_id: u8, vector: u8) {
+        assert!(index < self.size, "Invalid MSI-X table entry {}", index);
+
+        let message_addr = MSI_ADDR_BASE | u32::from(apic_id) << 12;
+
+        self.set_masked(index, true);
+        unsafe {
+            let entry = self.entries.add(4 * index as usize);
+            write_volatile(entry, message_addr);
+            write_volatile(entry.add(1), 0);
+            write_volatile(entry.add(2), vector.into());
+        }
+        self.set_masked(index, false);
+    }
+
+    // Masks an entry that is no longer used
+    pub fn clear_entry(&mut self, index: u16) {
+        assert!(index < self.size, "Invalid MSI-X table entry {}", index);
+
+        self.set_masked(index, true);
+    }
+
+    fn set_masked(&mut self, index: u16, masked: bool) {
+        unsafe {
+            let vector_control = self.entries.add(4 * index as usize + 3);
+            write_volatile(vector_control, masked as u32);
+        }
+    }
+}
+
 impl PciDevice {
     #[allow(dead_code)]
     pub fn set_interrupt_line(&self, line: u8) {
@@ -74,7 +161,6 @@ impl PciDevice {
         unsafe { pci_config_space.write(&self.addr, 0x3c, new_word) };
     }
 
-    #[allow(dead_code)]
     pub fn read_interrupt_line(&self) -> u8 {
         let mut pci_config_space = PciConfigSpace::new();
 
@@ -84,10 +170,82 @@ impl PciDevice {
         bits[..8].load()
     }
 
+    pub fn enable_legacy_interrupts(&self) {
+        let mut pci_config_space = PciConfigSpace::new();
+
+        let mut word = unsafe { pci_config_space.read(&self.addr, 0x04) };
+
+        // Command register: clearing "Interrupt Disable"
+        let bits = word.view_bits_mut::<Lsb0>();
+        bits.set(10, false);
+
+        unsafe { pci_config_space.write(&self.addr, 0x04, bits.load()) };
+    }
+
+    pub fn disable_legacy_interrupts(&self) {
+        let mut pci_config_space = PciConfigSpace::new();
+
+        let mut word = unsafe { pci_config_space.read(&self.addr, 0x04) };
+
+        // Command register: setting "Interrupt Disable"
+        let bits = word.view_bits_mut::<Lsb0>();
+        bits.set(10, true);
+
+        unsafe { pci_config_space.write(&self.addr, 0x04, bits.load()) };
+    }
+
+    // Enables MSI-X with all table entries masked, and disables the legacy INTx line.
+    // Returns None if the device doesn't support MSI-X.
+    pub fn enable_msix(&self) -> Option<MsixTable> {
+        let mut pci_config_space = PciConfigSpace::new();
+
+        let cap = self.capabilities.iter().find(|cap| cap.vendor == CAP_ID_MSIX)?;
+
+        // Message control (upper half of the first word): table size, minus one
+        let word_0 = unsafe { pci_config_space.read(&self.addr, cap.offset) };
+        let bits_0 = word_0.view_bits::<Lsb0>();
+        let size = bits_0[16..27].load::<u16>() + 1;
+
+        // Table location: BAR index (BIR) in the lower 3 bits, offset in that BAR in the rest
+        let word_4 = unsafe { pci_config_space.read(&self.addr, cap.offset + 4) };
+        let bits_4 = word_4.view_bits::<Lsb0>();
+        let bir = bits_4[..3].load::<u32>();
+        let table_offset: u64 = (word_4 & !0x7).into();
+
+        let bar_addr = match self.bars.get(&bir) {
+            Some(PciBar::Memory { base_addr, .. }) => *base_addr,
+            _ => {
+                log::warn!("MSI-X table of PCI device {} is not in a memory BAR", self.addr);
+                return None;
+            }
+        };
+
+        let table_addr = memory::get_mapper().phys_to_virt(PhysAddr::new(bar_addr + table_offset));
+        let mut table = MsixTable {
+            entries: table_addr.as_mut_ptr(),
+            size,
+        };
+
+        for index in 0..size {
+            table.set_masked(index, true);
+        }
+
+        // Message control: setting "MSI-X Enable" and clearing "Function Mask"
+        let mut word_0 = word_0;
+        let bits_0 = word_0.view_bits_mut::<Lsb0>();
+        bits_0.set(31, true);
+        bits_0.set(30, false);
+        unsafe { pci_config_space.write(&self.addr, cap.offset, bits_0.load()) };
+
+        self.disable_legacy_interrupts();
+
+        Some(table)
+    }
+
     pub fn disable_msix(&self) {
         let mut pci_config_space = PciConfigSpace::new();
 
-        let cap = self.capabilities.iter().find(|cap| cap.vendor == 0x11);
+        let cap = self.capabilities.iter().find(|cap| cap.vendor == CAP_ID_MSIX);
 
         let cap = if let Some(cap) = cap { cap } else { return };
 
@@ -100,68 +258,262 @@ impl PciDevice {
     }
 }
 
+// Looks for memory-mapped configuration space (ECAM) in the ACPI MCFG table.
+// Must be called after acpi::init(), otherwise config space is accessed through port I/O.
+pub fn init() {
+    let regions: Vec<EcamRegion> = acpi::ecam_regions()
+        .into_iter()
+        .filter(|region| {
+            // Port I/O can only reach segment 0, and so does PciAddress
+            if region.segment != 0 {
+                log::warn!("Ignoring ECAM region for PCI segment {}", region.segment);
+            }
+            region.segment == 0
+        })
+        .collect();
+
+    if regions.is_empty() {
+        log::info!("No PCIe ECAM found, using legacy port I/O for PCI config space");
+    }
+
+    for region in regions.iter() {
+        log::info!(
+            "PCIe ECAM at {:#x}, buses {:#04x}-{:#04x}",
+            region.base_addr,
+            region.start_bus,
+            region.end_bus
+        );
+    }
+
+    ECAM_REGIONS.call_once(|| regions);
+}
+
+// Header types (bit 7 is the multi-function flag)
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+
 pub fn enumerate() -> Vec<PciDevice> {
     let mut pci_config_space = PciConfigSpace::new();
 
-    // Don't care about multi-function devices for now
-    let function = 0;
+    let mut devices = Vec::new();
+    let mut scanned_buses = [false; 256];
 
-    let bus_iter = 0..=255u8;
-    let device_iter = 0..32u8;
+    // If the host bridge at 00:00.0 is multi-function, each of its functions
+    // is a separate host controller with its own root bus
+    let host_addr = PciAddress { bus: 0, device: 0, function: 0 };
+    let (_, multi_function) = unsafe { read_header_type(&mut pci_config_space, &host_addr) };
+    let root_buses = match multi_function {
+        false => 0..1u8,
+        true => 0..8u8,
+    };
 
-    bus_iter
-        .flat_map(move |bus| device_iter.clone().map(move |device| (bus, device)))
-        .filter_map(move |(bus, device)| {
-            let addr = PciAddress {
-                bus,
-                device,
-                function,
-            };
+    for function in root_buses {
+        let addr = PciAddress { bus: 0, device: 0, function };
+        if function > 0 && unsafe { pci_config_space.read(&addr, 0x0) } == u32::MAX {
+            continue;
+        }
+        scan_bus(&mut pci_config_space, function, &mut scanned_buses, &mut devices);
+    }
 
-            let word_0 = unsafe { pci_config_space.read(&addr, 0x0) };
+    devices
+}
 
-            // No device at this address
-            if word_0 == u32::MAX {
-                return None;
-            }
+fn scan_bus(
+    pci_config_space: &mut PciConfigSpace,
+    bus: u8,
+    scanned_buses: &mut [bool; 256],
+    devices: &mut Vec<PciDevice>,
+) {
+    // Guarding against misconfigured bridges pointing back to an upstream bus
+    if scanned_buses[bus as usize] {
+        log::warn!("PCI bus {:#04x} already scanned", bus);
+        return;
+    }
+    scanned_buses[bus as usize] = true;
+
+    for device in 0..32u8 {
+        let addr = PciAddress { bus, device, function: 0 };
+
+        // No device at this address
+        if unsafe { pci_config_space.read(&addr, 0x0) } == u32::MAX {
+            continue;
+        }
+
+        let (_, multi_function) = unsafe { read_header_type(pci_config_space, &addr) };
+        let nb_functions = if multi_function { 8 } else { 1 };
+
+        for function in 0..nb_functions {
+            let addr = PciAddress { bus, device, function };
+            scan_function(pci_config_space, addr, scanned_buses, devices);
+        }
+    }
+}
 
-            // Header type
-            let word_0c = unsafe { pci_config_space.read(&addr, 0x0c) };
-            let bits_0c = word_0c.view_bits::<Lsb0>();
-            let mut header_bits = bits_0c[16..24].to_owned();
-            header_bits.set(7, false);
-            let header_type = header_bits.load::<u8>();
-            assert_eq!(header_type, 0x00); // We don't support PCI bridges for now
+fn scan_function(
+    pci_config_space: &mut PciConfigSpace,
+    addr: PciAddress,
+    scanned_buses: &mut [bool; 256],
+    devices: &mut Vec<PciDevice>,
+) {
+    let word_0 = unsafe { pci_config_space.read(&addr, 0x0) };
+
+    // Function not implemented
+    if word_0 == u32::MAX {
+        return;
+    }
 
-            // Device/Vendor IDs
-            let bits_0 = word_0.view_bits::<Lsb0>();
-            let device_id = bits_0[16..32].load();
-            let vendor_id = bits_0[0..16].load();
+    // Device/Vendor IDs
+    let bits_0 = word_0.view_bits::<Lsb0>();
+    let device_id = bits_0[16..32].load();
+    let vendor_id = bits_0[0..16].load();
 
-            // Device class
-            let word_8 = unsafe { pci_config_space.read(&addr, 0x8) };
-            let bits_8 = word_8.view_bits::<Lsb0>();
-            let class = bits_8[24..32].load();
+    // Device class
+    let word_8 = unsafe { pci_config_space.read(&addr, 0x8) };
+    let bits_8 = word_8.view_bits::<Lsb0>();
+    let prog_if = bits_8[8..16].load();
+    let subclass = bits_8[16..24].load();
+    let class = bits_8[24..32].load();
 
-            let capabilities = get_capabilities(&mut pci_config_space, &addr);
-            let bars = get_bars(&mut pci_config_space, &addr);
+    let (header_type, _) = unsafe { read_header_type(pci_config_space, &addr) };
+
+    match header_type {
+        HEADER_TYPE_DEVICE => {
+            let capabilities = get_capabilities(pci_config_space, &addr);
+            let extended_capabilities = get_extended_capabilities(pci_config_space, &addr);
+            let bars = get_bars(pci_config_space, &addr);
 
             log::info!(
-                "Found PCI device, vendor={:#x} device={:#x}",
+                "Found PCI device at {}, vendor={:#x} device={:#x} ({})",
+                addr,
                 vendor_id,
-                device_id
+                device_id,
+                class_name(class, subclass, prog_if)
             );
 
-            Some(PciDevice {
+            for cap in extended_capabilities.iter() {
+                log::debug!(
+                    "  PCIe extended capability {:#06x} v{} at {:#x}",
+                    cap.id,
+                    cap.version,
+                    cap.offset
+                );
+            }
+
+            devices.push(PciDevice {
                 addr,
                 vendor_id,
                 device_id,
                 class,
+                subclass,
+                prog_if,
                 capabilities,
+                extended_capabilities,
                 bars,
             })
-        })
-        .collect()
+        }
+
+        HEADER_TYPE_PCI_BRIDGE => {
+            // Bus numbers: primary, secondary, subordinate
+            let word_18 = unsafe { pci_config_space.read(&addr, 0x18) };
+            let bits_18 = word_18.view_bits::<Lsb0>();
+            let secondary_bus = bits_18[8..16].load::<u8>();
+
+            log::info!(
+                "Found PCI bridge at {}, vendor={:#x} device={:#x}, secondary bus {:#04x}",
+                addr,
+                vendor_id,
+                device_id,
+                secondary_bus
+            );
+
+            // Not configured by the firmware
+            if secondary_bus == 0 {
+                log::warn!("PCI bridge at {} has no secondary bus, skipping", addr);
+                return;
+            }
+
+            scan_bus(pci_config_space, secondary_bus, scanned_buses, devices);
+        }
+
+        header_type => log::warn!(
+            "Unsupported PCI header type {:#x} at {} ({})",
+            header_type,
+            addr,
+            class_name(class, subclass, prog_if)
+        ),
+    }
+}
+
+// Returns the header type (without the multi-function bit) and the multi-function flag
+unsafe fn read_header_type(pci_config_space: &mut PciConfigSpace, addr: &PciAddress) -> (u8, bool) {
+    let word_0c = pci_config_space.read(addr, 0x0c);
+    let bits_0c = word_0c.view_bits::<Lsb0>();
+    let mut header_bits = bits_0c[16..24].to_owned();
+    let multi_function = header_bits[7];
+    header_bits.set(7, false);
+    (header_bits.load::<u8>(), multi_function)
+}
+
+// Human-readable description of a class code, see the PCI Code and ID Assignment specification
+pub fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x00, 0x01, _) => "VGA-compatible unclassified device",
+        (0x00, _, _) => "Unclassified device",
+
+        (0x01, 0x00, _) => "SCSI storage controller",
+        (0x01, 0x01, _) => "IDE controller",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x07, _) => "Serial Attached SCSI controller",
+        (0x01, 0x08, 0x02) => "NVMe controller",
+        (0x01, 0x08, _) => "Non-volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+
+        (0x03, 0x00, 0x00) => "VGA controller",
+        (0x03, 0x00, _) => "VGA-compatible controller",
+        (0x03, 0x02, _) => "3D controller",
+        (0x03, _, _) => "Display controller",
+
+        (0x04, 0x01, _) => "Audio device",
+        (0x04, 0x03, _) => "Audio controller",
+        (0x04, _, _) => "Multimedia controller",
+
+        (0x05, _, _) => "Memory controller",
+
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI-to-PCI bridge",
+        (0x06, 0x07, _) => "CardBus bridge",
+        (0x06, _, _) => "Bridge",
+
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, _, _) => "Communication controller",
+
+        (0x08, 0x00, _) => "Interrupt controller",
+        (0x08, 0x03, _) => "RTC controller",
+        (0x08, _, _) => "System peripheral",
+
+        (0x09, _, _) => "Input device controller",
+
+        (0x0c, 0x03, 0x00) => "USB UHCI controller",
+        (0x0c, 0x03, 0x10) => "USB OHCI controller",
+        (0x0c, 0x03, 0x20) => "USB EHCI controller",
+        (0x0c, 0x03, 0x30) => "USB xHCI controller",
+        (0x0c, 0x03, _) => "USB controller",
+        (0x0c, 0x05, _) => "SMBus controller",
+        (0x0c, _, _) => "Serial bus controller",
+
+        (0x0d, _, _) => "Wireless controller",
+        (0x10, _, _) => "Encryption controller",
+        (0x11, _, _) => "Signal processing controller",
+        (0x12, _, _) => "Processing accelerator",
+
+        _ => "Unknown device",
+    }
 }
 
 fn get_capabilities(
@@ -172,7 +524,7 @@ fn get_capabilities(
         let mut word_34 = unsafe { pci_config_space.read(&addr, 0x34) };
         let bits_34 = word_34.view_bits_mut::<Lsb0>();
         bits_34[..2].fill(false);
-        bits_34[..8].load::<u8>()
+        bits_34[..8].load::<u16>()
     };
 
     let mut capabilities = Vec::new();
@@ -193,6 +545,47 @@ fn get_capabilities(
     capabilities
 }
 
+fn get_extended_capabilities(
+    pci_config_space: &mut PciConfigSpace,
+    addr: &PciAddress,
+) -> Vec<PciExtendedCapability> {
+    let mut capabilities = Vec::new();
+
+    if !pci_config_space.has_extended_config(addr) {
+        return capabilities;
+    }
+
+    // The list always starts right after the legacy config space
+    let mut cap_ptr = LEGACY_CONFIG_SIZE;
+
+    // Each capability takes at least one dword, which bounds the walk on a broken list
+    let max_caps = (EXTENDED_CONFIG_SIZE - LEGACY_CONFIG_SIZE) / 4;
+
+    for _ in 0..max_caps {
+        let word_0 = unsafe { pci_config_space.read(addr, cap_ptr) };
+
+        // No extended capabilities (or not a PCIe function)
+        if word_0 == 0 || word_0 == u32::MAX {
+            break;
+        }
+
+        let bits_0 = word_0.view_bits::<Lsb0>();
+
+        capabilities.push(PciExtendedCapability {
+            id: bits_0[0..16].load(),
+            version: bits_0[16..20].load(),
+            offset: cap_ptr,
+        });
+
+        cap_ptr = bits_0[20..32].load();
+        if cap_ptr < LEGACY_CONFIG_SIZE {
+            break;
+        }
+    }
+
+    capabilities
+}
+
 fn get_bars(pci_config_space: &mut PciConfigSpace, addr: &PciAddress) -> BTreeMap<u32, PciBar> {
     const MAX_BARS: u32 = 6;
 
@@ -200,7 +593,7 @@ fn get_bars(pci_config_space: &mut PciConfigSpace, addr: &PciAddress) -> BTreeMa
     let mut it = 0..MAX_BARS;
 
     while let Some(i) = it.next() {
-        let offset = 0x10 + 0x4 * (i as u8);
+        let offset = 0x10 + 0x4 * (i as u16);
         let word_bars = unsafe { pci_config_space.read(&addr, offset) };
 
         let bits_bar = word_bars.view_bits::<Lsb0>();
@@ -256,7 +649,7 @@ fn get_bars(pci_config_space: &mut PciConfigSpace, addr: &PciAddress) -> BTreeMa
                     BarAddrType::Bar64 => {
                         // Grabbing high bits of the address from next BAR
                         let next_i = it.next().expect("64-bit BAR but already in last BAR");
-                        let next_offset = 0x10 + 0x4 * (next_i as u8);
+                        let next_offset = 0x10 + 0x4 * (next_i as u16);
                         let next_word_bars = unsafe { pci_config_space.read(&addr, next_offset) };
                         let next_word_bars: u64 = next_word_bars.into();
                         let addr_high_bits = next_word_bars.view_bits::<Lsb0>();
@@ -301,17 +694,23 @@ impl PciConfigSpace {
         PciConfigSpace {
             address_port: PortWriteOnly::<u32>::new(0xCF8),
             data_port: Port::<u32>::new(0xCFC),
+            ecam_regions: ECAM_REGIONS.r#try().map(|regions| regions.as_slice()).unwrap_or(&[]),
         }
     }
 
-    pub unsafe fn read_struct<T: Clone>(&mut self, addr: &PciAddress, offset: u8) -> T {
+    // Whether offsets past 0x100 can be accessed for this function
+    pub fn has_extended_config(&self, addr: &PciAddress) -> bool {
+        self.get_ecam_ptr(addr, 0).is_some()
+    }
+
+    pub unsafe fn read_struct<T: Clone>(&mut self, addr: &PciAddress, offset: u16) -> T {
         let n = mem::size_of::<T>();
         assert_eq!(n % 4, 0);
         let num_words = n / 4;
 
         let buf: Vec<u32> = (0..num_words)
             .map(|i| {
-                let i: u8 = i.try_into().unwrap();
+                let i: u16 = i.try_into().unwrap();
                 self.read(addr, offset + 4 * i)
             })
             .collect();
@@ -321,22 +720,55 @@ impl PciConfigSpace {
     }
 
     // Unsafe because addr and offset have to point to valid data
-    pub unsafe fn read(&mut self, addr: &PciAddress, offset: u8) -> u32 {
-        let addr_word = Self::get_addr_word(addr, offset);
-
-        self.address_port.write(addr_word);
-        self.data_port.read()
+    pub unsafe fn read(&mut self, addr: &PciAddress, offset: u16) -> u32 {
+        match self.get_ecam_ptr(addr, offset) {
+            Some(ptr) => read_volatile(ptr.as_ptr::<u32>()),
+            None => {
+                let addr_word = Self::get_addr_word(addr, offset);
+
+                self.address_port.write(addr_word);
+                self.data_port.read()
+            }
+        }
     }
 
     // Same
-    pub unsafe fn write(&mut self, addr: &PciAddress, offset: u8, val: u32) {
-        let addr_word = Self::get_addr_word(addr, offset);
+    pub unsafe fn write(&mut self, addr: &PciAddress, offset: u16, val: u32) {
+        match self.get_ecam_ptr(addr, offset) {
+            Some(ptr) => write_volatile(ptr.as_mut_ptr::<u32>(), val),
+            None => {
+                let addr_word = Self::get_addr_word(addr, offset);
+
+                self.address_port.write(addr_word);
+                self.data_port.write(val);
+            }
+        }
+    }
+
+    // Each function gets 4KiB in the ECAM region covering its bus
+    fn get_ecam_ptr(&self, addr: &PciAddress, offset: u16) -> Option<VirtAddr> {
+        assert!(offset < EXTENDED_CONFIG_SIZE, "Invalid PCI config space offset {:#x}", offset);
+
+        let region = self
+            .ecam_regions
+            .iter()
+            .find(|region| (region.start_bus..=region.end_bus).contains(&addr.bus))?;
 
-        self.address_port.write(addr_word);
-        self.data_port.write(val);
+        let bus: u64 = (addr.bus - region.start_bus).into();
+        let device: u64 = addr.device.into();
+        let function: u64 = addr.function.into();
+
+        // Accesses are always done as aligned dwords
+        let offset: u64 = (offset & !0x3).into();
+
+        let phys_addr = region.base_addr + (bus << 20 | device << 15 | function << 12 | offset);
+
+        Some(memory::get_mapper().phys_to_virt(PhysAddr::new(phys_addr)))
     }
 
-    fn get_addr_word(addr: &PciAddress, offset: u8) -> u32 {
+    fn get_addr_word(addr: &PciAddress, offset: u16) -> u32 {
+        assert!(offset < LEGACY_CONFIG_SIZE, "PCI extended config space requires ECAM");
+
         let mut val = 0u32;
         let bits = val.view_bits_mut::<Lsb0>();
 
@@ -4,6 +4,8 @@ use applib::{decode_png, Framebuffer, OwnedPixels, Rect, Color};
 use applib::{StyleSheet, StyleSheetColors};
 use lazy_static::lazy_static;
 
+const MB: usize = 1024 * 1024;
+
 lazy_static! {
 
     //
@@ -71,7 +73,7 @@ lazy_static! {
     //
     // WASM apps
 
-    pub static ref APPLICATIONS: [AppDescriptor; 5] = [
+    pub static ref APPLICATIONS: [AppDescriptor; 6] = [
         AppDescriptor {
             data: include_bytes!("../wasm/cube_3d.wasm"),
             name: "3D Demo",
@@ -82,6 +84,7 @@ lazy_static! {
                 h: 400
             },
             icon: &CUBE_ICON,
+            mem_quota: 32 * MB,
         },
         AppDescriptor {
             data: include_bytes!("../wasm/chronometer.wasm"),
@@ -93,6 +96,7 @@ lazy_static! {
                 h: 200
             },
             icon: &CHRONO_ICON,
+            mem_quota: 16 * MB,
         },
         AppDescriptor {
             data: include_bytes!("../wasm/terminal.wasm"),
@@ -104,6 +108,7 @@ lazy_static! {
                 h: 300
             },
             icon: &PYTHON_ICON,
+            mem_quota: 128 * MB,
         },
         AppDescriptor {
             data: include_bytes!("../wasm/web_browser.wasm"),
@@ -115,6 +120,7 @@ lazy_static! {
                 h: 600
             },
             icon: &WEB_ICON,
+            mem_quota: 256 * MB,
         },
         AppDescriptor {
             data: include_bytes!("../wasm/demo.wasm"),
@@ -126,6 +132,19 @@ lazy_static! {
                 h: 600
             },
             icon: &WEB_ICON,
+            mem_quota: 64 * MB,
+        },
+        AppDescriptor {
+            data: include_bytes!("../wasm/log_viewer.wasm"),
+            name: "Log viewer",
+            init_win_rect: Rect {
+                x0: 300,
+                y0: 200,
+                w: 900,
+                h: 600
+            },
+            icon: &TERMINAL_ICON,
+            mem_quota: 64 * MB,
         },
     ];
 }
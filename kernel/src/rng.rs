@@ -0,0 +1,106 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step, _rdtsc};
+
+use alloc::vec::Vec;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+use crate::virtio::rng::VirtioRng;
+
+// RDRAND/RDSEED fail transiently when the CPU's entropy buffer is drained
+const CPU_RNG_RETRIES: usize = 100;
+
+// Seeds the system CSPRNG with all the entropy sources available, XORed together,
+// so that a single good source is enough.
+pub fn new_system_rng(virtio_rng: Option<VirtioRng>) -> ChaCha20Rng {
+
+    let mut seed = [0u8; 32];
+    let mut sources = Vec::new();
+
+    if let Some((bytes, source)) = read_cpu_entropy() {
+        xor_into(&mut seed, &bytes);
+        sources.push(source);
+    }
+
+    if let Some(mut virtio_rng) = virtio_rng {
+        let mut bytes = [0u8; 32];
+        match virtio_rng.fill_bytes(&mut bytes) {
+            Ok(()) => {
+                xor_into(&mut seed, &bytes);
+                sources.push("virtio-rng");
+            }
+            Err(err) => log::warn!("Cannot read from VirtIO RNG device: {}", err),
+        }
+    }
+
+    if sources.is_empty() {
+        log::warn!(
+            "No entropy source (no RDSEED/RDRAND support and no virtio-rng device), \
+            random numbers will be predictable. Add -device virtio-rng-pci to the QEMU command line."
+        );
+
+        // Better than a constant seed, but easy to guess
+        let tsc = unsafe { _rdtsc() };
+        xor_into(&mut seed, &tsc.to_le_bytes());
+    } else {
+        log::info!("System RNG seeded from {}", sources.join(", "));
+    }
+
+    ChaCha20Rng::from_seed(seed)
+}
+
+// RDSEED gives raw entropy, RDRAND the output of a DRBG reseeded from it
+fn read_cpu_entropy() -> Option<([u8; 32], &'static str)> {
+
+    let (read_u64, source): (unsafe fn() -> Option<u64>, &'static str) = if rdseed_supported() {
+        (rdseed, "RDSEED")
+    } else if rdrand_supported() {
+        (rdrand, "RDRAND")
+    } else {
+        return None;
+    };
+
+    let mut bytes = [0u8; 32];
+    for chunk in bytes.chunks_exact_mut(8) {
+        let Some(val) = (0..CPU_RNG_RETRIES).find_map(|_| unsafe { read_u64() }) else {
+            log::warn!("{} keeps failing, not using it", source);
+            return None;
+        };
+        chunk.copy_from_slice(&val.to_le_bytes());
+    }
+
+    Some((bytes, source))
+}
+
+fn rdrand_supported() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 30) != 0
+}
+
+fn rdseed_supported() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0
+}
+
+// Some AMD CPUs have been known to return all ones while reporting success
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut val = 0;
+    match _rdrand64_step(&mut val) {
+        1 if val != u64::MAX => Some(val),
+        _ => None,
+    }
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut val = 0;
+    match _rdseed64_step(&mut val) {
+        1 if val != u64::MAX => Some(val),
+        _ => None,
+    }
+}
+
+fn xor_into(seed: &mut [u8; 32], bytes: &[u8]) {
+    for (s, b) in seed.iter_mut().zip(bytes) {
+        *s ^= b;
+    }
+}
@@ -2,9 +2,19 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
+pub const COM1_BASE: u16 = 0x3F8;
+pub const COM2_BASE: u16 = 0x2F8;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut port = unsafe { SerialPort::new(0x3F8) };
+        let mut port = unsafe { SerialPort::new(COM1_BASE) };
+        port.init();
+        Mutex::new(port)
+    };
+
+    // Reserved for the GDB stub
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM2_BASE) };
         port.init();
         Mutex::new(port)
     };
@@ -30,6 +30,12 @@ impl PieMenuEntry {
             PieMenuEntry::Spacer { weight, .. } => *weight,
         }
     }
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            PieMenuEntry::Button { text, .. } => Some(text),
+            PieMenuEntry::Spacer { .. } => None,
+        }
+    }
     // fn color(&self) -> Color {
     //     match self {
     //         PieMenuEntry::Button { color, .. } => *color,
@@ -44,11 +50,12 @@ pub struct PieDrawCalls {
     calls: Vec<DrawCall>
 }
 
-pub fn pie_menu<'a, F: FbViewMut>(
+// Returns the index of the entry clicked, if any
+pub fn pie_menu<F: FbViewMut>(
     uitk_context: &mut uitk::UiContext<F>,
-    entries: &'a [PieMenuEntry],
+    entries: &[PieMenuEntry],
     center: Point2D<i64>,
-) -> (Option<&'a str>, PieDrawCalls) {
+) -> (Option<usize>, PieDrawCalls) {
     const INNER_RADIUS: f32 = 50.0;
     const OUTER_RADIUS: f32 = 100.0;
     const DEADZONE_INNER_RADIUS: f32 = 25.0;
@@ -81,7 +88,7 @@ pub fn pie_menu<'a, F: FbViewMut>(
     let mut a0 = -PI * entries[0].weight() / total_weight;
     let mut draw_calls  = PieDrawCalls { calls: Vec::new() };
 
-    for entry in entries.iter() {
+    for (i, entry) in entries.iter().enumerate() {
         let delta_angle = 2.0 * PI * entry.weight() / total_weight;
         let a1 = a0 + delta_angle;
 
@@ -106,14 +113,14 @@ pub fn pie_menu<'a, F: FbViewMut>(
 
         let is_hovered = match entry {
             PieMenuEntry::Spacer { .. } => false,
-            PieMenuEntry::Button { text, .. } => {
+            PieMenuEntry::Button { .. } => {
                 let is_hovered = v_cursor.cross(v0) < 0.0
                     && v_cursor.cross(v1) > 0.0
                     && center_dist > DEADZONE_INNER_RADIUS
                     && center_dist < DEADZONE_OUTER_RADIUS;
 
                 if is_hovered && uitk_context.input_state.pointer.left_click_trigger {
-                    selected_entry = Some(text.as_str());
+                    selected_entry = Some(i);
                 }
 
                 is_hovered
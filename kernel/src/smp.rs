@@ -0,0 +1,393 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::PhysAddr;
+
+use crate::acpi;
+use crate::interrupts::{self, WAKEUP_VECTOR};
+use crate::memory;
+use crate::panic_screen;
+use crate::time::SystemClock;
+
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_SIZE: usize = 512 * 1024;
+const AP_START_TIMEOUT_MS: f64 = 100.0;
+
+static CORES: Once<Vec<Core>> = Once::new();
+// Work sent to a core. Tasks can borrow from the caller of run_parallel(), which
+// erases their lifetime but doesn't return before they are all done.
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+struct Core {
+    apic_id: u8,
+    queue: Mutex<VecDeque<Task>>,
+    online: AtomicBool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CoreReport {
+    pub busy_time: f64,
+    pub nb_tasks: usize,
+}
+
+// For data that is only accessed by one core at a time, but can't be proven Send
+pub struct AssertSend<T>(T);
+
+unsafe impl<T> Send for AssertSend<T> {}
+
+impl<T> AssertSend<T> {
+    pub unsafe fn new(val: T) -> Self {
+        AssertSend(val)
+    }
+
+    // Taking self as a whole, so that closures capture the wrapper and not its field
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+//
+// Application processors start in real mode at the beginning of a page below 1MB,
+// from which this trampoline goes straight to long mode using the BSP's page tables.
+// The code is copied at runtime, so addresses are either relative to the start of
+// the trampoline (real mode, where the parameters have to be at a known offset) or
+// RIP-relative (long mode). The absolute target of the far jump and the parameters
+// are patched in by init().
+
+global_asm!(
+    r#"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_tramp_params
+    .global ap_tramp_gdt
+    .global ap_tramp_farjmp
+    .global ap_tramp_long_mode
+
+    .code16
+ap_trampoline_start:
+    jmp ap_tramp_real_mode
+
+    // At offset 8, see TRAMPOLINE_PARAMS_OFFSET
+    .align 8
+ap_tramp_params:
+    .skip 48
+
+ap_tramp_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+
+ap_tramp_real_mode:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    mov eax, dword ptr [8 + 16]
+    mov cr4, eax
+    mov eax, dword ptr [8 + 12]
+    mov cr3, eax
+
+    // EFER: long mode and no-execute enable
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 0x900
+    wrmsr
+
+    lgdt [8 + 2]
+
+    // Enables protection and paging at once
+    mov eax, dword ptr [8 + 8]
+    mov cr0, eax
+
+    // jmp far 0x08:ap_tramp_long_mode (32-bit offset)
+    .byte 0x66, 0xEA
+ap_tramp_farjmp:
+    .long 0
+    .word 0x08
+
+    .code64
+ap_tramp_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, qword ptr [rip + ap_tramp_params + 24]
+    and rsp, -16
+    mov rdi, qword ptr [rip + ap_tramp_params + 32]
+    mov rax, qword ptr [rip + ap_tramp_params + 40]
+    call rax
+    ud2
+
+ap_trampoline_end:
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_tramp_params: u8;
+    static ap_tramp_gdt: u8;
+    static ap_tramp_farjmp: u8;
+    static ap_tramp_long_mode: u8;
+}
+
+const TRAMPOLINE_PARAMS_OFFSET: usize = 8;
+
+// Must match the layout of ap_tramp_params
+#[repr(C)]
+struct TrampolineParams {
+    _pad: u16,
+    gdt_limit: u16,
+    gdt_base: u32,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    _pad2: u32,
+    stack_top: u64,
+    arg: u64,
+    entry: u64,
+}
+
+pub fn init(clock: &SystemClock, trampoline_page: PhysAddr) {
+
+    let lapic = interrupts::local_apic();
+    let bsp_apic_id = lapic.id();
+
+    // The boot CPU is always core 0
+    let mut apic_ids = acpi::local_apic_ids();
+    apic_ids.retain(|apic_id| *apic_id != bsp_apic_id);
+    apic_ids.insert(0, bsp_apic_id);
+    if apic_ids.len() > MAX_CPUS {
+        log::warn!("Only using {} out of {} CPUs", MAX_CPUS, apic_ids.len());
+        apic_ids.truncate(MAX_CPUS);
+    }
+
+    let cores = CORES.call_once(|| {
+        apic_ids
+            .iter()
+            .map(|apic_id| Core {
+                apic_id: *apic_id,
+                queue: Mutex::new(VecDeque::new()),
+                online: AtomicBool::new(false),
+            })
+            .collect()
+    });
+
+    cores[0].online.store(true, Ordering::Release);
+
+    if cores.len() == 1 {
+        log::info!("Single CPU system");
+        return;
+    }
+
+    let page_addr = trampoline_page.as_u64();
+    assert!(page_addr < 0x10_0000 && page_addr % 4096 == 0, "Invalid AP trampoline page");
+
+    let params = unsafe { install_trampoline(page_addr) };
+
+    for (core_index, core) in cores.iter().enumerate().skip(1) {
+
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+
+        unsafe {
+            let stack_top = stack.as_ptr_range().end as u64;
+            core::ptr::addr_of_mut!((*params).stack_top).write_volatile(stack_top);
+            core::ptr::addr_of_mut!((*params).arg).write_volatile(core_index as u64);
+        }
+
+        // INIT-SIPI-SIPI sequence
+        lapic.send_init(core.apic_id);
+        clock.spin_delay(10.0);
+        for _ in 0..2 {
+            lapic.send_startup(core.apic_id, (page_addr >> 12) as u8);
+            clock.spin_delay(0.2);
+            if core.online.load(Ordering::Acquire) {
+                break;
+            }
+        }
+
+        // The trampoline parameters can only be reused once the AP is running on its own stack
+        let t0 = clock.time();
+        while !core.online.load(Ordering::Acquire) && clock.time() - t0 < AP_START_TIMEOUT_MS {
+            core::hint::spin_loop();
+        }
+
+        match core.online.load(Ordering::Acquire) {
+            true => log::debug!("Started core {} (APIC ID {})", core_index, core.apic_id),
+            false => log::warn!("Core {} (APIC ID {}) did not start", core_index, core.apic_id),
+        }
+    }
+
+    log::info!("{} cores online", nb_online_cores());
+}
+
+pub fn nb_cores() -> usize {
+    CORES.r#try().map(|cores| cores.len()).unwrap_or(1)
+}
+
+fn nb_online_cores() -> usize {
+    CORES
+        .r#try()
+        .map(|cores| cores.iter().filter(|core| core.online.load(Ordering::Acquire)).count())
+        .unwrap_or(1)
+}
+
+// Index of the calling core, 0 being the boot CPU
+pub fn cpu_index() -> usize {
+    let Some(cores) = CORES.r#try() else {
+        return 0;
+    };
+    let apic_id = interrupts::local_apic().id();
+    cores.iter().position(|core| core.apic_id == apic_id).unwrap_or(0)
+}
+
+// Spreads the tasks over the per-core queues, and works on them from the boot CPU too
+// until they are all done. Idle cores steal tasks from the others.
+pub fn run_parallel<'a>(clock: &SystemClock, tasks: Vec<Box<dyn FnOnce() + Send + 'a>>) -> Vec<CoreReport> {
+
+    let reports: Vec<Mutex<CoreReport>> = (0..nb_cores()).map(|_| Mutex::new(CoreReport::default())).collect();
+    let pending = AtomicUsize::new(tasks.len());
+
+    let Some(cores) = CORES.r#try() else {
+        // Not initialized: running everything here
+        for task in tasks {
+            let t0 = clock.time();
+            task();
+            let mut report = reports[0].lock();
+            report.busy_time += clock.time() - t0;
+            report.nb_tasks += 1;
+        }
+        return reports.into_iter().map(|report| report.into_inner()).collect();
+    };
+
+    let online: Vec<usize> = (0..cores.len())
+        .filter(|i| cores[*i].online.load(Ordering::Acquire))
+        .collect();
+
+    for (i, task) in tasks.into_iter().enumerate() {
+
+        let (reports, pending) = (&reports, &pending);
+
+        let task: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            let t0 = clock.time();
+            task();
+            let mut report = reports[cpu_index()].lock();
+            report.busy_time += clock.time() - t0;
+            report.nb_tasks += 1;
+            drop(report);
+            pending.fetch_sub(1, Ordering::Release);
+        });
+
+        // Safety: we don't return before all tasks are done, so their borrows stay valid
+        let task: Task = unsafe { core::mem::transmute(task) };
+
+        cores[online[i % online.len()]].queue.lock().push_back(task);
+    }
+
+    let lapic = interrupts::local_apic();
+    for core in cores.iter().skip(1) {
+        if !core.queue.lock().is_empty() {
+            lapic.send_fixed(core.apic_id, WAKEUP_VECTOR);
+        }
+    }
+
+    while pending.load(Ordering::Acquire) > 0 {
+        match next_task(cores, 0) {
+            Some(task) => task(),
+            None => {
+                // A task panicked on another core, which is now halted
+                if panic_screen::is_panicking() {
+                    loop {
+                        x86_64::instructions::hlt();
+                    }
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    reports.into_iter().map(|report| report.into_inner()).collect()
+}
+
+// Own queue first, then stealing from the back of the other ones
+fn next_task(cores: &[Core], core_index: usize) -> Option<Task> {
+
+    if let Some(task) = cores[core_index].queue.lock().pop_front() {
+        return Some(task);
+    }
+
+    (1..cores.len())
+        .map(|offset| &cores[(core_index + offset) % cores.len()])
+        .find_map(|core| core.queue.lock().pop_back())
+}
+
+extern "sysv64" fn ap_main(core_index: u64) -> ! {
+
+    let core_index = core_index as usize;
+
+    // Also replaces the trampoline's GDT
+    interrupts::init_ap();
+
+    let cores = CORES.r#try().expect("SMP not initialized");
+    cores[core_index].online.store(true, Ordering::Release);
+
+    loop {
+        match next_task(cores, core_index) {
+            Some(task) => task(),
+            None => {
+                // sti takes effect after hlt, so a wakeup IPI can't be missed in between
+                x86_64::instructions::interrupts::enable_and_hlt();
+                x86_64::instructions::interrupts::disable();
+            }
+        }
+    }
+}
+
+unsafe fn install_trampoline(page_addr: u64) -> *mut TrampolineParams {
+
+    let start = core::ptr::addr_of!(ap_trampoline_start);
+    let offset_of = |sym: *const u8| sym as usize - start as usize;
+
+    let len = offset_of(core::ptr::addr_of!(ap_trampoline_end));
+    let page = memory::get_mapper().phys_to_virt(PhysAddr::new(page_addr)).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(start, page, len);
+
+    let farjmp = page.add(offset_of(core::ptr::addr_of!(ap_tramp_farjmp))) as *mut u32;
+    let long_mode_addr = page_addr + offset_of(core::ptr::addr_of!(ap_tramp_long_mode)) as u64;
+    farjmp.write_unaligned(long_mode_addr as u32);
+
+    let (l4_frame, _) = Cr3::read();
+    let cr3 = l4_frame.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "Page tables must be below 4GB for AP startup");
+
+    // PCID can only be enabled from long mode
+    let cr4 = Cr4::read() & !Cr4Flags::PCID;
+
+    let params_offset = offset_of(core::ptr::addr_of!(ap_tramp_params));
+    assert_eq!(params_offset, TRAMPOLINE_PARAMS_OFFSET, "Unexpected AP trampoline layout");
+    let params = page.add(params_offset) as *mut TrampolineParams;
+    params.write_volatile(TrampolineParams {
+        _pad: 0,
+        gdt_limit: 3 * 8 - 1,
+        gdt_base: (page_addr + offset_of(core::ptr::addr_of!(ap_tramp_gdt)) as u64) as u32,
+        cr0: Cr0::read_raw() as u32,
+        cr3: cr3 as u32,
+        cr4: cr4.bits() as u32,
+        _pad2: 0,
+        stack_top: 0,
+        arg: 0,
+        entry: ap_main as usize as u64,
+    });
+
+    params
+}
@@ -1,4 +1,5 @@
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use crate::allocator::AllocStats;
 
 const HISTORY_SIZE: usize = 256; // In number of frames
@@ -8,6 +9,7 @@ pub struct SystemStats {
     pub heap_total: usize,
 
     by_app: BTreeMap<&'static str, [AppDataPoint; HISTORY_SIZE]>,
+    by_core: Vec<[CoreDataPoint; HISTORY_SIZE]>,
     system: [SystemDataPoint; HISTORY_SIZE],
 
     ring_index: usize,
@@ -27,11 +29,18 @@ pub struct AppDataPoint {
     pub net_sent: usize,
     pub mem_used: usize,
     pub frametime_used: f64,
+    pub core: usize, // Core the app was last stepped on
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CoreDataPoint {
+    pub busy_time: f64, // Time spent stepping apps, in milliseconds
+    pub nb_tasks: usize,
 }
 
 impl SystemStats {
 
-    pub fn new(alloc_stats: &AllocStats, app_names: &[&'static str]) -> Self {
+    pub fn new(alloc_stats: &AllocStats, app_names: &[&'static str], nb_cores: usize) -> Self {
 
         let by_app = app_names.iter().map(|app_name| {
 
@@ -39,13 +48,18 @@ impl SystemStats {
                 net_recv: 0,
                 net_sent: 0,
                 mem_used: 0,
-                frametime_used: 0.0
+                frametime_used: 0.0,
+                core: 0,
             });
 
             (*app_name, app_history)
         })
         .collect();
 
+        let by_core = (0..nb_cores)
+            .map(|_| core::array::from_fn(|_| CoreDataPoint::default()))
+            .collect();
+
         let system_history: [SystemDataPoint; HISTORY_SIZE] = core::array::from_fn(|_| SystemDataPoint {
             net_recv: 0,
             net_sent: 0,
@@ -56,6 +70,7 @@ impl SystemStats {
         SystemStats {
             heap_total: alloc_stats.total,
             by_app,
+            by_core,
             system: system_history,
             ring_index: 0,
         }
@@ -74,6 +89,14 @@ impl SystemStats {
         app_history.get_mut(self.ring_index).unwrap()
     }
 
+    pub fn nb_cores(&self) -> usize {
+        self.by_core.len()
+    }
+
+    pub fn get_core_point_mut(&mut self, core: usize) -> &mut CoreDataPoint {
+        self.by_core[core].get_mut(self.ring_index).unwrap()
+    }
+
     pub fn get_system_history<T, F>(&self, selector: F) -> [T; HISTORY_SIZE]
 
         where F: Fn(&SystemDataPoint) -> T
@@ -85,6 +108,16 @@ impl SystemStats {
 
     }
 
+    pub fn get_core_history<T, F>(&self, core: usize, selector: F) -> [T; HISTORY_SIZE]
+
+        where F: Fn(&CoreDataPoint) -> T
+    {
+        core::array::from_fn(|t| {
+            let dp = get_history_point(&self.by_core[core], self.ring_index, t);
+            selector(dp)
+        })
+    }
+
     pub fn get_app_history<T, F>(&self,  app_name: &str, selector: F) -> [T; HISTORY_SIZE]
     
         where F: Fn(&AppDataPoint) -> T
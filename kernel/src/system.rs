@@ -1,12 +1,14 @@
-use crate::{network::TcpStack, time::SystemClock};
-use rand::rngs::SmallRng;
+use crate::{network::TcpStack, time::SystemClock, vfs::Vfs, virtio::vsock::VirtioVsock};
+use rand_chacha::ChaCha20Rng;
 use applib::StyleSheet;
 use crate::stats::SystemStats;
 
 pub struct System {
     pub clock: SystemClock,
     pub tcp_stack: TcpStack,
-    pub rng: SmallRng,
+    pub vsock: Option<VirtioVsock>,
+    pub rng: ChaCha20Rng,
     pub stylesheet: &'static StyleSheet,
     pub stats: SystemStats,
+    pub vfs: Vfs,
 }
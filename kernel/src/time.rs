@@ -1,30 +1,73 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
 use uefi::prelude::RuntimeServices;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
 
 use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 
+use crate::{acpi, memory};
+
+// How long the TSC is measured against the reference timer
+const CALIBRATION_MS: f64 = 50.0;
+
+const PIT_FREQ_HZ: f64 = 1_193_182.0;
+const PM_TIMER_FREQ_HZ: f64 = 3_579_545.0;
+
+// HPET registers
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_MAIN_COUNTER: usize = 0xF0;
+const HPET_COUNTER_64BIT: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1 << 0;
+
+// The TSC is the time source, the other timers are only used to find its frequency.
+// Monotonic time starts at zero when the clock is created and is never adjusted;
+// wall time is the RTC time read at boot plus the monotonic time elapsed since.
+#[derive(Clone)]
 pub struct SystemClock {
-    period_s: f64,
-    epoch_offset: f64,
+    tsc_freq_hz: f64,
+    tsc_origin: u64,
+    boot_epoch_ns: u64,
 }
 
 impl SystemClock {
     pub fn new(runtime_services: &RuntimeServices) -> Self {
-        let period_s = Self::estimate_cpu_period(runtime_services);
-        let secs_since_epoch = Self::get_epoch_time(runtime_services);
 
-        let n = unsafe { core::arch::x86_64::_rdtsc() };
-        let epoch_offset: f64 = secs_since_epoch - (n as f64 * period_s);
+        if !tsc_is_invariant() {
+            log::warn!("TSC is not invariant, timings may drift if the CPU frequency changes");
+        }
+
+        let (tsc_freq_hz, source) = calibrate_tsc();
+        log::info!("TSC frequency: {:.3}GHz (calibrated against {})", tsc_freq_hz / 1e9, source);
+
+        let boot_epoch_ns = Self::get_epoch_time(runtime_services);
 
         SystemClock {
-            period_s,
-            epoch_offset,
+            tsc_freq_hz,
+            tsc_origin: rdtsc(),
+            boot_epoch_ns,
         }
     }
 
+    // Milliseconds since boot, for measuring durations
     pub fn time(&self) -> f64 {
-        // in milliseconds
-        let n = unsafe { core::arch::x86_64::_rdtsc() };
-        1000f64 * (n as f64) * self.period_s + self.epoch_offset
+        self.monotonic_ns() as f64 / 1e6
+    }
+
+    pub fn monotonic_ns(&self) -> u64 {
+        // Cores may be very slightly out of sync
+        let ticks = rdtsc().saturating_sub(self.tsc_origin);
+        (ticks as f64 * 1e9 / self.tsc_freq_hz) as u64
+    }
+
+    // Nanoseconds since the UNIX epoch
+    pub fn wall_time_ns(&self) -> u64 {
+        self.boot_epoch_ns + self.monotonic_ns()
+    }
+
+    pub fn utc_datetime(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_nanos(self.wall_time_ns() as i64)
     }
 
     pub fn spin_delay(&self, duration: f64) {
@@ -32,7 +75,7 @@ impl SystemClock {
         while self.time() - t0 < duration {}
     }
 
-    pub fn utc_datetime(runtime_services: &RuntimeServices) -> DateTime<Utc> {
+    fn read_rtc(runtime_services: &RuntimeServices) -> DateTime<Utc> {
 
         let t_uefi = runtime_services.get_time().unwrap();
 
@@ -55,48 +98,122 @@ impl SystemClock {
             .unwrap()
     }
 
-    fn estimate_cpu_period(runtime_services: &RuntimeServices) -> f64 {
-        // Waiting for the "seconds" value to change
-        let s1 = runtime_services.get_time().unwrap().second();
-        let s2 = loop {
-            let s2 = runtime_services.get_time().unwrap().second();
-            if s1 != s2 {
-                break s2;
-            }
-        };
-
-        // Waiting approximately one second and measuring the change in rdtsc
-        let n1 = unsafe { core::arch::x86_64::_rdtsc() };
-        loop {
-            let s3 = runtime_services.get_time().unwrap().second();
-            if s2 != s3 {
-                break;
-            }
-        }
-        let n2 = unsafe { core::arch::x86_64::_rdtsc() };
+    fn get_epoch_time(runtime_services: &RuntimeServices) -> u64 {
+
+        let t_chrono = Self::read_rtc(runtime_services);
+
+        let nanos_since_epoch: u64 = (t_chrono - DateTime::UNIX_EPOCH)
+            .num_nanoseconds()
+            .and_then(|ns| ns.try_into().ok())
+            .expect("Current time before UNIX epoch");
 
-        let period_s = 1.0 / ((n2 - n1) as f64);
-        let freq_ghz = 1.0 / period_s / 1E9;
-        log::debug!(
-            "CPU frequency estimated to {:.3}Ghz ({} cycles in approx 1s)",
-            freq_ghz,
-            n2 - n1
-        );
+        log::debug!("UNIX time: {}", nanos_since_epoch / 1_000_000_000);
 
-        period_s
+        nanos_since_epoch
     }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
 
-    fn get_epoch_time(runtime_services: &RuntimeServices) -> f64 {
+// Invariant TSC runs at a constant rate regardless of power states
+fn tsc_is_invariant() -> bool {
+    let max_ext_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_ext_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
 
-        let t_chrono = Self::utc_datetime(runtime_services);
+// Returns the TSC frequency in Hz, and the name of the reference timer used
+fn calibrate_tsc() -> (f64, &'static str) {
 
-        let secs_since_epoch: u64 = (t_chrono - DateTime::UNIX_EPOCH)
-            .num_seconds()
-            .try_into()
-            .expect("Current time before UNIX epoch");
+    if let Some(base_addr) = acpi::hpet_base() {
+        return (calibrate_with_hpet(base_addr), "HPET");
+    }
 
-        log::debug!("UNIX time: {}", secs_since_epoch);
+    if let Some(pm_timer) = acpi::pm_timer() {
+        let mut port = Port::<u32>::new(pm_timer.port);
+        let bits = if pm_timer.extended { 32 } else { 24 };
+        let freq = calibrate_against(|| unsafe { port.read() } as u64, bits, PM_TIMER_FREQ_HZ);
+        return (freq, "ACPI PM timer");
+    }
+
+    (calibrate_with_pit(), "PIT")
+}
+
+fn calibrate_with_hpet(base_addr: u64) -> f64 {
+
+    let base = memory::get_mapper().phys_to_virt(PhysAddr::new(base_addr));
+    let reg = |offset: usize| unsafe { base.as_mut_ptr::<u8>().add(offset) as *mut u64 };
+
+    let capabilities = unsafe { reg(HPET_CAPABILITIES).read_volatile() };
+    let period_fs = capabilities >> 32;
+    let bits = if capabilities & HPET_COUNTER_64BIT != 0 { 64 } else { 32 };
+
+    unsafe {
+        let config = reg(HPET_CONFIG).read_volatile();
+        reg(HPET_CONFIG).write_volatile(config | HPET_ENABLE);
+    }
+
+    let freq_hz = 1e15 / period_fs as f64;
+    calibrate_against(|| unsafe { reg(HPET_MAIN_COUNTER).read_volatile() }, bits, freq_hz)
+}
+
+// Uses channel 2, which can be gated through port 0x61 and doesn't raise interrupts
+fn calibrate_with_pit() -> f64 {
+
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let mut gate = Port::<u8>::new(0x61);
 
-        secs_since_epoch as f64
+    let gate_before = unsafe { gate.read() };
+
+    unsafe {
+        // Gate high, speaker off
+        gate.write((gate_before & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte access, mode 2 (rate generator), reload value 0 (65536)
+        command.write(0b10_11_010_0);
+        channel_2.write(0);
+        channel_2.write(0);
+    }
+
+    let freq = calibrate_against(
+        || unsafe {
+            // Latching the count, which goes down
+            command.write(0b10_00_0000);
+            let lo = channel_2.read() as u64;
+            let hi = channel_2.read() as u64;
+            0xFFFF - (hi << 8 | lo)
+        },
+        16,
+        PIT_FREQ_HZ,
+    );
+
+    unsafe { gate.write(gate_before) };
+
+    freq
+}
+
+// Counts TSC cycles while a counter of known frequency advances by CALIBRATION_MS.
+// The counter may wrap around, but not more than once during that time.
+fn calibrate_against<F: FnMut() -> u64>(mut read_counter: F, counter_bits: u32, counter_freq_hz: f64) -> f64 {
+
+    let mask = match counter_bits {
+        64 => u64::MAX,
+        bits => (1 << bits) - 1,
+    };
+
+    let target_ticks = (counter_freq_hz * CALIBRATION_MS / 1000.0) as u64;
+
+    let c0 = read_counter();
+    let tsc0 = rdtsc();
+
+    let mut elapsed = 0;
+    while elapsed < target_ticks {
+        elapsed = read_counter().wrapping_sub(c0) & mask;
     }
+
+    let tsc1 = rdtsc();
+
+    (tsc1 - tsc0) as f64 * counter_freq_hz / elapsed as f64
 }
@@ -1,4 +1,6 @@
 use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use applib::{FbView, OwnedPixels, Framebuffer};
 use applib::drawing::primitives::draw_rect;
 use applib::drawing::text::{draw_str, draw_line_in_rect, TextJustification};
@@ -8,6 +10,7 @@ use applib::{uitk::{self}, FbViewMut};
 use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc, Month};
 use num_traits::float::FloatCore;
 
+use crate::config;
 use crate::resources;
 use crate::stats::SystemStats;
 use crate::TOPBAR_H;
@@ -16,7 +19,7 @@ pub fn topbar<'a, F: FbViewMut>(
     uitk_context: &mut uitk::UiContext<F>,
     system_stats: &SystemStats,
     datetime: DateTime<Utc>,
-) {
+) -> Rect {
 
     let font = uitk_context.font_family.get_default();
 
@@ -164,20 +167,51 @@ pub fn topbar<'a, F: FbViewMut>(
     });
 
 
+    // Time spent stepping apps on each core, relative to the frame budget
+    let core_loads: Vec<(f32, usize)> = (0..system_stats.nb_cores())
+        .map(|core| {
+            let busy_data = system_stats.get_core_history(core, |dp| dp.busy_time as f32);
+            let agg_busy = busy_data.iter().take(FRAMETIME_WINDOW_LEN).sum::<f32>() / FRAMETIME_WINDOW_LEN as f32;
+            let nb_tasks = system_stats.get_core_history(core, |dp| dp.nb_tasks)[0];
+            (agg_busy, nb_tasks)
+        })
+        .collect();
+    let agg_core_load = core_loads.iter().map(|(busy, _)| busy).sum::<f32>() / core_loads.len() as f32;
+    let core_loads_str: Vec<String> = core_loads
+        .iter()
+        .map(|(busy, nb_tasks)| format!("{:.1}ms ({})", busy, nb_tasks))
+        .collect();
+
+    draw_monitor(uitk_context, &mut x, &ResourceMonitor {
+        bar_values: &[BarValue { color: Color::AQUA, val: agg_core_load }],
+        max_val: max_frametime,
+        icon: &resources::CHIP_ICON,
+        text: &format!("{} cores: {}", core_loads.len(), core_loads_str.join(" / ")),
+    });
+
+
     let heap_allocated_data = system_stats.get_system_history(|dp| dp.alloc.allocated as f32);
     let agg_allocated = heap_allocated_data.iter().fold(0.0, |acc, v| acc + v / heap_allocated_data.len() as f32);
+    let heap_used_data = system_stats.get_system_history(|dp| dp.alloc.used as f32);
+    let agg_used = heap_used_data.iter().fold(0.0, |acc, v| acc + v / heap_used_data.len() as f32);
     let heap_total = system_stats.heap_total as f32;
 
+    let fragmentation = system_stats.get_system_history(|dp| dp.alloc.fragmentation);
+    let agg_fragmentation = fragmentation.iter().fold(0.0, |acc, v| acc + v / fragmentation.len() as f32);
+
     draw_monitor(uitk_context, &mut x, &ResourceMonitor { 
         bar_values: &[
             BarValue { color: Color::AQUA, val: agg_allocated },
+            // Including rounding up to block sizes
+            BarValue { color: Color::BLUE, val: agg_used },
         ],
         max_val: heap_total,
         icon: &resources::CHIP_ICON,
         text: &format!(
-            "{:.0}/{:.0}MB",
+            "{:.0}/{:.0}MB ({:.0}% frag.)",
             agg_allocated / 1_000_000.0,
-            heap_total / 1_000_000.0
+            heap_total / 1_000_000.0,
+            agg_fragmentation * 100.0,
         ),
     });
 
@@ -187,7 +221,7 @@ pub fn topbar<'a, F: FbViewMut>(
     let agg_net_sent = net_sent_data.iter().sum::<f32>();
     let agg_net_recv = net_recv_data.iter().sum::<f32>();
 
-    let target_frametime: f32 = 1000.0 / crate::FPS_TARGET as f32;
+    let target_frametime: f32 = 1000.0 / config::get().fps_target as f32;
     let history_duration_sec = target_frametime * net_recv_data.len() as f32 / 1000.0;
     let net_recv_rate = net_recv_data.iter().sum::<f32>() / history_duration_sec;
     let net_sent_rate = net_sent_data.iter().sum::<f32>() / history_duration_sec;
@@ -201,4 +235,7 @@ pub fn topbar<'a, F: FbViewMut>(
         icon: &resources::NETWORK_ICON,
         text: &format!("{:.1}/{:.1} kB/s", net_sent_rate / 1000.0, net_recv_rate / 1000.0),
     });
+
+    // Area that may have changed, including the tooltips under the bar
+    Rect { x0: 0, y0: 0, w, h: 2 * TOPBAR_H + TOOLTIP_OFFSET_GAP_H }
 }
@@ -0,0 +1,1116 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use chrono::{Datelike, NaiveDate, Timelike};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::time::SystemClock;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+// Directory entry attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+
+// First byte of a directory entry
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+// Set by Windows and mtools in the reserved byte of 8.3 entries with lowercase names
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const MAX_NAME_LEN: usize = 255;
+
+// FAT entries are 28 bits wide, the top 4 bits are reserved
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const FAT_FREE: u32 = 0;
+const FAT_EOC_MIN: u32 = 0x0FFF_FFF8;
+const FAT_EOC: u32 = 0x0FFF_FFFF;
+
+// MBR partition types
+const MBR_FAT32_CHS: u8 = 0x0B;
+const MBR_FAT32_LBA: u8 = 0x0C;
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+// FSInfo sector fields
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// FAT32 volume, either the whole device or its first FAT32 partition (MBR or GPT).
+// Long file names are supported, and compatible with what mtools and Linux write.
+pub struct Fat32 {
+    dev: Box<dyn BlockDevice + Send>,
+    clock: SystemClock,
+
+    // In sectors from the start of the device
+    fat_start: u64,
+    fat_size: u64,
+    nb_fats: u64,
+    data_start: u64,
+    fsinfo_sector: Option<u64>,
+
+    sectors_per_cluster: u64,
+    root_cluster: u32,
+    nb_clusters: u32,
+
+    // From the FSInfo sector, kept up to date and written back on flush
+    free_count: Option<u32>,
+    next_free: u32,
+    fsinfo_dirty: bool,
+
+    // Last FAT sector read, since consecutive lookups are usually in the same one
+    fat_cache: Option<(u64, [u8; SECTOR_SIZE])>,
+}
+
+// Contents of a directory, and the clusters it is stored in
+struct Dir {
+    cluster: u32,
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+// The short (8.3) entry of a file, and where it is stored in its directory
+#[derive(Clone)]
+struct FatEntry {
+    name: String,
+    raw: [u8; DIR_ENTRY_SIZE],
+    // Slot of the short entry, and of the first long name entry before it
+    index: usize,
+    first_index: usize,
+}
+
+enum Node {
+    Root,
+    Entry { parent: Dir, entry: FatEntry },
+}
+
+impl Fat32 {
+    pub fn mount(mut dev: Box<dyn BlockDevice + Send>, clock: SystemClock) -> Result<Self, FsError> {
+
+        let volume_start = find_volume(dev.as_mut())?;
+
+        let mut bpb = [0u8; SECTOR_SIZE];
+        dev.read_sectors(volume_start, &mut bpb).map_err(io_error)?;
+
+        let reserved_sectors = read_u16(&bpb, 14) as u64;
+        let nb_fats = bpb[16] as u64;
+        let total_sectors = match read_u16(&bpb, 19) {
+            0 => read_u32(&bpb, 32) as u64,
+            n => n as u64,
+        };
+        let fat_size = read_u32(&bpb, 36) as u64;
+        let sectors_per_cluster = bpb[13] as u64;
+
+        let fat_start = volume_start + reserved_sectors;
+        let data_start = fat_start + nb_fats * fat_size;
+
+        let data_sectors = (volume_start + total_sectors).saturating_sub(data_start);
+        let max_clusters = fat_size * (SECTOR_SIZE as u64 / 4) - 2;
+        let nb_clusters = u64::min(data_sectors / sectors_per_cluster, max_clusters) as u32;
+
+        let fsinfo_sector = match read_u16(&bpb, 48) {
+            0 | 0xFFFF => None,
+            n => Some(volume_start + n as u64),
+        };
+
+        let mut fat32 = Fat32 {
+            dev,
+            clock,
+            fat_start,
+            fat_size,
+            nb_fats,
+            data_start,
+            fsinfo_sector,
+            sectors_per_cluster,
+            root_cluster: read_u32(&bpb, 44),
+            nb_clusters,
+            free_count: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+            fat_cache: None,
+        };
+
+        fat32.read_fsinfo()?;
+
+        log::info!(
+            "FAT32 volume at sector {}: {} clusters of {}B, {} free",
+            volume_start,
+            nb_clusters,
+            fat32.cluster_size(),
+            match fat32.free_count {
+                Some(n) => alloc::format!("{}", n),
+                None => "unknown".into(),
+            }
+        );
+
+        Ok(fat32)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        match self.dev.is_read_only() {
+            true => Err(FsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    //
+    // FSInfo
+
+    fn read_fsinfo(&mut self) -> Result<(), FsError> {
+        let Some(sector) = self.fsinfo_sector else {
+            return Ok(());
+        };
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.dev.read_sectors(sector, &mut buf).map_err(io_error)?;
+
+        // Lead and structure signatures
+        if read_u32(&buf, 0) != 0x4161_5252 || read_u32(&buf, 484) != 0x6141_7272 {
+            log::warn!("Invalid FAT32 FSInfo sector");
+            self.fsinfo_sector = None;
+            return Ok(());
+        }
+
+        self.free_count = match read_u32(&buf, FSINFO_FREE_COUNT) {
+            n if n <= self.nb_clusters => Some(n),
+            _ => None,
+        };
+        self.next_free = match read_u32(&buf, FSINFO_NEXT_FREE) {
+            n if n >= 2 && n < self.nb_clusters + 2 => n,
+            _ => 2,
+        };
+
+        Ok(())
+    }
+
+    fn write_fsinfo(&mut self) -> Result<(), FsError> {
+        let Some(sector) = self.fsinfo_sector else {
+            return Ok(());
+        };
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.dev.read_sectors(sector, &mut buf).map_err(io_error)?;
+        write_u32(&mut buf, FSINFO_FREE_COUNT, self.free_count.unwrap_or(FSINFO_UNKNOWN));
+        write_u32(&mut buf, FSINFO_NEXT_FREE, self.next_free);
+        self.dev.write_sectors(sector, &buf).map_err(io_error)?;
+
+        self.fsinfo_dirty = false;
+
+        Ok(())
+    }
+
+    //
+    // File allocation table
+
+    fn read_fat_entry(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let offset = cluster as u64 * 4;
+        let sector = self.fat_start + offset / SECTOR_SIZE as u64;
+        let offset = (offset % SECTOR_SIZE as u64) as usize;
+
+        let buf = match &mut self.fat_cache {
+            Some((cached_sector, buf)) if *cached_sector == sector => buf,
+            cache => {
+                let mut buf = [0u8; SECTOR_SIZE];
+                self.dev.read_sectors(sector, &mut buf).map_err(io_error)?;
+                &mut cache.insert((sector, buf)).1
+            }
+        };
+
+        Ok(read_u32(buf, offset) & FAT_ENTRY_MASK)
+    }
+
+    // Updates all the copies of the FAT
+    fn write_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = cluster as u64 * 4;
+        let sector_offset = offset / SECTOR_SIZE as u64;
+        let offset = (offset % SECTOR_SIZE as u64) as usize;
+
+        for i in 0..self.nb_fats {
+            let sector = self.fat_start + i * self.fat_size + sector_offset;
+
+            let mut buf = [0u8; SECTOR_SIZE];
+            self.dev.read_sectors(sector, &mut buf).map_err(io_error)?;
+            let reserved_bits = read_u32(&buf, offset) & !FAT_ENTRY_MASK;
+            write_u32(&mut buf, offset, reserved_bits | (value & FAT_ENTRY_MASK));
+            self.dev.write_sectors(sector, &buf).map_err(io_error)?;
+
+            if let Some((cached_sector, cached_buf)) = &mut self.fat_cache {
+                if *cached_sector == sector {
+                    *cached_buf = buf;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_chain(&mut self, first_cluster: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+
+        while cluster >= 2 && cluster < FAT_EOC_MIN {
+            if cluster >= self.nb_clusters + 2 || chain.len() > self.nb_clusters as usize {
+                log::warn!("Corrupted FAT32 cluster chain starting at {}", first_cluster);
+                return Err(FsError::Io);
+            }
+            chain.push(cluster);
+            cluster = self.read_fat_entry(cluster)?;
+        }
+
+        Ok(chain)
+    }
+
+    // Allocates a cluster at the end of the chain (or a new chain if prev is None)
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, FsError> {
+        if self.free_count == Some(0) {
+            return Err(FsError::NoSpace);
+        }
+
+        let nb_clusters = self.nb_clusters;
+        let start = self.next_free.clamp(2, nb_clusters + 1);
+        let candidates = (start..nb_clusters + 2).chain(2..start);
+
+        let mut found = None;
+        for cluster in candidates {
+            if self.read_fat_entry(cluster)? == FAT_FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+
+        let Some(cluster) = found else {
+            self.free_count = Some(0);
+            return Err(FsError::NoSpace);
+        };
+
+        self.write_fat_entry(cluster, FAT_EOC)?;
+        if let Some(prev) = prev {
+            self.write_fat_entry(prev, cluster)?;
+        }
+
+        self.next_free = cluster + 1;
+        self.free_count = self.free_count.map(|n| n - 1);
+        self.fsinfo_dirty = true;
+
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, first_cluster: u32) -> Result<(), FsError> {
+        for cluster in self.get_chain(first_cluster)? {
+            self.write_fat_entry(cluster, FAT_FREE)?;
+            self.free_count = self.free_count.map(|n| n + 1);
+        }
+        self.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    // Makes sure the chain is long enough to hold `size` bytes
+    fn extend_chain(&mut self, entry: &mut FatEntry, chain: &mut Vec<u32>, size: u64) -> Result<(), FsError> {
+        let needed = size.div_ceil(self.cluster_size() as u64) as usize;
+        while chain.len() < needed {
+            let cluster = self.alloc_cluster(chain.last().copied())?;
+            if chain.is_empty() {
+                entry.set_first_cluster(cluster);
+            }
+            chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    //
+    // Data
+
+    fn read_range(&mut self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let mut cluster_buf = vec![0u8; cluster_size];
+
+        let mut pos = offset;
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster = chain[(pos / cluster_size as u64) as usize];
+            let start = (pos % cluster_size as u64) as usize;
+            let n = usize::min(cluster_size - start, buf.len() - done);
+
+            self.dev.read_sectors(self.cluster_sector(cluster), &mut cluster_buf).map_err(io_error)?;
+            buf[done..done + n].copy_from_slice(&cluster_buf[start..start + n]);
+
+            pos += n as u64;
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    fn write_range(&mut self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let mut cluster_buf = vec![0u8; cluster_size];
+
+        let mut pos = offset;
+        let mut done = 0;
+        while done < data.len() {
+            let cluster = chain[(pos / cluster_size as u64) as usize];
+            let sector = self.cluster_sector(cluster);
+            let start = (pos % cluster_size as u64) as usize;
+            let n = usize::min(cluster_size - start, data.len() - done);
+
+            if n == cluster_size {
+                self.dev.write_sectors(sector, &data[done..done + n]).map_err(io_error)?;
+            } else {
+                self.dev.read_sectors(sector, &mut cluster_buf).map_err(io_error)?;
+                cluster_buf[start..start + n].copy_from_slice(&data[done..done + n]);
+                self.dev.write_sectors(sector, &cluster_buf).map_err(io_error)?;
+            }
+
+            pos += n as u64;
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    // One cluster at a time, since the range can be as large as the file
+    fn zero_range(&mut self, chain: &[u32], offset: u64, len: u64) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size() as u64;
+        let zeros = vec![0u8; cluster_size as usize];
+
+        let mut pos = offset;
+        while pos < offset + len {
+            let n = u64::min(cluster_size - pos % cluster_size, offset + len - pos);
+            self.write_range(chain, pos, &zeros[..n as usize])?;
+            pos += n;
+        }
+
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FsError> {
+        let zeros = vec![0u8; self.cluster_size()];
+        self.dev.write_sectors(self.cluster_sector(cluster), &zeros).map_err(io_error)
+    }
+
+    //
+    // Directories
+
+    fn read_dir_at(&mut self, cluster: u32) -> Result<Dir, FsError> {
+        // ".." entries use 0 for the root directory
+        let cluster = if cluster == 0 { self.root_cluster } else { cluster };
+
+        let clusters = self.get_chain(cluster)?;
+        let mut data = vec![0u8; clusters.len() * self.cluster_size()];
+        self.read_range(&clusters, 0, &mut data)?;
+
+        Ok(Dir { cluster, clusters, data })
+    }
+
+    // Writes back the sector holding a directory entry slot
+    fn write_dir_slot(&mut self, dir: &Dir, index: usize) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let offset = index * DIR_ENTRY_SIZE;
+        let cluster = dir.clusters[offset / cluster_size];
+        let sector_in_cluster = (offset % cluster_size) / SECTOR_SIZE;
+
+        let sector = self.cluster_sector(cluster) + sector_in_cluster as u64;
+        let start = offset - offset % SECTOR_SIZE;
+
+        self.dev.write_sectors(sector, &dir.data[start..start + SECTOR_SIZE]).map_err(io_error)
+    }
+
+    fn write_entry(&mut self, parent: &mut Dir, entry: &FatEntry) -> Result<(), FsError> {
+        let offset = entry.index * DIR_ENTRY_SIZE;
+        parent.data[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(&entry.raw);
+        self.write_dir_slot(parent, entry.index)
+    }
+
+    fn find(&mut self, path: &[&str]) -> Result<Node, FsError> {
+        let Some((name, parent_path)) = path.split_last() else {
+            return Ok(Node::Root);
+        };
+
+        let parent = self.find_dir(parent_path)?;
+        let entry = parse_entries(&parent.data)
+            .into_iter()
+            .find(|entry| names_equal(&entry.name, name))
+            .ok_or(FsError::NotFound)?;
+
+        Ok(Node::Entry { parent, entry })
+    }
+
+    fn find_dir(&mut self, path: &[&str]) -> Result<Dir, FsError> {
+        match self.find(path)? {
+            Node::Root => self.read_dir_at(self.root_cluster),
+            Node::Entry { entry, .. } if entry.is_dir() => self.read_dir_at(entry.first_cluster()),
+            Node::Entry { .. } => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn find_file(&mut self, path: &[&str]) -> Result<(Dir, FatEntry), FsError> {
+        match self.find(path)? {
+            Node::Entry { parent, entry } if !entry.is_dir() => Ok((parent, entry)),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    // Finds `n` consecutive free slots, growing the directory if needed
+    fn alloc_slots(&mut self, dir: &mut Dir, n: usize) -> Result<usize, FsError> {
+        loop {
+            let nb_slots = dir.data.len() / DIR_ENTRY_SIZE;
+            let mut run = 0;
+            for index in 0..nb_slots {
+                let first_byte = dir.data[index * DIR_ENTRY_SIZE];
+                match first_byte == ENTRY_END || first_byte == ENTRY_DELETED {
+                    true => run += 1,
+                    false => run = 0,
+                }
+                if run == n {
+                    return Ok(index + 1 - n);
+                }
+            }
+
+            let cluster = self.alloc_cluster(dir.clusters.last().copied())?;
+            self.zero_cluster(cluster)?;
+            dir.clusters.push(cluster);
+            dir.data.resize(dir.data.len() + self.cluster_size(), 0);
+        }
+    }
+
+    fn update_modified(&self, entry: &mut FatEntry) {
+        let (date, time) = self.fat_datetime();
+        write_u16(&mut entry.raw, 22, time);
+        write_u16(&mut entry.raw, 24, date);
+        write_u16(&mut entry.raw, 18, date);
+        entry.raw[11] |= ATTR_ARCHIVE;
+    }
+
+    fn fat_datetime(&self) -> (u16, u16) {
+        let now = self.clock.utc_datetime();
+        let year = now.year().clamp(1980, 2107) as u16;
+        let date = (year - 1980) << 9 | (now.month() as u16) << 5 | now.day() as u16;
+        let time = (now.hour() as u16) << 11 | (now.minute() as u16) << 5 | now.second() as u16 / 2;
+        (date, time)
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn metadata(&mut self, path: &[&str]) -> Result<Metadata, FsError> {
+        match self.find(path)? {
+            Node::Root => Ok(Metadata { file_type: FileType::Directory, size: 0, modified: 0 }),
+            Node::Entry { entry, .. } => Ok(Metadata {
+                file_type: if entry.is_dir() { FileType::Directory } else { FileType::File },
+                size: if entry.is_dir() { 0 } else { entry.size() as u64 },
+                modified: entry.modified(),
+            }),
+        }
+    }
+
+    fn read_dir(&mut self, path: &[&str]) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.find_dir(path)?;
+        Ok(parse_entries(&dir.data)
+            .into_iter()
+            .map(|entry| DirEntry {
+                file_type: if entry.is_dir() { FileType::Directory } else { FileType::File },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read(&mut self, path: &[&str], offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let (_, entry) = self.find_file(path)?;
+
+        let size = entry.size() as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let n = u64::min(buf.len() as u64, size - offset) as usize;
+
+        let chain = self.get_chain(entry.first_cluster())?;
+        if (chain.len() as u64) < size.div_ceil(self.cluster_size() as u64) {
+            log::warn!("FAT32 cluster chain of {} too short for its size", entry.name);
+            return Err(FsError::Io);
+        }
+
+        self.read_range(&chain, offset, &mut buf[..n])?;
+
+        Ok(n)
+    }
+
+    fn write(&mut self, path: &[&str], offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+
+        let (mut parent, mut entry) = self.find_file(path)?;
+
+        // File sizes are 32 bits
+        let end = offset.saturating_add(data.len() as u64);
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let old_size = entry.size() as u64;
+        let mut chain = self.get_chain(entry.first_cluster())?;
+        self.extend_chain(&mut entry, &mut chain, end)?;
+
+        if offset > old_size {
+            self.zero_range(&chain, old_size, offset - old_size)?;
+        }
+        self.write_range(&chain, offset, data)?;
+
+        entry.set_size(u64::max(old_size, end) as u32);
+        self.update_modified(&mut entry);
+        self.write_entry(&mut parent, &entry)?;
+
+        Ok(data.len())
+    }
+
+    fn set_len(&mut self, path: &[&str], size: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+
+        let (mut parent, mut entry) = self.find_file(path)?;
+
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let old_size = entry.size() as u64;
+        let mut chain = self.get_chain(entry.first_cluster())?;
+        let needed = size.div_ceil(self.cluster_size() as u64) as usize;
+
+        if size > old_size {
+            self.extend_chain(&mut entry, &mut chain, size)?;
+            self.zero_range(&chain, old_size, size - old_size)?;
+        } else if needed < chain.len() {
+            match needed {
+                0 => entry.set_first_cluster(0),
+                n => self.write_fat_entry(chain[n - 1], FAT_EOC)?,
+            }
+            self.free_chain(chain[needed])?;
+        }
+
+        entry.set_size(size as u32);
+        self.update_modified(&mut entry);
+        self.write_entry(&mut parent, &entry)
+    }
+
+    fn create(&mut self, path: &[&str], file_type: FileType) -> Result<(), FsError> {
+        self.check_writable()?;
+
+        let (name, parent_path) = path.split_last().ok_or(FsError::InvalidPath)?;
+        check_name(name)?;
+
+        let mut parent = self.find_dir(parent_path)?;
+        let existing = parse_entries(&parent.data);
+        if existing.iter().any(|entry| names_equal(&entry.name, name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, case_flags, needs_lfn) = make_short_name(name, &existing);
+
+        let lfn_entries = match needs_lfn {
+            true => make_lfn_entries(name, checksum(&short_name)),
+            false => Vec::new(),
+        };
+
+        let (date, time) = self.fat_datetime();
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[..11].copy_from_slice(&short_name);
+        raw[12] = case_flags;
+        for offset in [14, 22] {
+            write_u16(&mut raw, offset, time);
+        }
+        for offset in [16, 18, 24] {
+            write_u16(&mut raw, offset, date);
+        }
+
+        let mut entry = FatEntry { name: String::from(*name), raw, index: 0, first_index: 0 };
+
+        match file_type {
+            FileType::File => entry.raw[11] = ATTR_ARCHIVE,
+            FileType::Directory => {
+                entry.raw[11] = ATTR_DIRECTORY;
+
+                let cluster = self.alloc_cluster(None)?;
+                entry.set_first_cluster(cluster);
+
+                let parent_cluster = match parent.cluster == self.root_cluster {
+                    true => 0,
+                    false => parent.cluster,
+                };
+
+                let mut dot = entry.raw;
+                dot[..11].copy_from_slice(b".          ");
+                let mut dot_dot = entry.raw;
+                dot_dot[..11].copy_from_slice(b"..         ");
+                write_u16(&mut dot_dot, 20, (parent_cluster >> 16) as u16);
+                write_u16(&mut dot_dot, 26, parent_cluster as u16);
+
+                let mut data = vec![0u8; self.cluster_size()];
+                data[..DIR_ENTRY_SIZE].copy_from_slice(&dot);
+                data[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE].copy_from_slice(&dot_dot);
+                self.write_range(&[cluster], 0, &data)?;
+            }
+        }
+
+        let first_index = self.alloc_slots(&mut parent, lfn_entries.len() + 1)?;
+        entry.first_index = first_index;
+        entry.index = first_index + lfn_entries.len();
+
+        for (i, lfn_entry) in lfn_entries.iter().enumerate() {
+            let offset = (first_index + i) * DIR_ENTRY_SIZE;
+            parent.data[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(lfn_entry);
+            self.write_dir_slot(&parent, first_index + i)?;
+        }
+
+        self.write_entry(&mut parent, &entry)
+    }
+
+    fn remove(&mut self, path: &[&str]) -> Result<(), FsError> {
+        self.check_writable()?;
+
+        let Node::Entry { mut parent, entry } = self.find(path)? else {
+            return Err(FsError::InvalidPath);
+        };
+
+        if entry.is_dir() {
+            let dir = self.read_dir_at(entry.first_cluster())?;
+            if !parse_entries(&dir.data).is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+
+        for index in entry.first_index..=entry.index {
+            parent.data[index * DIR_ENTRY_SIZE] = ENTRY_DELETED;
+            self.write_dir_slot(&parent, index)?;
+        }
+
+        if entry.first_cluster() != 0 {
+            self.free_chain(entry.first_cluster())?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        if self.fsinfo_dirty {
+            self.write_fsinfo()?;
+        }
+        self.dev.flush().map_err(io_error)
+    }
+}
+
+impl FatEntry {
+    fn attr(&self) -> u8 {
+        self.raw[11]
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr() & ATTR_DIRECTORY != 0
+    }
+
+    fn first_cluster(&self) -> u32 {
+        (read_u16(&self.raw, 20) as u32) << 16 | read_u16(&self.raw, 26) as u32
+    }
+
+    fn set_first_cluster(&mut self, cluster: u32) {
+        write_u16(&mut self.raw, 20, (cluster >> 16) as u16);
+        write_u16(&mut self.raw, 26, cluster as u16);
+    }
+
+    fn size(&self) -> u32 {
+        read_u32(&self.raw, 28)
+    }
+
+    fn set_size(&mut self, size: u32) {
+        write_u32(&mut self.raw, 28, size);
+    }
+
+    // Nanoseconds since the UNIX epoch
+    fn modified(&self) -> u64 {
+        let time = read_u16(&self.raw, 22) as u32;
+        let date = read_u16(&self.raw, 24) as u32;
+
+        let datetime = NaiveDate::from_ymd_opt(1980 + (date >> 9) as i32, (date >> 5) & 0xF, date & 0x1F)
+            .and_then(|date| date.and_hms_opt(time >> 11, (time >> 5) & 0x3F, (time & 0x1F) * 2));
+
+        match datetime {
+            Some(datetime) => datetime.and_utc().timestamp() as u64 * 1_000_000_000,
+            None => 0,
+        }
+    }
+}
+
+// Entries of a directory, without "." and ".." or the volume label
+fn parse_entries(data: &[u8]) -> Vec<FatEntry> {
+    let mut entries = Vec::new();
+
+    // Long name parts seen so far (in reverse order), with the checksum and index of the first one
+    let mut lfn_parts: Vec<[u16; LFN_CHARS]> = Vec::new();
+    let mut lfn_checksum = 0;
+    let mut lfn_first_index = 0;
+    let mut lfn_next_order = 0;
+
+    for (index, raw) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        let raw: [u8; DIR_ENTRY_SIZE] = raw.try_into().unwrap();
+
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                lfn_parts.clear();
+                continue;
+            }
+            _ => (),
+        }
+
+        if raw[11] & 0x3F == ATTR_LFN {
+            let order = raw[0] & !LFN_LAST;
+
+            if raw[0] & LFN_LAST != 0 {
+                lfn_parts.clear();
+                lfn_checksum = raw[13];
+                lfn_first_index = index;
+                lfn_next_order = order;
+            }
+
+            // The parts are stored from last to first, numbered down to 1
+            if order == 0 || order != lfn_next_order || raw[13] != lfn_checksum {
+                lfn_parts.clear();
+                lfn_next_order = 0;
+                continue;
+            }
+
+            lfn_parts.push(lfn_chars(&raw));
+            lfn_next_order -= 1;
+            continue;
+        }
+
+        if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            lfn_parts.clear();
+            continue;
+        }
+
+        let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+        let has_lfn = !lfn_parts.is_empty() && lfn_next_order == 0 && lfn_checksum == checksum(&short_name);
+
+        let name = match has_lfn {
+            true => {
+                let units: Vec<u16> = lfn_parts
+                    .iter()
+                    .rev()
+                    .flatten()
+                    .copied()
+                    .take_while(|&c| c != 0x0000)
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            false => short_name_to_string(&raw),
+        };
+
+        entries.push(FatEntry {
+            name,
+            raw,
+            index,
+            first_index: if has_lfn { lfn_first_index } else { index },
+        });
+
+        lfn_parts.clear();
+    }
+
+    entries
+}
+
+fn lfn_chars(raw: &[u8; DIR_ENTRY_SIZE]) -> [u16; LFN_CHARS] {
+    let mut chars = [0u16; LFN_CHARS];
+    let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+    for (c, offset) in chars.iter_mut().zip(offsets) {
+        *c = read_u16(raw, offset);
+    }
+    chars
+}
+
+fn make_lfn_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let nb_entries = units.len().div_ceil(LFN_CHARS);
+
+    // Null-terminated if there is room, then padded with 0xFFFF
+    let padded: Vec<u16> = units
+        .iter()
+        .copied()
+        .chain(core::iter::once(0x0000))
+        .chain(core::iter::repeat(0xFFFF))
+        .take(nb_entries * LFN_CHARS)
+        .collect();
+
+    (1..=nb_entries)
+        .rev()
+        .map(|order| {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            raw[0] = order as u8 | if order == nb_entries { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LFN;
+            raw[13] = checksum;
+
+            let chars = &padded[(order - 1) * LFN_CHARS..order * LFN_CHARS];
+            let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+            for (c, offset) in chars.iter().zip(offsets) {
+                write_u16(&mut raw, offset, *c);
+            }
+
+            raw
+        })
+        .collect()
+}
+
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| (sum >> 1 | sum << 7).wrapping_add(b))
+}
+
+fn short_name_to_string(raw: &[u8; DIR_ENTRY_SIZE]) -> String {
+    let mut name_bytes: [u8; 11] = raw[..11].try_into().unwrap();
+    if name_bytes[0] == ENTRY_KANJI_E5 {
+        name_bytes[0] = 0xE5;
+    }
+
+    let to_string = |bytes: &[u8], lowercase: bool| -> String {
+        bytes
+            .iter()
+            .map(|&b| match lowercase {
+                true => b.to_ascii_lowercase() as char,
+                false => b as char,
+            })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+
+    let base = to_string(&name_bytes[..8], raw[12] & NT_LOWERCASE_BASE != 0);
+    let ext = to_string(&name_bytes[8..], raw[12] & NT_LOWERCASE_EXT != 0);
+
+    match ext.is_empty() {
+        true => base,
+        false => alloc::format!("{}.{}", base, ext),
+    }
+}
+
+// Returns the 8.3 name, the NT lowercase flags, and whether long name entries are needed
+fn make_short_name(name: &str, existing: &[FatEntry]) -> ([u8; 11], u8, bool) {
+
+    let is_short_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c);
+
+    // Names that fit as they are, possibly with the lowercase flags
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    let single_case = |s: &str| !(s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()));
+    let fits = (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && !(name.contains('.') && ext.is_empty())
+        && base.chars().chain(ext.chars()).all(is_short_char)
+        && single_case(base)
+        && single_case(ext);
+
+    if fits {
+        let mut short_name = [b' '; 11];
+        short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        short_name[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+
+        let mut flags = 0;
+        if base.chars().any(|c| c.is_ascii_lowercase()) {
+            flags |= NT_LOWERCASE_BASE;
+        }
+        if ext.chars().any(|c| c.is_ascii_lowercase()) {
+            flags |= NT_LOWERCASE_EXT;
+        }
+
+        return (short_name, flags, false);
+    }
+
+    // Otherwise a "BASIS~N.EXT" alias, along with the long name
+    let to_short = |s: &str, max_len: usize| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match is_short_char(c) {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_',
+            })
+            .take(max_len)
+            .collect()
+    };
+
+    let (base, ext) = match name.trim_start_matches('.').rsplit_once('.') {
+        Some((base, ext)) => (to_short(base, 8), to_short(ext, 3)),
+        None => (to_short(name, 8), Vec::new()),
+    };
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + ext.len()].copy_from_slice(&ext);
+
+    for n in 1.. {
+        let suffix = alloc::format!("~{}", n);
+        let base_len = usize::min(base.len(), 8 - suffix.len());
+        short_name[..8].fill(b' ');
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len..base_len + suffix.len()].copy_from_slice(suffix.as_bytes());
+
+        if !existing.iter().any(|entry| entry.raw[..11] == short_name) {
+            break;
+        }
+    }
+
+    (short_name, 0, true)
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    let valid = !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.ends_with(' ')
+        && !name.ends_with('.')
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c));
+
+    match valid {
+        true => Ok(()),
+        false => Err(FsError::InvalidPath),
+    }
+}
+
+// FAT names are case-insensitive
+fn names_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.chars().zip(b.chars()).all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+}
+
+//
+// Partitions
+
+// Returns the first sector of the FAT32 volume
+fn find_volume(dev: &mut dyn BlockDevice) -> Result<u64, FsError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    dev.read_sectors(0, &mut sector).map_err(io_error)?;
+
+    // Unpartitioned ("superfloppy"), as created by mkfs.fat on a whole image
+    if is_fat32_boot_sector(&sector) {
+        return Ok(0);
+    }
+
+    if sector[510..512] != [0x55, 0xAA] {
+        log::warn!("No partition table or FAT32 volume on block device");
+        return Err(FsError::NotFound);
+    }
+
+    let partitions: Vec<(u8, u64)> = (0..4)
+        .map(|i| 446 + i * 16)
+        .map(|offset| (sector[offset + 4], read_u32(&sector, offset + 8) as u64))
+        .collect();
+
+    for (part_type, start) in partitions {
+        match part_type {
+            MBR_FAT32_CHS | MBR_FAT32_LBA if check_volume(dev, start)? => return Ok(start),
+            MBR_GPT_PROTECTIVE => return find_gpt_volume(dev),
+            _ => (),
+        }
+    }
+
+    log::warn!("No FAT32 partition found on block device");
+    Err(FsError::NotFound)
+}
+
+fn find_gpt_volume(dev: &mut dyn BlockDevice) -> Result<u64, FsError> {
+    let mut header = [0u8; SECTOR_SIZE];
+    dev.read_sectors(1, &mut header).map_err(io_error)?;
+
+    if &header[..8] != b"EFI PART" {
+        log::warn!("Invalid GPT header");
+        return Err(FsError::NotFound);
+    }
+
+    let entries_start = read_u64(&header, 72);
+    let nb_entries = read_u32(&header, 80) as u64;
+    let entry_size = read_u32(&header, 84) as u64;
+
+    if entry_size < 128 || SECTOR_SIZE as u64 % entry_size != 0 {
+        log::warn!("Unsupported GPT entry size {}", entry_size);
+        return Err(FsError::NotFound);
+    }
+
+    let entries_per_sector = SECTOR_SIZE as u64 / entry_size;
+    let mut sector = [0u8; SECTOR_SIZE];
+
+    for i in 0..nb_entries {
+        if i % entries_per_sector == 0 {
+            dev.read_sectors(entries_start + i / entries_per_sector, &mut sector).map_err(io_error)?;
+        }
+
+        let offset = ((i % entries_per_sector) * entry_size) as usize;
+        let entry = &sector[offset..offset + entry_size as usize];
+
+        // Unused entries have a null type GUID
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        let start = read_u64(entry, 32);
+        if check_volume(dev, start)? {
+            return Ok(start);
+        }
+    }
+
+    log::warn!("No FAT32 partition found in GPT");
+    Err(FsError::NotFound)
+}
+
+fn check_volume(dev: &mut dyn BlockDevice, start: u64) -> Result<bool, FsError> {
+    if start >= dev.nb_sectors() {
+        return Ok(false);
+    }
+    let mut sector = [0u8; SECTOR_SIZE];
+    dev.read_sectors(start, &mut sector).map_err(io_error)?;
+    Ok(is_fat32_boot_sector(&sector))
+}
+
+fn is_fat32_boot_sector(sector: &[u8; SECTOR_SIZE]) -> bool {
+    sector[510..512] == [0x55, 0xAA]
+        && read_u16(sector, 11) as usize == SECTOR_SIZE
+        && sector[13].is_power_of_two()
+        && read_u16(sector, 14) != 0 // Reserved sectors
+        && sector[16] != 0 // Number of FATs
+        && read_u16(sector, 17) == 0 // Root directory entries (FAT12/16 only)
+        && read_u16(sector, 22) == 0 // 16-bit FAT size (FAT12/16 only)
+        && read_u32(sector, 36) != 0
+}
+
+fn io_error(err: anyhow::Error) -> FsError {
+    log::warn!("FAT32 I/O error: {}", err);
+    FsError::Io
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
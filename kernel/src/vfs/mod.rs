@@ -0,0 +1,228 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+pub mod fat32;
+pub mod p9fs;
+pub mod tmpfs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    // Nanoseconds since the UNIX epoch
+    pub modified: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+// Not anyhow errors, since they have to be turned into WASI error codes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidPath,
+    ReadOnly,
+    NoSpace,
+    Busy,
+    Io,
+}
+
+impl core::fmt::Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::ReadOnly => "read-only filesystem",
+            FsError::NoSpace => "no space left on device",
+            FsError::Busy => "mount point busy",
+            FsError::Io => "I/O error",
+        };
+        f.write_str(msg)
+    }
+}
+
+// Paths are given to filesystems as components relative to their root,
+// already normalized (no empty, "." or ".." components)
+pub trait FileSystem: Send {
+    fn metadata(&mut self, path: &[&str]) -> Result<Metadata, FsError>;
+
+    fn read_dir(&mut self, path: &[&str]) -> Result<Vec<DirEntry>, FsError>;
+
+    // Returns the number of bytes read, 0 at the end of the file
+    fn read(&mut self, path: &[&str], offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    // Writing past the end of the file extends it, filling the gap with zeros
+    fn write(&mut self, path: &[&str], offset: u64, data: &[u8]) -> Result<usize, FsError>;
+
+    fn set_len(&mut self, path: &[&str], size: u64) -> Result<(), FsError>;
+
+    // The parent directory must exist
+    fn create(&mut self, path: &[&str], file_type: FileType) -> Result<(), FsError>;
+
+    // Directories must be empty
+    fn remove(&mut self, path: &[&str]) -> Result<(), FsError>;
+
+    fn flush(&mut self) -> Result<(), FsError>;
+}
+
+struct Mount {
+    path: Vec<String>,
+    fs: Box<dyn FileSystem>,
+}
+
+// Single namespace for all filesystems. Each path is served by the filesystem
+// mounted on its longest matching prefix.
+pub struct Vfs {
+    mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub fn new(root: Box<dyn FileSystem>) -> Self {
+        Vfs {
+            mounts: [Mount { path: Vec::new(), fs: root }].into(),
+        }
+    }
+
+    // The mount point doesn't have to exist in the parent filesystem
+    pub fn mount(&mut self, path: &str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+        let path = normalize(path)?;
+
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(FsError::Busy);
+        }
+
+        let path = path.iter().map(|s| s.to_string()).collect();
+        self.mounts.push(Mount { path, fs });
+
+        Ok(())
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<Metadata, FsError> {
+        let path = normalize(path)?;
+        let (fs, rel_path) = self.resolve(&path);
+        fs.metadata(rel_path)
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let path = normalize(path)?;
+
+        // Mount points directly under this directory
+        let mount_names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter(|mount| mount.path.len() == path.len() + 1 && mount.path.iter().zip(path.iter()).all(|(a, b)| a == b))
+            .map(|mount| mount.path.last().unwrap().clone())
+            .collect();
+
+        let (fs, rel_path) = self.resolve(&path);
+        let mut entries = match fs.read_dir(rel_path) {
+            Ok(entries) => entries,
+            // The mount point doesn't exist in the parent filesystem
+            Err(FsError::NotFound) if !mount_names.is_empty() => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        entries.retain(|entry| !mount_names.contains(&entry.name));
+        entries.extend(mount_names.into_iter().map(|name| DirEntry { name, file_type: FileType::Directory }));
+
+        Ok(entries)
+    }
+
+    pub fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let path = normalize(path)?;
+        let (fs, rel_path) = self.resolve(&path);
+        fs.read(rel_path, offset, buf)
+    }
+
+    pub fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let path = normalize(path)?;
+        let (fs, rel_path) = self.resolve(&path);
+        fs.write(rel_path, offset, data)
+    }
+
+    pub fn set_len(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        let (fs, rel_path) = self.resolve(&path);
+        fs.set_len(rel_path, size)
+    }
+
+    pub fn create(&mut self, path: &str, file_type: FileType) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        if self.is_mount_point(&path) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (fs, rel_path) = self.resolve(&path);
+        fs.create(rel_path, file_type)
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        if self.is_mount_point(&path) {
+            return Err(FsError::Busy);
+        }
+        let (fs, rel_path) = self.resolve(&path);
+        fs.remove(rel_path)
+    }
+
+    // Flushes all filesystems, even if one of them fails
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.mounts
+            .iter_mut()
+            .map(|mount| mount.fs.flush())
+            .fold(Ok(()), |acc, res| acc.and(res))
+    }
+
+    fn is_mount_point(&self, path: &[&str]) -> bool {
+        self.mounts.iter().any(|mount| mount.path == path)
+    }
+
+    fn resolve<'a, 'b>(&'a mut self, path: &'b [&'b str]) -> (&'a mut dyn FileSystem, &'b [&'b str]) {
+        let mount = self
+            .mounts
+            .iter_mut()
+            .filter(|mount| mount.path.len() <= path.len() && mount.path.iter().zip(path.iter()).all(|(a, b)| a == b))
+            .max_by_key(|mount| mount.path.len())
+            .expect("No root filesystem");
+
+        let prefix_len = mount.path.len();
+        (mount.fs.as_mut(), &path[prefix_len..])
+    }
+}
+
+// Absolute path to components, resolving "." and ".." (which stops at the root)
+pub fn normalize(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    Ok(components)
+}
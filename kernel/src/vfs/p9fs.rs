@@ -0,0 +1,449 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::virtio::p9::{VirtioP9, MSIZE};
+
+const VERSION: &str = "9P2000.L";
+
+// T-messages, the matching R-message being type + 1
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const NOTAG: u16 = 0xFFFF;
+const NOFID: u32 = 0xFFFF_FFFF;
+const ROOT_FID: u32 = 0;
+
+// Room left for the headers of read/write messages, as Linux does
+const IO_HEADER_SIZE: usize = 24;
+
+// Most names a single Twalk can take
+const MAX_WALK_NAMES: usize = 16;
+
+// Linux open flags and file modes
+const O_RDONLY: u32 = 0o0;
+const O_WRONLY: u32 = 0o1;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_DIRECTORY: u32 = 0o200000;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const DT_DIR: u8 = 4;
+
+const GETATTR_BASIC: u64 = 0x7FF;
+const SETATTR_SIZE: u32 = 0x8;
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Linux errno values, from Rlerror
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EACCES: u32 = 13;
+const EBUSY: u32 = 16;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const EROFS: u32 = 30;
+const ENAMETOOLONG: u32 = 36;
+const ENOTEMPTY: u32 = 39;
+
+// Host directory shared over 9P2000.L. Each operation walks from the root fid
+// to a new fid, and clunks it once done. Requests are sent one at a time, so
+// they all use tag 0.
+pub struct P9Fs {
+    dev: VirtioP9,
+    msize: usize,
+    next_fid: u32,
+}
+
+impl P9Fs {
+    pub fn mount(dev: VirtioP9) -> Result<Self, FsError> {
+        let mut fs = P9Fs {
+            dev,
+            msize: MSIZE,
+            next_fid: ROOT_FID + 1,
+        };
+
+        let resp = fs.rpc(Message::new(TVERSION).tag(NOTAG).u32(MSIZE as u32).str(VERSION))?;
+        let mut reader = Reader::new(&resp);
+        fs.msize = usize::min(reader.u32()? as usize, MSIZE);
+        let version = reader.str()?;
+        if version != VERSION {
+            log::warn!("9P server doesn't support {} (offered {})", VERSION, version);
+            return Err(FsError::Io);
+        }
+
+        fs.rpc(Message::new(TATTACH).u32(ROOT_FID).u32(NOFID).str("root").str("").u32(0))?;
+
+        log::info!("9P share \"{}\" attached (msize {})", fs.dev.mount_tag(), fs.msize);
+
+        Ok(fs)
+    }
+
+    fn rpc(&mut self, request: Message) -> Result<Vec<u8>, FsError> {
+        let msg_type = request.msg_type;
+        let response = self.dev.send_request(&request.finish()).map_err(io_error)?;
+
+        // Size, type and tag, then the body
+        let body = response[7..].to_vec();
+        match response[4] {
+            RLERROR => Err(errno_to_fs_error(Reader::new(&body).u32()?)),
+            resp_type if resp_type == msg_type + 1 => Ok(body),
+            resp_type => {
+                log::warn!("Unexpected 9P response type {} to request type {}", resp_type, msg_type);
+                Err(FsError::Io)
+            }
+        }
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        let fid = self.next_fid;
+        self.next_fid = match self.next_fid.wrapping_add(1) {
+            ROOT_FID | NOFID => ROOT_FID + 1,
+            fid => fid,
+        };
+        fid
+    }
+
+    // Returns a new fid for the path
+    fn walk(&mut self, path: &[&str]) -> Result<u32, FsError> {
+        let fid = self.alloc_fid();
+
+        // Walking zero names just clones the root fid. On failure, the new fid isn't created.
+        let first_names = &path[..usize::min(path.len(), MAX_WALK_NAMES)];
+        self.walk_names(ROOT_FID, fid, first_names)?;
+
+        for names in path.chunks(MAX_WALK_NAMES).skip(1) {
+            if let Err(err) = self.walk_names(fid, fid, names) {
+                self.clunk(fid);
+                return Err(err);
+            }
+        }
+
+        Ok(fid)
+    }
+
+    fn walk_names(&mut self, fid: u32, new_fid: u32, names: &[&str]) -> Result<(), FsError> {
+        let mut msg = Message::new(TWALK).u32(fid).u32(new_fid).u16(names.len() as u16);
+        for name in names {
+            msg = msg.str(name);
+        }
+
+        let resp = self.rpc(msg)?;
+
+        // Stopping early means one of the names doesn't exist
+        let nb_qids = Reader::new(&resp).u16()? as usize;
+        match nb_qids < names.len() {
+            true => Err(FsError::NotFound),
+            false => Ok(()),
+        }
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        if let Err(err) = self.rpc(Message::new(TCLUNK).u32(fid)) {
+            log::warn!("Failed to clunk 9P fid {}: {}", fid, err);
+        }
+    }
+
+    // Walks to the path, and clunks the fid after running func, whatever the result
+    fn with_fid<T, F>(&mut self, path: &[&str], func: F) -> Result<T, FsError>
+    where
+        F: FnOnce(&mut Self, u32) -> Result<T, FsError>,
+    {
+        let fid = self.walk(path)?;
+        let res = func(self, fid);
+        self.clunk(fid);
+        res
+    }
+
+    fn getattr(&mut self, fid: u32) -> Result<Metadata, FsError> {
+        let resp = self.rpc(Message::new(TGETATTR).u32(fid).u64(GETATTR_BASIC))?;
+        let mut reader = Reader::new(&resp);
+
+        reader.skip(8 + 13)?; // Valid mask, qid
+        let mode = reader.u32()?;
+        reader.skip(4 + 4 + 8 + 8)?; // uid, gid, nlink, rdev
+        let size = reader.u64()?;
+        reader.skip(8 + 8 + 8 + 8)?; // blksize, blocks, atime
+        let mtime_sec = reader.u64()?;
+        let mtime_nsec = reader.u64()?;
+
+        let file_type = match mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            _ => FileType::File,
+        };
+
+        Ok(Metadata {
+            file_type,
+            size: if file_type == FileType::Directory { 0 } else { size },
+            modified: mtime_sec * 1_000_000_000 + mtime_nsec,
+        })
+    }
+
+    fn open_file(&mut self, fid: u32, flags: u32) -> Result<(), FsError> {
+        if self.getattr(fid)?.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.rpc(Message::new(TLOPEN).u32(fid).u32(flags))?;
+        Ok(())
+    }
+}
+
+impl FileSystem for P9Fs {
+    fn metadata(&mut self, path: &[&str]) -> Result<Metadata, FsError> {
+        self.with_fid(path, |fs, fid| fs.getattr(fid))
+    }
+
+    fn read_dir(&mut self, path: &[&str]) -> Result<Vec<DirEntry>, FsError> {
+        self.with_fid(path, |fs, fid| {
+            fs.rpc(Message::new(TLOPEN).u32(fid).u32(O_RDONLY | O_DIRECTORY))?;
+
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let count = (fs.msize - IO_HEADER_SIZE) as u32;
+                let resp = fs.rpc(Message::new(TREADDIR).u32(fid).u64(offset).u32(count))?;
+
+                let mut reader = Reader::new(&resp);
+                let count = reader.u32()? as usize;
+                if count == 0 {
+                    break;
+                }
+
+                // Each entry has its qid, the offset of the next entry, its type and its name
+                let mut reader = Reader::new(reader.bytes(count)?);
+                while !reader.is_empty() {
+                    reader.skip(13)?;
+                    offset = reader.u64()?;
+                    let d_type = reader.u8()?;
+                    let name = reader.str()?;
+
+                    if name != "." && name != ".." {
+                        let file_type = if d_type == DT_DIR { FileType::Directory } else { FileType::File };
+                        entries.push(DirEntry { name, file_type });
+                    }
+                }
+            }
+
+            Ok(entries)
+        })
+    }
+
+    fn read(&mut self, path: &[&str], offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.with_fid(path, |fs, fid| {
+            fs.open_file(fid, O_RDONLY)?;
+
+            let mut done = 0;
+            while done < buf.len() {
+                let count = usize::min(buf.len() - done, fs.msize - IO_HEADER_SIZE);
+                let resp = fs.rpc(Message::new(TREAD).u32(fid).u64(offset + done as u64).u32(count as u32))?;
+
+                let mut reader = Reader::new(&resp);
+                let read_len = reader.u32()? as usize;
+                if read_len == 0 {
+                    break;
+                }
+                buf[done..done + read_len].copy_from_slice(reader.bytes(read_len)?);
+                done += read_len;
+            }
+
+            Ok(done)
+        })
+    }
+
+    fn write(&mut self, path: &[&str], offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.with_fid(path, |fs, fid| {
+            fs.open_file(fid, O_WRONLY)?;
+
+            let mut done = 0;
+            for chunk in data.chunks(fs.msize - IO_HEADER_SIZE) {
+                let msg = Message::new(TWRITE).u32(fid).u64(offset + done as u64).u32(chunk.len() as u32).bytes(chunk);
+                let written_len = Reader::new(&fs.rpc(msg)?).u32()? as usize;
+                done += written_len;
+                if written_len < chunk.len() {
+                    break;
+                }
+            }
+
+            Ok(done)
+        })
+    }
+
+    fn set_len(&mut self, path: &[&str], size: u64) -> Result<(), FsError> {
+        self.with_fid(path, |fs, fid| {
+            // Valid mask, mode, uid, gid, size, atime, mtime
+            let msg = Message::new(TSETATTR).u32(fid).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(size);
+            fs.rpc(msg.u64(0).u64(0).u64(0).u64(0))?;
+            Ok(())
+        })
+    }
+
+    fn create(&mut self, path: &[&str], file_type: FileType) -> Result<(), FsError> {
+        let (name, parent_path) = path.split_last().ok_or(FsError::InvalidPath)?;
+
+        self.with_fid(parent_path, |fs, fid| {
+            let msg = match file_type {
+                FileType::File => Message::new(TLCREATE).u32(fid).str(name).u32(O_WRONLY | O_CREAT | O_EXCL).u32(0o644),
+                FileType::Directory => Message::new(TMKDIR).u32(fid).str(name).u32(0o755),
+            };
+            fs.rpc(msg.u32(0))?; // gid
+            Ok(())
+        })
+    }
+
+    fn remove(&mut self, path: &[&str]) -> Result<(), FsError> {
+        let (name, parent_path) = path.split_last().ok_or(FsError::InvalidPath)?;
+
+        let flags = match self.metadata(path)?.file_type {
+            FileType::Directory => AT_REMOVEDIR,
+            FileType::File => 0,
+        };
+
+        self.with_fid(parent_path, |fs, fid| {
+            fs.rpc(Message::new(TUNLINKAT).u32(fid).str(name).u32(flags))?;
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        // Writes are done by the host as soon as they're received
+        Ok(())
+    }
+}
+
+// Little-endian 9P message, built field by field
+struct Message {
+    msg_type: u8,
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u8) -> Self {
+        // Size, filled in by finish(), then type and tag
+        let mut buf = vec![0u8; 4];
+        buf.push(msg_type);
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        Message { msg_type, buf }
+    }
+
+    fn tag(mut self, tag: u16) -> Self {
+        self.buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        self
+    }
+
+    fn u16(mut self, val: u16) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, val: u32) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, val: u64) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn str(self, s: &str) -> Self {
+        self.u16(s.len() as u16).bytes(s.as_bytes())
+    }
+
+    fn bytes(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], FsError> {
+        let bytes = self.buf.get(self.pos..self.pos + len).ok_or_else(|| {
+            log::warn!("Truncated 9P response");
+            FsError::Io
+        })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), FsError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, FsError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FsError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, FsError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, FsError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, FsError> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+fn errno_to_fs_error(errno: u32) -> FsError {
+    match errno {
+        ENOENT => FsError::NotFound,
+        EEXIST => FsError::AlreadyExists,
+        ENOTDIR => FsError::NotADirectory,
+        EISDIR => FsError::IsADirectory,
+        ENOTEMPTY => FsError::DirectoryNotEmpty,
+        EINVAL | ENAMETOOLONG => FsError::InvalidPath,
+        EROFS | EPERM | EACCES => FsError::ReadOnly,
+        ENOSPC => FsError::NoSpace,
+        EBUSY => FsError::Busy,
+        errno => {
+            log::warn!("9P server error (errno {})", errno);
+            FsError::Io
+        }
+    }
+}
+
+fn io_error(err: anyhow::Error) -> FsError {
+    log::warn!("9P transport error: {}", err);
+    FsError::Io
+}
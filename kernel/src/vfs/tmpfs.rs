@@ -0,0 +1,184 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::time::SystemClock;
+
+// Files live in the kernel heap, so apps mustn't be able to grow them without bound
+const MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+const MAX_TOTAL_SIZE: usize = 256 * 1024 * 1024;
+
+// Files kept in the kernel heap, lost on reboot
+pub struct TmpFs {
+    root: Node,
+    clock: SystemClock,
+    // File contents and names, counted against MAX_TOTAL_SIZE
+    used: usize,
+}
+
+struct Node {
+    kind: NodeKind,
+    modified: u64,
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+impl TmpFs {
+    pub fn new(clock: SystemClock) -> Self {
+        let modified = clock.wall_time_ns();
+        TmpFs {
+            root: Node { kind: NodeKind::Directory(BTreeMap::new()), modified },
+            clock,
+            used: 0,
+        }
+    }
+
+    fn get_node(&mut self, path: &[&str]) -> Result<&mut Node, FsError> {
+        self.root.get_descendant(path)
+    }
+
+    // Also returns the usage counter, to be updated if the file is resized
+    fn get_file(&mut self, path: &[&str]) -> Result<(&mut Vec<u8>, &mut u64, &mut usize), FsError> {
+        let node = self.root.get_descendant(path)?;
+        match &mut node.kind {
+            NodeKind::File(data) => Ok((data, &mut node.modified, &mut self.used)),
+            NodeKind::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    // Returns the children of the parent directory, the name of the node, and the usage counter
+    fn get_parent<'a>(
+        &mut self,
+        path: &[&'a str],
+    ) -> Result<(&mut BTreeMap<String, Node>, &'a str, &mut usize), FsError> {
+        let (name, parent_path) = path.split_last().ok_or(FsError::InvalidPath)?;
+        let parent = self.root.get_descendant(parent_path)?;
+        match &mut parent.kind {
+            NodeKind::Directory(children) => Ok((children, name, &mut self.used)),
+            NodeKind::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl Node {
+    fn get_descendant(&mut self, path: &[&str]) -> Result<&mut Node, FsError> {
+        let mut node = self;
+        for name in path {
+            node = match &mut node.kind {
+                NodeKind::Directory(children) => children.get_mut(*name).ok_or(FsError::NotFound)?,
+                NodeKind::File(_) => return Err(FsError::NotADirectory),
+            };
+        }
+        Ok(node)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn metadata(&mut self, path: &[&str]) -> Result<Metadata, FsError> {
+        let node = self.get_node(path)?;
+        let (file_type, size) = match &node.kind {
+            NodeKind::File(data) => (FileType::File, data.len() as u64),
+            NodeKind::Directory(_) => (FileType::Directory, 0),
+        };
+        Ok(Metadata { file_type, size, modified: node.modified })
+    }
+
+    fn read_dir(&mut self, path: &[&str]) -> Result<Vec<DirEntry>, FsError> {
+        match &self.get_node(path)?.kind {
+            NodeKind::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    file_type: match node.kind {
+                        NodeKind::File(_) => FileType::File,
+                        NodeKind::Directory(_) => FileType::Directory,
+                    },
+                })
+                .collect()),
+            NodeKind::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read(&mut self, path: &[&str], offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let (data, _, _) = self.get_file(path)?;
+        let start = usize::min(offset as usize, data.len());
+        let n = usize::min(buf.len(), data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, path: &[&str], offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let now = self.clock.wall_time_ns();
+        let (data, modified, used) = self.get_file(path)?;
+        let start = offset as usize;
+        let end = start.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if data.len() < end {
+            resize_file(data, end, used)?;
+        }
+        data[start..end].copy_from_slice(buf);
+        *modified = now;
+        Ok(buf.len())
+    }
+
+    fn set_len(&mut self, path: &[&str], size: u64) -> Result<(), FsError> {
+        let now = self.clock.wall_time_ns();
+        let (data, modified, used) = self.get_file(path)?;
+        let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+        resize_file(data, size, used)?;
+        *modified = now;
+        Ok(())
+    }
+
+    fn create(&mut self, path: &[&str], file_type: FileType) -> Result<(), FsError> {
+        let modified = self.clock.wall_time_ns();
+        let (children, name, used) = self.get_parent(path)?;
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        if *used + name.len() > MAX_TOTAL_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        *used += name.len();
+        let kind = match file_type {
+            FileType::File => NodeKind::File(Vec::new()),
+            FileType::Directory => NodeKind::Directory(BTreeMap::new()),
+        };
+        children.insert(name.to_string(), Node { kind, modified });
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &[&str]) -> Result<(), FsError> {
+        let (children, name, used) = self.get_parent(path)?;
+        let data_len = match children.get(name).map(|node| &node.kind) {
+            None => return Err(FsError::NotFound),
+            Some(NodeKind::Directory(grandchildren)) if !grandchildren.is_empty() => {
+                return Err(FsError::DirectoryNotEmpty)
+            }
+            Some(NodeKind::Directory(_)) => 0,
+            Some(NodeKind::File(data)) => data.len(),
+        };
+        children.remove(name);
+        *used -= name.len() + data_len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+// Zero-fills when growing, and fails instead of running the kernel out of memory
+fn resize_file(data: &mut Vec<u8>, len: usize, used: &mut usize) -> Result<(), FsError> {
+    let growth = len.saturating_sub(data.len());
+    if len > MAX_FILE_SIZE || *used + growth > MAX_TOTAL_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    data.try_reserve_exact(growth).map_err(|_| FsError::NoSpace)?;
+    *used = *used + len - data.len();
+    data.resize(len, 0);
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use core::mem::MaybeUninit;
+
+use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::pci::PciDevice;
+use alloc::vec::Vec;
+use anyhow::bail;
+
+const Q_SIZE: usize = 256;
+
+// Larger transfers are split into several requests
+const MAX_SECTORS_PER_REQUEST: usize = 8;
+
+const BUF_SIZE: usize = core::mem::size_of::<VirtioBlkMsg>();
+
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+enum BlockFeatureBits {
+    VIRTIO_BLK_F_RO = 0x1 << 5,
+    VIRTIO_BLK_F_FLUSH = 0x1 << 9,
+}
+
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+enum VirtioBlkReqType {
+    VIRTIO_BLK_T_IN = 0,
+    VIRTIO_BLK_T_OUT = 1,
+    VIRTIO_BLK_T_FLUSH = 4,
+}
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub struct VirtioBlock {
+    pub virtio_dev: VirtioDevice,
+    requestq: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    nb_sectors: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtioBlkConfig {
+    // In 512-byte sectors, whatever the logical block size
+    capacity: u64,
+}
+
+impl VirtioBlock {
+    // There may not be any disk attached
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        let i = (0..pci_devices.len()).find(|&i| {
+            pci_devices[i].vendor_id == 0x1af4
+                && (pci_devices[i].device_id == 0x1001 || pci_devices[i].device_id == 0x1040 + 2)
+        })?;
+
+        let pci_dev = pci_devices.swap_remove(i);
+        let feature_bits =
+            BlockFeatureBits::VIRTIO_BLK_F_RO as u32 | BlockFeatureBits::VIRTIO_BLK_F_FLUSH as u32;
+        let mut virtio_dev = VirtioDevice::new(pci_dev, feature_bits);
+
+        // Requests are synchronous, the queue is polled
+        let requestq = virtio_dev.initialize_queue(0)?; // queue 0 (requestq)
+        virtio_dev.write_status(0x04); // DRIVER_OK
+
+        let device_config = unsafe { virtio_dev.read_device_specific_config::<VirtioBlkConfig>() };
+        let nb_sectors = device_config.capacity;
+
+        log::info!(
+            "VirtIO block device: {} sectors ({}MB){}",
+            nb_sectors,
+            nb_sectors * SECTOR_SIZE as u64 / 1_000_000,
+            if virtio_dev.has_feature(BlockFeatureBits::VIRTIO_BLK_F_RO as u32) { ", read-only" } else { "" },
+        );
+
+        Some(VirtioBlock {
+            virtio_dev,
+            requestq,
+            nb_sectors,
+        })
+    }
+
+    fn send_request<const N: usize>(&mut self, messages: &[QueueMessage<VirtioBlkMsg>; N]) -> anyhow::Result<[VirtioBlkMsg; N]> {
+        unsafe {
+            self.requestq.try_push(messages).unwrap();
+            self.requestq.notify_device();
+        }
+
+        let resp_list = loop {
+            if let Some(resp_list) = unsafe { self.requestq.try_pop::<VirtioBlkMsg, N>() } {
+                break resp_list;
+            }
+        };
+
+        // The status byte is always in the last buffer
+        match unsafe { resp_list[N - 1].status } {
+            VIRTIO_BLK_S_OK => Ok(resp_list),
+            VIRTIO_BLK_S_IOERR => bail!("VirtIO block device I/O error"),
+            VIRTIO_BLK_S_UNSUPP => bail!("Request not supported by the VirtIO block device"),
+            status => bail!("Unknown VirtIO block status {}", status),
+        }
+    }
+
+    fn request_header(req_type: VirtioBlkReqType, sector: u64) -> QueueMessage<VirtioBlkMsg> {
+        QueueMessage::DevReadOnly {
+            data: VirtioBlkMsg {
+                hdr: VirtioBlkReqHdr {
+                    _type: req_type as u32,
+                    reserved: 0,
+                    sector,
+                },
+            },
+            len: Some(core::mem::size_of::<VirtioBlkReqHdr>()),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn nb_sectors(&self) -> u64 {
+        self.nb_sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.virtio_dev.has_feature(BlockFeatureBits::VIRTIO_BLK_F_RO as u32)
+    }
+
+    fn read_sectors(&mut self, start: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        block::check_request(self.nb_sectors, start, buf.len())?;
+
+        let chunks = buf.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE);
+        for (sector, chunk) in (start..).step_by(MAX_SECTORS_PER_REQUEST).zip(chunks) {
+            let resp_list = self.send_request(&[
+                Self::request_header(VirtioBlkReqType::VIRTIO_BLK_T_IN, sector),
+                QueueMessage::DevWriteOnlyLen { len: chunk.len() },
+                QueueMessage::DevWriteOnlyLen { len: 1 },
+            ])?;
+
+            chunk.copy_from_slice(unsafe { &resp_list[1].data[..chunk.len()] });
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, start: u64, buf: &[u8]) -> anyhow::Result<()> {
+        block::check_request(self.nb_sectors, start, buf.len())?;
+
+        if self.is_read_only() {
+            bail!("VirtIO block device is read-only");
+        }
+
+        let chunks = buf.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE);
+        for (sector, chunk) in (start..).step_by(MAX_SECTORS_PER_REQUEST).zip(chunks) {
+            let mut data = VirtioBlkMsg::default();
+            unsafe { data.data[..chunk.len()].copy_from_slice(chunk) };
+
+            self.send_request(&[
+                Self::request_header(VirtioBlkReqType::VIRTIO_BLK_T_OUT, sector),
+                QueueMessage::DevReadOnly { data, len: Some(chunk.len()) },
+                QueueMessage::DevWriteOnlyLen { len: 1 },
+            ])?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        // Without a write cache, writes are persistent as soon as they complete
+        if !self.virtio_dev.has_feature(BlockFeatureBits::VIRTIO_BLK_F_FLUSH as u32) {
+            return Ok(());
+        }
+
+        self.send_request(&[
+            Self::request_header(VirtioBlkReqType::VIRTIO_BLK_T_FLUSH, 0),
+            QueueMessage::DevWriteOnlyLen { len: 1 },
+        ])?;
+
+        Ok(())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union VirtioBlkMsg {
+    hdr: VirtioBlkReqHdr,
+    data: [u8; MAX_SECTORS_PER_REQUEST * SECTOR_SIZE],
+    status: u8,
+}
+
+impl Default for VirtioBlkMsg {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioBlkMsg {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct VirtioBlkReqHdr {
+    _type: u32,
+    reserved: u32,
+    sector: u64,
+}
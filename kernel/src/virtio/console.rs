@@ -0,0 +1,328 @@
+use core::mem::MaybeUninit;
+
+use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
+use crate::pci::PciDevice;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// At most the queue sizes of the device
+const DATA_Q_SIZE: usize = 128;
+const CONTROL_Q_SIZE: usize = 32;
+
+const DATA_BUF_SIZE: usize = core::mem::size_of::<VirtioConsoleData>();
+const CONTROL_BUF_SIZE: usize = core::mem::size_of::<VirtioConsoleControl>();
+
+// Size of the control message header, which PORT_NAME messages follow with the name
+const CONTROL_HDR_SIZE: usize = 8;
+
+// Ports with a higher id are refused
+const MAX_PORTS: usize = 4;
+
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+enum ConsoleFeatureBits {
+    VIRTIO_CONSOLE_F_MULTIPORT = 0x1 << 1,
+}
+
+#[repr(u16)]
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Debug, enumn::N)]
+enum ConsoleControlEvent {
+    VIRTIO_CONSOLE_DEVICE_READY = 0,
+    VIRTIO_CONSOLE_DEVICE_ADD = 1,
+    VIRTIO_CONSOLE_DEVICE_REMOVE = 2,
+    VIRTIO_CONSOLE_PORT_READY = 3,
+    VIRTIO_CONSOLE_CONSOLE_PORT = 4,
+    VIRTIO_CONSOLE_RESIZE = 5,
+    VIRTIO_CONSOLE_PORT_OPEN = 6,
+    VIRTIO_CONSOLE_PORT_NAME = 7,
+}
+
+// Serial ports between the guest and the host, each with its own pair of queues.
+// Ports are named by the host (virtserialport's name=), and announced on the control queues.
+// Everything is polled, see poll().
+pub struct VirtioConsole {
+    #[allow(dead_code)]
+    pub virtio_dev: VirtioDevice,
+    control_rx: VirtioQueue<CONTROL_Q_SIZE, CONTROL_BUF_SIZE>,
+    control_tx: VirtioQueue<CONTROL_Q_SIZE, CONTROL_BUF_SIZE>,
+    ports: Vec<ConsolePort>,
+}
+
+struct ConsolePort {
+    rx: VirtioQueue<DATA_Q_SIZE, DATA_BUF_SIZE>,
+    tx: VirtioQueue<DATA_Q_SIZE, DATA_BUF_SIZE>,
+    name: Option<String>,
+    host_connected: bool,
+    received: Vec<u8>,
+    // Written but not handed to the device yet, because the transmit queue was full
+    pending: VecDeque<u8>,
+    // Buffers given to the device and not given back yet
+    in_flight: usize,
+}
+
+impl VirtioConsole {
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        let i = (0..pci_devices.len()).find(|&i| {
+            pci_devices[i].vendor_id == 0x1af4
+                && (pci_devices[i].device_id == 0x1003 || pci_devices[i].device_id == 0x1040 + 3)
+        })?;
+
+        let pci_dev = pci_devices.swap_remove(i);
+        let feature_bits = ConsoleFeatureBits::VIRTIO_CONSOLE_F_MULTIPORT as u32;
+        let mut virtio_dev = VirtioDevice::new(pci_dev, feature_bits);
+
+        if !virtio_dev.has_feature(ConsoleFeatureBits::VIRTIO_CONSOLE_F_MULTIPORT as u32) {
+            log::warn!("VirtIO console device without multiport support, not using it");
+            return None;
+        }
+
+        // Config space: cols (u16), rows (u16), max_nr_ports (u32)
+        let max_nr_ports = unsafe {
+            let config = virtio_dev.device_specific_config_ptr::<[u32; 2]>();
+            core::ptr::read_volatile(config)[1] as usize
+        };
+        let nb_ports = usize::min(max_nr_ports, MAX_PORTS);
+
+        // Port 0 uses queues 0 and 1, then come the control queues, then the other ports
+        let data_queue_index = |port: usize| match port {
+            0 => 0,
+            _ => 2 + 2 * port as u16,
+        };
+
+        let control_rx = virtio_dev.initialize_queue(2)?; // queue 2 (control receiveq)
+        let control_tx = virtio_dev.initialize_queue(3)?; // queue 3 (control transmitq)
+
+        let ports = (0..nb_ports)
+            .map(|port| {
+                Some(ConsolePort {
+                    rx: virtio_dev.initialize_queue(data_queue_index(port))?,
+                    tx: virtio_dev.initialize_queue(data_queue_index(port) + 1)?,
+                    name: None,
+                    host_connected: false,
+                    received: Vec::new(),
+                    pending: VecDeque::new(),
+                    in_flight: 0,
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        virtio_dev.write_status(0x04); // DRIVER_OK
+
+        let mut console = VirtioConsole {
+            virtio_dev,
+            control_rx,
+            control_tx,
+            ports,
+        };
+
+        unsafe {
+            let msg = [QueueMessage::<VirtioConsoleControl>::DevWriteOnly];
+            while console.control_rx.try_push(&msg).is_some() {}
+            console.control_rx.notify_device();
+
+            for port in console.ports.iter_mut() {
+                let msg = [QueueMessage::<VirtioConsoleData>::DevWriteOnly];
+                while port.rx.try_push(&msg).is_some() {}
+                port.rx.notify_device();
+            }
+        }
+
+        // The device answers with a DEVICE_ADD message for each port
+        console.send_control(0, ConsoleControlEvent::VIRTIO_CONSOLE_DEVICE_READY, 1);
+
+        log::info!("VirtIO console device initialized ({} ports max)", max_nr_ports);
+
+        Some(console)
+    }
+
+    // Handles port announcements, receives data and sends what is pending.
+    // Should be called regularly, the device doesn't interrupt.
+    pub fn poll(&mut self) {
+        while let Some((resp, len)) = unsafe { self.control_rx.try_pop_with_len::<VirtioConsoleControl, 1>() } {
+            self.handle_control(&resp[0], len);
+            unsafe {
+                self.control_rx.try_push(&[QueueMessage::<VirtioConsoleControl>::DevWriteOnly]).unwrap();
+                self.control_rx.notify_device();
+            }
+        }
+
+        for port in self.ports.iter_mut() {
+            let mut received = false;
+            while let Some((resp, len)) = unsafe { port.rx.try_pop_with_len::<VirtioConsoleData, 1>() } {
+                port.received.extend_from_slice(&resp[0].data[..usize::min(len, DATA_BUF_SIZE)]);
+                unsafe { port.rx.try_push(&[QueueMessage::<VirtioConsoleData>::DevWriteOnly]).unwrap() };
+                received = true;
+            }
+            if received {
+                unsafe { port.rx.notify_device() };
+            }
+
+            port.send_pending();
+        }
+    }
+
+    // Whether a host application is connected to the port (e.g. to the socket of its chardev)
+    pub fn is_connected(&self, port_name: &str) -> bool {
+        self.get_port(port_name).map(|port| port.host_connected).unwrap_or(false)
+    }
+
+    // Takes the data received on a port since the last call
+    pub fn read(&mut self, port_name: &str) -> Vec<u8> {
+        match self.get_port_mut(port_name) {
+            Some(port) => core::mem::take(&mut port.received),
+            None => Vec::new(),
+        }
+    }
+
+    // Queues data to send on a port, actually sent by poll()
+    pub fn write(&mut self, port_name: &str, data: &[u8]) {
+        if let Some(port) = self.get_port_mut(port_name) {
+            port.pending.extend(data);
+            port.send_pending();
+        }
+    }
+
+    // Amount of data written to a port that the device hasn't taken yet
+    pub fn pending_len(&self, port_name: &str) -> usize {
+        self.get_port(port_name).map(|port| port.pending.len()).unwrap_or(0)
+    }
+
+    fn get_port(&self, port_name: &str) -> Option<&ConsolePort> {
+        self.ports.iter().find(|port| port.name.as_deref() == Some(port_name))
+    }
+
+    fn get_port_mut(&mut self, port_name: &str) -> Option<&mut ConsolePort> {
+        self.ports.iter_mut().find(|port| port.name.as_deref() == Some(port_name))
+    }
+
+    fn handle_control(&mut self, msg: &VirtioConsoleControl, len: usize) {
+        let id = msg.id as usize;
+
+        let Some(event) = ConsoleControlEvent::n(msg.event) else {
+            log::warn!("Unknown VirtIO console control event {}", msg.event);
+            return;
+        };
+
+        match event {
+            ConsoleControlEvent::VIRTIO_CONSOLE_DEVICE_ADD => {
+                if id >= self.ports.len() {
+                    log::warn!("VirtIO console port {} is over the limit of {}, ignoring it", id, self.ports.len());
+                    self.send_control(msg.id, ConsoleControlEvent::VIRTIO_CONSOLE_PORT_READY, 0);
+                    return;
+                }
+                self.send_control(msg.id, ConsoleControlEvent::VIRTIO_CONSOLE_PORT_READY, 1);
+                // The host doesn't send anything to ports the guest hasn't opened
+                self.send_control(msg.id, ConsoleControlEvent::VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+
+            ConsoleControlEvent::VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                if let Some(port) = self.ports.get_mut(id) {
+                    port.name = None;
+                    port.host_connected = false;
+                }
+            }
+
+            ConsoleControlEvent::VIRTIO_CONSOLE_PORT_NAME => {
+                if let Some(port) = self.ports.get_mut(id) {
+                    // Null-terminated
+                    let name = &msg.name[..len.saturating_sub(CONTROL_HDR_SIZE).min(msg.name.len())];
+                    let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    log::info!("VirtIO console port {} is \"{}\"", id, name);
+                    port.name = Some(name);
+                }
+            }
+
+            ConsoleControlEvent::VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id) {
+                    port.host_connected = msg.value == 1;
+                }
+            }
+
+            // Only relevant for terminals
+            ConsoleControlEvent::VIRTIO_CONSOLE_CONSOLE_PORT | ConsoleControlEvent::VIRTIO_CONSOLE_RESIZE => (),
+
+            _ => log::warn!("Unexpected VirtIO console control event {:?}", event),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: ConsoleControlEvent, value: u16) {
+        let data = VirtioConsoleControl {
+            id,
+            event: event as u16,
+            value,
+            ..VirtioConsoleControl::default()
+        };
+
+        unsafe {
+            self.control_tx
+                .try_push(&[QueueMessage::DevReadOnly { data, len: Some(CONTROL_HDR_SIZE) }])
+                .unwrap();
+            self.control_tx.notify_device();
+        }
+
+        while unsafe { self.control_tx.try_pop::<VirtioConsoleControl, 1>() }.is_none() {}
+    }
+}
+
+impl ConsolePort {
+    // Hands as much pending data as the transmit queue can take to the device
+    fn send_pending(&mut self) {
+        while unsafe { self.tx.try_pop::<VirtioConsoleData, 1>() }.is_some() {
+            self.in_flight -= 1;
+        }
+
+        let mut sent = false;
+        while !self.pending.is_empty() && self.in_flight < DATA_Q_SIZE {
+            let len = usize::min(self.pending.len(), DATA_BUF_SIZE);
+            let mut data = VirtioConsoleData::default();
+            for (dst, src) in data.data.iter_mut().zip(self.pending.drain(..len)) {
+                *dst = src;
+            }
+
+            unsafe { self.tx.try_push(&[QueueMessage::DevReadOnly { data, len: Some(len) }]).unwrap() };
+            self.in_flight += 1;
+            sent = true;
+        }
+
+        if sent {
+            unsafe { self.tx.notify_device() };
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioConsoleData {
+    data: [u8; 256],
+}
+
+impl Default for VirtioConsoleData {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioConsoleData {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+    // Only for PORT_NAME
+    name: [u8; 120],
+}
+
+impl Default for VirtioConsoleControl {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioConsoleControl {}
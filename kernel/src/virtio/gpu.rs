@@ -1,21 +1,51 @@
 use alloc::{boxed::Box, vec, vec::Vec};
+use applib::Rect;
 
 use crate::memory;
 use crate::pci::PciDevice;
 use core::mem::MaybeUninit;
+use core::ptr::{read_volatile, write_volatile};
 
 use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
 
-pub const W: usize = 1366;
-pub const H: usize = 768;
+// Used when the device doesn't report an enabled display
+const DEFAULT_W: usize = 1366;
+const DEFAULT_H: usize = 768;
 
+// Set in events_read when the host changed the display configuration (e.g window resized)
+const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
+
+// At most the queue sizes of the device
 const Q_SIZE: usize = 64;
+const CURSOR_Q_SIZE: usize = 16;
 const BUF_SIZE: usize = core::mem::size_of::<GpuVirtioMsg>();
 
+// Cursor images are always 64x64 RGBA
+pub const CURSOR_SIZE: usize = 64;
+
+// Cursor resources use their own id range, so they never collide with the framebuffer's
+const CURSOR_RESOURCE_ID_BASE: u32 = 0x1000;
+
 pub struct VirtioGPU {
     pub virtio_dev: VirtioDevice,
     pub framebuffer: Box<[u8]>,
     controlq: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    cursorq: VirtioQueue<CURSOR_Q_SIZE, BUF_SIZE>,
+    width: usize,
+    height: usize,
+    // A new resource is created every time the display is resized
+    resource_id: u32,
+    cursors: Vec<CursorImage>,
+    // Index in cursors and position last sent to the device
+    cursor_state: Option<(usize, u32, u32)>,
+}
+
+struct CursorImage {
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    // Backing of the resource, must live as long as it
+    _pixels: Box<[u8]>,
 }
 
 #[repr(C)]
@@ -27,6 +57,9 @@ union GpuVirtioMsg {
     set_scanout: VirtioGpuSetScanout,
     transfer_to_host_2d: VirtioGpuTransferToHost2d,
     resource_flush: VirtioGpuResourceFlush,
+    resource_unref: VirtioGpuResourceUnref,
+    resource_detach_backing: VirtioGpuResourceDetachBacking,
+    update_cursor: VirtioGpuUpdateCursor,
     ctrl_hdr: VirtioGpuCtrlHdr,
 }
 
@@ -51,63 +84,88 @@ impl VirtioGPU {
         let pci_dev = pci_devices.swap_remove(i);
         let mut virtio_dev = VirtioDevice::new(pci_dev, 0x0);
 
-        let controlq = virtio_dev.initialize_queue(0); // queue 0 (controlq)
+        let controlq = virtio_dev.initialize_queue(0).expect("Cannot initialize VirtIO GPU controlq"); // queue 0
+        let cursorq = virtio_dev.initialize_queue(1).expect("Cannot initialize VirtIO GPU cursorq"); // queue 1
         virtio_dev.write_status(0x04); // DRIVER_OK
 
-        VirtioGPU {
+        let mut gpu = VirtioGPU {
             virtio_dev,
-            framebuffer: vec![0u8; W * H * 4].into_boxed_slice(),
+            framebuffer: Box::new([]),
             controlq,
-        }
+            cursorq,
+            width: 0,
+            height: 0,
+            resource_id: 0,
+            cursors: Vec::new(),
+            cursor_state: None,
+        };
+
+        let (w, h) = gpu.get_display_size().unwrap_or_else(|| {
+            log::warn!("No enabled VirtIO GPU display, defaulting to {}x{}", DEFAULT_W, DEFAULT_H);
+            (DEFAULT_W, DEFAULT_H)
+        });
+
+        log::info!("Display size: {}x{}", w, h);
+
+        gpu.width = w;
+        gpu.height = h;
+        gpu.framebuffer = vec![0u8; w * h * 4].into_boxed_slice();
+
+        gpu
     }
 
     pub fn get_dims(&self) -> (usize, usize) {
-        (W, H)
+        (self.width, self.height)
     }
 
-    /*
-    pub fn get_max_scanouts(&self, boot_info: &'static BootInfo) -> u32 {
+    // Checks whether the host changed the display size, in which case the framebuffer is
+    // reallocated (and blank) and the new dimensions are returned
+    pub fn poll_resize(&mut self) -> Option<(usize, usize)> {
 
-        let addr = self.get_device_config_bar_addr(boot_info);
-        let ptr: *mut u32 = addr.as_mut_ptr();
-
-        unsafe { *ptr.offset(2) }
-    }
+        if self.read_events() & VIRTIO_GPU_EVENT_DISPLAY == 0 {
+            return None;
+        }
 
-    pub fn read_events(&self, boot_info: &'static BootInfo) -> u32 {
+        self.clear_events(VIRTIO_GPU_EVENT_DISPLAY);
 
-        let addr = self.get_device_config_bar_addr(boot_info);
-        let ptr: *mut u32 = addr.as_mut_ptr();
+        // Keeping the current size if the display was disabled
+        let (w, h) = self.get_display_size()?;
+        if (w, h) == (self.width, self.height) {
+            return None;
+        }
 
-        unsafe { *ptr }
-    }
+        log::info!("Display resized to {}x{}", w, h);
 
-    pub fn clear_events(&self, boot_info: &'static BootInfo) {
+        let old_resource_id = self.resource_id;
 
-        let addr = self.get_device_config_bar_addr(boot_info);
-        let ptr: *mut u32 = addr.as_mut_ptr();
+        self.width = w;
+        self.height = h;
 
-        unsafe { *ptr.offset(1) = 1 };
-        fence(Ordering::SeqCst);
-    }
+        // The old framebuffer is still the backing of the old resource until it is released
+        let _old_framebuffer = core::mem::replace(
+            &mut self.framebuffer,
+            vec![0u8; w * h * 4].into_boxed_slice(),
+        );
 
-    fn get_device_config_bar_addr(&self, boot_info: &'static BootInfo) -> VirtAddr {
+        self.init_framebuffer();
+        self.release_resource(old_resource_id);
 
-        let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
+        // The cursor may not survive the scanout change
+        self.cursor_state = None;
 
-        let config_cap = self.virtio_dev.capabilities.iter()
-            .find(|cap| cap.virtio_cap.cfg_type == CfgType::VIRTIO_PCI_CAP_DEVICE_CFG)
-            .expect("No VirtIO device config capability?");
+        Some((w, h))
+    }
 
-        let bar_addr = match self.virtio_dev.pci_device.bars[&config_cap.virtio_cap.bar.into()] {
-            PciBar::Memory { base_addr, .. } => base_addr,
-            PciBar::IO { .. } => unimplemented!(
-                "Support for I/O BARs in VirtIO not implemented")
-        };
+    // Pending events, see VIRTIO_GPU_EVENT_DISPLAY
+    fn read_events(&self) -> u32 {
+        let config = unsafe { self.virtio_dev.device_specific_config_ptr::<VirtioGpuConfig>() };
+        unsafe { read_volatile(&(*config).events_read) }
+    }
 
-        phys_offset + bar_addr + (config_cap.virtio_cap.offset as u64)
+    fn clear_events(&mut self, events: u32) {
+        let config = unsafe { self.virtio_dev.device_specific_config_ptr::<VirtioGpuConfig>() };
+        unsafe { write_volatile(&mut (*config).events_clear, events) };
     }
-    */
 
     fn send_command(&mut self, input: GpuVirtioMsg) -> GpuVirtioMsg {
         unsafe {
@@ -131,6 +189,21 @@ impl VirtioGPU {
         }
     }
 
+    // Commands on the cursor queue don't have a response, the buffer is just given back
+    fn send_cursor_command(&mut self, input: GpuVirtioMsg) {
+        unsafe {
+            self.cursorq
+                .try_push(&[QueueMessage::DevReadOnly {
+                    data: input,
+                    len: None,
+                }])
+                .unwrap();
+            self.cursorq.notify_device();
+        }
+
+        while unsafe { self.cursorq.try_pop::<GpuVirtioMsg, 1>() }.is_none() {}
+    }
+
     fn send_command_noreply(&mut self, input: GpuVirtioMsg) -> Option<()> {
         let resp = self.send_command(input);
         let resp: VirtioGpuCtrlHdr = unsafe { resp.ctrl_hdr };
@@ -154,8 +227,27 @@ impl VirtioGPU {
         unsafe { res.resp_display_info }
     }
 
+    // Size of the first scanout, if enabled
+    fn get_display_size(&mut self) -> Option<(usize, usize)> {
+        let info = self.get_display_info();
+
+        if info.hdr._type != VirtioGpuCtrlType::VIRTIO_GPU_RESP_OK_DISPLAY_INFO as u32 {
+            log::warn!("VirtIO GPU display info request failed: {:#x}", info.hdr._type);
+            return None;
+        }
+
+        let mode = info.pmodes[0];
+        match mode.enabled != 0 && mode.r.width > 0 && mode.r.height > 0 {
+            true => Some((mode.r.width as usize, mode.r.height as usize)),
+            false => None,
+        }
+    }
+
+    // Creates a resource the size of the display, backed by the framebuffer, and scans it out
     pub fn init_framebuffer(&mut self) {
-        let resource_id = 0x1;
+        self.resource_id += 1;
+        let resource_id = self.resource_id;
+        let (w, h) = (self.width as u32, self.height as u32);
 
         self.send_command_noreply(GpuVirtioMsg {
             resource_create_2d: VirtioGpuResourceCreate2d {
@@ -165,8 +257,8 @@ impl VirtioGPU {
                 },
                 resource_id,
                 format: 67, // RGBA,
-                width: W as u32,
-                height: H as u32,
+                width: w,
+                height: h,
             },
         })
         .unwrap();
@@ -205,8 +297,8 @@ impl VirtioGPU {
                 r: VirtioGpuRect {
                     x: 0,
                     y: 0,
-                    width: W as u32,
-                    height: H as u32,
+                    width: w,
+                    height: h,
                 },
                 scanout_id: 0,
                 resource_id,
@@ -215,46 +307,231 @@ impl VirtioGPU {
         .unwrap();
     }
 
-    pub fn flush(&mut self) {
-        let resource_id = 0x1;
-
+    fn release_resource(&mut self, resource_id: u32) {
         self.send_command_noreply(GpuVirtioMsg {
-            transfer_to_host_2d: VirtioGpuTransferToHost2d {
+            resource_detach_backing: VirtioGpuResourceDetachBacking {
                 hdr: VirtioGpuCtrlHdr {
-                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D as u32,
+                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING as u32,
                     ..VirtioGpuCtrlHdr::default()
                 },
-                r: VirtioGpuRect {
-                    x: 0,
-                    y: 0,
-                    width: W as u32,
-                    height: H as u32,
+                resource_id,
+                padding: 0x0,
+            },
+        })
+        .unwrap();
+
+        self.send_command_noreply(GpuVirtioMsg {
+            resource_unref: VirtioGpuResourceUnref {
+                hdr: VirtioGpuCtrlHdr {
+                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_UNREF as u32,
+                    ..VirtioGpuCtrlHdr::default()
                 },
-                offset: 0x0,
                 resource_id,
                 padding: 0x0,
             },
         })
         .unwrap();
+    }
+
+    // Uploads a CURSOR_SIZE x CURSOR_SIZE RGBA image, and returns the index to pass to set_cursor()
+    pub fn add_cursor(&mut self, pixels: Box<[u8]>, hot_x: u32, hot_y: u32) -> usize {
+        assert_eq!(pixels.len(), CURSOR_SIZE * CURSOR_SIZE * 4);
+
+        let index = self.cursors.len();
+        let resource_id = CURSOR_RESOURCE_ID_BASE + index as u32;
+        let size = CURSOR_SIZE as u32;
+
+        self.send_command_noreply(GpuVirtioMsg {
+            resource_create_2d: VirtioGpuResourceCreate2d {
+                hdr: VirtioGpuCtrlHdr {
+                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_CREATE_2D as u32,
+                    ..VirtioGpuCtrlHdr::default()
+                },
+                resource_id,
+                format: 67, // RGBA,
+                width: size,
+                height: size,
+            },
+        })
+        .unwrap();
+
+        let pixels_addr = memory::get_mapper()
+            .ref_to_phys(pixels.as_ref())
+            .as_u64();
 
         self.send_command_noreply(GpuVirtioMsg {
-            resource_flush: VirtioGpuResourceFlush {
+            resource_attach_backing: VirtioGpuResourceAttachBacking {
                 hdr: VirtioGpuCtrlHdr {
-                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_FLUSH as u32,
+                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING as u32,
+                    ..VirtioGpuCtrlHdr::default()
+                },
+                resource_id,
+                nr_entries: 1,
+                entries: {
+                    let mut entries = [VirtioGpuMemEntry::default(); MAX_MEM_PAGES];
+                    entries[0] = VirtioGpuMemEntry {
+                        addr: pixels_addr,
+                        length: pixels.len() as u32,
+                        padding: 0x0,
+                    };
+                    entries
+                },
+            },
+        })
+        .unwrap();
+
+        // The image never changes, so it is transferred only once
+        self.send_command_noreply(GpuVirtioMsg {
+            transfer_to_host_2d: VirtioGpuTransferToHost2d {
+                hdr: VirtioGpuCtrlHdr {
+                    _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D as u32,
                     ..VirtioGpuCtrlHdr::default()
                 },
                 r: VirtioGpuRect {
                     x: 0,
                     y: 0,
-                    width: W as u32,
-                    height: H as u32,
+                    width: size,
+                    height: size,
                 },
+                offset: 0x0,
                 resource_id,
                 padding: 0x0,
             },
         })
         .unwrap();
+
+        self.cursors.push(CursorImage {
+            resource_id,
+            hot_x,
+            hot_y,
+            _pixels: pixels,
+        });
+
+        index
+    }
+
+    // Shows the cursor image at the given index, with its hotspot at (x, y).
+    // The device is only told about what changed since the last call.
+    pub fn set_cursor(&mut self, index: usize, x: u32, y: u32) {
+        let prev_index = self.cursor_state.map(|(prev_index, _, _)| prev_index);
+
+        let cmd_type = match prev_index == Some(index) {
+            true => VirtioGpuCtrlType::VIRTIO_GPU_CMD_MOVE_CURSOR,
+            false => VirtioGpuCtrlType::VIRTIO_GPU_CMD_UPDATE_CURSOR,
+        };
+
+        self.send_cursor_update(cmd_type, index, x, y);
+    }
+
+    // Moves the current cursor without changing its image
+    pub fn move_cursor(&mut self, x: u32, y: u32) {
+        if let Some((index, _, _)) = self.cursor_state {
+            self.send_cursor_update(VirtioGpuCtrlType::VIRTIO_GPU_CMD_MOVE_CURSOR, index, x, y);
+        }
+    }
+
+    fn send_cursor_update(&mut self, cmd_type: VirtioGpuCtrlType, index: usize, x: u32, y: u32) {
+        if self.cursor_state == Some((index, x, y)) {
+            return;
+        }
+
+        let cursor = &self.cursors[index];
+
+        self.send_cursor_command(GpuVirtioMsg {
+            update_cursor: VirtioGpuUpdateCursor {
+                hdr: VirtioGpuCtrlHdr {
+                    _type: cmd_type as u32,
+                    ..VirtioGpuCtrlHdr::default()
+                },
+                pos: VirtioGpuCursorPos {
+                    scanout_id: 0,
+                    x,
+                    y,
+                    padding: 0x0,
+                },
+                resource_id: cursor.resource_id,
+                hot_x: cursor.hot_x,
+                hot_y: cursor.hot_y,
+                padding: 0x0,
+            },
+        });
+
+        self.cursor_state = Some((index, x, y));
+    }
+
+    // Sends the damaged parts of the framebuffer to the display.
+    // Overlapping rects are merged first, so that no pixel is transferred twice.
+    pub fn flush(&mut self, damage: &[Rect]) {
+        let resource_id = self.resource_id;
+        let screen_rect = Rect { x0: 0, y0: 0, w: self.width as u32, h: self.height as u32 };
+
+        let regions = merge_rects(
+            damage
+                .iter()
+                .filter(|rect| rect.w > 0 && rect.h > 0)
+                .filter_map(|rect| rect.intersection(&screen_rect))
+                .collect(),
+        );
+
+        for region in regions {
+            let r = VirtioGpuRect {
+                x: region.x0 as u32,
+                y: region.y0 as u32,
+                width: region.w,
+                height: region.h,
+            };
+
+            // Offset of the first pixel of the region in the backing, rows keep the framebuffer stride
+            let offset = (r.y as u64 * self.width as u64 + r.x as u64) * 4;
+
+            self.send_command_noreply(GpuVirtioMsg {
+                transfer_to_host_2d: VirtioGpuTransferToHost2d {
+                    hdr: VirtioGpuCtrlHdr {
+                        _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D as u32,
+                        ..VirtioGpuCtrlHdr::default()
+                    },
+                    r,
+                    offset,
+                    resource_id,
+                    padding: 0x0,
+                },
+            })
+            .unwrap();
+
+            self.send_command_noreply(GpuVirtioMsg {
+                resource_flush: VirtioGpuResourceFlush {
+                    hdr: VirtioGpuCtrlHdr {
+                        _type: VirtioGpuCtrlType::VIRTIO_GPU_CMD_RESOURCE_FLUSH as u32,
+                        ..VirtioGpuCtrlHdr::default()
+                    },
+                    r,
+                    resource_id,
+                    padding: 0x0,
+                },
+            })
+            .unwrap();
+        }
+    }
+
+    pub fn flush_all(&mut self) {
+        let (w, h) = (self.width as u32, self.height as u32);
+        self.flush(&[Rect { x0: 0, y0: 0, w, h }]);
+    }
+}
+
+// Replaces overlapping rects with their bounding box, until none overlap
+fn merge_rects(mut rects: Vec<Rect>) -> Vec<Rect> {
+    let mut merged: Vec<Rect> = Vec::with_capacity(rects.len());
+
+    while let Some(mut rect) = rects.pop() {
+        // The bounding box may now overlap rects that were already merged
+        while let Some(i) = merged.iter().position(|other| rect.intersection(other).is_some()) {
+            rect = rect.bounding_box(&merged.swap_remove(i));
+        }
+        merged.push(rect);
     }
+
+    merged
 }
 
 impl VirtqSerializable for VirtioGpuCtrlHdr {}
@@ -270,6 +547,15 @@ impl Default for VirtioGpuCtrlHdr {
 
 const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
 
+// Device-specific configuration
+#[repr(C)]
+struct VirtioGpuConfig {
+    events_read: u32,
+    events_clear: u32,
+    num_scanouts: u32,
+    num_capsets: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct VirtioGpuCtrlHdr {
@@ -285,12 +571,18 @@ struct VirtioGpuCtrlHdr {
 enum VirtioGpuCtrlType {
     VIRTIO_GPU_CMD_GET_DISPLAY_INFO = 0x0100,
     VIRTIO_GPU_CMD_RESOURCE_CREATE_2D = 0x0101,
+    VIRTIO_GPU_CMD_RESOURCE_UNREF = 0x0102,
     VIRTIO_GPU_CMD_SET_SCANOUT = 0x0103,
     VIRTIO_GPU_CMD_RESOURCE_FLUSH = 0x0104,
     VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D = 0x0105,
     VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING = 0x0106,
+    VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING = 0x0107,
+
+    VIRTIO_GPU_CMD_UPDATE_CURSOR = 0x0300,
+    VIRTIO_GPU_CMD_MOVE_CURSOR = 0x0301,
 
     VIRTIO_GPU_RESP_OK_NODATA = 0x1100,
+    VIRTIO_GPU_RESP_OK_DISPLAY_INFO = 0x1101,
 }
 
 #[repr(C)]
@@ -398,3 +690,49 @@ pub struct VirtioGpuResourceFlush {
     resource_id: u32,
     padding: u32,
 }
+
+//
+// VIRTIO_GPU_CMD_RESOURCE_UNREF
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioGpuResourceUnref {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+//
+// VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioGpuResourceDetachBacking {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+//
+// VIRTIO_GPU_CMD_UPDATE_CURSOR / VIRTIO_GPU_CMD_MOVE_CURSOR
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioGpuCursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    padding: u32,
+}
+
+// MOVE_CURSOR only uses the position
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioGpuUpdateCursor {
+    hdr: VirtioGpuCtrlHdr,
+    pos: VirtioGpuCursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    padding: u32,
+}
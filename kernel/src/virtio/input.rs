@@ -1,37 +1,125 @@
+use core::ptr::{read_volatile, write_volatile};
+
 use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
 use crate::pci::PciDevice;
+use alloc::string::String;
 use alloc::vec::Vec;
+use applib::input::keymap::EventType;
 
 const Q_SIZE: usize = 64;
 const BUF_SIZE: usize = core::mem::size_of::<VirtioInputEvent>();
 
+// Absolute axes used for the pointer position
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+
+#[repr(u8)]
+#[allow(non_camel_case_types)]
+enum VirtioInputConfigSelect {
+    VIRTIO_INPUT_CFG_ID_NAME = 0x01,
+    VIRTIO_INPUT_CFG_EV_BITS = 0x11,
+    VIRTIO_INPUT_CFG_ABS_INFO = 0x12,
+}
+
 pub struct VirtioInput {
     pub virtio_dev: VirtioDevice,
     eventq: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    buffered: Vec<VirtioInputEvent>,
+    // Ranges of ABS_X and ABS_Y, for devices reporting absolute positions (e.g. tablets)
+    abs_ranges: [Option<AbsRange>; 2],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AbsRange {
+    min: i32,
+    max: i32,
 }
 
 impl VirtioInput {
-    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Self {
-        let i = (0..pci_devices.len())
-            .find(|&i| {
-                pci_devices[i].vendor_id == 0x1af4 && pci_devices[i].device_id == 0x1040 + 18
-            })
-            .expect("Cannot find VirtIO input device");
+    // Keyboards, mice and tablets all use the same device type, this returns the next one
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        let i = (0..pci_devices.len()).find(|&i| {
+            pci_devices[i].vendor_id == 0x1af4 && pci_devices[i].device_id == 0x1040 + 18
+        })?;
 
         let pci_dev = pci_devices.swap_remove(i);
         let mut virtio_dev = VirtioDevice::new(pci_dev, 0x0);
 
-        let mut eventq = virtio_dev.initialize_queue(0); // queue 0 (eventq)
+        virtio_dev.enable_interrupts();
+        let mut eventq = virtio_dev.initialize_queue(0)?; // queue 0 (eventq)
                                                          //log::debug!("out of initialize_queue(): {:?}", eventq.descriptor_area.as_ptr());
         virtio_dev.write_status(0x04); // DRIVER_OK
 
         let msg = [QueueMessage::<VirtioInputEvent>::DevWriteOnly];
         unsafe { while eventq.try_push(&msg).is_some() {} };
 
-        VirtioInput { virtio_dev, eventq }
+        let name = query_config(&virtio_dev, VirtioInputConfigSelect::VIRTIO_INPUT_CFG_ID_NAME, 0);
+        let name = String::from_utf8_lossy(&name).into_owned();
+
+        let has_events = |ev_type: EventType| {
+            let bitmap = query_config(&virtio_dev, VirtioInputConfigSelect::VIRTIO_INPUT_CFG_EV_BITS, ev_type as u8);
+            bitmap.iter().any(|&b| b != 0)
+        };
+
+        let capabilities: Vec<&str> = [
+            (EventType::EV_KEY, "keys"),
+            (EventType::EV_REL, "relative axes"),
+            (EventType::EV_ABS, "absolute axes"),
+        ]
+        .into_iter()
+        .filter(|&(ev_type, _)| has_events(ev_type))
+        .map(|(_, desc)| desc)
+        .collect();
+
+        let abs_ranges = match has_events(EventType::EV_ABS) {
+            true => [ABS_X, ABS_Y].map(|axis| get_abs_range(&virtio_dev, axis)),
+            false => [None, None],
+        };
+
+        log::info!("VirtIO input device \"{}\" ({})", name, capabilities.join(", "));
+
+        Some(VirtioInput {
+            virtio_dev,
+            eventq,
+            buffered: Vec::new(),
+            abs_ranges,
+        })
+    }
+
+    // Maps the value of an EV_ABS event on ABS_X or ABS_Y to a screen coordinate
+    pub fn abs_to_screen(&self, axis: u16, value: u32, screen_len: u32) -> Option<i64> {
+        let AbsRange { min, max } = match axis {
+            ABS_X => self.abs_ranges[0],
+            ABS_Y => self.abs_ranges[1],
+            _ => None,
+        }?;
+
+        let (min, max) = (min as i64, max as i64);
+        let value = i64::max(min, i64::min(max, value as i32 as i64));
+
+        Some((value - min) * (screen_len as i64 - 1) / (max - min))
+    }
+
+    // Called when woken up between frames, so that the event queue never fills up
+    pub fn service_irq(&mut self) {
+        if self.eventq.take_irq() {
+            let events = self.drain_eventq();
+            self.buffered.extend(events);
+        }
+    }
+
+    // Events received since the last poll(), without consuming them
+    pub fn buffered_events(&self) -> &[VirtioInputEvent] {
+        &self.buffered
     }
 
     pub fn poll(&mut self) -> Vec<VirtioInputEvent> {
+        let mut out = core::mem::take(&mut self.buffered);
+        out.extend(self.drain_eventq());
+        out
+    }
+
+    fn drain_eventq(&mut self) -> Vec<VirtioInputEvent> {
         let mut out = Vec::new();
 
         while let Some(resp_list) = unsafe { self.eventq.try_pop::<_, 1>() } {
@@ -50,6 +138,43 @@ impl VirtioInput {
     }
 }
 
+// Selects a config item, and returns its content (empty if the device doesn't have it)
+fn query_config(virtio_dev: &VirtioDevice, select: VirtioInputConfigSelect, subsel: u8) -> Vec<u8> {
+    unsafe {
+        let config = virtio_dev.device_specific_config_ptr::<VirtioInputConfig>();
+        write_volatile(&mut (*config).select, select as u8);
+        write_volatile(&mut (*config).subsel, subsel);
+        let size = usize::min(read_volatile(&(*config).size) as usize, (*config).u.len());
+        (0..size).map(|i| read_volatile(&(*config).u[i])).collect()
+    }
+}
+
+fn get_abs_range(virtio_dev: &VirtioDevice, axis: u16) -> Option<AbsRange> {
+    let info = query_config(virtio_dev, VirtioInputConfigSelect::VIRTIO_INPUT_CFG_ABS_INFO, axis as u8);
+    if info.len() < 8 {
+        return None;
+    }
+
+    let min = i32::from_le_bytes(info[0..4].try_into().unwrap());
+    let max = i32::from_le_bytes(info[4..8].try_into().unwrap());
+
+    match max > min {
+        true => Some(AbsRange { min, max }),
+        false => None,
+    }
+}
+
+// Device-specific configuration
+#[repr(C)]
+struct VirtioInputConfig {
+    select: u8,
+    subsel: u8,
+    size: u8,
+    reserved: [u8; 5],
+    // Name string, bitmap or axis info, depending on select
+    u: [u8; 128],
+}
+
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct VirtioInputEvent {
@@ -1,5 +1,7 @@
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::borrow::BorrowMut;
 use core::convert::TryInto;
 use core::hash::Hasher;
@@ -9,14 +11,23 @@ use core::{mem, usize};
 use tinyvec::ArrayVec;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::interrupts::{self, IrqEvent};
 use crate::memory;
-use crate::pci::{PciBar, PciConfigSpace, PciDevice};
+use crate::pci::{MsixTable, PciBar, PciConfigSpace, PciDevice};
 
 const VIRTIO_PCI_VENDOR: u8 = 0x09;
 
+// Written to msix_config or queue_msix_vector to disable interrupts for that source
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+
+pub mod block;
+pub mod console;
 pub mod gpu;
 pub mod input;
 pub mod network;
+pub mod p9;
+pub mod rng;
+pub mod vsock;
 
 #[repr(u32)]
 #[allow(non_camel_case_types)]
@@ -24,21 +35,49 @@ pub enum FeatureBits {
     VIRTIO_F_VERSION_1 = 0x1,
 }
 
-#[allow(dead_code)]
 pub struct VirtioInterruptAck {
     isr_ptr: &'static mut u8,
     pub latest_status: Option<IsrStatus>,
 }
 
 unsafe impl Sync for VirtioInterruptAck {}
+unsafe impl Send for VirtioInterruptAck {}
+
+impl VirtioInterruptAck {
+    // Reading the ISR status also de-asserts the (level-triggered) interrupt line
+    pub fn ack(&mut self) -> IsrStatus {
+        let val = unsafe { read_volatile(self.isr_ptr) };
+
+        let status = IsrStatus {
+            queue: val & 0x1 != 0,
+            config: val & 0x2 != 0,
+        };
+
+        self.latest_status = Some(status);
+
+        status
+    }
+}
 
 #[allow(dead_code)]
 pub struct VirtioDevice {
     pci_device: PciDevice,
     common_config_cap: VirtioCapability,
     notification_cap: VirtioCapability,
+    isr_cap: Option<VirtioCapability>,
     device_specific_config_cap: Option<VirtioCapability>,
     pub common_config: &'static mut VirtioPciCommonCfg,
+    interrupts: Option<VirtioInterrupts>,
+    // Device-specific feature bits (0 to 31) that the device also supports
+    features: u32,
+}
+
+// Set up by enable_interrupts(), before the queues are initialized
+enum VirtioInterrupts {
+    // One vector per queue, indexed by MSI-X table entry
+    Msix { table: MsixTable, vectors: Vec<u8> },
+    // Single line shared by all queues (and possibly other devices), the ISR telling what happened
+    Legacy(Arc<IrqEvent>),
 }
 
 #[repr(u8)]
@@ -104,6 +143,7 @@ pub struct VirtioQueue<const Q_SIZE: usize, const BUF_SIZE: usize> {
     pop_index: usize,
     notify_ptr: VirtAddr,
     avail_desc: [bool; Q_SIZE],
+    irq_event: Option<Arc<IrqEvent>>,
 }
 
 pub trait VirtqSerializable: Clone + Default {}
@@ -111,6 +151,8 @@ pub trait VirtqSerializable: Clone + Default {}
 #[derive(Clone)]
 pub enum QueueMessage<T: VirtqSerializable> {
     DevWriteOnly,
+    // For device-writable buffers smaller than T
+    DevWriteOnlyLen { len: usize },
     DevReadOnly { data: T, len: Option<usize> },
 }
 
@@ -147,6 +189,16 @@ pub struct VirtioPciCommonCfg {
 
 impl<const Q_SIZE: usize, const BUF_SIZE: usize> VirtioQueue<Q_SIZE, BUF_SIZE> {
 
+    // Whether the device signaled this queue since the last call.
+    // With a legacy interrupt line, this is shared with the other queues of the device.
+    // Queues without an interrupt are polled, so this is always true for them.
+    pub fn take_irq(&self) -> bool {
+        match &self.irq_event {
+            Some(irq_event) => irq_event.take_queue(),
+            None => true,
+        }
+    }
+
     fn take_descriptor(&mut self) -> Option<usize> {
         for (desc_index, available) in self.avail_desc.iter_mut().enumerate() {
             if *available {
@@ -208,6 +260,12 @@ impl<const Q_SIZE: usize, const BUF_SIZE: usize> VirtioQueue<Q_SIZE, BUF_SIZE> {
                     descriptor.len = mem::size_of::<T>() as u32;
                     T::default()
                 }
+                QueueMessage::DevWriteOnlyLen { len } => {
+                    assert!(*len <= mem::size_of::<T>());
+                    descriptor.flags = 0x2;
+                    descriptor.len = *len as u32;
+                    T::default()
+                }
             };
 
             let mapper = memory::get_mapper();
@@ -248,6 +306,11 @@ impl<const Q_SIZE: usize, const BUF_SIZE: usize> VirtioQueue<Q_SIZE, BUF_SIZE> {
     }
 
     pub unsafe fn try_pop<T: VirtqSerializable, const N: usize>(&mut self) -> Option<[T; N]> {
+        self.try_pop_with_len().map(|(out, _)| out)
+    }
+
+    // Also returns the number of bytes the device wrote, for devices that may not fill their buffers
+    pub unsafe fn try_pop_with_len<T: VirtqSerializable, const N: usize>(&mut self) -> Option<([T; N], usize)> {
         let mapper = memory::get_mapper();
 
         let new_index = read_volatile(&self.storage.device_area.idx) as usize;
@@ -278,12 +341,11 @@ impl<const Q_SIZE: usize, const BUF_SIZE: usize> VirtioQueue<Q_SIZE, BUF_SIZE> {
                 Box::leak(desc_buffer);
             };
 
-            let next_desc = descriptor.next.into();
-
             self.return_descriptor(desc_index);
 
-            if next_desc != 0 {
-                desc_index = next_desc
+            // The next field is left over from previous uses if the NEXT flag isn't set
+            if descriptor.flags & 0x1 != 0 {
+                desc_index = descriptor.next.into();
             } else {
                 break;
             }
@@ -291,13 +353,13 @@ impl<const Q_SIZE: usize, const BUF_SIZE: usize> VirtioQueue<Q_SIZE, BUF_SIZE> {
 
         self.pop_index += 1;
 
-        Some(out.into_inner())
+        Some((out.into_inner(), it.len as usize))
     }
 }
 
 #[derive(Debug)]
 pub struct VirtioCapability {
-    config_space_offset: u8,
+    config_space_offset: u16,
     virtio_cap: VirtioPciCap,
 }
 
@@ -354,6 +416,7 @@ impl VirtioDevice {
 
         let common_config_cap = find_cap(CfgType::VIRTIO_PCI_CAP_COMMON_CFG);
         let notification_cap = find_cap(CfgType::VIRTIO_PCI_CAP_NOTIFY_CFG);
+        let isr_cap = find_cap(CfgType::VIRTIO_PCI_CAP_ISR_CFG);
         let device_specific_config_cap = find_cap(CfgType::VIRTIO_PCI_CAP_DEVICE_CFG);
 
         let common_config_cap = common_config_cap.unwrap();
@@ -369,8 +432,11 @@ impl VirtioDevice {
             pci_device,
             common_config_cap,
             notification_cap,
+            isr_cap,
             device_specific_config_cap,
             common_config,
+            interrupts: None,
+            features: 0,
         };
 
         dev.initialize(feature_bits);
@@ -386,7 +452,10 @@ impl VirtioDevice {
         self.write_status(0x01); // ACKNOWLEDGE
         self.write_status(0x02); // DRIVER
 
-        let bits_0 = feature_bits;
+        // Asking for a feature the device doesn't have would fail FEATURES_OK
+        let bits_0 = feature_bits & self.read_feature_bits(0x0);
+        self.features = bits_0;
+
         let bits_1 = FeatureBits::VIRTIO_F_VERSION_1 as u32;
 
         self.write_feature_bits(0x0, bits_0);
@@ -399,14 +468,51 @@ impl VirtioDevice {
         assert_eq!(status, 0x08);
     }
 
+    // Returns None if the device doesn't have that queue, or if it is smaller than Q_SIZE
     pub fn initialize_queue<const Q_SIZE: usize, const BUF_SIZE: usize>(
         &mut self,
         q_index: u16,
-    ) -> VirtioQueue<Q_SIZE, BUF_SIZE> {
+    ) -> Option<VirtioQueue<Q_SIZE, BUF_SIZE>> {
+        self.setup_queue(q_index, false)
+    }
+
+    // Same, but the queue never gets an interrupt, for queues that are only checked when sending
+    pub fn initialize_polled_queue<const Q_SIZE: usize, const BUF_SIZE: usize>(
+        &mut self,
+        q_index: u16,
+    ) -> Option<VirtioQueue<Q_SIZE, BUF_SIZE>> {
+        self.setup_queue(q_index, true)
+    }
+
+    fn setup_queue<const Q_SIZE: usize, const BUF_SIZE: usize>(
+        &mut self,
+        q_index: u16,
+        polled: bool,
+    ) -> Option<VirtioQueue<Q_SIZE, BUF_SIZE>> {
         let mapper = memory::get_mapper();
 
         // TODO: prevent a queue from being initialized twice
 
+        // The device reports its maximum queue size, which the driver may reduce (split queues
+        // must be a power of 2 though)
+        let max_q_size = unsafe {
+            write_volatile(&mut self.common_config.queue_select, q_index);
+            read_volatile(&self.common_config.queue_size) as usize
+        };
+        if max_q_size == 0 {
+            log::error!("VirtIO device has no queue {}", q_index);
+            return None;
+        }
+        if Q_SIZE > max_q_size || !Q_SIZE.is_power_of_two() {
+            log::error!("Invalid size {} for VirtIO queue {} (at most {})", Q_SIZE, q_index, max_q_size);
+            return None;
+        }
+
+        let (msix_vector, mut irq_event) = match polled {
+            true => (VIRTIO_MSI_NO_VECTOR, None),
+            false => self.get_queue_interrupt(q_index),
+        };
+
         let mut storage = Box::new(VirtQStorage::new());
 
         for descriptor in storage.descriptor_area.0.iter_mut() {
@@ -429,41 +535,132 @@ impl VirtioDevice {
         // log::debug!("driver_area_addr={:x}", driver_area_addr);
         // log::debug!("dev_area_addr={:x}", dev_area_addr);
 
-        unsafe {
+        let vector_rejected = unsafe {
 
             let c = &mut self.common_config;
 
 
             write_volatile(&mut c.queue_select, q_index);
+            write_volatile(&mut c.queue_size, Q_SIZE as u16);
             write_volatile(&mut c.queue_desc, descr_area_addr);
             write_volatile(&mut c.queue_driver, driver_area_addr);
             write_volatile(&mut c.queue_device, dev_area_addr);
+            write_volatile(&mut c.queue_msix_vector, msix_vector);
+
+            // The device may fail to allocate the vector, in which case it reports NO_VECTOR
+            let vector_rejected = read_volatile(&c.queue_msix_vector) != msix_vector;
+            if vector_rejected {
+                write_volatile(&mut c.queue_msix_vector, VIRTIO_MSI_NO_VECTOR);
+            }
+
             write_volatile(&mut c.queue_enable, 1);
 
-            // Reading back queue size
-            let q_size = read_volatile(&c.queue_size) as usize;
+            vector_rejected
+        };
 
-            assert_eq!(q_size, Q_SIZE);
+        if vector_rejected {
+            log::warn!("VirtIO device rejected MSI-X vector for queue {}, polling it instead", q_index);
+            self.release_queue_interrupt(msix_vector);
+            irq_event = None;
         }
 
         let notify_ptr = self.get_queue_notify_ptr(q_index);
 
-        VirtioQueue {
+        Some(VirtioQueue {
             q_index,
             storage,
             pop_index: 0,
             notify_ptr,
             avail_desc: [true; Q_SIZE],
+            irq_event,
+        })
+    }
+
+    // Must be called before initializing the queues, which then each get their own
+    // MSI-X vector if the device supports it, or else share the legacy interrupt line
+    pub fn enable_interrupts(&mut self) {
+        assert!(self.interrupts.is_none(), "VirtIO interrupts already enabled");
+
+        // Configuration changes are not handled
+        unsafe { write_volatile(&mut self.common_config.msix_config, VIRTIO_MSI_NO_VECTOR) };
+
+        let interrupts = match self.pci_device.enable_msix() {
+            Some(table) => {
+                log::debug!("Using MSI-X for VirtIO device ({} vectors)", table.size());
+                VirtioInterrupts::Msix { table, vectors: Vec::new() }
+            }
+            None => {
+                let isr_cap = self.isr_cap.as_ref().expect("No VirtIO ISR capability");
+
+                let addr = get_addr_in_bar(&self.pci_device, &isr_cap.virtio_cap);
+                let isr_ptr = unsafe { addr.as_mut_ptr::<u8>().as_mut().unwrap() };
+
+                let ack = VirtioInterruptAck {
+                    isr_ptr,
+                    latest_status: None,
+                };
+
+                self.pci_device.enable_legacy_interrupts();
+                let line = self.pci_device.read_interrupt_line();
+
+                VirtioInterrupts::Legacy(interrupts::register_virtio_irq(line, ack))
+            }
+        };
+
+        self.interrupts = Some(interrupts);
+    }
+
+    // Returns the MSI-X vector to set for the queue, and the event the queue should check
+    fn get_queue_interrupt(&mut self, q_index: u16) -> (u16, Option<Arc<IrqEvent>>) {
+        match &mut self.interrupts {
+            None => (VIRTIO_MSI_NO_VECTOR, None),
+
+            Some(VirtioInterrupts::Legacy(irq_event)) => (VIRTIO_MSI_NO_VECTOR, Some(irq_event.clone())),
+
+            Some(VirtioInterrupts::Msix { table, vectors }) => {
+                let entry = vectors.len() as u16;
+                if entry >= table.size() {
+                    log::warn!("No MSI-X table entry left for VirtIO queue {}", q_index);
+                    return (VIRTIO_MSI_NO_VECTOR, None);
+                }
+
+                let Some((target, irq_event)) = interrupts::register_msi() else {
+                    log::warn!("No interrupt vector left for VirtIO queue {}", q_index);
+                    return (VIRTIO_MSI_NO_VECTOR, None);
+                };
+
+                table.set_entry(entry, target.apic_id, target.vector);
+                vectors.push(target.vector);
+
+                (entry, Some(irq_event))
+            }
         }
     }
 
+    // Undoes get_queue_interrupt() when the queue won't use its MSI-X entry after all
+    fn release_queue_interrupt(&mut self, msix_vector: u16) {
+        let Some(VirtioInterrupts::Msix { table, vectors }) = &mut self.interrupts else {
+            return;
+        };
+
+        // Entries are handed out in order, so this is always the last one
+        assert_eq!(usize::from(msix_vector) + 1, vectors.len());
+
+        let vector = vectors.pop().unwrap();
+        table.clear_entry(msix_vector);
+        interrupts::unregister_msi(vector);
+    }
+
     unsafe fn read_device_specific_config<T>(&self) -> &'static T {
+        self.device_specific_config_ptr::<T>().as_ref().unwrap()
+    }
+
+    // For configs with fields the driver writes to
+    unsafe fn device_specific_config_ptr<T>(&self) -> *mut T {
         let cap = self.device_specific_config_cap.as_ref().unwrap();
 
         let addr = get_addr_in_bar(&self.pci_device, &cap.virtio_cap);
-        let ptr = addr.as_ptr() as *const T;
-
-        ptr.as_ref().unwrap()
+        addr.as_mut_ptr() as *mut T
     }
 
     fn get_queue_notify_ptr(&mut self, q_index: u16) -> VirtAddr {
@@ -487,6 +684,10 @@ impl VirtioDevice {
         addr
     }
 
+    pub fn has_feature(&self, feature_bit: u32) -> bool {
+        self.features & feature_bit != 0
+    }
+
     pub fn write_status(&mut self, val: u8) {
         unsafe { write_volatile(&mut self.common_config.device_status, val) };
     }
@@ -504,7 +705,6 @@ impl VirtioDevice {
         }
     }
 
-    #[allow(dead_code)]
     fn read_feature_bits(&mut self, select: u32) -> u32 {
 
         unsafe {
@@ -45,8 +45,10 @@ impl VirtioNetwork {
         let feature_bits = NetworkFeatureBits::VIRTIO_NET_F_MAC as u32;
         let mut virtio_dev = VirtioDevice::new(pci_dev, feature_bits);
 
-        let mut receiveq1 = virtio_dev.initialize_queue(0); // queue 0 (receiveq1)
-        let transmitq1 = virtio_dev.initialize_queue(1); // queue 1 (transmitq1)
+        virtio_dev.enable_interrupts();
+        let mut receiveq1 = virtio_dev.initialize_queue(0).expect("Cannot initialize VirtIO receiveq1"); // queue 0
+        // Sending waits for the device to be done with the packet, no need for an interrupt
+        let transmitq1 = virtio_dev.initialize_polled_queue(1).expect("Cannot initialize VirtIO transmitq1"); // queue 1
         virtio_dev.write_status(0x04); // DRIVER_OK
 
         let device_config = unsafe { virtio_dev.read_device_specific_config::<VirtioNetConfig>() };
@@ -65,6 +67,10 @@ impl VirtioNetwork {
         }
     }
 
+    pub fn take_irq(&self) -> bool {
+        self.receiveq1.take_irq()
+    }
+
     pub fn try_recv(&mut self) -> Option<[u8; MAX_PACKET_SIZE]> {
         let resp_list = unsafe { self.receiveq1.try_pop::<_, 1>()? };
 
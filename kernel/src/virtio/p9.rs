@@ -0,0 +1,122 @@
+use core::mem::MaybeUninit;
+use core::ptr::read_volatile;
+
+use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
+use crate::pci::PciDevice;
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::bail;
+
+// At most the queue size of the device (QEMU's MAX_REQ)
+const Q_SIZE: usize = 128;
+
+// Largest 9P message, negotiated with the server on mount
+pub const MSIZE: usize = 8192;
+
+const BUF_SIZE: usize = core::mem::size_of::<VirtioP9Msg>();
+
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+enum P9FeatureBits {
+    VIRTIO_9P_MOUNT_TAG = 0x1 << 0,
+}
+
+// Transport for the 9P messages of a host directory shared with QEMU's -virtfs.
+// The device doesn't know about the protocol, it just carries one request and its response at a time.
+pub struct VirtioP9 {
+    #[allow(dead_code)]
+    pub virtio_dev: VirtioDevice,
+    requestq: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    mount_tag: String,
+}
+
+impl VirtioP9 {
+    // There may be any number of shared directories, this returns the next one
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        let i = (0..pci_devices.len()).find(|&i| {
+            pci_devices[i].vendor_id == 0x1af4
+                && (pci_devices[i].device_id == 0x1009 || pci_devices[i].device_id == 0x1040 + 9)
+        })?;
+
+        let pci_dev = pci_devices.swap_remove(i);
+        let feature_bits = P9FeatureBits::VIRTIO_9P_MOUNT_TAG as u32;
+        let mut virtio_dev = VirtioDevice::new(pci_dev, feature_bits);
+
+        // Requests are synchronous, the queue is polled
+        let requestq = virtio_dev.initialize_queue(0)?; // queue 0 (requestq)
+        virtio_dev.write_status(0x04); // DRIVER_OK
+
+        // Config space: tag length (u16), then the tag, not null-terminated
+        let mount_tag = match virtio_dev.has_feature(P9FeatureBits::VIRTIO_9P_MOUNT_TAG as u32) {
+            true => unsafe {
+                let ptr = virtio_dev.device_specific_config_ptr::<u8>();
+                let tag_len = u16::from_le_bytes([read_volatile(ptr), read_volatile(ptr.add(1))]);
+                let tag: Vec<u8> = (0..tag_len as usize).map(|i| read_volatile(ptr.add(2 + i))).collect();
+                String::from_utf8_lossy(&tag).into_owned()
+            },
+            false => String::new(),
+        };
+
+        log::info!("VirtIO 9P device with mount tag \"{}\"", mount_tag);
+
+        Some(VirtioP9 {
+            virtio_dev,
+            requestq,
+            mount_tag,
+        })
+    }
+
+    pub fn mount_tag(&self) -> &str {
+        &self.mount_tag
+    }
+
+    // Sends a complete 9P message and returns the complete response
+    pub fn send_request(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if request.len() > MSIZE {
+            bail!("9P request too large ({} bytes)", request.len());
+        }
+
+        let mut data = VirtioP9Msg::default();
+        data.data[..request.len()].copy_from_slice(request);
+
+        unsafe {
+            self.requestq
+                .try_push(&[
+                    QueueMessage::DevReadOnly { data, len: Some(request.len()) },
+                    QueueMessage::DevWriteOnly,
+                ])
+                .unwrap();
+            self.requestq.notify_device();
+        }
+
+        let resp_list = loop {
+            if let Some(resp_list) = unsafe { self.requestq.try_pop::<VirtioP9Msg, 2>() } {
+                break resp_list;
+            }
+        };
+
+        // Messages start with their total size
+        let response = &resp_list[1].data;
+        let size = u32::from_le_bytes(response[..4].try_into().unwrap()) as usize;
+        if size < 7 || size > MSIZE {
+            bail!("Invalid 9P response size {}", size);
+        }
+
+        Ok(response[..size].to_vec())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioP9Msg {
+    data: [u8; MSIZE],
+}
+
+impl Default for VirtioP9Msg {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioP9Msg {}
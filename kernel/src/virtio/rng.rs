@@ -0,0 +1,85 @@
+use core::mem::MaybeUninit;
+
+use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
+use crate::pci::PciDevice;
+use alloc::vec::Vec;
+use anyhow::bail;
+
+const Q_SIZE: usize = 8;
+
+// Largest amount of entropy asked for in a single request
+const MAX_REQUEST_LEN: usize = 64;
+
+const BUF_SIZE: usize = core::mem::size_of::<VirtioRngMsg>();
+
+// Entropy from the host, usually its /dev/urandom
+pub struct VirtioRng {
+    #[allow(dead_code)]
+    pub virtio_dev: VirtioDevice,
+    requestq: VirtioQueue<Q_SIZE, BUF_SIZE>,
+}
+
+impl VirtioRng {
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        let i = (0..pci_devices.len()).find(|&i| {
+            pci_devices[i].vendor_id == 0x1af4
+                && (pci_devices[i].device_id == 0x1005 || pci_devices[i].device_id == 0x1040 + 4)
+        })?;
+
+        let pci_dev = pci_devices.swap_remove(i);
+        let mut virtio_dev = VirtioDevice::new(pci_dev, 0);
+
+        // Requests are synchronous, the queue is polled
+        let requestq = virtio_dev.initialize_queue(0)?; // queue 0 (requestq)
+        virtio_dev.write_status(0x04); // DRIVER_OK
+
+        log::info!("VirtIO RNG device initialized");
+
+        Some(VirtioRng { virtio_dev, requestq })
+    }
+
+    // The device may return fewer bytes than asked for, so this loops until the buffer is full
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = usize::min(buf.len() - done, MAX_REQUEST_LEN);
+
+            unsafe {
+                self.requestq
+                    .try_push::<VirtioRngMsg, 1>(&[QueueMessage::DevWriteOnlyLen { len }])
+                    .unwrap();
+                self.requestq.notify_device();
+            }
+
+            let (resp_list, written_len) = loop {
+                if let Some(resp) = unsafe { self.requestq.try_pop_with_len::<VirtioRngMsg, 1>() } {
+                    break resp;
+                }
+            };
+
+            if written_len == 0 || written_len > len {
+                bail!("VirtIO RNG device returned {} bytes instead of up to {}", written_len, len);
+            }
+
+            buf[done..done + written_len].copy_from_slice(&resp_list[0].data[..written_len]);
+            done += written_len;
+        }
+
+        Ok(())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioRngMsg {
+    data: [u8; MAX_REQUEST_LEN],
+}
+
+impl Default for VirtioRngMsg {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioRngMsg {}
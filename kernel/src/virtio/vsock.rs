@@ -0,0 +1,434 @@
+use core::mem::MaybeUninit;
+
+use super::{QueueMessage, VirtioDevice, VirtioQueue, VirtqSerializable};
+use crate::pci::PciDevice;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use anyhow::bail;
+
+// At most the queue sizes of the device (vhost-vsock uses the same for all three)
+const Q_SIZE: usize = 128;
+
+// Largest payload of a packet, the same as Linux's receive buffers
+const PKT_DATA_SIZE: usize = 4096;
+
+const HDR_SIZE: usize = core::mem::size_of::<VirtioVsockHdr>();
+const BUF_SIZE: usize = core::mem::size_of::<VirtioVsockPacket>();
+const EVENT_BUF_SIZE: usize = core::mem::size_of::<VirtioVsockEvent>();
+
+// Receive buffer of each connection, which is the credit advertised to the peer
+const CONN_BUF_SIZE: usize = 64 * 1024;
+
+// Local ports are taken from the ephemeral range, like on Linux
+const FIRST_LOCAL_PORT: u32 = 49152;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 0x1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 0x2;
+
+#[repr(u16)]
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Debug, Clone, Copy, enumn::N)]
+enum VsockOp {
+    VIRTIO_VSOCK_OP_INVALID = 0,
+    VIRTIO_VSOCK_OP_REQUEST = 1,
+    VIRTIO_VSOCK_OP_RESPONSE = 2,
+    VIRTIO_VSOCK_OP_RST = 3,
+    VIRTIO_VSOCK_OP_SHUTDOWN = 4,
+    VIRTIO_VSOCK_OP_RW = 5,
+    VIRTIO_VSOCK_OP_CREDIT_UPDATE = 6,
+    VIRTIO_VSOCK_OP_CREDIT_REQUEST = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+// Stream sockets to other VMs or to the host, addressed by context id (CID) and port,
+// without any IP configuration. Only outgoing connections are supported.
+// Everything is polled, see poll().
+pub struct VirtioVsock {
+    #[allow(dead_code)]
+    pub virtio_dev: VirtioDevice,
+    rx: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    tx: VirtioQueue<Q_SIZE, BUF_SIZE>,
+    eventq: VirtioQueue<Q_SIZE, EVENT_BUF_SIZE>,
+    guest_cid: u64,
+    // Indexed by local port
+    connections: BTreeMap<u32, VsockConnection>,
+    next_port: u32,
+}
+
+struct VsockConnection {
+    peer_cid: u64,
+    peer_port: u32,
+    state: ConnectionState,
+    received: VecDeque<u8>,
+    // The peer won't send more data
+    peer_shutdown_send: bool,
+    // The peer won't accept more data
+    peer_shutdown_recv: bool,
+    // Total bytes sent, and read by the app (the peer tracks its credit with those)
+    tx_cnt: u32,
+    fwd_cnt: u32,
+    // Last fwd_cnt sent to the peer
+    reported_fwd_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl VsockConnection {
+    // How much the peer can still take
+    fn peer_credit(&self) -> usize {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+
+    // Every packet tells the peer about our credit
+    fn header(&mut self, guest_cid: u64, local_port: u32, op: VsockOp, flags: u32) -> VirtioVsockHdr {
+        self.reported_fwd_cnt = self.fwd_cnt;
+
+        VirtioVsockHdr {
+            src_cid: guest_cid,
+            dst_cid: self.peer_cid,
+            src_port: local_port,
+            dst_port: self.peer_port,
+            len: 0, // Set by send_packet()
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: op as u16,
+            flags,
+            buf_alloc: CONN_BUF_SIZE as u32,
+            fwd_cnt: self.fwd_cnt,
+        }
+    }
+}
+
+impl VirtioVsock {
+    pub fn new(pci_devices: &mut Vec<PciDevice>) -> Option<Self> {
+        // No legacy device ID for this one
+        let i = (0..pci_devices.len())
+            .find(|&i| pci_devices[i].vendor_id == 0x1af4 && pci_devices[i].device_id == 0x1040 + 19)?;
+
+        let pci_dev = pci_devices.swap_remove(i);
+        let mut virtio_dev = VirtioDevice::new(pci_dev, 0x0);
+
+        let rx = virtio_dev.initialize_queue(0)?; // queue 0 (rx)
+        let tx = virtio_dev.initialize_queue(1)?; // queue 1 (tx)
+        let eventq = virtio_dev.initialize_queue(2)?; // queue 2 (event)
+
+        virtio_dev.write_status(0x04); // DRIVER_OK
+
+        // Config space: guest_cid (u64)
+        let guest_cid = unsafe { core::ptr::read_volatile(virtio_dev.device_specific_config_ptr::<u64>()) };
+
+        let mut vsock = VirtioVsock {
+            virtio_dev,
+            rx,
+            tx,
+            eventq,
+            guest_cid,
+            connections: BTreeMap::new(),
+            next_port: FIRST_LOCAL_PORT,
+        };
+
+        unsafe {
+            let msg = [QueueMessage::<VirtioVsockPacket>::DevWriteOnly];
+            while vsock.rx.try_push(&msg).is_some() {}
+            vsock.rx.notify_device();
+
+            let msg = [QueueMessage::<VirtioVsockEvent>::DevWriteOnly];
+            while vsock.eventq.try_push(&msg).is_some() {}
+            vsock.eventq.notify_device();
+        }
+
+        log::info!("VirtIO vsock device initialized (guest CID {})", guest_cid);
+
+        Some(vsock)
+    }
+
+    // Starts connecting to a port of another context (2 being the host), and returns the local port
+    // identifying the connection. Writes are accepted once the peer has answered.
+    pub fn connect(&mut self, peer_cid: u64, peer_port: u32) -> anyhow::Result<u32> {
+        let local_port = self.next_port;
+        self.next_port = match self.next_port {
+            u32::MAX => FIRST_LOCAL_PORT,
+            port => port + 1,
+        };
+
+        if self.connections.contains_key(&local_port) {
+            bail!("No free vsock port");
+        }
+
+        let mut conn = VsockConnection {
+            peer_cid,
+            peer_port,
+            state: ConnectionState::Connecting,
+            received: VecDeque::new(),
+            peer_shutdown_send: false,
+            peer_shutdown_recv: false,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        };
+
+        let hdr = conn.header(self.guest_cid, local_port, VsockOp::VIRTIO_VSOCK_OP_REQUEST, 0);
+        self.connections.insert(local_port, conn);
+        self.send_packet(hdr, &[]);
+
+        log::debug!("Connecting to vsock {}:{} (local port {})", peer_cid, peer_port, local_port);
+
+        Ok(local_port)
+    }
+
+    // Returns how much was sent, which is limited by the space left in the peer's buffer
+    pub fn write(&mut self, local_port: u32, buf: &[u8]) -> anyhow::Result<usize> {
+        self.poll();
+
+        let Some(conn) = self.connections.get_mut(&local_port) else {
+            bail!("Unknown vsock connection {}", local_port);
+        };
+
+        match conn.state {
+            ConnectionState::Connecting => return Ok(0),
+            ConnectionState::Closed => bail!("vsock connection {} closed", local_port),
+            ConnectionState::Connected if conn.peer_shutdown_recv => {
+                bail!("vsock connection {} closed by the peer", local_port)
+            }
+            ConnectionState::Connected => (),
+        }
+
+        let sent_len = usize::min(buf.len(), conn.peer_credit());
+        conn.tx_cnt = conn.tx_cnt.wrapping_add(sent_len as u32);
+
+        let hdr = conn.header(self.guest_cid, local_port, VsockOp::VIRTIO_VSOCK_OP_RW, 0);
+        for chunk in buf[..sent_len].chunks(PKT_DATA_SIZE) {
+            self.send_packet(hdr, chunk);
+        }
+
+        Ok(sent_len)
+    }
+
+    // Returns 0 if nothing was received yet, and fails once the peer is done sending
+    pub fn read(&mut self, local_port: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        self.poll();
+
+        let Some(conn) = self.connections.get_mut(&local_port) else {
+            bail!("Unknown vsock connection {}", local_port);
+        };
+
+        if conn.received.is_empty() {
+            match conn.state {
+                ConnectionState::Closed => bail!("vsock connection {} closed", local_port),
+                _ if conn.peer_shutdown_send => bail!("vsock connection {} closed by the peer", local_port),
+                _ => return Ok(0),
+            }
+        }
+
+        let read_len = usize::min(buf.len(), conn.received.len());
+        for (dst, src) in buf.iter_mut().zip(conn.received.drain(..read_len)) {
+            *dst = src;
+        }
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(read_len as u32);
+
+        // Otherwise the peer runs out of credit and stops sending
+        let unreported = conn.fwd_cnt.wrapping_sub(conn.reported_fwd_cnt) as usize;
+        if conn.state == ConnectionState::Connected && unreported >= CONN_BUF_SIZE / 2 {
+            let hdr = conn.header(self.guest_cid, local_port, VsockOp::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+            self.send_packet(hdr, &[]);
+        }
+
+        Ok(read_len)
+    }
+
+    pub fn close(&mut self, local_port: u32) {
+        log::debug!("Closing vsock connection {}", local_port);
+
+        let Some(mut conn) = self.connections.remove(&local_port) else {
+            return;
+        };
+
+        // The peer answers with a RST, which is ignored since the connection is gone
+        if conn.state == ConnectionState::Connected {
+            let flags = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+            let hdr = conn.header(self.guest_cid, local_port, VsockOp::VIRTIO_VSOCK_OP_SHUTDOWN, flags);
+            self.send_packet(hdr, &[]);
+        }
+    }
+
+    // Handles received packets and events.
+    // Should be called regularly, the device doesn't interrupt.
+    pub fn poll(&mut self) {
+        let mut received = false;
+        while let Some((resp, len)) = unsafe { self.rx.try_pop_with_len::<VirtioVsockPacket, 1>() } {
+            self.handle_packet(&resp[0], len);
+            unsafe { self.rx.try_push(&[QueueMessage::<VirtioVsockPacket>::DevWriteOnly]).unwrap() };
+            received = true;
+        }
+        if received {
+            unsafe { self.rx.notify_device() };
+        }
+
+        while let Some(resp) = unsafe { self.eventq.try_pop::<VirtioVsockEvent, 1>() } {
+            // e.g after a live migration, existing connections are gone
+            if resp[0].id == VIRTIO_VSOCK_EVENT_TRANSPORT_RESET {
+                log::warn!("VirtIO vsock transport reset");
+                self.guest_cid = unsafe { core::ptr::read_volatile(self.virtio_dev.device_specific_config_ptr::<u64>()) };
+                for conn in self.connections.values_mut() {
+                    conn.state = ConnectionState::Closed;
+                }
+            }
+            unsafe {
+                self.eventq.try_push(&[QueueMessage::<VirtioVsockEvent>::DevWriteOnly]).unwrap();
+                self.eventq.notify_device();
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &VirtioVsockPacket, len: usize) {
+        let hdr = packet.hdr;
+        let local_port = hdr.dst_port;
+
+        let Some(op) = VsockOp::n(hdr.op) else {
+            log::warn!("Unknown VirtIO vsock operation {}", { hdr.op });
+            return;
+        };
+
+        let conn = self
+            .connections
+            .get_mut(&local_port)
+            .filter(|conn| conn.peer_cid == hdr.src_cid && conn.peer_port == hdr.src_port);
+
+        let Some(conn) = conn.filter(|_| hdr.type_ == VIRTIO_VSOCK_TYPE_STREAM) else {
+            // Incoming connections are refused, like any packet for a connection that doesn't exist
+            if !matches!(op, VsockOp::VIRTIO_VSOCK_OP_RST) {
+                self.send_reset(&hdr);
+            }
+            return;
+        };
+
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        match op {
+            VsockOp::VIRTIO_VSOCK_OP_RESPONSE if conn.state == ConnectionState::Connecting => {
+                log::debug!("Connected to vsock {}:{}", conn.peer_cid, conn.peer_port);
+                conn.state = ConnectionState::Connected;
+            }
+
+            VsockOp::VIRTIO_VSOCK_OP_RST => {
+                if conn.state == ConnectionState::Connecting {
+                    log::warn!("vsock connection to {}:{} refused", conn.peer_cid, conn.peer_port);
+                }
+                conn.state = ConnectionState::Closed;
+            }
+
+            VsockOp::VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.peer_shutdown_send |= hdr.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0;
+                conn.peer_shutdown_recv |= hdr.flags & VIRTIO_VSOCK_SHUTDOWN_RCV != 0;
+            }
+
+            VsockOp::VIRTIO_VSOCK_OP_RW if conn.state == ConnectionState::Connected => {
+                let data_len = usize::min(hdr.len as usize, len.saturating_sub(HDR_SIZE));
+                let data_len = usize::min(data_len, PKT_DATA_SIZE);
+
+                // The peer ignored the credit it was given, what was received within it stays readable
+                let space = CONN_BUF_SIZE - conn.received.len();
+                conn.received.extend(&packet.data[..usize::min(data_len, space)]);
+                if data_len > space {
+                    log::warn!("vsock peer {}:{} overflowed its credit", conn.peer_cid, conn.peer_port);
+                    conn.state = ConnectionState::Closed;
+                    self.send_reset(&hdr);
+                }
+            }
+
+            // The peer info was updated above
+            VsockOp::VIRTIO_VSOCK_OP_CREDIT_UPDATE => (),
+
+            VsockOp::VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                let hdr = conn.header(self.guest_cid, local_port, VsockOp::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+                self.send_packet(hdr, &[]);
+            }
+
+            _ => log::warn!("Unexpected VirtIO vsock operation {:?} (state {:?})", op, conn.state),
+        }
+    }
+
+    fn send_reset(&mut self, received: &VirtioVsockHdr) {
+        let hdr = VirtioVsockHdr {
+            src_cid: self.guest_cid,
+            dst_cid: received.src_cid,
+            src_port: received.dst_port,
+            dst_port: received.src_port,
+            len: 0,
+            type_: received.type_,
+            op: VsockOp::VIRTIO_VSOCK_OP_RST as u16,
+            flags: 0,
+            buf_alloc: 0,
+            fwd_cnt: 0,
+        };
+        self.send_packet(hdr, &[]);
+    }
+
+    fn send_packet(&mut self, mut hdr: VirtioVsockHdr, data: &[u8]) {
+        assert!(data.len() <= PKT_DATA_SIZE);
+        hdr.len = data.len() as u32;
+
+        let mut packet = VirtioVsockPacket { hdr, ..VirtioVsockPacket::default() };
+        packet.data[..data.len()].copy_from_slice(data);
+
+        unsafe {
+            self.tx
+                .try_push(&[QueueMessage::DevReadOnly { data: packet, len: Some(HDR_SIZE + data.len()) }])
+                .unwrap();
+            self.tx.notify_device();
+        }
+
+        while unsafe { self.tx.try_pop::<VirtioVsockPacket, 1>() }.is_none() {}
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct VirtioVsockHdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct VirtioVsockPacket {
+    hdr: VirtioVsockHdr,
+    data: [u8; PKT_DATA_SIZE],
+}
+
+impl Default for VirtioVsockPacket {
+    fn default() -> Self {
+        let x = MaybeUninit::<Self>::zeroed();
+        unsafe { x.assume_init() }
+    }
+}
+
+impl VirtqSerializable for VirtioVsockPacket {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioVsockEvent {
+    id: u32,
+}
+
+impl VirtqSerializable for VirtioVsockEvent {}
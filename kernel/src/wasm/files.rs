@@ -0,0 +1,95 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+
+use crate::vfs::{FileType, FsError};
+
+use super::Errno;
+
+// File descriptors 0 to 2 are stdin/stdout/stderr, and are not in the table
+pub const STDIN_FD: i32 = 0;
+pub const STDOUT_FD: i32 = 1;
+pub const STDERR_FD: i32 = 2;
+
+// Apps get the whole VFS as a single pre-opened directory, which is how
+// wasi-libc and Rust's std resolve absolute paths
+pub const PREOPEN_FD: i32 = 3;
+pub const PREOPEN_PATH: &str = "/";
+
+#[derive(Clone)]
+pub struct OpenFile {
+    pub path: String,
+    pub file_type: FileType,
+    pub position: u64,
+    pub readable: bool,
+    pub writable: bool,
+    pub append: bool,
+}
+
+pub struct FilesStore {
+    files: BTreeMap<i32, OpenFile>,
+    next_fd: i32,
+}
+
+impl FilesStore {
+    pub fn new() -> Self {
+        let preopen = OpenFile {
+            path: PREOPEN_PATH.to_string(),
+            file_type: FileType::Directory,
+            position: 0,
+            readable: true,
+            writable: false,
+            append: false,
+        };
+
+        Self {
+            files: [(PREOPEN_FD, preopen)].into(),
+            next_fd: PREOPEN_FD + 1,
+        }
+    }
+
+    pub fn add_file(&mut self, file: OpenFile) -> i32 {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        fd
+    }
+
+    pub fn get_file(&self, fd: i32) -> Result<&OpenFile, Errno> {
+        self.files.get(&fd).ok_or(Errno::EBADFS)
+    }
+
+    pub fn get_file_mut(&mut self, fd: i32) -> Result<&mut OpenFile, Errno> {
+        self.files.get_mut(&fd).ok_or(Errno::EBADFS)
+    }
+
+    pub fn close(&mut self, fd: i32) -> Result<(), Errno> {
+        self.files.remove(&fd).map(|_| ()).ok_or(Errno::EBADFS)
+    }
+
+    // Absolute VFS path of a path relative to a directory descriptor
+    pub fn resolve_path(&self, dir_fd: i32, rel_path: &str) -> Result<String, Errno> {
+        let dir = self.get_file(dir_fd)?;
+        if dir.file_type != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        Ok(format!("{}/{}", dir.path, rel_path))
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::DirectoryNotEmpty => Errno::ENOTEMPTY,
+            FsError::InvalidPath => Errno::EINVAL,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::Busy => Errno::EBUSY,
+            FsError::Io => Errno::EIO,
+        }
+    }
+}
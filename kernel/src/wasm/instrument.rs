@@ -0,0 +1,402 @@
+/*
+    Rewrites a WASM module so that every loop iteration decrements a counter, and every
+    YIELD_CHECK_INTERVAL iterations calls a host function through an exported table.
+
+    The host function can then return an error to interrupt the execution, which wasmi
+    turns into a resumable call. New entities (type, table, global) are appended after
+    the existing ones, so that no existing index has to be remapped.
+*/
+
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail};
+use wasmparser::{BinaryReader, FunctionBody, Operator};
+
+pub const YIELD_TABLE_EXPORT: &str = "__yield_table";
+const YIELD_CHECK_INTERVAL: u32 = 1000;
+
+const WASM_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+enum SectionId {
+    Custom = 0,
+    Type = 1,
+    Import = 2,
+    Function = 3,
+    Table = 4,
+    Memory = 5,
+    Global = 6,
+    Export = 7,
+    Start = 8,
+    Element = 9,
+    Code = 10,
+    Data = 11,
+    DataCount = 12,
+    Tag = 13,
+}
+
+// Sections must appear in this order in a module (custom sections excepted)
+fn section_rank(id: u8) -> Option<usize> {
+    const ORDER: [u8; 13] = [1, 2, 3, 4, 5, 13, 6, 7, 8, 9, 12, 10, 11];
+    ORDER.iter().position(|&x| x == id)
+}
+
+struct Section<'a> {
+    id: u8,
+    content: &'a [u8],
+}
+
+struct ModuleCounts {
+    types: u32,
+    imported_tables: u32,
+    imported_globals: u32,
+    tables: u32,
+    globals: u32,
+}
+
+struct NewIndices {
+    type_index: u32,
+    table_index: u32,
+    global_index: u32,
+}
+
+pub fn instrument_yield_points(wasm: &[u8]) -> anyhow::Result<Vec<u8>> {
+
+    if wasm.len() < WASM_HEADER.len() || wasm[..WASM_HEADER.len()] != WASM_HEADER {
+        bail!("Not a WASM module");
+    }
+
+    let sections = read_sections(&wasm[WASM_HEADER.len()..])?;
+    let counts = count_entities(&sections)?;
+
+    let new = NewIndices {
+        type_index: counts.types,
+        table_index: counts.imported_tables + counts.tables,
+        global_index: counts.imported_globals + counts.globals,
+    };
+
+    let mut out = WASM_HEADER.to_vec();
+
+    let mut emitted_table = false;
+    let mut emitted_global = false;
+    let mut emitted_export = false;
+    let mut emitted_type = false;
+
+    for section in sections.iter() {
+
+        if let Some(rank) = section_rank(section.id) {
+
+            // Creating the sections we need if the module doesn't have them
+            let missing = [
+                (SectionId::Type, &mut emitted_type),
+                (SectionId::Table, &mut emitted_table),
+                (SectionId::Global, &mut emitted_global),
+                (SectionId::Export, &mut emitted_export),
+            ];
+            for (id, emitted) in missing {
+                if !*emitted && section_rank(id as u8).unwrap() < rank {
+                    write_section(&mut out, id as u8, &extend_vec_section(&[], &new_entry(id, &new))?);
+                    *emitted = true;
+                }
+            }
+        }
+
+        let content = match section.id {
+            id if id == SectionId::Type as u8 => {
+                emitted_type = true;
+                extend_vec_section(section.content, &new_entry(SectionId::Type, &new))?
+            }
+            id if id == SectionId::Table as u8 => {
+                emitted_table = true;
+                extend_vec_section(section.content, &new_entry(SectionId::Table, &new))?
+            }
+            id if id == SectionId::Global as u8 => {
+                emitted_global = true;
+                extend_vec_section(section.content, &new_entry(SectionId::Global, &new))?
+            }
+            id if id == SectionId::Export as u8 => {
+                emitted_export = true;
+                extend_vec_section(section.content, &new_entry(SectionId::Export, &new))?
+            }
+            id if id == SectionId::Code as u8 => instrument_code_section(section.content, &new)?,
+            _ => section.content.to_vec(),
+        };
+
+        write_section(&mut out, section.id, &content);
+    }
+
+    let trailing = [
+        (SectionId::Type, emitted_type),
+        (SectionId::Table, emitted_table),
+        (SectionId::Global, emitted_global),
+        (SectionId::Export, emitted_export),
+    ];
+    for (id, emitted) in trailing {
+        if !emitted {
+            write_section(&mut out, id as u8, &extend_vec_section(&[], &new_entry(id, &new))?);
+        }
+    }
+
+    Ok(out)
+}
+
+fn new_entry(id: SectionId, new: &NewIndices) -> Vec<u8> {
+    let mut entry = Vec::new();
+    match id {
+        SectionId::Type => {
+            // func type () -> ()
+            entry.extend_from_slice(&[0x60, 0x00, 0x00]);
+        }
+        SectionId::Table => {
+            // funcref table, min 1 max 1
+            entry.extend_from_slice(&[0x70, 0x01, 0x01, 0x01]);
+        }
+        SectionId::Global => {
+            // (mut i32) initialized to YIELD_CHECK_INTERVAL
+            entry.extend_from_slice(&[0x7F, 0x01, 0x41]);
+            write_sleb32(&mut entry, YIELD_CHECK_INTERVAL as i32);
+            entry.push(0x0B);
+        }
+        SectionId::Export => {
+            write_uleb(&mut entry, YIELD_TABLE_EXPORT.len() as u64);
+            entry.extend_from_slice(YIELD_TABLE_EXPORT.as_bytes());
+            entry.push(0x01); // Table export
+            write_uleb(&mut entry, new.table_index as u64);
+        }
+        _ => unreachable!(),
+    }
+    entry
+}
+
+fn yield_check_sequence(new: &NewIndices) -> Vec<u8> {
+    let mut code = Vec::new();
+
+    let g = new.global_index as u64;
+
+    // counter -= 1
+    code.push(0x23); write_uleb(&mut code, g);       // global.get
+    code.extend_from_slice(&[0x41, 0x01, 0x6B]);       // i32.const 1, i32.sub
+    code.push(0x24); write_uleb(&mut code, g);       // global.set
+
+    // if counter == 0
+    code.push(0x23); write_uleb(&mut code, g);       // global.get
+    code.extend_from_slice(&[0x45, 0x04, 0x40]);       // i32.eqz, if (empty block type)
+
+    //   counter = YIELD_CHECK_INTERVAL
+    code.push(0x41); write_sleb32(&mut code, YIELD_CHECK_INTERVAL as i32);
+    code.push(0x24); write_uleb(&mut code, g);
+
+    //   call_indirect yield_table[0]
+    code.extend_from_slice(&[0x41, 0x00, 0x11]);
+    write_uleb(&mut code, new.type_index as u64);
+    write_uleb(&mut code, new.table_index as u64);
+
+    code.push(0x0B); // end
+
+    code
+}
+
+fn instrument_code_section(content: &[u8], new: &NewIndices) -> anyhow::Result<Vec<u8>> {
+
+    let check_code = yield_check_sequence(new);
+
+    let mut pos = 0;
+    let nb_bodies = read_uleb(content, &mut pos)?;
+
+    let mut out = Vec::new();
+    write_uleb(&mut out, nb_bodies);
+
+    for _ in 0..nb_bodies {
+        let body_size = read_uleb(content, &mut pos)? as usize;
+        let body = content
+            .get(pos..pos + body_size)
+            .ok_or(anyhow!("Truncated code section"))?;
+        pos += body_size;
+
+        // Finding the end of every loop instruction (i.e after its block type)
+        let mut insert_points = Vec::new();
+        let func_body = FunctionBody::new(BinaryReader::new(body, 0));
+        let mut ops_reader = func_body.get_operators_reader().map_err(anyhow::Error::msg)?;
+        while !ops_reader.eof() {
+            let (op, _offset) = ops_reader.read_with_offset().map_err(anyhow::Error::msg)?;
+            if let Operator::Loop { .. } = op {
+                insert_points.push(ops_reader.original_position());
+            }
+        }
+
+        let mut new_body = Vec::with_capacity(body.len() + insert_points.len() * check_code.len());
+        let mut last = 0;
+        for p in insert_points {
+            new_body.extend_from_slice(&body[last..p]);
+            new_body.extend_from_slice(&check_code);
+            last = p;
+        }
+        new_body.extend_from_slice(&body[last..]);
+
+        write_uleb(&mut out, new_body.len() as u64);
+        out.extend_from_slice(&new_body);
+    }
+
+    Ok(out)
+}
+
+fn read_sections(data: &[u8]) -> anyhow::Result<Vec<Section>> {
+    let mut sections = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let id = data[pos];
+        pos += 1;
+        let size = read_uleb(data, &mut pos)? as usize;
+        let content = data
+            .get(pos..pos + size)
+            .ok_or(anyhow!("Truncated section {}", id))?;
+        pos += size;
+        sections.push(Section { id, content });
+    }
+
+    Ok(sections)
+}
+
+fn count_entities(sections: &[Section]) -> anyhow::Result<ModuleCounts> {
+
+    let mut counts = ModuleCounts {
+        types: 0,
+        imported_tables: 0,
+        imported_globals: 0,
+        tables: 0,
+        globals: 0,
+    };
+
+    for section in sections {
+        let mut pos = 0;
+        let data = section.content;
+
+        match section.id {
+            id if id == SectionId::Type as u8 => counts.types = read_uleb(data, &mut pos)? as u32,
+            id if id == SectionId::Table as u8 => counts.tables = read_uleb(data, &mut pos)? as u32,
+            id if id == SectionId::Global as u8 => counts.globals = read_uleb(data, &mut pos)? as u32,
+            id if id == SectionId::Import as u8 => {
+                let nb_imports = read_uleb(data, &mut pos)?;
+                for _ in 0..nb_imports {
+                    skip_name(data, &mut pos)?; // module
+                    skip_name(data, &mut pos)?; // field
+                    let kind = read_byte(data, &mut pos)?;
+                    match kind {
+                        // Function: type index
+                        0x00 => { read_uleb(data, &mut pos)?; }
+                        // Table: ref type + limits
+                        0x01 => {
+                            read_byte(data, &mut pos)?;
+                            skip_limits(data, &mut pos)?;
+                            counts.imported_tables += 1;
+                        }
+                        // Memory: limits
+                        0x02 => skip_limits(data, &mut pos)?,
+                        // Global: value type + mutability
+                        0x03 => {
+                            read_byte(data, &mut pos)?;
+                            read_byte(data, &mut pos)?;
+                            counts.imported_globals += 1;
+                        }
+                        // Tag: attribute + type index
+                        0x04 => {
+                            read_byte(data, &mut pos)?;
+                            read_uleb(data, &mut pos)?;
+                        }
+                        _ => bail!("Unknown import kind {:#x}", kind),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(counts)
+}
+
+// Appends an entry to a section made of a vector of entries
+fn extend_vec_section(content: &[u8], entry: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut pos = 0;
+    let count = match content.is_empty() {
+        true => 0,
+        false => read_uleb(content, &mut pos)?,
+    };
+
+    let mut out = Vec::with_capacity(content.len() + entry.len() + 5);
+    write_uleb(&mut out, count + 1);
+    out.extend_from_slice(&content[pos..]);
+    out.extend_from_slice(entry);
+
+    Ok(out)
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    write_uleb(out, content.len() as u64);
+    out.extend_from_slice(content);
+}
+
+fn skip_name(data: &[u8], pos: &mut usize) -> anyhow::Result<()> {
+    let len = read_uleb(data, pos)? as usize;
+    *pos += len;
+    Ok(())
+}
+
+fn skip_limits(data: &[u8], pos: &mut usize) -> anyhow::Result<()> {
+    let flags = read_byte(data, pos)?;
+    read_uleb(data, pos)?;
+    if flags & 0x1 != 0 {
+        read_uleb(data, pos)?;
+    }
+    Ok(())
+}
+
+fn read_byte(data: &[u8], pos: &mut usize) -> anyhow::Result<u8> {
+    let b = *data.get(*pos).ok_or(anyhow!("Unexpected end of WASM data"))?;
+    *pos += 1;
+    Ok(b)
+}
+
+fn read_uleb(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut val = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = read_byte(data, pos)?;
+        val |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            bail!("Invalid LEB128 value");
+        }
+    }
+    Ok(val)
+}
+
+fn write_uleb(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let b = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(b);
+            break;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn write_sleb32(out: &mut Vec<u8>, mut val: i32) {
+    loop {
+        let b = (val & 0x7F) as u8;
+        val >>= 7;
+        let done = (val == 0 && b & 0x40 == 0) || (val == -1 && b & 0x40 != 0);
+        if done {
+            out.push(b);
+            break;
+        }
+        out.push(b | 0x80);
+    }
+}
@@ -1,5 +1,6 @@
 use alloc::collections::BTreeMap;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 use alloc::{format, vec};
 use alloc::{borrow::ToOwned, string::String};
 use applib::content::TrackedContent;
@@ -9,23 +10,142 @@ use applib::content::UuidProvider;
 use core::fmt::Write;
 use core::mem::size_of;
 use smoltcp::iface::SocketHandle;
+use spin::{Mutex, MutexGuard};
 
 use rand::RngCore;
 use smoltcp::wire::Ipv4Address;
+use wasmi::core::HostError;
+use wasmi::errors::{MemoryError, TableError};
 use wasmi::{
-    AsContext, AsContextMut, Caller, Config, Engine, Func, Instance, Linker, Memory, Module, Store,
-    TypedFunc,
+    AsContext, AsContextMut, Caller, Config, Engine, Error, Func, Instance, Linker, Memory, Module, ResourceLimiter,
+    Store, TypedFunc, TypedResumableCall, TypedResumableInvocation,
 };
 
-use applib::{input::InputState, FbViewMut, Framebuffer, Rect};
+use applib::{input::{CursorShape, InputState}, FbViewMut, Framebuffer, Rect};
 
+use crate::logging;
+use crate::memory;
 use crate::serial_println;
+use crate::smp;
 use crate::stats::AppDataPoint;
 use crate::system::System;
+use crate::vfs::{FileType, FsError};
+
+use files::{FilesStore, OpenFile, PREOPEN_FD, PREOPEN_PATH, STDERR_FD, STDIN_FD, STDOUT_FD};
+
+mod files;
+mod instrument;
 
 pub struct WasmEngine;
 
-const STEP_FUEL: u64 = u64::MAX;
+// Fuel budget of an app for a single frame. Once consumed, the step() call is
+// suspended at the next yield check and resumed on the next frame.
+const STEP_FUEL: u64 = 20_000_000;
+
+// Fuel actually given to a call. Running out traps for good, and there is no bound on the
+// work between two yield checks (e.g. deep recursion without loops), so it is never allowed to happen.
+const UNLIMITED_FUEL: u64 = u64::MAX;
+
+// Consecutive suspended frames before an app is shown as not responding
+const NOT_RESPONDING_FRAMES: u32 = 30;
+
+#[derive(Debug)]
+struct StepSuspended;
+
+impl core::fmt::Display for StepSuspended {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "WASM step suspended (fuel budget exhausted)")
+    }
+}
+
+impl HostError for StepSuspended {}
+
+// Why a linear memory growth was refused
+#[derive(Debug, Clone, Copy)]
+enum MemoryDenial {
+    Quota,
+    KernelHeap,
+}
+
+// Enforces the memory quota of an app. Also refuses growths that the kernel heap
+// can't satisfy, since wasmi grows linear memories with infallible allocations.
+struct MemoryLimiter {
+    quota: usize,
+    // Latest denial in the current init() or step() call, reset when a new one starts
+    denied: Option<MemoryDenial>,
+}
+
+impl MemoryLimiter {
+    fn new(quota: usize) -> Self {
+        MemoryLimiter { quota, denied: None }
+    }
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool, MemoryError> {
+
+        // The buddy allocator rounds up to a power of two, and the old buffer
+        // is only freed after the new one is allocated
+        let kernel_free = memory::ALLOCATOR.get_stats().largest_free;
+
+        let denial = if desired > self.quota {
+            Some(MemoryDenial::Quota)
+        } else if desired.next_power_of_two() > kernel_free {
+            Some(MemoryDenial::KernelHeap)
+        } else {
+            None
+        };
+
+        // memory.grow returns -1 in the app, which may or may not cope with it
+        match denial {
+            Some(denial) => {
+                self.denied = Some(denial);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        _desired: u32,
+        _maximum: Option<u32>,
+    ) -> Result<bool, TableError> {
+        Ok(true)
+    }
+}
+
+// Kernel state that apps access through host calls. Apps may be stepped on several
+// cores at once: their WASM code runs in parallel, but host calls are serialized.
+pub struct HostState<'a> {
+    inner: Mutex<HostStateInner<'a>>,
+}
+
+struct HostStateInner<'a> {
+    system: &'a mut System,
+    uuid_provider: &'a mut UuidProvider,
+}
+
+// Safety: the inner state is only accessed with the lock held
+unsafe impl Sync for HostState<'_> {}
+
+impl<'a> HostState<'a> {
+    pub fn new(system: &'a mut System, uuid_provider: &'a mut UuidProvider) -> Self {
+        HostState {
+            inner: Mutex::new(HostStateInner { system, uuid_provider }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<HostStateInner<'a>> {
+        self.inner.lock()
+    }
+}
 
 impl WasmEngine {
     pub fn new() -> Self {
@@ -34,28 +154,47 @@ impl WasmEngine {
 
     pub fn instantiate_app(
         &self,
-        system: &mut System,
-        uuid_provider: &mut UuidProvider,
+        host: &HostState,
         input_state: &InputState,
         wasm_code: &[u8],
         app_name: &str,
+        mem_quota: usize,
         init_rect: &Rect,
-    ) -> WasmApp {
+    ) -> Result<WasmApp, anyhow::Error> {
 
 
         let engine = Engine::new(&Config::default().consume_fuel(true));
 
-        let module = Module::new(&engine, wasm_code).unwrap();
-        let store_data = StoreData::new(uuid_provider, app_name);
+        let wasm_code = instrument::instrument_yield_points(wasm_code)
+            .expect("Failed to instrument WASM app");
+
+        let module = Module::new(&engine, &wasm_code).unwrap();
+        let store_data = StoreData::new(host.lock().uuid_provider, app_name, mem_quota);
         let mut store: Store<StoreData> = Store::new(&engine, store_data);
+        store.limiter(|data| &mut data.mem_limiter);
         let mut linker = <Linker<StoreData>>::new(&engine);
 
         add_host_apis(&mut store, &mut linker);
 
+        // Fails if the initial linear memory is already over the quota
         let instance = linker
             .instantiate(&mut store, &module)
-            .unwrap()
-            .start(&mut store)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|wasm_err| wasm_error(&store, wasm_err))?;
+
+        // Instrumented loops call this function via the yield table
+        let yield_check = Func::wrap(&mut store, |caller: Caller<StoreData>| -> Result<(), Error> {
+            let remaining = caller.get_fuel().expect("Fuel metering disabled");
+            let consumed = caller.data().slice_fuel - remaining;
+            match caller.data().preemptible && consumed >= STEP_FUEL {
+                true => Err(Error::host(StepSuspended)),
+                false => Ok(()),
+            }
+        });
+        instance
+            .get_table(&store, instrument::YIELD_TABLE_EXPORT)
+            .expect("No yield table export")
+            .set(&mut store, 0, yield_check.into())
             .unwrap();
 
         let wasm_init = instance.get_typed_func::<(), ()>(&store, "init").unwrap();
@@ -63,18 +202,46 @@ impl WasmEngine {
 
         let mut store_wrapper = StoreWrapper { store };
 
-        store_wrapper.with_context(system, uuid_provider, input_state, init_rect, |store| {
+        // Init is not time-sliced
+        let init_res = store_wrapper.with_context(host, input_state, init_rect, UNLIMITED_FUEL, |store| {
             log::info!("Initializing {}", app_name);
-            wasm_init
-                .call(store, ())
-                .expect("Failed to initialize WASM app");
+            store.data_mut().mem_limiter.denied = None;
+            wasm_init.call(store, ())
         });
 
-        WasmApp {
+        let mut wasm_app = WasmApp {
             store_wrapper,
             instance,
             wasm_step,
+            suspended: None,
+            suspended_frames: 0,
+        };
+
+        if let Err(wasm_err) = init_res {
+            let err = wasm_error(&wasm_app.store_wrapper.store, wasm_err);
+            wasm_app.release_resources(host.lock().system);
+            return Err(err);
         }
+
+        Ok(wasm_app)
+    }
+}
+
+// A trap following a refused memory growth in the same call is most likely the app
+// aborting on a failed allocation, so it is reported as an out of memory error
+fn wasm_error(store: &Store<StoreData>, wasm_err: Error) -> anyhow::Error {
+    let limiter = &store.data().mem_limiter;
+    match limiter.denied {
+        Some(MemoryDenial::Quota) => anyhow::format_err!(
+            "Out of memory: app exceeded its {} MB quota ({})",
+            limiter.quota / (1024 * 1024),
+            wasm_err
+        ),
+        Some(MemoryDenial::KernelHeap) => anyhow::format_err!(
+            "Out of memory: kernel heap exhausted ({})",
+            wasm_err
+        ),
+        None => anyhow::format_err!(wasm_err),
     }
 }
 
@@ -88,18 +255,53 @@ fn get_wasm_mem_slice<'a>(caller: &'a Caller<StoreData>, addr: i32, len: i32) ->
     &mem_data[addr..addr + len]
 }
 
-fn get_wasm_mem_slice_mut<'a>(
-    caller: &'a mut Caller<StoreData>,
-    addr: i32,
-    len: i32,
-) -> &'a mut [u8] {
+// For ranges given by the app, which get_wasm_mem_slice() would panic on if invalid
+fn check_wasm_mem_range(caller: &Caller<StoreData>, addr: i32, len: i32) -> anyhow::Result<(usize, usize)> {
+    let mem_len = get_linear_memory(caller).data(caller).len();
+    let (addr, len) = (addr as u32 as usize, len as u32 as usize);
+    match addr.checked_add(len) {
+        Some(end) if end <= mem_len => Ok((addr, len)),
+        _ => Err(anyhow::format_err!("Invalid app memory range {:#x} (+{})", addr, len)),
+    }
+}
+
+fn get_wasm_str(caller: &Caller<StoreData>, addr: i32, len: i32) -> Result<String, Errno> {
+    check_wasm_mem_range(caller, addr, len).map_err(|_| Errno::EFAULT)?;
+    let bytes = get_wasm_mem_slice(caller, addr, len);
+    core::str::from_utf8(bytes).map(|s| s.to_owned()).map_err(|_| Errno::EINVAL)
+}
+
+// For WASI output pointers given by the app
+fn write_wasi_out(caller: &mut Caller<StoreData>, addr: i32, data: &[u8]) -> Result<(), Errno> {
+    let (addr, len) = check_wasm_mem_range(caller, addr, data.len() as i32).map_err(|_| Errno::EFAULT)?;
     let mem = get_linear_memory(caller);
+    mem.data_mut(caller)[addr..addr + len].copy_from_slice(data);
+    Ok(())
+}
 
-    let mem_data = mem.data_mut(caller);
-    let len = len as usize;
-    let addr = addr as usize;
+// WASI scatter/gather buffers, as (address, length) pairs, checked against the linear memory
+fn get_iovecs(caller: &Caller<StoreData>, iovs: i32, iovs_len: i32) -> Result<Vec<(usize, usize)>, Errno> {
+    let mem = get_linear_memory(caller);
+    let mem_data = mem.data(caller);
 
-    &mut mem_data[addr..addr + len]
+    let iovs = iovs as u32 as usize;
+    let iovs_end = (iovs_len as u32 as usize)
+        .checked_mul(8)
+        .and_then(|len| len.checked_add(iovs))
+        .filter(|&end| end <= mem_data.len())
+        .ok_or(Errno::EFAULT)?;
+
+    mem_data[iovs..iovs_end]
+        .chunks_exact(8)
+        .map(|iovec| {
+            let addr = u32::from_le_bytes(iovec[0..4].try_into().unwrap()) as usize;
+            let len = u32::from_le_bytes(iovec[4..8].try_into().unwrap()) as usize;
+            match addr.checked_add(len) {
+                Some(end) if end <= mem_data.len() => Ok((addr, len)),
+                _ => Err(Errno::EFAULT),
+            }
+        })
+        .collect()
 }
 
 fn write_to_wasm_mem<'a, T: Sized>(caller: &'a mut Caller<StoreData>, addr: i32, data: &T) {
@@ -129,12 +331,13 @@ struct WasmFramebufferDef {
     w: u32,
 }
 
-struct SocketsStore {
-    sockets: BTreeMap<i32, SocketHandle>,
+// Per-app IDs for kernel socket handles, so that apps can only use their own
+struct SocketsStore<H: Clone> {
+    sockets: BTreeMap<i32, H>,
     next_id: i32,
 }
 
-impl SocketsStore {
+impl<H: Clone> SocketsStore<H> {
     fn new() -> Self {
         Self {
             sockets: BTreeMap::new(),
@@ -142,16 +345,24 @@ impl SocketsStore {
         }
     }
 
-    fn add_handle(&mut self, handle: SocketHandle) -> i32 {
+    fn add_handle(&mut self, handle: H) -> i32 {
         let new_id = self.next_id;
         self.next_id += 1;
         self.sockets.insert(new_id, handle);
         new_id
     }
 
-    fn get_handle(&self, handle_id: i32) -> Option<SocketHandle> {
+    fn get_handle(&self, handle_id: i32) -> Option<H> {
         self.sockets.get(&handle_id).cloned()
     }
+
+    fn remove_handle(&mut self, handle_id: i32) -> Option<H> {
+        self.sockets.remove(&handle_id)
+    }
+
+    fn take_handles(&mut self) -> Vec<H> {
+        core::mem::take(&mut self.sockets).into_values().collect()
+    }
 }
 
 struct StoreWrapper {
@@ -161,28 +372,32 @@ struct StoreWrapper {
 impl StoreWrapper {
     fn with_context<F, T>(
         &mut self,
-        system: &mut System,
-        uuid_provider: &mut UuidProvider,
+        host: &HostState,
         input_state: &InputState,
         win_rect: &Rect,
+        fuel: u64,
         mut func: F,
     ) -> T where
         F: FnMut(&mut Store<StoreData>) -> T,
     {
-        self.store.set_fuel(STEP_FUEL).unwrap();
+        self.store.set_fuel(fuel).unwrap();
+        self.store.data_mut().slice_fuel = fuel;
 
         self.store.as_context_mut().data_mut().step_context = Some(StepContext {
             // reference -> raw pointer conversions here
-            system,
-            uuid_provider,
+            host: (host as *const HostState).cast(),
             input_state,
 
             win_rect: win_rect.clone(),
             timings: BTreeMap::new(),
         });
 
+        logging::set_current_app(Some(&self.store.data().app_name));
+
         let res = func(&mut self.store);
 
+        logging::set_current_app(None);
+
         self.store.as_context_mut().data_mut().step_context = None;
 
         res
@@ -212,16 +427,29 @@ impl StoreWrapper {
 struct StoreData {
     app_name: String,
     framebuffer: Option<WasmFramebufferDef>,
-    sockets_store: SocketsStore,
+    sockets_store: SocketsStore<SocketHandle>,
+    vsock_store: SocketsStore<u32>, // vsock local ports
+    files_store: FilesStore,
     step_context: Option<StepContext>,
     net_recv: usize,
     net_sent: usize,
     console_output: TrackedContent<String>,
+    cursor_shape: CursorShape,
+
+    // Time-slicing state
+    preemptible: bool,
+    slice_fuel: u64,
+    prev_slices_fuel: u64,
+
+    // Time spent running the app, in milliseconds, for the CPU-time clocks
+    cpu_time: f64,
+    step_start_t: f64,
+
+    mem_limiter: MemoryLimiter,
 }
 
 struct StepContext {
-    system: *mut System,
-    uuid_provider: *mut UuidProvider,
+    host: *const HostState<'static>,
     input_state: *const InputState,
     win_rect: Rect,
     timings: BTreeMap<String, u64>,
@@ -238,15 +466,24 @@ struct StepContextView<'a> {
 }
 
 impl StoreData {
-    fn new(uuid_provider: &mut UuidProvider,  app_name: &str) -> Self {
+    fn new(uuid_provider: &mut UuidProvider,  app_name: &str, mem_quota: usize) -> Self {
         StoreData {
             app_name: app_name.to_owned(),
             framebuffer: None,
             sockets_store: SocketsStore::new(),
+            vsock_store: SocketsStore::new(),
+            files_store: FilesStore::new(),
             step_context: None,
             net_recv: 0,
             net_sent: 0,
             console_output: TrackedContent::new(String::new(), uuid_provider),
+            cursor_shape: CursorShape::Arrow,
+            preemptible: false,
+            slice_fuel: 0,
+            prev_slices_fuel: 0,
+            cpu_time: 0.0,
+            step_start_t: 0.0,
+            mem_limiter: MemoryLimiter::new(mem_quota),
         }
     }
 
@@ -259,10 +496,16 @@ impl StoreData {
 
         let step_context = step_context.as_mut().expect("No StepContext set");
 
+        // Safety: thanks to the StoreDataWrapper scope, those pointers should always be valid
+        let host = unsafe { step_context.host.as_ref().unwrap() };
+
+        // Waiting for host calls from apps running on other cores
+        let mut host = host.lock();
+        let HostStateInner { system, uuid_provider } = &mut *host;
+
         let step_context_view = StepContextView {
-            // Safety: thanks to the StoreDataWrapper scope, those pointers should always be valid
-            system: unsafe { step_context.system.as_mut().unwrap() },
-            uuid_provider: unsafe { step_context.uuid_provider.as_mut().unwrap() },
+            system,
+            uuid_provider,
             input_state: unsafe { step_context.input_state.as_ref().unwrap() },
 
             win_rect: &step_context.win_rect,
@@ -279,14 +522,15 @@ pub struct WasmApp {
     store_wrapper: StoreWrapper,
     instance: Instance,
     wasm_step: TypedFunc<(), ()>,
+    suspended: Option<TypedResumableInvocation<()>>,
+    suspended_frames: u32,
 }
 
 impl WasmApp {
 
     pub fn step(
         &mut self,
-        system: &mut System,
-        uuid_provider: &mut UuidProvider,
+        host: &HostState,
         input_state: &InputState,
         win_rect: &Rect,
         is_foreground: bool,
@@ -311,23 +555,63 @@ impl WasmApp {
         //
         // Stepping WASM app
 
-        let t0 = system.clock.time();
+        let t0 = host.lock().system.clock.time();
+
+        let Self { store_wrapper, wasm_step, suspended, .. } = self;
 
-        let step_ret = self.store_wrapper
-            .with_context(system, uuid_provider, &relative_input_state, win_rect, |mut store| {
+        let step_ret = store_wrapper
+            .with_context(host, &relative_input_state, win_rect, UNLIMITED_FUEL, |mut store| {
 
                 store.data_mut().net_recv = 0;
                 store.data_mut().net_sent = 0;
+                store.data_mut().step_start_t = t0;
+
+                if is_paused {
+                    return Ok(());
+                }
 
-                match is_paused {
-                    false => self.wasm_step.call(&mut store, ()),
-                    true => Ok(())
+                store.data_mut().preemptible = true;
+
+                let call_res = match suspended.take() {
+                    Some(invocation) => invocation.resume(&mut store, &[]),
+                    None => {
+                        // A suspended step keeps its denial until it finishes
+                        store.data_mut().prev_slices_fuel = 0;
+                        store.data_mut().mem_limiter.denied = None;
+                        wasm_step.call_resumable(&mut store, ())
+                    },
+                };
+
+                store.data_mut().preemptible = false;
+
+                match call_res? {
+                    TypedResumableCall::Finished(()) => Ok(()),
+                    TypedResumableCall::Resumable(invocation) => {
+                        let host_error = invocation.host_error();
+                        if host_error.downcast_ref::<StepSuspended>().is_none() {
+                            return Err(Error::new(host_error.to_string()));
+                        }
+                        let remaining = store.get_fuel().unwrap();
+                        let data = store.data_mut();
+                        data.prev_slices_fuel += data.slice_fuel - remaining;
+                        *suspended = Some(invocation);
+                        Ok(())
+                    }
                 }
             })
-            .map_err(|wasm_err| anyhow::format_err!(wasm_err));
+            .map_err(|wasm_err| wasm_error(&store_wrapper.store, wasm_err));
+
+        match self.suspended.is_some() {
+            true => self.suspended_frames += 1,
+            false => self.suspended_frames = 0,
+        }
             
+        let mut host = host.lock();
+        let system = &mut *host.system;
+
         let t1 = system.clock.time();
 
+        self.store_wrapper.store.data_mut().cpu_time += t1 - t0;
 
         //
         // Filling app stats
@@ -347,6 +631,7 @@ impl WasmApp {
             net_sent,
             mem_used: mem_size as usize,
             frametime_used: t1 - t0,
+            core: smp::cpu_index(),
         };
 
         step_ret
@@ -359,6 +644,24 @@ impl WasmApp {
     pub fn get_console_output(&self) -> &TrackedContent<String> {
         &self.store_wrapper.store.data().console_output
     }
+
+    pub fn is_responding(&self) -> bool {
+        self.suspended_frames < NOT_RESPONDING_FRAMES
+    }
+
+    pub fn get_cursor_shape(&self) -> CursorShape {
+        self.store_wrapper.store.data().cursor_shape
+    }
+
+    // Closes the connections the app still has open, before it is dropped
+    pub fn release_resources(&mut self, system: &mut System) {
+        let local_ports = self.store_wrapper.store.data_mut().vsock_store.take_handles();
+        if let Some(vsock) = system.vsock.as_mut() {
+            for local_port in local_ports {
+                vsock.close(local_port);
+            }
+        }
+    }
 }
 
 // fn debug_stall(t0: f64, t1: f64, fu0: u64, fu1: u64, store_data: &StoreData) {
@@ -438,28 +741,11 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
 
     let m = "wasi_snapshot_preview1";
 
-    linker_stub!(m, "fd_filestat_set_size", [i32, i64], i32);
-    linker_stub!(m, "fd_read", [i32, i32, i32, i32], i32);
-    linker_stub!(m, "fd_readdir", [i32, i32, i32, i64, i32], i32);
-    linker_stub!(m, "path_create_directory", [i32, i32, i32], i32);
-    linker_stub!(m, "path_filestat_get", [i32, i32, i32, i32, i32], i32);
     linker_stub!(m, "path_link", [i32, i32, i32, i32, i32, i32, i32], i32);
-    linker_stub!(
-        m,
-        "path_open",
-        [i32, i32, i32, i32, i32, i64, i64, i32, i32],
-        i32
-    );
     linker_stub!(m, "path_readlink", [i32, i32, i32, i32, i32, i32], i32);
-    linker_stub!(m, "path_remove_directory", [i32, i32, i32], i32);
     linker_stub!(m, "path_rename", [i32, i32, i32, i32, i32, i32], i32);
-    linker_stub!(m, "path_unlink_file", [i32, i32, i32], i32);
     linker_stub!(m, "poll_oneoff", [i32, i32, i32, i32], i32);
     linker_stub!(m, "sched_yield", [], i32);
-    linker_stub!(m, "fd_close", [i32], i32);
-    linker_stub!(m, "fd_filestat_get", [i32, i32], i32);
-    linker_stub!(m, "fd_prestat_dir_name", [i32, i32, i32], i32);
-    linker_stub!(m, "fd_sync", [i32], i32);
     linker_stub!(
         m,
         "path_filestat_set_times",
@@ -473,15 +759,6 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
 
     linker_stub!(m, "args_get", [i32, i32], i32, Errno::SUCCESS as i32);
     linker_stub!(m, "proc_exit", [i32], (), ());
-    linker_stub!(m, "fd_fdstat_get", [i32, i32], i32, Errno::EBADFS as i32);
-    linker_stub!(
-        m,
-        "fd_seek",
-        [i32, i64, i32, i32],
-        i32,
-        Errno::EBADFS as i32
-    );
-    linker_stub!(m, "fd_prestat_get", [i32, i32], i32, Errno::EBADFS as i32);
 
     //
     // WASMI implementations
@@ -500,10 +777,28 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
             precision
         );
 
-        let t = caller.data_mut().with_step_context(|step_context| {
-            (step_context.system.clock.time() * 1e9) as u64 // Not sure about the 1e9
+        let data = caller.data_mut();
+        let cpu_time = data.cpu_time;
+        let step_start_t = data.step_start_t;
+
+        // Nanoseconds
+        let t = data.with_step_context(|step_context| {
+            let clock = &step_context.system.clock;
+            match clock_id {
+                CLOCK_REALTIME => Some(clock.wall_time_ns()),
+                CLOCK_MONOTONIC => Some(clock.monotonic_ns()),
+                CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+                    let ms = cpu_time + clock.time() - step_start_t;
+                    Some((ms * 1e6) as u64)
+                }
+                _ => None,
+            }
         });
 
+        let Some(t) = t else {
+            return Errno::EINVAL as i32;
+        };
+
         let mem = get_linear_memory(&caller);
         let mem_data = mem.data_mut(&mut caller);
 
@@ -615,30 +910,460 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
         0
     });
 
+    //
+    // WASI filesystem, backed by the kernel VFS
+
+    linker_impl!(m, "fd_prestat_get", |mut caller: Caller<StoreData>,
+                                       fd: i32,
+                                       prestat: i32|
+     -> i32 {
+        if fd != PREOPEN_FD {
+            return Errno::EBADFS as i32;
+        }
+
+        // Tag 0 (directory), then the length of its name
+        let mut buf = [0u8; 8];
+        buf[4..8].copy_from_slice(&(PREOPEN_PATH.len() as u32).to_le_bytes());
+        errno_result(write_wasi_out(&mut caller, prestat, &buf))
+    });
+
+    linker_impl!(m, "fd_prestat_dir_name", |mut caller: Caller<StoreData>,
+                                            fd: i32,
+                                            path: i32,
+                                            path_len: i32|
+     -> i32 {
+        if fd != PREOPEN_FD {
+            return Errno::EBADFS as i32;
+        }
+
+        let len = usize::min(path_len as u32 as usize, PREOPEN_PATH.len());
+        errno_result(write_wasi_out(&mut caller, path, &PREOPEN_PATH.as_bytes()[..len]))
+    });
+
+    linker_impl!(m, "path_open", |mut caller: Caller<StoreData>,
+                                  dir_fd: i32,
+                                  _dirflags: i32,
+                                  path: i32,
+                                  path_len: i32,
+                                  oflags: i32,
+                                  rights_base: i64,
+                                  _rights_inheriting: i64,
+                                  fdflags: i32,
+                                  fd_out: i32|
+     -> i32 {
+        let mut try_open = || -> Result<(), Errno> {
+            let rel_path = get_wasm_str(&caller, path, path_len)?;
+            let path = caller.data().files_store.resolve_path(dir_fd, &rel_path)?;
+
+            log::debug!("Function path_open() called (path {} oflags {:#x})", path, oflags);
+
+            let file_type = caller.data_mut().with_step_context(|step_context| -> Result<FileType, Errno> {
+                let vfs = &mut step_context.system.vfs;
+                match vfs.metadata(&path) {
+                    Ok(_) if oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_EXCL != 0 => Err(Errno::EEXIST),
+                    Ok(metadata) => match metadata.file_type {
+                        FileType::File if oflags & OFLAGS_DIRECTORY != 0 => Err(Errno::ENOTDIR),
+                        FileType::Directory if oflags & OFLAGS_TRUNC != 0 => Err(Errno::EISDIR),
+                        FileType::File if oflags & OFLAGS_TRUNC != 0 => {
+                            vfs.set_len(&path, 0)?;
+                            Ok(FileType::File)
+                        }
+                        file_type => Ok(file_type),
+                    },
+                    Err(FsError::NotFound) if oflags & OFLAGS_CREAT != 0 => {
+                        vfs.create(&path, FileType::File)?;
+                        Ok(FileType::File)
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            })?;
+
+            let rights_base = rights_base as u64;
+            let fd = caller.data_mut().files_store.add_file(OpenFile {
+                path,
+                file_type,
+                position: 0,
+                readable: rights_base & RIGHTS_FD_READ != 0,
+                writable: rights_base & RIGHTS_FD_WRITE != 0,
+                append: fdflags & FDFLAGS_APPEND != 0,
+            });
+
+            write_wasi_out(&mut caller, fd_out, &(fd as u32).to_le_bytes())?;
+
+            Ok(())
+        };
+
+        errno_result(try_open())
+    });
+
+    linker_impl!(m, "fd_close", |mut caller: Caller<StoreData>, fd: i32| -> i32 {
+        errno_result(caller.data_mut().files_store.close(fd))
+    });
+
+    linker_impl!(m, "fd_read", |mut caller: Caller<StoreData>,
+                                fd: i32,
+                                iovs: i32,
+                                iovs_len: i32,
+                                nread: i32|
+     -> i32 {
+        let mut try_read = || -> Result<(), Errno> {
+            let iovecs = get_iovecs(&caller, iovs, iovs_len)?;
+            let total_len: usize = iovecs.iter().map(|(_, len)| len).sum();
+            let total_len = usize::min(total_len, MAX_READ_LEN);
+
+            let data = match fd {
+                // Apps have no input stream
+                STDIN_FD => Vec::new(),
+                fd => {
+                    let file = caller.data().files_store.get_file(fd)?.clone();
+                    if file.file_type == FileType::Directory {
+                        return Err(Errno::EISDIR);
+                    }
+                    if !file.readable {
+                        return Err(Errno::EBADFS);
+                    }
+
+                    let mut buf = vec![0u8; total_len];
+                    let read_len = caller.data_mut().with_step_context(|step_context| {
+                        step_context.system.vfs.read(&file.path, file.position, &mut buf)
+                    })?;
+                    buf.truncate(read_len);
+
+                    caller.data_mut().files_store.get_file_mut(fd)?.position += read_len as u64;
+
+                    buf
+                }
+            };
+
+            let mem = get_linear_memory(&caller);
+            let mem_data = mem.data_mut(&mut caller);
+
+            let mut done = 0;
+            for (addr, len) in iovecs {
+                let n = usize::min(len, data.len() - done);
+                mem_data[addr..addr + n].copy_from_slice(&data[done..done + n]);
+                done += n;
+            }
+
+            let nread = nread as u32 as usize;
+            mem_data
+                .get_mut(nread..nread + 4)
+                .ok_or(Errno::EFAULT)?
+                .copy_from_slice(&(data.len() as u32).to_le_bytes());
+
+            Ok(())
+        };
+
+        errno_result(try_read())
+    });
+
     linker_impl!(m, "fd_write", |mut caller: Caller<StoreData>,
-                                 _fd: i32,
+                                 fd: i32,
                                  iovs: i32,
-                                 _iovs_len: i32,
+                                 iovs_len: i32,
                                  nwritten: i32|
      -> i32 {
         //log::debug!("Function fd_write() called (fd {} iovs_len {})", fd, iovs_len);
 
-        let mem = get_linear_memory(&caller);
-        let mem_data = mem.data_mut(&mut caller);
+        let mut try_write = || -> Result<(), Errno> {
+            let data: Vec<u8> = get_iovecs(&caller, iovs, iovs_len)?
+                .into_iter()
+                .flat_map(|(addr, len)| get_wasm_mem_slice(&caller, addr as i32, len as i32).to_vec())
+                .collect();
+
+            match fd {
+                STDOUT_FD | STDERR_FD => log::debug!("{}", String::from_utf8_lossy(&data)),
+                fd => {
+                    let file = caller.data().files_store.get_file(fd)?.clone();
+                    if file.file_type == FileType::Directory {
+                        return Err(Errno::EISDIR);
+                    }
+                    if !file.writable {
+                        return Err(Errno::EBADFS);
+                    }
+
+                    let position = caller.data_mut().with_step_context(|step_context| {
+                        let vfs = &mut step_context.system.vfs;
+                        let offset = match file.append {
+                            true => vfs.metadata(&file.path)?.size,
+                            false => file.position,
+                        };
+                        let written_len = vfs.write(&file.path, offset, &data)?;
+                        Ok::<u64, FsError>(offset + written_len as u64)
+                    })?;
+
+                    caller.data_mut().files_store.get_file_mut(fd)?.position = position;
+                }
+            }
 
-        let iovs = iovs as usize;
-        let nwritten = nwritten as usize;
+            write_wasi_out(&mut caller, nwritten, &(data.len() as u32).to_le_bytes())?;
 
-        let buf_ptr = u32::from_le_bytes(mem_data[iovs..iovs + 4].try_into().unwrap()) as usize;
-        let buf_len = u32::from_le_bytes(mem_data[iovs + 4..iovs + 8].try_into().unwrap()) as usize;
+            Ok(())
+        };
 
-        let s = core::str::from_utf8(&mem_data[buf_ptr..buf_ptr + buf_len]).unwrap();
+        errno_result(try_write())
+    });
 
-        log::debug!("{}", s);
+    linker_impl!(m, "fd_seek", |mut caller: Caller<StoreData>,
+                                fd: i32,
+                                offset: i64,
+                                whence: i32,
+                                new_offset: i32|
+     -> i32 {
+        let mut try_seek = || -> Result<(), Errno> {
+            if let STDIN_FD | STDOUT_FD | STDERR_FD = fd {
+                return Err(Errno::ESPIPE);
+            }
 
-        mem_data[nwritten..nwritten + 4].copy_from_slice((buf_len as u32).to_le_bytes().as_slice());
+            let file = caller.data().files_store.get_file(fd)?.clone();
 
-        0
+            let base = match whence {
+                WHENCE_SET => 0,
+                WHENCE_CUR => file.position,
+                WHENCE_END => caller
+                    .data_mut()
+                    .with_step_context(|step_context| step_context.system.vfs.metadata(&file.path))?
+                    .size,
+                _ => return Err(Errno::EINVAL),
+            };
+
+            let position = (base as i64)
+                .checked_add(offset)
+                .filter(|&position| position >= 0)
+                .ok_or(Errno::EINVAL)? as u64;
+
+            caller.data_mut().files_store.get_file_mut(fd)?.position = position;
+            write_wasi_out(&mut caller, new_offset, &position.to_le_bytes())?;
+
+            Ok(())
+        };
+
+        errno_result(try_seek())
+    });
+
+    linker_impl!(m, "fd_fdstat_get", |mut caller: Caller<StoreData>,
+                                      fd: i32,
+                                      fdstat: i32|
+     -> i32 {
+        let mut try_fdstat = || -> Result<(), Errno> {
+            let (filetype, flags, rights) = match fd {
+                STDIN_FD | STDOUT_FD | STDERR_FD => (FILETYPE_CHARACTER_DEVICE, 0, RIGHTS_ALL),
+                fd => {
+                    let file = caller.data().files_store.get_file(fd)?;
+
+                    // Everything is allowed, except reading or writing if the file wasn't opened for it
+                    let mut rights = RIGHTS_ALL & !(RIGHTS_FD_READ | RIGHTS_FD_WRITE);
+                    if file.readable {
+                        rights |= RIGHTS_FD_READ;
+                    }
+                    if file.writable {
+                        rights |= RIGHTS_FD_WRITE;
+                    }
+
+                    let flags = if file.append { FDFLAGS_APPEND as u16 } else { 0 };
+
+                    (wasi_filetype(file.file_type), flags, rights)
+                }
+            };
+
+            let mut buf = [0u8; 24];
+            buf[0] = filetype;
+            buf[2..4].copy_from_slice(&flags.to_le_bytes());
+            buf[8..16].copy_from_slice(&rights.to_le_bytes());
+            buf[16..24].copy_from_slice(&RIGHTS_ALL.to_le_bytes());
+            write_wasi_out(&mut caller, fdstat, &buf)?;
+
+            Ok(())
+        };
+
+        errno_result(try_fdstat())
+    });
+
+    linker_impl!(m, "fd_filestat_get", |mut caller: Caller<StoreData>,
+                                        fd: i32,
+                                        filestat: i32|
+     -> i32 {
+        let mut try_filestat = || -> Result<(), Errno> {
+            let buf = match fd {
+                STDIN_FD | STDOUT_FD | STDERR_FD => filestat_bytes(FILETYPE_CHARACTER_DEVICE, 0, 0),
+                fd => {
+                    let path = caller.data().files_store.get_file(fd)?.path.clone();
+                    let metadata = caller
+                        .data_mut()
+                        .with_step_context(|step_context| step_context.system.vfs.metadata(&path))?;
+                    filestat_bytes(wasi_filetype(metadata.file_type), metadata.size, metadata.modified)
+                }
+            };
+
+            write_wasi_out(&mut caller, filestat, &buf)?;
+
+            Ok(())
+        };
+
+        errno_result(try_filestat())
+    });
+
+    linker_impl!(m, "path_filestat_get", |mut caller: Caller<StoreData>,
+                                          dir_fd: i32,
+                                          _flags: i32,
+                                          path: i32,
+                                          path_len: i32,
+                                          filestat: i32|
+     -> i32 {
+        let mut try_filestat = || -> Result<(), Errno> {
+            let rel_path = get_wasm_str(&caller, path, path_len)?;
+            let path = caller.data().files_store.resolve_path(dir_fd, &rel_path)?;
+
+            let metadata = caller
+                .data_mut()
+                .with_step_context(|step_context| step_context.system.vfs.metadata(&path))?;
+
+            let buf = filestat_bytes(wasi_filetype(metadata.file_type), metadata.size, metadata.modified);
+            write_wasi_out(&mut caller, filestat, &buf)?;
+
+            Ok(())
+        };
+
+        errno_result(try_filestat())
+    });
+
+    linker_impl!(m, "fd_filestat_set_size", |mut caller: Caller<StoreData>,
+                                             fd: i32,
+                                             size: i64|
+     -> i32 {
+        let mut try_set_size = || -> Result<(), Errno> {
+            let file = caller.data().files_store.get_file(fd)?.clone();
+            if !file.writable {
+                return Err(Errno::EBADFS);
+            }
+
+            caller
+                .data_mut()
+                .with_step_context(|step_context| step_context.system.vfs.set_len(&file.path, size as u64))?;
+
+            Ok(())
+        };
+
+        errno_result(try_set_size())
+    });
+
+    linker_impl!(m, "fd_readdir", |mut caller: Caller<StoreData>,
+                                   fd: i32,
+                                   buf: i32,
+                                   buf_len: i32,
+                                   cookie: i64,
+                                   bufused: i32|
+     -> i32 {
+        let mut try_readdir = || -> Result<(), Errno> {
+            let file = caller.data().files_store.get_file(fd)?.clone();
+            if file.file_type != FileType::Directory {
+                return Err(Errno::ENOTDIR);
+            }
+
+            let entries = caller
+                .data_mut()
+                .with_step_context(|step_context| step_context.system.vfs.read_dir(&file.path))?;
+
+            // Cookies are entry indices. If the last entry is truncated, the app
+            // calls again with a bigger buffer.
+            let buf_len = buf_len as u32 as usize;
+            let mut data = Vec::new();
+            for (i, entry) in entries.iter().enumerate().skip(cookie as usize) {
+                if data.len() >= buf_len {
+                    break;
+                }
+
+                data.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+                data.extend_from_slice(&0u64.to_le_bytes()); // Inode
+                data.extend_from_slice(&(entry.name.len() as u32).to_le_bytes());
+                data.extend_from_slice(&[wasi_filetype(entry.file_type), 0, 0, 0]);
+                data.extend_from_slice(entry.name.as_bytes());
+            }
+            data.truncate(buf_len);
+
+            write_wasi_out(&mut caller, buf, &data)?;
+            write_wasi_out(&mut caller, bufused, &(data.len() as u32).to_le_bytes())?;
+
+            Ok(())
+        };
+
+        errno_result(try_readdir())
+    });
+
+    linker_impl!(m, "path_create_directory", |mut caller: Caller<StoreData>,
+                                              dir_fd: i32,
+                                              path: i32,
+                                              path_len: i32|
+     -> i32 {
+        let mut try_create = || -> Result<(), Errno> {
+            let rel_path = get_wasm_str(&caller, path, path_len)?;
+            let path = caller.data().files_store.resolve_path(dir_fd, &rel_path)?;
+
+            caller
+                .data_mut()
+                .with_step_context(|step_context| step_context.system.vfs.create(&path, FileType::Directory))?;
+
+            Ok(())
+        };
+
+        errno_result(try_create())
+    });
+
+    linker_impl!(m, "path_unlink_file", |mut caller: Caller<StoreData>,
+                                         dir_fd: i32,
+                                         path: i32,
+                                         path_len: i32|
+     -> i32 {
+        let mut try_unlink = || -> Result<(), Errno> {
+            let rel_path = get_wasm_str(&caller, path, path_len)?;
+            let path = caller.data().files_store.resolve_path(dir_fd, &rel_path)?;
+
+            caller.data_mut().with_step_context(|step_context| {
+                let vfs = &mut step_context.system.vfs;
+                match vfs.metadata(&path)?.file_type {
+                    FileType::Directory => Err(Errno::EISDIR),
+                    FileType::File => Ok(vfs.remove(&path)?),
+                }
+            })
+        };
+
+        errno_result(try_unlink())
+    });
+
+    linker_impl!(m, "path_remove_directory", |mut caller: Caller<StoreData>,
+                                              dir_fd: i32,
+                                              path: i32,
+                                              path_len: i32|
+     -> i32 {
+        let mut try_remove = || -> Result<(), Errno> {
+            let rel_path = get_wasm_str(&caller, path, path_len)?;
+            let path = caller.data().files_store.resolve_path(dir_fd, &rel_path)?;
+
+            caller.data_mut().with_step_context(|step_context| {
+                let vfs = &mut step_context.system.vfs;
+                match vfs.metadata(&path)?.file_type {
+                    FileType::File => Err(Errno::ENOTDIR),
+                    FileType::Directory => Ok(vfs.remove(&path)?),
+                }
+            })
+        };
+
+        errno_result(try_remove())
+    });
+
+    linker_impl!(m, "fd_sync", |mut caller: Caller<StoreData>, fd: i32| -> i32 {
+        let mut try_sync = || -> Result<(), Errno> {
+            caller.data().files_store.get_file(fd)?;
+
+            // The VFS can only flush everything
+            caller
+                .data_mut()
+                .with_step_context(|step_context| step_context.system.vfs.flush())?;
+
+            Ok(())
+        };
+
+        errno_result(try_sync())
     });
 
 
@@ -698,6 +1423,17 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
         }
     );
 
+    linker_impl!(
+        m,
+        "host_set_cursor_shape",
+        |mut caller: Caller<StoreData>, shape: i32| {
+            match CursorShape::n(shape as u32) {
+                Some(shape) => caller.data_mut().cursor_shape = shape,
+                None => log::warn!("Unknown cursor shape {}", shape),
+            }
+        }
+    );
+
     linker_impl!(m, "host_tcp_connect", |mut caller: Caller<StoreData>,
                                          ip_addr: i32,
                                          port: i32|
@@ -848,6 +1584,121 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
         }
     );
 
+    linker_impl!(m, "host_vsock_connect", |mut caller: Caller<StoreData>,
+                                           cid: i32,
+                                           port: i32|
+     -> i32 {
+        let mut try_connect = || -> anyhow::Result<i32> {
+            let local_port = caller.data_mut().with_step_context(|step_context| {
+                match step_context.system.vsock.as_mut() {
+                    Some(vsock) => vsock.connect(cid as u32 as u64, port as u32),
+                    None => Err(anyhow::format_err!("No vsock device")),
+                }
+            })?;
+
+            let handle_id = caller.data_mut().vsock_store.add_handle(local_port);
+            Ok(handle_id)
+        };
+
+        match try_connect() {
+            Ok(handle_id) => handle_id,
+            Err(err) => {
+                log::error!("{}", err);
+                -1
+            }
+        }
+    });
+
+    linker_impl!(m, "host_vsock_write", |mut caller: Caller<StoreData>,
+                                         addr: i32,
+                                         len: i32,
+                                         handle_id: i32|
+     -> i32 {
+        let mut try_write = || -> anyhow::Result<usize> {
+            check_wasm_mem_range(&caller, addr, len)?;
+            let buf = get_wasm_mem_slice(&mut caller, addr, len).to_vec();
+
+            let local_port = caller
+                .data_mut()
+                .vsock_store
+                .get_handle(handle_id)
+                .ok_or(anyhow::format_err!("No vsock connection {}", handle_id))?;
+
+            caller.data_mut().with_step_context(|step_context| {
+                match step_context.system.vsock.as_mut() {
+                    Some(vsock) => vsock.write(local_port, &buf),
+                    None => Err(anyhow::format_err!("No vsock device")),
+                }
+            })
+        };
+
+        match try_write() {
+            Ok(written_len) => written_len as i32,
+            Err(err) => {
+                log::error!("{}", err);
+                -1
+            }
+        }
+    });
+
+    linker_impl!(m, "host_vsock_read", |mut caller: Caller<StoreData>,
+                                        addr: i32,
+                                        len: i32,
+                                        handle_id: i32|
+     -> i32 {
+        let mut try_read = || -> anyhow::Result<i32> {
+            let (addr, len) = check_wasm_mem_range(&caller, addr, len)?;
+            let len = usize::min(len, MAX_VSOCK_READ_LEN);
+
+            let mut buf = vec![0u8; len];
+
+            let local_port = caller
+                .data_mut()
+                .vsock_store
+                .get_handle(handle_id)
+                .ok_or(anyhow::format_err!("No vsock connection {}", handle_id))?;
+
+            let read_len = caller.data_mut().with_step_context(|step_context| {
+                match step_context.system.vsock.as_mut() {
+                    Some(vsock) => vsock.read(local_port, &mut buf),
+                    None => Err(anyhow::format_err!("No vsock device")),
+                }
+            })?;
+
+            let mem = get_linear_memory(&caller);
+            let mem_data = mem.data_mut(&mut caller);
+
+            mem_data[addr..addr + read_len].copy_from_slice(&buf[..read_len]);
+
+            Ok(read_len as i32)
+        };
+
+        match try_read() {
+            Ok(read_len) => read_len,
+            Err(err) => {
+                log::error!("{}", err);
+                -1
+            }
+        }
+    });
+
+    linker_impl!(
+        m,
+        "host_vsock_close",
+        |mut caller: Caller<StoreData>, handle_id: i32| {
+            let Some(local_port) = caller.data_mut().vsock_store.remove_handle(handle_id) else {
+                log::error!("No vsock connection {}", handle_id);
+                return;
+            };
+
+            caller.data_mut().with_step_context(|step_context| {
+                if let Some(vsock) = step_context.system.vsock.as_mut() {
+                    vsock.close(local_port)
+                }
+            })
+        }
+    );
+
     linker_impl!(
         m,
         "host_get_time",
@@ -883,7 +1734,8 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
         "host_get_consumed_fuel",
         |mut caller: Caller<StoreData>, consumed_addr: i32| {
             let remaining = caller.get_fuel().expect("Fuel metering disabled");
-            let consumed = STEP_FUEL - remaining;
+            let data = caller.data();
+            let consumed = data.prev_slices_fuel + data.slice_fuel - remaining;
             write_to_wasm_mem(&mut caller, consumed_addr, &consumed.to_le_bytes());
         }
     );
@@ -908,6 +1760,37 @@ fn add_host_apis(mut store: &mut Store<StoreData>, linker: &mut Linker<StoreData
         }
     );
 
+    linker_impl!(
+        m,
+        "host_read_log",
+        |mut caller: Caller<StoreData>, since_seq: i64, buf_addr: i32, buf_len: i32| -> i32 {
+
+            let (buf_addr, buf_len) = match check_wasm_mem_range(&caller, buf_addr, buf_len) {
+                Ok(range) => range,
+                Err(err) => {
+                    log::error!("{}", err);
+                    return -1;
+                }
+            };
+
+            // Only writing whole records; the app calls again with the next sequence number
+            let mut data = Vec::new();
+            logging::read_records(since_seq as u64, |record| {
+                if data.len() + record.serialized_len() > buf_len {
+                    return false;
+                }
+                record.serialize(&mut data);
+                true
+            });
+
+            let mem = get_linear_memory(&caller);
+            let mem_data = mem.data_mut(&mut caller);
+            mem_data[buf_addr..buf_addr + data.len()].copy_from_slice(&data);
+
+            data.len() as i32
+        }
+    );
+
     linker_impl!(
         m,
         "host_qemu_dump",
@@ -933,19 +1816,96 @@ fn log_message(msg: &str, level: i32, step_context: &mut StepContextView) {
     console_output.write_str(&msg).unwrap();
     console_output.write_char('\n').unwrap();
 
-    match level {
-        1 => log::error!("{}", msg),
-        2 => log::warn!("{}", msg),
-        3 => log::info!("{}", msg),
-        4 => log::debug!("{}", msg),
-        _ => log::trace!("{}", msg),
+    // guestlib's logger formats messages as "<module> -- <text>"
+    let (target, text) = msg.split_once(" -- ").unwrap_or(("wasm", msg));
+
+    let level = match level {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
     };
 
+    log::log!(target: target, level, "{}", text);
+
 }
 
 #[repr(i32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Errno {
     SUCCESS = 0,
     EBADFS = 8,
+    EBUSY = 10,
+    EEXIST = 20,
+    EFAULT = 21,
+    EINVAL = 28,
+    EIO = 29,
+    EISDIR = 31,
+    ENOENT = 44,
+    ENOSPC = 51,
+    ENOTDIR = 54,
+    ENOTEMPTY = 55,
+    EROFS = 69,
+    ESPIPE = 70,
+}
+
+fn errno_result(res: Result<(), Errno>) -> i32 {
+    match res {
+        Ok(()) => Errno::SUCCESS as i32,
+        Err(errno) => errno as i32,
+    }
+}
+
+// The vsock driver doesn't buffer more than this per connection anyway
+const MAX_VSOCK_READ_LEN: usize = 64 * 1024;
+
+// fd_read() returns less than asked beyond this, which WASI allows
+const MAX_READ_LEN: usize = 1024 * 1024;
+
+// WASI clock IDs
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME_ID: i32 = 2;
+const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+
+// WASI path_open flags
+const OFLAGS_CREAT: i32 = 1 << 0;
+const OFLAGS_DIRECTORY: i32 = 1 << 1;
+const OFLAGS_EXCL: i32 = 1 << 2;
+const OFLAGS_TRUNC: i32 = 1 << 3;
+const FDFLAGS_APPEND: i32 = 1 << 0;
+
+// WASI rights
+const RIGHTS_FD_READ: u64 = 1 << 1;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+const RIGHTS_ALL: u64 = (1 << 30) - 1;
+
+// WASI file types
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+// WASI fd_seek whence
+const WHENCE_SET: i32 = 0;
+const WHENCE_CUR: i32 = 1;
+const WHENCE_END: i32 = 2;
+
+fn wasi_filetype(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => FILETYPE_REGULAR_FILE,
+        FileType::Directory => FILETYPE_DIRECTORY,
+    }
+}
+
+// WASI filestat: device, inode, file type, link count, size, then access/modification/change times
+fn filestat_bytes(filetype: u8, size: u64, time: u64) -> [u8; 64] {
+    let mut buf = [0u8; 64];
+    buf[16] = filetype;
+    buf[24..32].copy_from_slice(&1u64.to_le_bytes());
+    buf[32..40].copy_from_slice(&size.to_le_bytes());
+    for offset in [40, 48, 56] {
+        buf[offset..offset + 8].copy_from_slice(&time.to_le_bytes());
+    }
+    buf
 }
@@ -14,6 +14,7 @@ WASM_APPS = [
     "terminal",
     "web_browser",
     "demo",
+    "log_viewer",
 ]
 
 CRATE_PATHS = [
@@ -29,7 +30,12 @@ def main():
     parser = argparse.ArgumentParser()
     subparsers = parser.add_subparsers(dest="cmd", required=True)
     subparsers.add_parser("build")
-    subparsers.add_parser("run")
+    run_parser = subparsers.add_parser("run")
+    run_parser.add_argument("--q35", action="store_true", help="Emulate a Q35 machine, with devices behind PCIe root ports")
+    run_parser.add_argument("--disk", help="Raw disk image to attach as a VirtIO block device")
+    run_parser.add_argument("--share", help="Host directory to share with the OS over VirtIO 9P, mounted at /host")
+    run_parser.add_argument("--tablet", action="store_true", help="Use a VirtIO tablet instead of a mouse, so that QEMU doesn't grab the host pointer")
+    run_parser.add_argument("--vsock", action="store_true", help="Add a VirtIO vsock device (guest CID 3), needs access to /dev/vhost-vsock")
     subparsers.add_parser("fmt")
     subparsers.add_parser("fix")
     args = parser.parse_args()
@@ -38,7 +44,7 @@ def main():
         _build()
     elif args.cmd == "run":
         _build()
-        _run()
+        _run(q35=args.q35, disk=args.disk, share=args.share, tablet=args.tablet, vsock=args.vsock)
     elif args.cmd == "fmt":
         _fmt()
     elif args.cmd == "fix":
@@ -72,9 +78,10 @@ def _build():
     )
 
     _copy_if_new(kernel_bin_path, Path("esp/efi/boot/") / "bootx64.efi")
+    _copy_if_new(Path("config.ini"), Path("esp/") / "config.ini")
 
 
-def _run():
+def _run(q35=False, disk=None, share=None, tablet=False, vsock=False):
 
     #
     # Running QEMU
@@ -83,6 +90,7 @@ def _run():
         [
             "-enable-kvm",
             "-m 1G",
+            "-smp 4",
             "-rtc base=utc",
             "-display sdl",
 
@@ -92,14 +100,29 @@ def _run():
             "-drive format=raw,file=fat:rw:esp",
 
             # VirtIO peripherals
-            "-device virtio-keyboard",
-            "-device virtio-mouse",
-            "-device virtio-net-pci,netdev=network0 -netdev user,id=network0",
+            *(_q35_devices(tablet, vsock) if q35 else [
+                "-device virtio-keyboard",
+                "-device virtio-tablet" if tablet else "-device virtio-mouse",
+                "-device virtio-net-pci,netdev=network0",
+                "-device virtio-rng-pci",
+                "-device virtio-serial-pci",
+                *(["-device vhost-vsock-pci,guest-cid=3"] if vsock else []),
+            ]),
+            "-netdev user,id=network0",
+            *([f"-drive if=virtio,format=raw,file={disk}"] if disk is not None else []),
+            *([f"-virtfs local,path={share},mount_tag=host,security_model=none"] if share is not None else []),
             "-vga virtio",
 
+            # Kernel console ports, reachable with e.g. nc localhost 1236
+            "-chardev socket,id=console_log,host=localhost,port=1236,server=on,wait=off",
+            "-device virtserialport,chardev=console_log,name=log",
+            "-chardev socket,id=console_shell,host=localhost,port=1237,server=on,wait=off",
+            "-device virtserialport,chardev=console_shell,name=shell",
+
             # Debugging
             "-monitor stdio",
             "-serial file:log.txt",
+            "-serial tcp::1235,server=on,wait=off",  # GDB stub
             #"--trace \"virt*\"",
             # "-object filter-dump,id=f1,netdev=network0,file=dump.dat",
         ]
@@ -111,6 +134,23 @@ def _run():
         sys.exit(1)
 
 
+def _q35_devices(tablet, vsock):
+    # One root port per device, to exercise PCI bridge enumeration
+    devices = [
+        "virtio-keyboard-pci",
+        "virtio-tablet-pci" if tablet else "virtio-mouse-pci",
+        "virtio-net-pci,netdev=network0",
+        "virtio-rng-pci",
+        "virtio-serial-pci",
+        *(["vhost-vsock-pci,guest-cid=3"] if vsock else []),
+    ]
+    args = ["-machine q35"]
+    for i, device in enumerate(devices):
+        args.append(f"-device pcie-root-port,id=root_port{i},chassis={i + 1}")
+        args.append(f"-device {device},bus=root_port{i}")
+    return args
+
+
 def _fmt():
     for crate_path in CRATE_PATHS:
         subprocess.check_call("cargo fmt", shell=True, cwd=crate_path)
@@ -0,0 +1,290 @@
+extern crate alloc;
+
+use alloc::collections::{BTreeSet, VecDeque};
+use applib::content::{ContentId, TrackedContent};
+use applib::drawing::text::{RichText, DEFAULT_FONT_FAMILY};
+use applib::logging::LogRecord;
+use applib::uitk::{self, ChoiceButtonsConfig, ChoiceConfig, TextBoxState, UuidProvider};
+use applib::{Color, Rect, StyleSheet};
+use core::cell::OnceCell;
+use guestlib::{PixelData, WasmLogger};
+
+// Records kept by the app, and max number of (filtered) records displayed
+const MAX_RECORDS: usize = 2000;
+const MAX_DISPLAYED: usize = 300;
+
+const ROW_H: u32 = 80;
+const SEARCH_ROW_H: u32 = 30;
+
+const LEVEL_CHOICES: [(&str, log::LevelFilter); 5] = [
+    ("All", log::LevelFilter::Trace),
+    ("Error", log::LevelFilter::Error),
+    ("Warn", log::LevelFilter::Warn),
+    ("Info", log::LevelFilter::Info),
+    ("Debug", log::LevelFilter::Debug),
+];
+
+struct AppState {
+    pixel_data: PixelData,
+    ui_store: uitk::UiStore,
+    uuid_provider: UuidProvider,
+
+    records: VecDeque<LogRecord>,
+    next_seq: u64,
+    app_names: BTreeSet<String>,
+
+    selected_level: usize,
+    // By name, since new app names can be inserted before it
+    selected_app: AppFilter,
+
+    search: TrackedContent<String>,
+    search_state: TextBoxState,
+    log_state: TextBoxState,
+}
+
+static mut APP_STATE: OnceCell<AppState> = OnceCell::new();
+
+static LOGGER: WasmLogger = WasmLogger;
+const LOGGING_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+
+fn main() {}
+
+#[no_mangle]
+pub fn init() -> () {
+    log::set_max_level(LOGGING_LEVEL);
+    log::set_logger(&LOGGER).unwrap();
+
+    let mut uuid_provider = uitk::UuidProvider::new();
+
+    let state = AppState {
+        pixel_data: PixelData::new(),
+        ui_store: uitk::UiStore::new(),
+
+        records: VecDeque::new(),
+        next_seq: 0,
+        app_names: BTreeSet::new(),
+
+        selected_level: 0,
+        selected_app: AppFilter::All,
+
+        search: TrackedContent::new(String::new(), &mut uuid_provider),
+        search_state: TextBoxState::new(),
+        log_state: TextBoxState::new(),
+
+        uuid_provider,
+    };
+    unsafe {
+        APP_STATE
+            .set(state)
+            .unwrap_or_else(|_| panic!("App already initialized"));
+    }
+}
+
+#[no_mangle]
+pub fn step() {
+    let state = unsafe { APP_STATE.get_mut().expect("App not initialized") };
+
+    //
+    // Fetching new log records (no logging from this app in here, or it would feed itself)
+
+    for record in guestlib::read_log(state.next_seq) {
+        state.next_seq = record.seq + 1;
+        if let Some(app_name) = &record.app_name {
+            if !state.app_names.contains(app_name) {
+                state.app_names.insert(app_name.clone());
+            }
+        }
+        if state.records.len() >= MAX_RECORDS {
+            state.records.pop_front();
+        }
+        state.records.push_back(record);
+    }
+
+    //
+    // UI
+
+    let time = guestlib::get_time();
+    let stylesheet = guestlib::get_stylesheet();
+    let input_state = guestlib::get_input_state();
+    let Rect { w, h, .. } = guestlib::get_win_rect();
+
+    let mut framebuffer = state.pixel_data.get_framebuffer();
+
+    let mut uitk_context = state.ui_store.get_context(
+        &mut framebuffer,
+        &stylesheet,
+        &input_state,
+        &mut state.uuid_provider,
+        time
+    );
+
+    let mut y = 0;
+
+    // Level filter
+
+    uitk_context.section(
+        &Rect { x0: 0, y0: y, w, h: ROW_H },
+        "Level",
+        |context, inner_rect| context.choice_buttons_exclusive(
+            &ChoiceButtonsConfig {
+                rect: inner_rect.clone(),
+                choices: LEVEL_CHOICES.iter().map(|(text, _)| ChoiceConfig {
+                    text: text.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            },
+            &mut state.selected_level
+        )
+    );
+
+    y += ROW_H as i64;
+
+    // App filter
+
+    let app_choices: Vec<String> = ["All", "Kernel"]
+        .iter()
+        .map(|s| s.to_string())
+        .chain(state.app_names.iter().cloned())
+        .collect();
+
+    let mut selected_app_index = match &state.selected_app {
+        AppFilter::All => 0,
+        AppFilter::Kernel => 1,
+        AppFilter::App(app_name) => 2 + state.app_names.iter().position(|name| name == app_name).unwrap(),
+    };
+
+    uitk_context.section(
+        &Rect { x0: 0, y0: y, w, h: ROW_H },
+        "App",
+        |context, inner_rect| context.choice_buttons_exclusive(
+            &ChoiceButtonsConfig {
+                rect: inner_rect.clone(),
+                choices: app_choices.iter().map(|text| ChoiceConfig {
+                    text: text.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            },
+            &mut selected_app_index
+        )
+    );
+
+    state.selected_app = match selected_app_index {
+        0 => AppFilter::All,
+        1 => AppFilter::Kernel,
+        i => AppFilter::App(app_choices[i].clone()),
+    };
+
+    y += ROW_H as i64;
+
+    // Search
+
+    let search_rect = Rect { x0: 0, y0: y, w, h: SEARCH_ROW_H };
+    uitk_context.editable_text_box(
+        &search_rect,
+        &mut state.search,
+        &mut state.search_state,
+        false,
+        false,
+        None::<&TrackedContent<String>>,
+    );
+
+    y += SEARCH_ROW_H as i64;
+
+    // Log records
+
+    let filter = RecordFilter {
+        max_level: LEVEL_CHOICES[state.selected_level].1,
+        app: &state.selected_app,
+        search: state.search.as_ref(),
+    };
+
+    let log_text = {
+        let rich_text = format_records(&stylesheet, &state.records, &filter);
+        let content_id = ContentId::from_hash(&(
+            state.next_seq,
+            state.selected_level,
+            &state.selected_app,
+            state.search.get_id(),
+        ));
+        TrackedContent::new_with_id(rich_text, content_id)
+    };
+
+    let log_rect = Rect { x0: 0, y0: y, w, h: h.saturating_sub(y as u32) };
+    uitk_context.text_box(&log_rect, &log_text, &mut state.log_state, true);
+}
+
+#[derive(Hash)]
+enum AppFilter {
+    All,
+    Kernel,
+    App(String),
+}
+
+struct RecordFilter<'a> {
+    max_level: log::LevelFilter,
+    app: &'a AppFilter,
+    search: &'a str,
+}
+
+impl<'a> RecordFilter<'a> {
+    fn matches(&self, record: &LogRecord) -> bool {
+
+        let level_ok = record.level <= self.max_level;
+
+        let app_ok = match (self.app, &record.app_name) {
+            (AppFilter::All, _) => true,
+            (AppFilter::Kernel, app_name) => app_name.is_none(),
+            (AppFilter::App(filter_name), Some(app_name)) => filter_name == app_name,
+            (AppFilter::App(_), None) => false,
+        };
+
+        let search_ok = self.search.is_empty()
+            || record.message.contains(self.search)
+            || record.module.contains(self.search);
+
+        level_ok && app_ok && search_ok
+    }
+}
+
+fn format_records(stylesheet: &StyleSheet, records: &VecDeque<LogRecord>, filter: &RecordFilter) -> RichText {
+
+    let font = DEFAULT_FONT_FAMILY.get_default();
+
+    let mut rich_text = RichText::new();
+
+    let matching: Vec<&LogRecord> = records.iter().filter(|record| filter.matches(record)).collect();
+    let skipped = matching.len().saturating_sub(MAX_DISPLAYED);
+
+    for record in matching.into_iter().skip(skipped) {
+
+        let level_color = match record.level {
+            log::Level::Error => Color::rgb(230, 60, 60),
+            log::Level::Warn => stylesheet.colors.yellow,
+            log::Level::Info => stylesheet.colors.green,
+            log::Level::Debug | log::Level::Trace => Color::rgb(150, 150, 150),
+        };
+
+        let source = match &record.app_name {
+            Some(app_name) => format!("[{}] {}", app_name, record.module),
+            None => record.module.clone(),
+        };
+
+        rich_text.add_part(&format_timestamp(record.timestamp), Color::rgb(150, 150, 150), font);
+        rich_text.add_part(&format!(" {:<5} ", record.level), level_color, font);
+        rich_text.add_part(&source, Color::AQUA, font);
+        rich_text.add_part(&format!(" {}\n", record.message), stylesheet.colors.text, font);
+    }
+
+    rich_text
+}
+
+// Time of day (UTC) from a UNIX timestamp in milliseconds
+fn format_timestamp(timestamp: f64) -> String {
+    let ms_in_day = (timestamp as u64) % (24 * 3600 * 1000);
+    let (h, rem) = (ms_in_day / 3_600_000, ms_in_day % 3_600_000);
+    let (m, rem) = (rem / 60_000, rem % 60_000);
+    let (s, ms) = (rem / 1000, rem % 1000);
+    format!("{:02}:{:02}:{:02}.{:03}", h, m, s, ms)
+}
@@ -10,7 +10,7 @@ use applib::drawing::text::{
     draw_rich_slice, format_rich_lines, FontFamily, FormattedRichText, RichText, DEFAULT_FONT_FAMILY
 };
 use applib::input::InputEvent;
-use applib::input::{InputState, Keycode};
+use applib::input::{CursorShape, InputState, Keycode};
 use applib::uitk::{self, UiStore, UuidProvider, TextBoxState, EditableRichText};
 use applib::{Color, FbViewMut, Rect, StyleSheet};
 use core::cell::OnceCell;
@@ -61,6 +61,10 @@ pub fn init() -> () {
         textbox_state: TextBoxState::new(),
         python: python::Python::new(),
     };
+
+    // The whole window is a text box
+    guestlib::set_cursor_shape(CursorShape::IBeam);
+
     unsafe {
         APP_STATE
             .set(state)